@@ -1,56 +1,20 @@
-use std::fs;
+use std::env;
 
-use esp_idf_part::{AppType, DataType, Partition, PartitionTable, SubType, Type};
-
-// const LITTLEFS_SUBTYPE: u8 = 0x83;
+use partition_gen::{Filesystem, TableSpec};
 
 fn main() -> eyre::Result<()> {
     embuild::espidf::sysenv::output();
 
-    let partitions = vec![
-        Partition::new(
-            "nvs",
-            Type::Data,
-            SubType::Data(DataType::Nvs),
-            0x9_000, // the first 0x8000 bytes (32KB) of the partition are reserved for the bootloader,
-            // then another 0x1000 bytes (4KB) for the partition table,
-            // so the first partition must start at 0x9000
-            0x6_000, // Data partitions must be multiples of 0x1000 (4KB)
-            false,
-        ),
-        Partition::new(
-            "phy_init",
-            Type::Data,
-            SubType::Data(DataType::Phy),
-            0xf_000,
-            0x1_000,
-            false,
-        ),
-        Partition::new(
-            "factory",
-            Type::App,
-            SubType::App(AppType::Factory),
-            0x10_000,  // App offsets must be multiples of 0x10000 (64KB)
-            0x200_000, // 2MB factory partition
-            false,
-        ),
-        Partition::new(
-            "fs",
-            Type::Data,
-            SubType::Data(DataType::Fat),
-            0x210_000, // App offsets must be multiples of 0x10000 (64KB)
-            0x100_000, // 1MB filesystem partition
-            false,
-        ),
-    ];
-
-    let table = PartitionTable::new(partitions);
-
-    let csv = table.to_csv()?;
-    let bin = table.to_bin()?;
+    let filesystem = if env::var_os("CARGO_FEATURE_LITTLEFS").is_some() {
+        Filesystem::LittleFs
+    } else {
+        Filesystem::Fat
+    };
 
-    fs::write("./partition/4mb.csv", csv)?;
-    fs::write("./partition/4mb.bin", bin)?;
+    // Two app slots for OTA updates, see partition_gen for overriding the layout. Devices
+    // flashed with the factory table keep their NVS, the fs image has to be moved over by hand,
+    // see `TableSpec::ota`.
+    partition_gen::generate(|flash_size| TableSpec::ota(flash_size, filesystem))?;
 
     Ok(())
 }
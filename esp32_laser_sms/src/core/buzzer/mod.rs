@@ -0,0 +1,214 @@
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::{Duration, Instant},
+};
+
+use esp_idf_svc::{hal::ledc::LedcDriver, sys};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    core::device_state::SendSyncDeviceStateService,
+    util::{
+        ffi::esp::esp_unsafe,
+        result::{bail, Result},
+        sync::{arc_sync_rw_lock, Arc, ArcSyncRwLock},
+    },
+};
+
+pub use laser_sms_core::buzzer::{alarm, pattern, Pattern};
+
+use alarm::AlarmTimer;
+
+const PLAYER_TASK_STACK_SIZE: usize = 4 * 1024;
+/// Short enough for the siren sweep to sound smooth
+const PLAYER_TICK: Duration = Duration::from_millis(20);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct BuzzerSettings {
+    #[serde(default)]
+    pub pattern: Pattern,
+    /// 0 to 100
+    #[serde(default = "default_volume")]
+    pub volume: u8,
+    /// Keeps alarming this long after the beam is restored
+    #[serde(default)]
+    pub latch_secs: u32,
+    /// Silences an alarm that has gone on this long, 0 never silences
+    #[serde(default)]
+    pub max_duration_secs: u32,
+}
+
+fn default_volume() -> u8 {
+    80
+}
+
+impl Default for BuzzerSettings {
+    fn default() -> Self {
+        Self {
+            pattern: Pattern::default(),
+            volume: default_volume(),
+            latch_secs: 0,
+            max_duration_secs: 0,
+        }
+    }
+}
+
+impl BuzzerSettings {
+    pub fn validate(&self) -> Result<()> {
+        if self.volume > 100 {
+            bail!("Volume must be between 0 and 100");
+        }
+
+        Ok(())
+    }
+
+    fn latch(&self) -> Duration {
+        Duration::from_secs(self.latch_secs.into())
+    }
+
+    fn max_duration(&self) -> Option<Duration> {
+        (self.max_duration_secs > 0).then(|| Duration::from_secs(self.max_duration_secs.into()))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Config {
+    enabled: bool,
+    settings: BuzzerSettings,
+}
+
+/// Tells the player whether the alarm condition holds, it does the rest on its own task
+#[derive(Clone)]
+pub struct BuzzerHandle {
+    triggered: Arc<AtomicBool>,
+}
+
+impl BuzzerHandle {
+    pub fn set_triggered(&self, triggered: bool) {
+        self.triggered.store(triggered, Ordering::Relaxed);
+    }
+}
+
+/// Plays the configured pattern on a piezo buzzer driven by an LEDC channel.
+/// Pitch changes retune the LEDC timer, volume is the duty cycle.
+pub struct Buzzer;
+
+impl Buzzer {
+    /// `speed_mode` and `timer` are the ones the driver's timer was created with
+    pub fn start(
+        driver: LedcDriver<'static>,
+        speed_mode: sys::ledc_mode_t,
+        timer: sys::ledc_timer_t,
+        dev_svc: SendSyncDeviceStateService,
+    ) -> Result<BuzzerHandle> {
+        let triggered = Arc::new(AtomicBool::new(false));
+
+        let config = {
+            let mut dvc = dev_svc.lock();
+            let config = arc_sync_rw_lock(Config {
+                enabled: dvc.buzzer_enabled(),
+                settings: *dvc.buzzer(),
+            });
+
+            dvc.subscribe({
+                let config = config.clone();
+                move |_, new| {
+                    *config.write() = Config {
+                        enabled: new.buzzer_enabled,
+                        settings: new.buzzer,
+                    };
+                }
+            });
+
+            config
+        };
+
+        let player = Player {
+            driver,
+            speed_mode,
+            timer,
+            playing: None,
+        };
+
+        thread::Builder::new()
+            .name("buzzer".into())
+            .stack_size(PLAYER_TASK_STACK_SIZE)
+            .spawn({
+                let triggered = triggered.clone();
+                move || run(player, triggered, config)
+            })?;
+
+        Ok(BuzzerHandle { triggered })
+    }
+}
+
+struct Player {
+    driver: LedcDriver<'static>,
+    speed_mode: sys::ledc_mode_t,
+    timer: sys::ledc_timer_t,
+    /// Frequency and volume being played, `None` while silent
+    playing: Option<(u32, u8)>,
+}
+
+impl Player {
+    fn play(&mut self, frequency_hz: Option<u32>, volume: u8) -> Result<()> {
+        let next = frequency_hz
+            .filter(|_| volume > 0)
+            .map(|frequency_hz| (frequency_hz, volume));
+        if next == self.playing {
+            return Ok(());
+        }
+
+        match next {
+            None => self.driver.set_duty(0)?,
+            Some((frequency_hz, volume)) => {
+                if self.playing.map(|(playing_hz, _)| playing_hz) != Some(frequency_hz) {
+                    esp_unsafe!(sys::ledc_set_freq(
+                        self.speed_mode,
+                        self.timer,
+                        frequency_hz
+                    ))?;
+                }
+
+                // A piezo is loudest at half duty
+                let duty = self.driver.get_max_duty() / 2 * volume as u32 / 100;
+                self.driver.set_duty(duty)?;
+            }
+        }
+
+        self.playing = next;
+
+        Ok(())
+    }
+}
+
+fn run(mut player: Player, triggered: Arc<AtomicBool>, config: ArcSyncRwLock<Config>) {
+    let started = Instant::now();
+    let mut current = *config.read();
+    let mut alarm = AlarmTimer::new(current.settings.latch(), current.settings.max_duration());
+
+    loop {
+        thread::sleep(PLAYER_TICK);
+
+        let latest = *config.read();
+        if latest != current {
+            alarm.set_limits(latest.settings.latch(), latest.settings.max_duration());
+            current = latest;
+        }
+
+        let elapsed = if current.enabled {
+            alarm.update(started.elapsed(), triggered.load(Ordering::Relaxed))
+        } else {
+            alarm.reset();
+            None
+        };
+
+        let frequency_hz =
+            elapsed.and_then(|elapsed| current.settings.pattern.frequency_at(elapsed));
+
+        if let Err(e) = player.play(frequency_hz, current.settings.volume) {
+            tracing::error!("Buzzer error: {:?}", e);
+        }
+    }
+}
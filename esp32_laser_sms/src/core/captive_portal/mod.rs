@@ -0,0 +1,180 @@
+use std::{
+    io::ErrorKind,
+    net::{Ipv4Addr, SocketAddrV4, UdpSocket},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+use crate::util::result::Result;
+
+pub const DNS_PORT: u16 = 53;
+
+/// Paths requested by Android, iOS/macOS and Windows to detect a captive portal.
+/// Redirecting these to the setup page makes the OS pop it up automatically.
+pub const CONNECTIVITY_CHECK_PATHS: &[&str] = &[
+    "/generate_204",
+    "/gen_204",
+    "/hotspot-detect.html",
+    "/library/test/success.html",
+    "/connecttest.txt",
+    "/ncsi.txt",
+    "/redirect",
+    "/canonical.html",
+    "/success.txt",
+];
+
+const DNS_TASK_STACK_SIZE: usize = 8 * 1024;
+const MAX_PACKET_SIZE: usize = 512;
+const POLL_INTERVAL: Duration = Duration::from_millis(500);
+const ANSWER_TTL_SECS: u32 = 60;
+
+const HEADER_LEN: usize = 12;
+const FLAG_RESPONSE: u16 = 0x8000;
+const FLAG_AUTHORITATIVE: u16 = 0x0400;
+const FLAG_RECURSION_DESIRED: u16 = 0x0100;
+const FLAG_RECURSION_AVAILABLE: u16 = 0x0080;
+const TYPE_A: u16 = 1;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+
+/// A tiny DNS server that resolves every name to the AP address so clients
+/// connected to the setup AP always land on the configuration page.
+/// The server task is stopped when this is dropped.
+pub struct CaptivePortal {
+    ip: Ipv4Addr,
+    running: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl CaptivePortal {
+    pub fn start(ip: Ipv4Addr) -> Result<Self> {
+        let socket = UdpSocket::bind(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, DNS_PORT))?;
+        // The timeout lets the task notice when it has been asked to stop
+        socket.set_read_timeout(Some(POLL_INTERVAL))?;
+
+        let running = Arc::new(AtomicBool::new(true));
+
+        let handle = thread::Builder::new()
+            .name("captive-dns".into())
+            .stack_size(DNS_TASK_STACK_SIZE)
+            .spawn({
+                let running = running.clone();
+                move || serve(socket, ip, running)
+            })?;
+
+        tracing::info!("Captive portal DNS started, resolving to {}", ip);
+
+        Ok(Self {
+            ip,
+            running,
+            handle: Some(handle),
+        })
+    }
+
+    pub fn ip(&self) -> Ipv4Addr {
+        self.ip
+    }
+}
+
+impl Drop for CaptivePortal {
+    fn drop(&mut self) {
+        self.running.store(false, Ordering::Relaxed);
+
+        if let Some(handle) = self.handle.take() {
+            _ = handle.join();
+        }
+
+        tracing::info!("Captive portal DNS stopped");
+    }
+}
+
+fn serve(socket: UdpSocket, ip: Ipv4Addr, running: Arc<AtomicBool>) {
+    let mut buff = [0u8; MAX_PACKET_SIZE];
+
+    while running.load(Ordering::Relaxed) {
+        let (read, peer) = match socket.recv_from(&mut buff) {
+            Ok(received) => received,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => continue,
+            Err(e) => {
+                tracing::error!("Captive portal DNS receive failed: {:?}", e);
+                continue;
+            }
+        };
+
+        let Some(response) = answer(&buff[..read], ip) else {
+            continue;
+        };
+
+        if let Err(e) = socket.send_to(&response, peer) {
+            tracing::error!("Captive portal DNS send failed: {:?}", e);
+        }
+    }
+}
+
+/// Builds the response to a DNS query, resolving A (and ANY) questions to `ip`.
+/// Other record types get an empty answer so clients fall back to IPv4.
+/// Returns `None` for packets that aren't a single-question standard query.
+pub fn answer(query: &[u8], ip: Ipv4Addr) -> Option<Vec<u8>> {
+    let header = query.get(..HEADER_LEN)?;
+
+    let flags = u16::from_be_bytes([header[2], header[3]]);
+    let opcode = (flags >> 11) & 0xF;
+    let question_count = u16::from_be_bytes([header[4], header[5]]);
+
+    if flags & FLAG_RESPONSE != 0 || opcode != 0 || question_count != 1 {
+        return None;
+    }
+
+    // Walk the QNAME labels, compression pointers are not valid in a query
+    let mut pos = HEADER_LEN;
+    loop {
+        let len = *query.get(pos)? as usize;
+        pos += 1;
+
+        if len == 0 {
+            break;
+        }
+
+        if len & 0xC0 != 0 {
+            return None;
+        }
+
+        pos += len;
+    }
+
+    let qtype = u16::from_be_bytes([*query.get(pos)?, *query.get(pos + 1)?]);
+    let qclass = u16::from_be_bytes([*query.get(pos + 2)?, *query.get(pos + 3)?]);
+    let question = &query[HEADER_LEN..pos + 4];
+
+    let has_answer = matches!(qtype, TYPE_A | TYPE_ANY) && qclass == CLASS_IN;
+
+    let flags = FLAG_RESPONSE
+        | FLAG_AUTHORITATIVE
+        | FLAG_RECURSION_AVAILABLE
+        | (flags & FLAG_RECURSION_DESIRED);
+
+    let mut response = Vec::with_capacity(HEADER_LEN + question.len() + 16);
+    response.extend_from_slice(&header[..2]); // Transaction ID
+    response.extend_from_slice(&flags.to_be_bytes());
+    response.extend_from_slice(&1u16.to_be_bytes()); // Questions
+    response.extend_from_slice(&(has_answer as u16).to_be_bytes()); // Answers
+    response.extend_from_slice(&0u16.to_be_bytes()); // Authority records
+    response.extend_from_slice(&0u16.to_be_bytes()); // Additional records
+    response.extend_from_slice(question);
+
+    if has_answer {
+        // Name is a pointer to the QNAME in the question section
+        response.extend_from_slice(&[0xC0, HEADER_LEN as u8]);
+        response.extend_from_slice(&TYPE_A.to_be_bytes());
+        response.extend_from_slice(&CLASS_IN.to_be_bytes());
+        response.extend_from_slice(&ANSWER_TTL_SECS.to_be_bytes());
+        response.extend_from_slice(&4u16.to_be_bytes());
+        response.extend_from_slice(&ip.octets());
+    }
+
+    Some(response)
+}
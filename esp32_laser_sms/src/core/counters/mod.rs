@@ -0,0 +1,109 @@
+use metrics_registry::{Counter, CounterVec, Metric};
+use serde::Serialize;
+
+/// Distinct route and status pairs kept for `/metrics`
+const HTTP_SERIES: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NotificationChannel {
+    Sms,
+}
+
+impl NotificationChannel {
+    pub const ALL: [NotificationChannel; 1] = [NotificationChannel::Sms];
+
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            NotificationChannel::Sms => "sms",
+        }
+    }
+}
+
+/// Event counts since boot, cheap enough to bump from any task
+pub struct Counters {
+    sensor_trips: Counter,
+    notifications: CounterVec<2, 8>,
+    http_requests: CounterVec<2, HTTP_SERIES>,
+}
+
+pub static COUNTERS: Counters = Counters::new();
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct CountersSnapshot {
+    pub sensor_trips: u32,
+    pub notifications_sent: u32,
+    pub notifications_failed: u32,
+}
+
+impl Counters {
+    pub const fn new() -> Self {
+        Self {
+            sensor_trips: Counter::new(
+                "laser_sensor_trips_total",
+                "Times the beam was cut while the alarm was active",
+            ),
+            notifications: CounterVec::new(
+                "laser_notifications_total",
+                "Notifications sent or failed, by channel",
+                ["channel", "result"],
+            ),
+            http_requests: CounterVec::new(
+                "laser_http_requests_total",
+                "HTTP requests handled, by route and status",
+                ["route", "status"],
+            ),
+        }
+    }
+
+    pub fn record_sensor_trip(&self) {
+        self.sensor_trips.inc();
+    }
+
+    pub fn record_notification(&self, channel: NotificationChannel, sent: bool) {
+        let result = if sent { "sent" } else { "failed" };
+
+        self.notifications.inc([channel.as_str(), result]);
+    }
+
+    pub fn record_http_request(&self, route: &'static str, status: u16) {
+        self.http_requests.inc([route, status_label(status)]);
+    }
+
+    pub fn snapshot(&self) -> CountersSnapshot {
+        let notifications = |result| {
+            NotificationChannel::ALL
+                .iter()
+                .map(|channel| self.notifications.get([channel.as_str(), result]))
+                .sum::<u32>()
+        };
+
+        CountersSnapshot {
+            sensor_trips: self.sensor_trips.get(),
+            notifications_sent: notifications("sent"),
+            notifications_failed: notifications("failed"),
+        }
+    }
+
+    pub fn metrics(&'static self) -> [&'static dyn Metric; 3] {
+        [&self.sensor_trips, &self.notifications, &self.http_requests]
+    }
+}
+
+impl Default for Counters {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Label values have to be `'static`, statuses the server doesn't send are grouped
+fn status_label(status: u16) -> &'static str {
+    match status {
+        200 => "200",
+        204 => "204",
+        302 => "302",
+        400 => "400",
+        404 => "404",
+        500 => "500",
+        _ => "other",
+    }
+}
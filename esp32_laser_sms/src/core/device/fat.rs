@@ -0,0 +1,59 @@
+use std::ptr::addr_of_mut;
+
+use esp_idf_svc::sys;
+
+use crate::util::{ffi::esp::esp_unsafe, result::Result};
+
+use super::{StorageUsage, BASE_PATH, MAX_FILES, PARTITION_LABEL};
+
+static mut WL_HANDLE: i32 = sys::WL_INVALID_HANDLE;
+
+/// Mounts FAT with wear levelling, formatting the partition if it doesn't hold one yet
+pub fn mount() -> Result<()> {
+    esp_unsafe!(sys::esp_vfs_fat_spiflash_mount_rw_wl(
+        BASE_PATH.as_ptr(),
+        PARTITION_LABEL.as_ptr(),
+        &sys::esp_vfs_fat_mount_config_t {
+            format_if_mount_failed: true,
+            max_files: MAX_FILES as i32,
+            allocation_unit_size: sys::CONFIG_WL_SECTOR_SIZE as usize,
+            disk_status_check_enable: false,
+        },
+        addr_of_mut!(WL_HANDLE),
+    ))?;
+
+    Ok(())
+}
+
+pub fn unmount() -> Result<()> {
+    esp_unsafe!(sys::esp_vfs_fat_spiflash_unmount_rw_wl(
+        BASE_PATH.as_ptr(),
+        WL_HANDLE,
+    ))?;
+
+    Ok(())
+}
+
+pub fn format() -> Result<()> {
+    esp_unsafe!(sys::esp_vfs_fat_spiflash_format_rw_wl(
+        BASE_PATH.as_ptr(),
+        PARTITION_LABEL.as_ptr(),
+    ))?;
+
+    Ok(())
+}
+
+pub fn usage() -> Result<StorageUsage> {
+    let mut total_bytes = 0;
+    let mut free_bytes = 0;
+    esp_unsafe!(sys::esp_vfs_fat_info(
+        BASE_PATH.as_ptr(),
+        &mut total_bytes,
+        &mut free_bytes,
+    ))?;
+
+    Ok(StorageUsage {
+        total_bytes,
+        used_bytes: total_bytes - free_bytes,
+    })
+}
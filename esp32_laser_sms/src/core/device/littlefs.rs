@@ -0,0 +1,531 @@
+use std::{
+    ffi::{CStr, CString},
+    fs,
+    path::{Path, PathBuf},
+    ptr,
+    sync::Mutex,
+};
+
+use esp_idf_svc::sys::{self, littlefs};
+use scopeguard::{guard, ScopeGuard};
+use sha2::{Digest, Sha256};
+
+use crate::util::{
+    ffi::esp::esp_unsafe,
+    result::{bail, error, Result},
+};
+
+use super::{StorageUsage, BASE_PATH, MAX_FILES, PARTITION_LABEL};
+
+/// Where the old FAT filesystem is mounted while its files are copied out
+const LEGACY_FAT_BASE_PATH: &CStr = c"/fatold";
+const LEGACY_FAT_ROOT: &str = "/fatold";
+
+/// Files are held in RAM while they're copied, a FAT filesystem holding more isn't migrated
+const MIGRATION_MAX_BYTES: usize = 64 * 1024;
+
+/// Starts a copy of the FAT files staged in the inactive app slot
+const STAGE_MAGIC: [u8; 8] = *b"FAT2LFS1";
+/// Magic, payload length and the payload's SHA-256
+const STAGE_HEADER_LEN: usize = STAGE_MAGIC.len() + 4 + 32;
+const FLASH_SECTOR_SIZE: usize = 0x1000;
+
+/// Set while a migration is refused and `/spiflash` is the old FAT filesystem
+static LEGACY_FAT: Mutex<Option<LegacyFat>> = Mutex::new(None);
+
+fn conf(format_if_mount_failed: bool) -> littlefs::esp_vfs_littlefs_conf_t {
+    let mut conf = littlefs::esp_vfs_littlefs_conf_t {
+        base_path: BASE_PATH.as_ptr(),
+        partition_label: PARTITION_LABEL.as_ptr(),
+        ..Default::default()
+    };
+    conf.set_format_if_mount_failed(format_if_mount_failed as u8);
+
+    conf
+}
+
+/// Mounts LittleFS. The first time this runs on a partition that still holds the FAT
+/// filesystem of an older build, its files are copied over before it's reformatted.
+///
+/// The files are staged in the inactive app slot before the partition is touched and only
+/// dropped from there once they're all restored, losing power at any point leaves either
+/// the FAT filesystem or the staged copy to pick up from on the next boot. The slot holds
+/// the image a new one rolls back to, so nothing is migrated until the running image is
+/// confirmed. Until then, or when the migration is refused, e.g. for a FAT filesystem
+/// holding more than [`MIGRATION_MAX_BYTES`], the FAT filesystem is mounted as it is.
+pub fn mount() -> Result<()> {
+    match read_staged() {
+        Ok(Some(entries)) => {
+            tracing::warn!("Finishing a migration from FAT that was cut short");
+
+            esp_unsafe!(littlefs::esp_vfs_littlefs_register(&conf(true)))?;
+            finish_migration(&entries);
+
+            return Ok(());
+        }
+        Ok(None) => {}
+        Err(e) => tracing::error!("Failed to read the staged FAT files: {:?}", e),
+    }
+
+    if esp_unsafe!(littlefs::esp_vfs_littlefs_register(&conf(false))).is_ok() {
+        return Ok(());
+    }
+
+    tracing::warn!("No LittleFS filesystem on the partition, looking for FAT to migrate");
+
+    let entries = match prepare_migration() {
+        Ok(entries) => entries,
+        Err(e) => {
+            tracing::error!("Not migrating FAT to LittleFS: {:?}", e);
+            return mount_legacy_fat();
+        }
+    };
+
+    esp_unsafe!(littlefs::esp_littlefs_format(PARTITION_LABEL.as_ptr()))?;
+    esp_unsafe!(littlefs::esp_vfs_littlefs_register(&conf(true)))?;
+
+    if let Some(entries) = entries {
+        finish_migration(&entries);
+    }
+
+    Ok(())
+}
+
+pub fn unmount() -> Result<()> {
+    if lock_legacy_fat().take().is_some() {
+        return Ok(());
+    }
+
+    esp_unsafe!(littlefs::esp_vfs_littlefs_unregister(
+        PARTITION_LABEL.as_ptr()
+    ))?;
+
+    Ok(())
+}
+
+/// Also drops the FAT filesystem of a migration that was refused
+pub fn format() -> Result<()> {
+    lock_legacy_fat().take();
+    esp_unsafe!(littlefs::esp_littlefs_format(PARTITION_LABEL.as_ptr()))?;
+
+    Ok(())
+}
+
+pub fn usage() -> Result<StorageUsage> {
+    if lock_legacy_fat().is_some() {
+        let mut total_bytes = 0;
+        let mut free_bytes = 0;
+        esp_unsafe!(sys::esp_vfs_fat_info(
+            BASE_PATH.as_ptr(),
+            &mut total_bytes,
+            &mut free_bytes,
+        ))?;
+
+        return Ok(StorageUsage {
+            total_bytes,
+            used_bytes: total_bytes - free_bytes,
+        });
+    }
+
+    let mut total_bytes = 0;
+    let mut used_bytes = 0;
+    esp_unsafe!(littlefs::esp_littlefs_info(
+        PARTITION_LABEL.as_ptr(),
+        &mut total_bytes,
+        &mut used_bytes,
+    ))?;
+
+    Ok(StorageUsage {
+        total_bytes: total_bytes as u64,
+        used_bytes: used_bytes as u64,
+    })
+}
+
+struct Entry {
+    /// Relative to the filesystem root
+    path: PathBuf,
+    /// `None` for directories
+    contents: Option<Vec<u8>>,
+}
+
+/// Reads the FAT files and stages them, `None` if there's no FAT filesystem to migrate. An
+/// error leaves the partition untouched.
+fn prepare_migration() -> Result<Option<Vec<Entry>>> {
+    let Some(entries) = read_legacy_fat()? else {
+        return Ok(None);
+    };
+
+    if !running_image_confirmed() {
+        bail!("The running image isn't confirmed yet, migrating once it is");
+    }
+    stage(&entries)?;
+
+    Ok(Some(entries))
+}
+
+/// Restores the staged entries to the mounted LittleFS. They stay staged if that fails and
+/// are restored again on the next boot.
+fn finish_migration(entries: &[Entry]) {
+    if let Err(e) = restore(entries) {
+        tracing::error!(
+            "Failed to restore the FAT files, retrying on the next boot: {:?}",
+            e
+        );
+        return;
+    }
+
+    if let Err(e) = clear_staged() {
+        tracing::error!("Failed to drop the staged FAT files: {:?}", e);
+    }
+
+    tracing::info!("Migrated {} entries from FAT to LittleFS", entries.len());
+}
+
+/// Whether the bootloader can still roll the running image back to the one in the inactive
+/// slot. Images that aren't OTA updates, e.g. flashed over serial, are confirmed.
+fn running_image_confirmed() -> bool {
+    let mut state = sys::esp_ota_img_states_t_ESP_OTA_IMG_UNDEFINED;
+    let running = unsafe { sys::esp_ota_get_running_partition() };
+    if esp_unsafe!(sys::esp_ota_get_state_partition(running, &mut state)).is_err() {
+        return true;
+    }
+
+    state != sys::esp_ota_img_states_t_ESP_OTA_IMG_NEW
+        && state != sys::esp_ota_img_states_t_ESP_OTA_IMG_PENDING_VERIFY
+}
+
+fn lock_legacy_fat() -> std::sync::MutexGuard<'static, Option<LegacyFat>> {
+    LEGACY_FAT.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Keeps the device running on the FAT filesystem it had, formatting it if it doesn't hold
+/// one after all
+fn mount_legacy_fat() -> Result<()> {
+    let fat = match LegacyFat::mount(BASE_PATH)? {
+        Some(fat) => fat,
+        None => {
+            tracing::warn!("No FAT filesystem to fall back to, formatting LittleFS");
+            esp_unsafe!(littlefs::esp_littlefs_format(PARTITION_LABEL.as_ptr()))?;
+            esp_unsafe!(littlefs::esp_vfs_littlefs_register(&conf(true)))?;
+
+            return Ok(());
+        }
+    };
+    *lock_legacy_fat() = Some(fat);
+
+    tracing::warn!("Running on the FAT filesystem until it's migrated");
+
+    Ok(())
+}
+
+/// The wear levelled FAT filesystem of an older build, mounted by hand, the partition
+/// subtype no longer says FAT so `esp_vfs_fat_spiflash_mount_rw_wl` won't find it.
+/// Unmounted when dropped.
+struct LegacyFat {
+    base_path: &'static CStr,
+    wl_handle: sys::wl_handle_t,
+    pdrv: u8,
+    drive: CString,
+}
+
+// The handles are only used to unmount, which ESP-IDF allows from any task
+unsafe impl Send for LegacyFat {}
+
+impl LegacyFat {
+    /// `None` if the partition holds no FAT filesystem
+    fn mount(base_path: &'static CStr) -> Result<Option<Self>> {
+        let partition = unsafe {
+            sys::esp_partition_find_first(
+                sys::esp_partition_type_t_ESP_PARTITION_TYPE_DATA,
+                sys::esp_partition_subtype_t_ESP_PARTITION_SUBTYPE_ANY,
+                PARTITION_LABEL.as_ptr(),
+            )
+        };
+        if partition.is_null() {
+            bail!("Partition {:?} not found", PARTITION_LABEL);
+        }
+
+        let mut wl_handle = sys::WL_INVALID_HANDLE;
+        if let Err(e) = esp_unsafe!(sys::wl_mount(partition, &mut wl_handle)) {
+            tracing::warn!("No wear levelled FAT filesystem: {:?}", e);
+            return Ok(None);
+        }
+        let wl_handle = guard(wl_handle, |wl_handle| unsafe {
+            sys::wl_unmount(wl_handle);
+        });
+
+        let mut pdrv = 0xFF;
+        esp_unsafe!(sys::ff_diskio_get_drive(&mut pdrv))?;
+        esp_unsafe!(sys::ff_diskio_register_wl_partition(pdrv, *wl_handle))?;
+        let pdrv = guard(pdrv, |pdrv| unsafe {
+            sys::ff_diskio_unregister(pdrv);
+        });
+
+        let drive = CString::new(format!("{}:", *pdrv))?;
+        let mut fatfs: *mut sys::FATFS = ptr::null_mut();
+        esp_unsafe!(sys::esp_vfs_fat_register(
+            base_path.as_ptr(),
+            drive.as_ptr(),
+            MAX_FILES,
+            &mut fatfs,
+        ))?;
+        let registered = guard((), |()| unsafe {
+            sys::esp_vfs_fat_unregister_path(base_path.as_ptr());
+        });
+
+        let res = unsafe { sys::f_mount(fatfs, drive.as_ptr(), 1) };
+        if res != sys::FRESULT_FR_OK {
+            tracing::warn!("No FAT filesystem found ({})", res);
+            return Ok(None);
+        }
+
+        ScopeGuard::into_inner(registered);
+        Ok(Some(Self {
+            base_path,
+            pdrv: ScopeGuard::into_inner(pdrv),
+            wl_handle: ScopeGuard::into_inner(wl_handle),
+            drive,
+        }))
+    }
+}
+
+impl Drop for LegacyFat {
+    fn drop(&mut self) {
+        unsafe {
+            sys::f_mount(ptr::null_mut(), self.drive.as_ptr(), 0);
+            sys::esp_vfs_fat_unregister_path(self.base_path.as_ptr());
+            sys::ff_diskio_unregister(self.pdrv);
+            sys::wl_unmount(self.wl_handle);
+        }
+    }
+}
+
+/// Reads the FAT filesystem into memory, `None` if the partition holds none
+fn read_legacy_fat() -> Result<Option<Vec<Entry>>> {
+    let Some(_fat) = LegacyFat::mount(LEGACY_FAT_BASE_PATH)? else {
+        tracing::warn!("Nothing to migrate");
+        return Ok(None);
+    };
+
+    let root = Path::new(LEGACY_FAT_ROOT);
+    let mut paths = Vec::new();
+    collect_paths(root, &mut paths)?;
+
+    let mut entries = Vec::with_capacity(paths.len());
+    let mut total = 0;
+    for (path, is_dir) in paths {
+        let relative = path.strip_prefix(root)?.to_path_buf();
+
+        if is_dir {
+            entries.push(Entry {
+                path: relative,
+                contents: None,
+            });
+            continue;
+        }
+
+        let contents = fs::read(&path)?;
+        total += contents.len();
+        if total > MIGRATION_MAX_BYTES {
+            bail!(
+                "The FAT filesystem holds more than {} bytes, refusing to migrate it. \
+                 Back its files up and remove some to migrate it",
+                MIGRATION_MAX_BYTES
+            );
+        }
+
+        entries.push(Entry {
+            path: relative,
+            contents: Some(contents),
+        });
+    }
+
+    Ok(Some(entries))
+}
+
+fn collect_paths(dir: &Path, paths: &mut Vec<(PathBuf, bool)>) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let is_dir = entry.file_type()?.is_dir();
+
+        paths.push((path.clone(), is_dir));
+
+        if is_dir {
+            collect_paths(&path, paths)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn restore(entries: &[Entry]) -> Result<()> {
+    let root = Path::new(super::BASE_PATH_STR);
+
+    for entry in entries {
+        let path = root.join(&entry.path);
+
+        match &entry.contents {
+            None => fs::create_dir_all(&path)?,
+            Some(contents) => {
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+
+                fs::write(&path, contents)?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Where the FAT files wait while the partition is reformatted. The device boots from the
+/// other slot and a downloaded update is only written there after the filesystem is mounted.
+/// Only used once the running image is confirmed, until then the slot is its rollback target.
+fn staging_partition() -> Option<*const sys::esp_partition_t> {
+    let partition = unsafe { sys::esp_ota_get_next_update_partition(ptr::null()) };
+
+    (!partition.is_null()).then_some(partition)
+}
+
+/// Copies the entries to the staging partition and reads them back, the header goes last so
+/// a copy cut short isn't taken for a staged one
+fn stage(entries: &[Entry]) -> Result<()> {
+    let Some(partition) = staging_partition() else {
+        bail!("No inactive app slot to stage the FAT files in");
+    };
+
+    let payload = encode(entries)?;
+    let digest: [u8; 32] = Sha256::digest(&payload).into();
+    let mut header = [0u8; STAGE_HEADER_LEN];
+    header[..8].copy_from_slice(&STAGE_MAGIC);
+    header[8..12].copy_from_slice(&(payload.len() as u32).to_le_bytes());
+    header[12..].copy_from_slice(&digest);
+
+    let len = (STAGE_HEADER_LEN + payload.len()).next_multiple_of(FLASH_SECTOR_SIZE);
+    if len > unsafe { (*partition).size } as usize {
+        bail!("The FAT files don't fit the inactive app slot");
+    }
+
+    esp_unsafe!(sys::esp_partition_erase_range(partition, 0, len))?;
+    esp_unsafe!(sys::esp_partition_write(
+        partition,
+        STAGE_HEADER_LEN,
+        payload.as_ptr().cast(),
+        payload.len(),
+    ))?;
+    esp_unsafe!(sys::esp_partition_write(
+        partition,
+        0,
+        header.as_ptr().cast(),
+        header.len(),
+    ))?;
+
+    match read_staged()? {
+        Some(staged) if staged.len() == entries.len() => Ok(()),
+        _ => bail!("The staged FAT files don't read back, not migrating"),
+    }
+}
+
+/// The staged entries, `None` if nothing intact is staged
+fn read_staged() -> Result<Option<Vec<Entry>>> {
+    let Some(partition) = staging_partition() else {
+        return Ok(None);
+    };
+
+    let mut header = [0u8; STAGE_HEADER_LEN];
+    esp_unsafe!(sys::esp_partition_read(
+        partition,
+        0,
+        header.as_mut_ptr().cast(),
+        header.len(),
+    ))?;
+    if header[..8] != STAGE_MAGIC {
+        return Ok(None);
+    }
+
+    let len = u32::from_le_bytes([header[8], header[9], header[10], header[11]]) as usize;
+    if len > 2 * MIGRATION_MAX_BYTES {
+        return Ok(None);
+    }
+
+    let mut payload = vec![0u8; len];
+    esp_unsafe!(sys::esp_partition_read(
+        partition,
+        STAGE_HEADER_LEN,
+        payload.as_mut_ptr().cast(),
+        len,
+    ))?;
+    if Sha256::digest(&payload)[..] != header[12..] {
+        return Ok(None);
+    }
+
+    decode(&payload).map(Some)
+}
+
+fn clear_staged() -> Result<()> {
+    if let Some(partition) = staging_partition() {
+        esp_unsafe!(sys::esp_partition_erase_range(
+            partition,
+            0,
+            FLASH_SECTOR_SIZE
+        ))?;
+    }
+
+    Ok(())
+}
+
+/// Every entry as its path length, path, and for files the contents length and contents.
+/// Directories have a contents length of `u32::MAX`.
+fn encode(entries: &[Entry]) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+
+    for entry in entries {
+        let path = entry
+            .path
+            .to_str()
+            .ok_or_else(|| error!("Path {:?} isn't UTF-8", entry.path))?;
+        buf.extend_from_slice(&(path.len() as u16).to_le_bytes());
+        buf.extend_from_slice(path.as_bytes());
+
+        match &entry.contents {
+            None => buf.extend_from_slice(&u32::MAX.to_le_bytes()),
+            Some(contents) => {
+                buf.extend_from_slice(&(contents.len() as u32).to_le_bytes());
+                buf.extend_from_slice(contents);
+            }
+        }
+    }
+
+    Ok(buf)
+}
+
+fn decode(mut buf: &[u8]) -> Result<Vec<Entry>> {
+    let mut entries = Vec::new();
+
+    while !buf.is_empty() {
+        let path_len = u16::from_le_bytes(take(&mut buf, 2)?.try_into()?) as usize;
+        let path = PathBuf::from(std::str::from_utf8(take(&mut buf, path_len)?)?);
+
+        let contents = match u32::from_le_bytes(take(&mut buf, 4)?.try_into()?) {
+            u32::MAX => None,
+            len => Some(take(&mut buf, len as usize)?.to_vec()),
+        };
+
+        entries.push(Entry { path, contents });
+    }
+
+    Ok(entries)
+}
+
+fn take<'a>(buf: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if buf.len() < len {
+        bail!("Staged FAT files are truncated");
+    }
+
+    let (taken, rest) = buf.split_at(len);
+    *buf = rest;
+
+    Ok(taken)
+}
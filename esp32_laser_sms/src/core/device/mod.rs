@@ -1,28 +1,38 @@
+use std::ffi::CStr;
 use std::fs;
 use std::path::Path;
-use std::ptr::addr_of_mut;
 
 use esp_idf_svc::sys;
+use serde::Serialize;
 
-use crate::util::{ffi::esp::esp_unsafe, result::Result};
+use crate::util::result::Result;
 
-pub struct Device;
+#[cfg(not(feature = "littlefs"))]
+mod fat;
+#[cfg(feature = "littlefs")]
+mod littlefs;
+
+#[cfg(not(feature = "littlefs"))]
+use fat as filesystem;
+#[cfg(feature = "littlefs")]
+use littlefs as filesystem;
+
+const BASE_PATH: &CStr = c"/spiflash";
+const BASE_PATH_STR: &str = "/spiflash";
+const PARTITION_LABEL: &CStr = c"fs";
+const MAX_FILES: usize = 5;
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct StorageUsage {
+    pub total_bytes: u64,
+    pub used_bytes: u64,
+}
 
-static mut WL_HANDLE: i32 = sys::WL_INVALID_HANDLE;
+pub struct Device;
 
 impl Device {
     pub fn init() -> Result<()> {
-        esp_unsafe!(sys::esp_vfs_fat_spiflash_mount_rw_wl(
-            c"/spiflash".as_ptr(),
-            c"fs".as_ptr(),
-            &sys::esp_vfs_fat_mount_config_t {
-                format_if_mount_failed: true,
-                max_files: 5,
-                allocation_unit_size: sys::CONFIG_WL_SECTOR_SIZE as usize,
-                disk_status_check_enable: false,
-            },
-            addr_of_mut!(WL_HANDLE),
-        ))?;
+        filesystem::mount()?;
 
         if !Path::new("/spiflash/conf").exists() {
             fs::create_dir_all("/spiflash/conf")?;
@@ -36,23 +46,22 @@ impl Device {
     }
 
     pub fn deinit() -> Result<()> {
-        esp_unsafe!(sys::esp_vfs_fat_spiflash_unmount_rw_wl(
-            c"/spiflash".as_ptr(),
-            WL_HANDLE,
-        ))?;
+        filesystem::unmount()?;
 
         Ok(())
     }
 
+    /// Space used on the `/spiflash` filesystem
+    pub fn storage_usage() -> Result<StorageUsage> {
+        filesystem::usage()
+    }
+
     pub fn restart() {
         unsafe { sys::esp_restart() };
     }
 
     pub fn reset() -> Result<()> {
-        esp_unsafe!(sys::esp_vfs_fat_spiflash_format_rw_wl(
-            c"/spiflash".as_ptr(),
-            c"fs".as_ptr(),
-        ))?;
+        filesystem::format()?;
         Self::restart();
 
         Ok(())
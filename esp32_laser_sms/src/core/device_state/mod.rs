@@ -8,7 +8,9 @@ use crate::util::{
     sync::{arc_sync_mutex, ArcSyncMutex},
 };
 
-use super::persistent_state;
+use super::{
+    buzzer::BuzzerSettings, mqtt::MqttSettings, ota::window::MaintenanceWindow, persistent_state,
+};
 use crate::util::sync::IntoSendSync;
 
 #[derive(Debug, Clone, PartialEq, Deserialize, Serialize, Eq)]
@@ -22,6 +24,28 @@ pub struct DeviceState {
     pub activation_time_start: Time,
     pub activation_time_end: Option<Time>,
     pub buzzer_enabled: bool,
+    #[serde(default)]
+    pub buzzer: BuzzerSettings,
+    /// While disarmed the sensor is ignored even inside the activation schedule
+    #[serde(default = "default_armed")]
+    pub armed: bool,
+    /// Update server manifest, periodic update checks are off while this is unset
+    #[serde(default)]
+    pub ota_manifest_url: Option<String>,
+    #[serde(default = "default_ota_check_interval_secs")]
+    pub ota_check_interval_secs: u64,
+    #[serde(default)]
+    pub ota_window: MaintenanceWindow,
+    #[serde(default)]
+    pub mqtt: Option<MqttSettings>,
+}
+
+fn default_armed() -> bool {
+    true
+}
+
+fn default_ota_check_interval_secs() -> u64 {
+    6 * 60 * 60
 }
 
 impl Default for DeviceState {
@@ -36,6 +60,12 @@ impl Default for DeviceState {
             activation_time_start: time!(20:00:00),
             activation_time_end: Some(time!(00:00:00)),
             buzzer_enabled: true,
+            buzzer: BuzzerSettings::default(),
+            armed: default_armed(),
+            ota_manifest_url: None,
+            ota_check_interval_secs: default_ota_check_interval_secs(),
+            ota_window: MaintenanceWindow::default(),
+            mqtt: None,
         }
     }
 }
@@ -76,6 +106,57 @@ impl DeviceState {
     pub fn buzzer_enabled(&self) -> bool {
         self.buzzer_enabled
     }
+
+    pub fn buzzer(&self) -> &BuzzerSettings {
+        &self.buzzer
+    }
+
+    pub fn armed(&self) -> bool {
+        self.armed
+    }
+
+    pub fn ota_manifest_url(&self) -> Option<&str> {
+        self.ota_manifest_url.as_deref()
+    }
+
+    pub fn ota_check_interval_secs(&self) -> u64 {
+        self.ota_check_interval_secs
+    }
+
+    pub fn ota_window(&self) -> &MaintenanceWindow {
+        &self.ota_window
+    }
+
+    pub fn mqtt(&self) -> Option<&MqttSettings> {
+        self.mqtt.as_ref()
+    }
+
+    fn validate(&self) -> Result<()> {
+        self.validate_ota_window()?;
+        self.buzzer.validate()?;
+
+        if let Some(mqtt) = &self.mqtt {
+            mqtt.validate()?;
+        }
+
+        Ok(())
+    }
+
+    /// Updates restart the device, so the window may not overlap the activation schedule
+    /// while update checks are on
+    fn validate_ota_window(&self) -> Result<()> {
+        if self.ota_manifest_url.is_some()
+            && self
+                .ota_window
+                .overlaps_activation(self.activation_time_start, self.activation_time_end)
+        {
+            return Err(error!(
+                "The maintenance window overlaps the activation schedule"
+            ));
+        }
+
+        Ok(())
+    }
 }
 
 pub type DeviceStateManager<S = DeviceStateStorage> =
@@ -122,6 +203,17 @@ impl<S: persistent_state::Storage<DeviceState>, M: BorrowMut<DeviceStateManager<
             .map_err(|e| error!("Error: {:?}", e))
     }
 
+    /// Like `update_state` but leaves the state untouched if the result isn't valid
+    fn update_validated_state(
+        &mut self,
+        f: impl FnOnce(&DeviceState) -> DeviceState,
+    ) -> Result<()> {
+        let new_state = f(self.state_manager.borrow().state());
+        new_state.validate()?;
+
+        self.update_state(|_| new_state)
+    }
+
     pub fn sms_send_phone_number(&self) -> Option<&str> {
         self.state_manager.borrow().state().sms_send_phone_number()
     }
@@ -225,12 +317,29 @@ impl<S: persistent_state::Storage<DeviceState>, M: BorrowMut<DeviceStateManager<
         })
     }
 
+    /// Clears the SMS recipient, message, Twilio account and MQTT broker, leaving everything
+    /// else as is
+    pub fn reset_notifications(&mut self) -> result::Result<()> {
+        self.update_state(|state| {
+            let defaults = DeviceState::default();
+            let mut c = state.clone();
+            c.sms_send_phone_number = defaults.sms_send_phone_number;
+            c.sms_send_throttle = defaults.sms_send_throttle;
+            c.sms_send_twilio_phone_number = defaults.sms_send_twilio_phone_number;
+            c.sms_send_twilio_account_sid = defaults.sms_send_twilio_account_sid;
+            c.sms_send_twilio_auth_token = defaults.sms_send_twilio_auth_token;
+            c.sms_send_message_body = defaults.sms_send_message_body;
+            c.mqtt = defaults.mqtt;
+            c
+        })
+    }
+
     pub fn activation_time_start(&self) -> &Time {
         self.state_manager.borrow().state().activation_time_start()
     }
 
     pub fn set_activation_time_start(&mut self, time_start: Time) -> result::Result<()> {
-        self.update_state(|state| {
+        self.update_validated_state(|state| {
             let mut c = state.clone();
             c.activation_time_start = time_start;
             c
@@ -242,7 +351,7 @@ impl<S: persistent_state::Storage<DeviceState>, M: BorrowMut<DeviceStateManager<
     }
 
     pub fn set_activation_time_end(&mut self, time_end: Time) -> result::Result<()> {
-        self.update_state(|state| {
+        self.update_validated_state(|state| {
             let mut c = state.clone();
             c.activation_time_end = Some(time_end);
             c
@@ -250,7 +359,7 @@ impl<S: persistent_state::Storage<DeviceState>, M: BorrowMut<DeviceStateManager<
     }
 
     pub fn set_activation(&mut self, time_start: &Time, time_end: &Time) -> result::Result<()> {
-        self.update_state(|state| {
+        self.update_validated_state(|state| {
             let mut c = state.clone();
             c.activation_time_start = *time_start;
             c.activation_time_end = Some(*time_end);
@@ -277,6 +386,73 @@ impl<S: persistent_state::Storage<DeviceState>, M: BorrowMut<DeviceStateManager<
             c
         })
     }
+
+    pub fn buzzer(&self) -> &BuzzerSettings {
+        self.state_manager.borrow().state().buzzer()
+    }
+
+    pub fn set_buzzer_settings(&mut self, settings: BuzzerSettings) -> result::Result<()> {
+        self.update_validated_state(|state| {
+            let mut c = state.clone();
+            c.buzzer = settings;
+            c
+        })
+    }
+
+    pub fn armed(&self) -> bool {
+        self.state_manager.borrow().state().armed()
+    }
+
+    pub fn set_armed(&mut self, armed: bool) -> result::Result<()> {
+        self.update_state(|state| {
+            let mut c = state.clone();
+            c.armed = armed;
+            c
+        })
+    }
+
+    pub fn ota_manifest_url(&self) -> Option<&str> {
+        self.state_manager.borrow().state().ota_manifest_url()
+    }
+
+    pub fn ota_check_interval_secs(&self) -> u64 {
+        self.state_manager
+            .borrow()
+            .state()
+            .ota_check_interval_secs()
+    }
+
+    pub fn ota_window(&self) -> &MaintenanceWindow {
+        self.state_manager.borrow().state().ota_window()
+    }
+
+    pub fn set_ota_update(
+        &mut self,
+        manifest_url: Option<&str>,
+        check_interval_secs: u64,
+        window: MaintenanceWindow,
+    ) -> result::Result<()> {
+        self.update_validated_state(|state| {
+            let mut c = state.clone();
+            c.ota_manifest_url = manifest_url.map(|s| s.to_string());
+            c.ota_check_interval_secs = check_interval_secs;
+            c.ota_window = window;
+            c
+        })
+    }
+
+    pub fn mqtt(&self) -> Option<&MqttSettings> {
+        self.state_manager.borrow().state().mqtt()
+    }
+
+    /// `None` turns MQTT off
+    pub fn set_mqtt(&mut self, settings: Option<MqttSettings>) -> result::Result<()> {
+        self.update_validated_state(|state| {
+            let mut c = state.clone();
+            c.mqtt = settings;
+            c
+        })
+    }
 }
 
 impl<S, M> IntoSendSync for DeviceStateService<S, M>
@@ -0,0 +1,86 @@
+use std::{
+    ffi::CStr,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use esp_idf_svc::sys;
+use serde::Serialize;
+
+use super::ota::FIRMWARE_VERSION;
+
+/// Hex characters of the ELF SHA-256 kept as the build hash, like `idf.py` prints
+const BUILD_HASH_LEN: usize = 16;
+
+/// Uptime in seconds at the last SNTP sync plus one, zero until the first sync.
+/// The ESP32 has no 64-bit atomics, 32 bits of seconds is plenty.
+static LAST_TIME_SYNC: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HeapInfo {
+    pub free_bytes: u32,
+    pub min_free_bytes: u32,
+    pub largest_free_block: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct FirmwareInfo {
+    pub version: &'static str,
+    pub build_hash: String,
+}
+
+pub fn uptime_secs() -> u64 {
+    (unsafe { sys::esp_timer_get_time() } / 1_000_000) as u64
+}
+
+/// Called from the SNTP callback on every sync
+pub fn record_time_sync() {
+    LAST_TIME_SYNC.store(uptime_secs() as u32 + 1, Ordering::Relaxed);
+}
+
+pub fn secs_since_time_sync() -> Option<u64> {
+    match LAST_TIME_SYNC.load(Ordering::Relaxed) {
+        0 => None,
+        synced_at => Some(uptime_secs() + 1 - synced_at as u64),
+    }
+}
+
+pub fn heap() -> HeapInfo {
+    unsafe {
+        HeapInfo {
+            free_bytes: sys::esp_get_free_heap_size(),
+            min_free_bytes: sys::esp_get_minimum_free_heap_size(),
+            largest_free_block: sys::heap_caps_get_largest_free_block(sys::MALLOC_CAP_DEFAULT),
+        }
+    }
+}
+
+pub fn reset_reason() -> &'static str {
+    #[allow(non_upper_case_globals)]
+    match unsafe { sys::esp_reset_reason() } {
+        sys::esp_reset_reason_t_ESP_RST_POWERON => "power_on",
+        sys::esp_reset_reason_t_ESP_RST_EXT => "external_pin",
+        sys::esp_reset_reason_t_ESP_RST_SW => "software",
+        sys::esp_reset_reason_t_ESP_RST_PANIC => "panic",
+        sys::esp_reset_reason_t_ESP_RST_INT_WDT => "interrupt_watchdog",
+        sys::esp_reset_reason_t_ESP_RST_TASK_WDT => "task_watchdog",
+        sys::esp_reset_reason_t_ESP_RST_WDT => "watchdog",
+        sys::esp_reset_reason_t_ESP_RST_DEEPSLEEP => "deep_sleep",
+        sys::esp_reset_reason_t_ESP_RST_BROWNOUT => "brownout",
+        sys::esp_reset_reason_t_ESP_RST_SDIO => "sdio",
+        _ => "unknown",
+    }
+}
+
+pub fn firmware() -> FirmwareInfo {
+    let mut hash = [0u8; BUILD_HASH_LEN + 1];
+    unsafe { sys::esp_app_get_elf_sha256(hash.as_mut_ptr() as *mut _, hash.len()) };
+
+    let build_hash = CStr::from_bytes_until_nul(&hash)
+        .map(|hash| hash.to_string_lossy().into_owned())
+        .unwrap_or_default();
+
+    FirmwareInfo {
+        version: FIRMWARE_VERSION,
+        build_hash,
+    }
+}
@@ -0,0 +1,61 @@
+use parking_lot::Mutex;
+
+use crate::util::sync::Arc;
+
+use super::counters::NotificationChannel;
+
+pub mod stream;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SensorEvent {
+    /// The beam changed, published whether or not the alarm is active
+    Beam { broken: bool },
+    /// The beam was cut while the alarm was armed and inside the activation schedule
+    Tripped,
+    Notification {
+        channel: NotificationChannel,
+        sent: bool,
+    },
+}
+
+type Subscriber<E> = Box<dyn FnMut(&E) + Send + 'static>;
+
+/// Fans events out to subscribers, the callbacks run on the publishing task so they should
+/// only hand the event off
+pub struct EventBus<E> {
+    subscribers: Arc<Mutex<Vec<Subscriber<E>>>>,
+}
+
+pub type SensorEventBus = EventBus<SensorEvent>;
+
+impl<E> EventBus<E> {
+    pub fn new() -> Self {
+        Self {
+            subscribers: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    pub fn subscribe<F: FnMut(&E) + Send + 'static>(&self, f: F) {
+        self.subscribers.lock().push(Box::new(f));
+    }
+
+    pub fn publish(&self, event: E) {
+        for subscriber in self.subscribers.lock().iter_mut() {
+            subscriber(&event);
+        }
+    }
+}
+
+impl<E> Clone for EventBus<E> {
+    fn clone(&self) -> Self {
+        Self {
+            subscribers: self.subscribers.clone(),
+        }
+    }
+}
+
+impl<E> Default for EventBus<E> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
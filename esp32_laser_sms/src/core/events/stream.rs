@@ -0,0 +1,181 @@
+use std::{
+    sync::mpsc::{self, RecvTimeoutError},
+    thread,
+    time::Duration,
+};
+
+use embedded_svc::ws::FrameType;
+use esp_idf_svc::http::server::ws::EspHttpWsDetachedSender;
+use serde::Serialize;
+
+use crate::{
+    core::{
+        device_state::SendSyncDeviceStateService,
+        wifi::supervisor::{LinkMonitor, LinkState},
+    },
+    util::result::Result,
+};
+
+use super::{SensorEvent, SensorEventBus};
+
+const STREAM_TASK_STACK_SIZE: usize = 8 * 1024;
+/// The link monitor has no subscriptions, so it's polled after every message and at least
+/// this often
+const LINK_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Each client holds one of the server's few sockets
+const MAX_CLIENTS: usize = 3;
+
+/// Pushed to `/events/stream` clients as JSON text frames
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LiveEvent {
+    Beam {
+        broken: bool,
+    },
+    Tripped,
+    Armed {
+        armed: bool,
+    },
+    Wifi {
+        state: LinkState,
+    },
+    /// Some other setting changed, the page reloads what it shows
+    Config,
+}
+
+enum Message {
+    Client(EspHttpWsDetachedSender),
+    Event(LiveEvent),
+}
+
+/// Forwards sensor, state and Wi-Fi changes to the connected WebSocket clients.
+///
+/// Frames are sent from a task of its own, sending from a state subscriber could run on the
+/// HTTP server task and wait on itself.
+#[derive(Clone)]
+pub struct EventStream {
+    tx: mpsc::Sender<Message>,
+}
+
+impl EventStream {
+    pub fn start(
+        dev_svc: SendSyncDeviceStateService,
+        sensor_events: &SensorEventBus,
+        link: LinkMonitor,
+    ) -> Result<Self> {
+        let (tx, rx) = mpsc::channel();
+
+        sensor_events.subscribe({
+            let tx = tx.clone();
+            move |event| {
+                let event = match *event {
+                    SensorEvent::Beam { broken } => LiveEvent::Beam { broken },
+                    SensorEvent::Tripped => LiveEvent::Tripped,
+                    SensorEvent::Notification { .. } => return,
+                };
+
+                let _ = tx.send(Message::Event(event));
+            }
+        });
+
+        let armed = {
+            let mut dvc = dev_svc.lock();
+            dvc.subscribe({
+                let tx = tx.clone();
+                move |old, new| {
+                    if old.armed != new.armed {
+                        let _ = tx.send(Message::Event(LiveEvent::Armed { armed: new.armed }));
+                    }
+
+                    let mut old = old.clone();
+                    old.armed = new.armed;
+                    if old != *new {
+                        let _ = tx.send(Message::Event(LiveEvent::Config));
+                    }
+                }
+            });
+
+            dvc.armed()
+        };
+
+        thread::Builder::new()
+            .name("event-stream".into())
+            .stack_size(STREAM_TASK_STACK_SIZE)
+            .spawn(move || run(rx, link, armed))?;
+
+        Ok(Self { tx })
+    }
+
+    /// Streams to a newly connected client, starting with the current state
+    pub fn add_client(&self, sender: EspHttpWsDetachedSender) {
+        let _ = self.tx.send(Message::Client(sender));
+    }
+}
+
+fn run(rx: mpsc::Receiver<Message>, link: LinkMonitor, mut armed: bool) {
+    let mut clients: Vec<EspHttpWsDetachedSender> = Vec::new();
+    let mut beam_broken = false;
+    let mut wifi = link.status().state;
+
+    loop {
+        let message = match rx.recv_timeout(LINK_POLL_INTERVAL) {
+            Ok(message) => Some(message),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+
+        // Checked on every pass, a steady stream of events would hold off a timeout forever
+        let state = link.status().state;
+        if state != wifi {
+            wifi = state;
+            broadcast(&mut clients, &LiveEvent::Wifi { state });
+        }
+
+        match message {
+            Some(Message::Client(mut client)) => {
+                clients.retain(|client| !client.is_closed());
+
+                if clients.len() >= MAX_CLIENTS {
+                    tracing::warn!("Too many event stream clients, closing the new one");
+                    let _ = client.send(FrameType::Close, &[]);
+                    continue;
+                }
+
+                let snapshot = [
+                    LiveEvent::Beam {
+                        broken: beam_broken,
+                    },
+                    LiveEvent::Armed { armed },
+                    LiveEvent::Wifi { state: wifi },
+                ];
+
+                if snapshot.iter().all(|event| send(&mut client, event)) {
+                    clients.push(client);
+                }
+            }
+            Some(Message::Event(event)) => {
+                match event {
+                    LiveEvent::Beam { broken } => beam_broken = broken,
+                    LiveEvent::Armed { armed: new } => armed = new,
+                    _ => {}
+                }
+
+                broadcast(&mut clients, &event);
+            }
+            None => {}
+        }
+    }
+}
+
+fn send(client: &mut EspHttpWsDetachedSender, event: &LiveEvent) -> bool {
+    let Ok(json) = serde_json::to_string(event) else {
+        return false;
+    };
+
+    !client.is_closed() && client.send(FrameType::Text(false), json.as_bytes()).is_ok()
+}
+
+/// Clients that went away are dropped
+fn broadcast(clients: &mut Vec<EspHttpWsDetachedSender>, event: &LiveEvent) {
+    clients.retain_mut(|client| send(client, event));
+}
@@ -0,0 +1,67 @@
+use std::sync::Once;
+
+use metrics_registry::{Gauge, Registry};
+
+use super::{counters::COUNTERS, diagnostics};
+
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4";
+
+static BEAM_BROKEN: Gauge = Gauge::new("laser_beam_broken", "1 while the beam is cut");
+static UPTIME: Gauge = Gauge::new("laser_uptime_seconds", "Seconds since boot");
+static HEAP_FREE: Gauge = Gauge::new("laser_heap_free_bytes", "Free heap");
+static HEAP_MIN_FREE: Gauge =
+    Gauge::new("laser_heap_min_free_bytes", "Lowest free heap since boot");
+static HEAP_LARGEST_FREE_BLOCK: Gauge = Gauge::new(
+    "laser_heap_largest_free_block_bytes",
+    "Largest block that can be allocated",
+);
+static WIFI_RSSI: Gauge = Gauge::new(
+    "laser_wifi_rssi_dbm",
+    "Signal strength of the connected access point, NaN when not connected",
+);
+static TIME_SINCE_SYNC: Gauge = Gauge::new(
+    "laser_time_since_sync_seconds",
+    "Seconds since the clock was last synced over SNTP, NaN before the first sync",
+);
+
+static REGISTRY: Registry<16> = Registry::new();
+static REGISTER: Once = Once::new();
+
+pub fn set_beam_broken(broken: bool) {
+    BEAM_BROKEN.set(if broken { 1.0 } else { 0.0 });
+}
+
+/// Values that are read when scraped rather than tracked as they change
+pub struct Sample {
+    pub wifi_rssi: Option<i8>,
+}
+
+/// Renders every metric in the Prometheus text exposition format
+pub fn render(sample: Sample) -> String {
+    REGISTER.call_once(|| {
+        REGISTRY.register_all(&COUNTERS.metrics());
+        REGISTRY.register_all(&[
+            &BEAM_BROKEN,
+            &UPTIME,
+            &HEAP_FREE,
+            &HEAP_MIN_FREE,
+            &HEAP_LARGEST_FREE_BLOCK,
+            &WIFI_RSSI,
+            &TIME_SINCE_SYNC,
+        ]);
+    });
+
+    let heap = diagnostics::heap();
+    UPTIME.set(diagnostics::uptime_secs() as f32);
+    HEAP_FREE.set(heap.free_bytes as f32);
+    HEAP_MIN_FREE.set(heap.min_free_bytes as f32);
+    HEAP_LARGEST_FREE_BLOCK.set(heap.largest_free_block as f32);
+    WIFI_RSSI.set(sample.wifi_rssi.map_or(f32::NAN, f32::from));
+    TIME_SINCE_SYNC.set(diagnostics::secs_since_time_sync().map_or(f32::NAN, |secs| secs as f32));
+
+    let mut out = String::new();
+    // Writing to a String can't fail
+    let _ = REGISTRY.render(&mut out);
+
+    out
+}
@@ -1,4 +1,13 @@
+pub mod buzzer;
+pub mod captive_portal;
+pub mod counters;
 pub mod device;
 pub mod device_state;
+pub mod diagnostics;
+pub mod events;
+pub mod metrics;
+pub mod mqtt;
+pub mod ota;
 pub mod persistent_state;
+pub mod reset;
 pub mod wifi;
@@ -0,0 +1,380 @@
+use std::{
+    sync::mpsc::{self, RecvTimeoutError},
+    thread,
+    time::Duration,
+};
+
+use embedded_svc::mqtt::client::{Details, EventPayload, QoS};
+use esp_idf_svc::{
+    mqtt::client::{EspMqttClient, LwtConfiguration, MqttClientConfiguration},
+    sys,
+};
+use serde::Serialize;
+
+use crate::{
+    core::{
+        counters::NotificationChannel,
+        device_state::SendSyncDeviceStateService,
+        events::{SensorEvent, SensorEventBus},
+        ota::FIRMWARE_VERSION,
+    },
+    util::{
+        ffi::esp::esp_unsafe,
+        result::Result,
+        sync::{arc_sync_rw_lock, ArcSyncRwLock},
+    },
+};
+
+pub use laser_sms_core::mqtt::{discovery, topics, MqttSettings, Update};
+
+use laser_sms_core::mqtt::{self as session, Client};
+use topics::{Command, Topics};
+
+const MQTT_TASK_STACK_SIZE: usize = 8 * 1024;
+/// How long to wait before trying again when the client couldn't be created
+const RETRY_INTERVAL: Duration = Duration::from_secs(30);
+const KEEP_ALIVE_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct MqttStatus {
+    pub connected: bool,
+    pub last_error: Option<String>,
+}
+
+enum Message {
+    Reconfigure,
+    Publish(Update),
+    /// From the client of the session with this generation. A replaced client can still
+    /// queue events before it's dropped, they're told apart from the new one's by it.
+    Client(u32, ClientEvent),
+}
+
+enum ClientEvent {
+    Connected,
+    Disconnected,
+    Received { topic: String, payload: Vec<u8> },
+    Error(String),
+}
+
+/// Read-only view of the broker connection
+#[derive(Clone)]
+pub struct MqttHandle {
+    status: ArcSyncRwLock<MqttStatus>,
+}
+
+impl MqttHandle {
+    pub fn status(&self) -> MqttStatus {
+        self.status.read().clone()
+    }
+}
+
+/// Keeps a broker connection alive for the settings in `DeviceState`, reconnecting when
+/// they change, publishes sensor events and state changes while connected, and applies
+/// arm/disarm and buzzer commands received from it
+pub struct Mqtt;
+
+impl Mqtt {
+    pub fn start(
+        dev_svc: SendSyncDeviceStateService,
+        sensor_events: &SensorEventBus,
+    ) -> Result<MqttHandle> {
+        let (tx, rx) = mpsc::channel();
+        let status = arc_sync_rw_lock(MqttStatus::default());
+
+        sensor_events.subscribe({
+            let tx = tx.clone();
+            move |event| {
+                let update = match *event {
+                    SensorEvent::Beam { broken } => Update::Beam(broken),
+                    SensorEvent::Tripped => Update::Event("tripped"),
+                    SensorEvent::Notification {
+                        channel: NotificationChannel::Sms,
+                        sent,
+                    } => Update::Event(if sent { "sms_sent" } else { "sms_failed" }),
+                };
+
+                let _ = tx.send(Message::Publish(update));
+            }
+        });
+
+        dev_svc.lock().subscribe({
+            let tx = tx.clone();
+            move |old, new| {
+                if old.mqtt != new.mqtt {
+                    let _ = tx.send(Message::Reconfigure);
+                }
+                if old.armed != new.armed {
+                    let _ = tx.send(Message::Publish(Update::Armed(new.armed)));
+                }
+                if old.buzzer_enabled != new.buzzer_enabled {
+                    let _ = tx.send(Message::Publish(Update::Buzzer(new.buzzer_enabled)));
+                }
+            }
+        });
+
+        let node_id = node_id()?;
+
+        thread::Builder::new()
+            .name("mqtt".into())
+            .stack_size(MQTT_TASK_STACK_SIZE)
+            .spawn({
+                let status = status.clone();
+                move || run(dev_svc, node_id, tx, rx, status)
+            })?;
+
+        Ok(MqttHandle { status })
+    }
+}
+
+fn node_id() -> Result<String> {
+    let mut mac = [0u8; 6];
+    esp_unsafe!(sys::esp_read_mac(
+        mac.as_mut_ptr(),
+        sys::esp_mac_type_t_ESP_MAC_WIFI_STA,
+    ))?;
+
+    Ok(session::node_id(&mac))
+}
+
+struct Session {
+    client: EspMqttClient<'static>,
+    topics: Topics,
+    connected: bool,
+    generation: u32,
+}
+
+impl Session {
+    fn connect(
+        settings: &MqttSettings,
+        node_id: &str,
+        generation: u32,
+        tx: mpsc::Sender<Message>,
+    ) -> Result<Self> {
+        let topics = Topics::new(settings.topic_prefix(node_id));
+
+        let client = EspMqttClient::new_cb(
+            &settings.broker_url,
+            &MqttClientConfiguration {
+                client_id: Some(node_id),
+                username: settings.username.as_deref(),
+                password: settings.password.as_deref(),
+                keep_alive_interval: Some(KEEP_ALIVE_INTERVAL),
+                lwt: Some(LwtConfiguration {
+                    topic: &topics.availability,
+                    payload: topics::OFFLINE.as_bytes(),
+                    qos: QoS::AtLeastOnce,
+                    retain: true,
+                }),
+                crt_bundle_attach: settings.tls.then_some(sys::esp_crt_bundle_attach as _),
+                ..Default::default()
+            },
+            move |event| {
+                let event = match event.payload() {
+                    EventPayload::Connected(_) => ClientEvent::Connected,
+                    EventPayload::Disconnected => ClientEvent::Disconnected,
+                    // Commands are tiny, anything split across events isn't one
+                    EventPayload::Received {
+                        topic: Some(topic),
+                        data,
+                        details: Details::Complete,
+                        ..
+                    } => ClientEvent::Received {
+                        topic: topic.to_string(),
+                        payload: data.to_vec(),
+                    },
+                    EventPayload::Error(e) => ClientEvent::Error(format!("{:?}", e)),
+                    _ => return,
+                };
+
+                let _ = tx.send(Message::Client(generation, event));
+            },
+        )?;
+
+        Ok(Self {
+            client,
+            topics,
+            connected: false,
+            generation,
+        })
+    }
+
+    fn publish_update(&mut self, update: Update) {
+        if self.connected {
+            session::publish_update(&mut QueuedClient(&mut self.client), &self.topics, update);
+        }
+    }
+
+    fn publish_discovery(&mut self, node_id: &str) {
+        if self.connected {
+            session::publish_discovery(
+                &mut QueuedClient(&mut self.client),
+                &self.topics,
+                node_id,
+                FIRMWARE_VERSION,
+            );
+        }
+    }
+
+    fn on_connected(&mut self, node_id: &str, state: [Update; 3]) {
+        self.connected = true;
+
+        session::on_connected(
+            &mut QueuedClient(&mut self.client),
+            &self.topics,
+            node_id,
+            FIRMWARE_VERSION,
+            &state,
+        );
+    }
+
+    /// A clean disconnect doesn't trigger the Last Will, so the broker is told directly
+    fn close(mut self) {
+        if self.connected {
+            let res = self.client.publish(
+                &self.topics.availability,
+                QoS::AtLeastOnce,
+                true,
+                topics::OFFLINE.as_bytes(),
+            );
+            if let Err(e) = res {
+                tracing::error!("Failed to publish availability: {:?}", e);
+            }
+        }
+    }
+}
+
+/// The client's own queue is used so publishing never blocks on the network
+struct QueuedClient<'a>(&'a mut EspMqttClient<'static>);
+
+impl Client for QueuedClient<'_> {
+    fn subscribe(&mut self, topic: &str) -> Result<()> {
+        self.0.subscribe(topic, QoS::AtLeastOnce)?;
+
+        Ok(())
+    }
+
+    fn publish(&mut self, topic: &str, retain: bool, payload: &str) -> Result<()> {
+        self.0
+            .enqueue(topic, QoS::AtLeastOnce, retain, payload.as_bytes())?;
+
+        Ok(())
+    }
+}
+
+fn run(
+    dev_svc: SendSyncDeviceStateService,
+    node_id: String,
+    tx: mpsc::Sender<Message>,
+    rx: mpsc::Receiver<Message>,
+    status: ArcSyncRwLock<MqttStatus>,
+) {
+    let mut beam_broken = false;
+    let mut generation = 0u32;
+
+    loop {
+        let settings = dev_svc.lock().mqtt().cloned();
+        generation = generation.wrapping_add(1);
+
+        let mut session = match &settings {
+            Some(settings) => match Session::connect(settings, &node_id, generation, tx.clone()) {
+                Ok(session) => {
+                    tracing::info!("Connecting to MQTT broker {}", settings.broker_url);
+                    Some(session)
+                }
+                Err(e) => {
+                    tracing::error!("Failed to create the MQTT client: {:?}", e);
+                    status.write().last_error = Some(e.to_string());
+                    None
+                }
+            },
+            None => None,
+        };
+
+        loop {
+            let message = match rx.recv_timeout(RETRY_INTERVAL) {
+                Ok(message) => message,
+                Err(RecvTimeoutError::Timeout) if settings.is_some() && session.is_none() => {
+                    break;
+                }
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => return,
+            };
+
+            if let Message::Publish(Update::Beam(broken)) = message {
+                beam_broken = broken;
+            }
+
+            if let Message::Reconfigure = message {
+                break;
+            }
+
+            let Some(session) = session.as_mut() else {
+                continue;
+            };
+
+            let event = match message {
+                Message::Publish(update) => {
+                    session.publish_update(update);
+                    continue;
+                }
+                Message::Client(from, event) if from == session.generation => event,
+                // Queued by a client that has been replaced
+                Message::Client(..) => continue,
+                // Handled before the session is looked at
+                Message::Reconfigure => unreachable!(),
+            };
+
+            match event {
+                ClientEvent::Connected => {
+                    tracing::info!("Connected to the MQTT broker");
+                    *status.write() = MqttStatus {
+                        connected: true,
+                        last_error: None,
+                    };
+
+                    let state = {
+                        let dvc = dev_svc.lock();
+                        [
+                            Update::Beam(beam_broken),
+                            Update::Armed(dvc.armed()),
+                            Update::Buzzer(dvc.buzzer_enabled()),
+                        ]
+                    };
+                    session.on_connected(&node_id, state);
+                }
+                ClientEvent::Disconnected => {
+                    tracing::warn!("Disconnected from the MQTT broker");
+                    session.connected = false;
+                    status.write().connected = false;
+                }
+                ClientEvent::Error(e) => {
+                    tracing::error!("MQTT error: {}", e);
+                    status.write().last_error = Some(e);
+                }
+                ClientEvent::Received { topic, payload } => {
+                    match session.topics.parse_command(&topic, &payload) {
+                        Some(Command::SetArmed(armed)) => {
+                            tracing::info!("MQTT command: armed = {}", armed);
+                            if let Err(e) = dev_svc.lock().set_armed(armed) {
+                                tracing::error!("Error: {:?}", e);
+                            }
+                        }
+                        Some(Command::SetBuzzer(enabled)) => {
+                            tracing::info!("MQTT command: buzzer = {}", enabled);
+                            if let Err(e) = dev_svc.lock().set_buzzer(enabled) {
+                                tracing::error!("Error: {:?}", e);
+                            }
+                        }
+                        Some(Command::RepublishDiscovery) => session.publish_discovery(&node_id),
+                        None => tracing::warn!("Ignoring MQTT message on {}", topic),
+                    }
+                }
+            }
+        }
+
+        if let Some(session) = session {
+            session.close();
+        }
+        *status.write() = MqttStatus::default();
+    }
+}
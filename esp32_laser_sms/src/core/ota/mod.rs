@@ -0,0 +1,225 @@
+use std::{
+    ptr, thread,
+    time::{Duration, Instant},
+};
+
+use esp_idf_svc::{
+    io::Write,
+    ota::{EspOta, EspOtaUpdate, SlotState},
+    sys,
+};
+use sha2::{Digest, Sha256};
+
+use crate::util::{
+    ffi::esp::esp_unsafe,
+    result::{bail, error, Result},
+    sync::{arc_sync_mutex, ArcSyncMutex, IntoSendSync},
+};
+
+pub mod updater;
+
+pub use laser_sms_core::ota::{manifest, window};
+pub use manifest::{parse_sha256, Sha256Digest, SHA256_LEN};
+
+pub const FIRMWARE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+pub type SendSyncFirmware = ArcSyncMutex<Firmware>;
+
+const HEALTH_CHECK_TASK_STACK_SIZE: usize = 8 * 1024;
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Checks the signature of an image digest against the public key baked in at build time
+#[cfg(feature = "signed-firmware")]
+fn verify_signature(digest: &Sha256Digest, signature: Option<&[u8]>) -> Result<()> {
+    use base64::Engine;
+    use ed25519_compact::{PublicKey, Signature};
+
+    let Some(signature) = signature else {
+        bail!("Firmware signature is required");
+    };
+
+    let public_key = base64::engine::general_purpose::STANDARD
+        .decode(env!(
+            "FIRMWARE_SIGNING_PUBLIC_KEY",
+            "Set FIRMWARE_SIGNING_PUBLIC_KEY to the base64 Ed25519 public key, see the README"
+        ))
+        .map_err(|_| error!("Invalid firmware signing public key"))?;
+    let public_key = PublicKey::from_slice(&public_key)
+        .map_err(|_| error!("Invalid firmware signing public key"))?;
+    let signature = Signature::from_slice(signature).map_err(|_| error!("Invalid signature"))?;
+
+    public_key
+        .verify(digest, &signature)
+        .map_err(|_| error!("Firmware signature does not match"))?;
+
+    Ok(())
+}
+
+#[cfg(not(feature = "signed-firmware"))]
+fn verify_signature(_digest: &Sha256Digest, _signature: Option<&[u8]>) -> Result<()> {
+    Ok(())
+}
+
+/// Owns the OTA handle, there can only be one per device
+pub struct Firmware {
+    ota: EspOta,
+}
+
+impl IntoSendSync for Firmware {
+    type SendSync = SendSyncFirmware;
+
+    fn into_send_sync(self) -> Self::SendSync {
+        arc_sync_mutex(self)
+    }
+}
+
+impl Firmware {
+    pub fn new() -> Result<Self> {
+        Ok(Self {
+            ota: EspOta::new()?,
+        })
+    }
+
+    /// Starts writing a new image to the inactive app slot
+    pub fn begin_update(&mut self) -> Result<FirmwareUpdate<'_>> {
+        let update = self.ota.initiate_update()?;
+
+        Ok(FirmwareUpdate {
+            update,
+            hasher: Sha256::new(),
+            written: 0,
+        })
+    }
+
+    /// True when this is the first boot of a new image and it hasn't been confirmed yet,
+    /// the bootloader rolls back to the previous image if it restarts before that happens
+    pub fn is_pending_verify(&self) -> Result<bool> {
+        let slot = self.ota.get_running_slot()?;
+
+        Ok(slot.state == SlotState::Unverified)
+    }
+
+    pub fn running_slot_label(&self) -> Result<String> {
+        let slot = self.ota.get_running_slot()?;
+
+        Ok(slot.label.to_string())
+    }
+
+    /// Boots the image staged by [`FirmwareUpdate::finish`] from the next restart on. ESP-IDF
+    /// verifies the image again before switching, a slot holding anything else is refused.
+    pub fn activate_staged(&mut self) -> Result<()> {
+        let partition = unsafe { sys::esp_ota_get_next_update_partition(ptr::null()) };
+        if partition.is_null() {
+            bail!("No app slot to boot a staged image from");
+        }
+
+        esp_unsafe!(sys::esp_ota_set_boot_partition(partition))?;
+
+        Ok(())
+    }
+
+    pub fn mark_valid(&mut self) -> Result<()> {
+        self.ota.mark_running_slot_valid()?;
+
+        Ok(())
+    }
+
+    /// Marks the running image as bad and reboots into the previous one
+    pub fn rollback(&mut self) -> Result<()> {
+        Err(self.ota.mark_running_slot_invalid_and_reboot().into())
+    }
+}
+
+/// An image being written to the inactive slot. Nothing changes until it's verified by
+/// [`FirmwareUpdate::finish`] and switched to by [`Firmware::activate_staged`].
+pub struct FirmwareUpdate<'a> {
+    update: EspOtaUpdate<'a>,
+    hasher: Sha256,
+    written: usize,
+}
+
+impl<'a> FirmwareUpdate<'a> {
+    pub fn write(&mut self, chunk: &[u8]) -> Result<()> {
+        self.update.write_all(chunk)?;
+        self.hasher.update(chunk);
+        self.written += chunk.len();
+
+        Ok(())
+    }
+
+    pub fn written(&self) -> usize {
+        self.written
+    }
+
+    /// Verifies the image against the expected digest (and signature when signing is enabled)
+    /// and leaves it staged in the inactive slot, the update is aborted if verification fails.
+    /// The device keeps booting the running image until the staged one is activated.
+    pub fn finish(self, expected: &Sha256Digest, signature: Option<&[u8]>) -> Result<()> {
+        let digest: Sha256Digest = self.hasher.finalize().into();
+
+        if &digest != expected {
+            self.update.abort()?;
+            bail!("Firmware SHA-256 does not match");
+        }
+
+        if let Err(e) = verify_signature(&digest, signature) {
+            self.update.abort()?;
+            return Err(e);
+        }
+
+        self.update.finish()?;
+        tracing::info!("Firmware image of {} bytes staged", self.written);
+
+        Ok(())
+    }
+
+    pub fn abort(self) -> Result<()> {
+        self.update.abort()?;
+
+        Ok(())
+    }
+}
+
+/// Confirms a freshly updated image once `is_healthy` passes, rolling back to the previous image
+/// if it doesn't within `timeout`. Does nothing when the running image is already confirmed.
+pub fn confirm_when_healthy(
+    firmware: SendSyncFirmware,
+    is_healthy: impl Fn() -> bool + Send + 'static,
+    timeout: Duration,
+) -> Result<()> {
+    if !firmware.lock().is_pending_verify()? {
+        return Ok(());
+    }
+
+    tracing::info!("Running a new firmware image, waiting for it to become healthy");
+
+    thread::Builder::new()
+        .name("ota-health-check".into())
+        .stack_size(HEALTH_CHECK_TASK_STACK_SIZE)
+        .spawn(move || {
+            let started = Instant::now();
+
+            while started.elapsed() < timeout {
+                thread::sleep(HEALTH_CHECK_INTERVAL);
+
+                if is_healthy() {
+                    match firmware.lock().mark_valid() {
+                        Ok(()) => tracing::info!("Firmware {} marked valid", FIRMWARE_VERSION),
+                        Err(e) => tracing::error!("Failed to mark firmware valid: {:?}", e),
+                    }
+
+                    return;
+                }
+            }
+
+            tracing::error!(
+                "Firmware {} failed its health check, rolling back",
+                FIRMWARE_VERSION
+            );
+            if let Err(e) = firmware.lock().rollback() {
+                tracing::error!("Failed to roll back: {:?}", e);
+            }
+        })?;
+
+    Ok(())
+}
@@ -0,0 +1,278 @@
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use base64::Engine;
+use embedded_svc::http::client::Client as HttpClient;
+use esp_idf_svc::{
+    http::client::{self, EspHttpConnection},
+    io::Read,
+};
+use serde::Serialize;
+use time::Time;
+
+use crate::{
+    core::{
+        device::Device, device_state::SendSyncDeviceStateService, wifi::supervisor::LinkMonitor,
+    },
+    util::{
+        result::{bail, error, Result},
+        sync::{arc_sync_rw_lock, ArcSyncRwLock},
+    },
+};
+
+use super::{
+    manifest::{self, Manifest, UpdateCheck, Version},
+    SendSyncFirmware, FIRMWARE_VERSION,
+};
+
+const UPDATER_TASK_STACK_SIZE: usize = 16 * 1024;
+const POLL_INTERVAL: Duration = Duration::from_secs(60);
+const DOWNLOAD_CHUNK_SIZE: usize = 4 * 1024;
+const HTTP_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct UpdaterStatus {
+    pub last_check_secs: Option<u64>,
+    pub latest_version: Option<Version>,
+    /// Downloaded and verified, waiting for the maintenance window to restart into it
+    pub staged_version: Option<Version>,
+    pub last_error: Option<String>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    last_check: Option<Instant>,
+    latest_version: Option<Version>,
+    staged_version: Option<Version>,
+    last_error: Option<String>,
+}
+
+impl State {
+    fn status(&self) -> UpdaterStatus {
+        UpdaterStatus {
+            last_check_secs: self.last_check.map(|at| at.elapsed().as_secs()),
+            latest_version: self.latest_version,
+            staged_version: self.staged_version,
+            last_error: self.last_error.clone(),
+        }
+    }
+}
+
+/// Read-only view of the background updater
+#[derive(Clone)]
+pub struct UpdateMonitor {
+    state: ArcSyncRwLock<State>,
+}
+
+impl UpdateMonitor {
+    pub fn status(&self) -> UpdaterStatus {
+        self.state.read().status()
+    }
+}
+
+/// Periodically checks the configured manifest and downloads newer images in the background.
+/// A downloaded image is only switched to and restarted into inside the maintenance window.
+pub struct Updater;
+
+impl Updater {
+    /// `local_time` returns the wall clock time, or `None` while it hasn't been synced yet
+    pub fn start(
+        firmware: SendSyncFirmware,
+        dev_svc: SendSyncDeviceStateService,
+        link: LinkMonitor,
+        local_time: fn() -> Option<Time>,
+    ) -> Result<UpdateMonitor> {
+        let running: Version = FIRMWARE_VERSION.parse()?;
+        let state = arc_sync_rw_lock(State::default());
+
+        thread::Builder::new()
+            .name("ota-updater".into())
+            .stack_size(UPDATER_TASK_STACK_SIZE)
+            .spawn({
+                let state = state.clone();
+                move || run(firmware, dev_svc, link, local_time, running, state)
+            })?;
+
+        Ok(UpdateMonitor { state })
+    }
+}
+
+fn run(
+    firmware: SendSyncFirmware,
+    dev_svc: SendSyncDeviceStateService,
+    link: LinkMonitor,
+    local_time: fn() -> Option<Time>,
+    running: Version,
+    state: ArcSyncRwLock<State>,
+) {
+    loop {
+        thread::sleep(POLL_INTERVAL);
+
+        let (manifest_url, check_interval, window, activation_start, activation_end) = {
+            let dvc = dev_svc.lock();
+
+            (
+                dvc.ota_manifest_url().map(|s| s.to_string()),
+                Duration::from_secs(dvc.ota_check_interval_secs()),
+                *dvc.ota_window(),
+                *dvc.activation_time_start(),
+                dvc.activation_time_end().copied(),
+            )
+        };
+
+        let Some(manifest_url) = manifest_url else {
+            continue;
+        };
+
+        if state.read().staged_version.is_some() {
+            // Without a synced clock there's no telling whether the sensor is active
+            if !window.allows_restart(local_time(), activation_start, activation_end) {
+                continue;
+            }
+
+            tracing::info!("Inside the maintenance window, restarting into the new firmware");
+            match firmware.lock().activate_staged() {
+                Ok(()) => Device::restart(),
+                Err(e) => {
+                    // Download it again on the next check
+                    tracing::error!("Failed to activate the staged firmware: {:?}", e);
+
+                    let mut state = state.write();
+                    state.staged_version = None;
+                    state.last_check = None;
+                    state.last_error = Some(e.to_string());
+                }
+            }
+
+            continue;
+        }
+
+        let is_due = state
+            .read()
+            .last_check
+            .map_or(true, |at| at.elapsed() >= check_interval);
+        if !is_due || !link.is_connected() {
+            continue;
+        }
+
+        // A freshly updated image has to pass its health check before it's replaced again
+        match firmware.lock().is_pending_verify() {
+            Ok(false) => {}
+            Ok(true) => continue,
+            Err(e) => {
+                tracing::error!("Failed to read the running slot: {:?}", e);
+                continue;
+            }
+        }
+
+        state.write().last_check = Some(Instant::now());
+
+        let result = check_and_download(&firmware, &manifest_url, &running, &state);
+
+        let mut state = state.write();
+        match result {
+            Ok(()) => state.last_error = None,
+            Err(e) => {
+                tracing::error!("Firmware update check failed: {:?}", e);
+                state.last_error = Some(e.to_string());
+            }
+        }
+    }
+}
+
+fn http_client() -> Result<HttpClient<EspHttpConnection>> {
+    let conn = EspHttpConnection::new(&client::Configuration {
+        timeout: Some(HTTP_TIMEOUT),
+        ..Default::default()
+    })?;
+
+    Ok(HttpClient::wrap(conn))
+}
+
+fn check_and_download(
+    firmware: &SendSyncFirmware,
+    manifest_url: &str,
+    running: &Version,
+    state: &ArcSyncRwLock<State>,
+) -> Result<()> {
+    let manifest = fetch_manifest(manifest_url)?;
+    state.write().latest_version = Some(manifest.version);
+
+    match manifest.check(running) {
+        UpdateCheck::UpToDate => {
+            tracing::info!("Firmware {} is up to date", running);
+            Ok(())
+        }
+        UpdateCheck::Unsupported { min_version } => {
+            bail!(
+                "Firmware {} needs at least {} to update from, running {}",
+                manifest.version,
+                min_version,
+                running
+            )
+        }
+        UpdateCheck::Available(version) => {
+            tracing::info!("Downloading firmware {}", version);
+
+            // The running image keeps booting until the window, a restart before then
+            // leaves the new one staged and unused
+            download(firmware, &manifest)?;
+            state.write().staged_version = Some(version);
+
+            tracing::info!("Firmware {} staged for the maintenance window", version);
+            Ok(())
+        }
+    }
+}
+
+fn fetch_manifest(url: &str) -> Result<Manifest> {
+    let mut http = http_client()?;
+    let mut res = http.get(url)?.submit()?;
+    let status = res.status();
+
+    manifest::read_manifest(status, |buf| Ok(res.read(buf)?))
+}
+
+fn download(firmware: &SendSyncFirmware, manifest: &Manifest) -> Result<()> {
+    let expected = manifest.digest()?;
+    let signature = manifest
+        .signature
+        .as_deref()
+        .map(|signature| base64::engine::general_purpose::STANDARD.decode(signature))
+        .transpose()?;
+
+    let mut firmware = firmware
+        .try_lock()
+        .ok_or_else(|| error!("A firmware update is already in progress"))?;
+
+    let mut http = http_client()?;
+    let mut res = http.get(&manifest.url)?.submit()?;
+
+    let status = res.status();
+    if !(200..300).contains(&status) {
+        bail!("Firmware download failed with status {}", status);
+    }
+
+    let mut update = firmware.begin_update()?;
+
+    let mut buff = [0u8; DOWNLOAD_CHUNK_SIZE];
+    loop {
+        let read = match res.read(&mut buff) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) => {
+                update.abort()?;
+                return Err(e.into());
+            }
+        };
+
+        if let Err(e) = update.write(&buff[..read]) {
+            update.abort()?;
+            return Err(e);
+        }
+    }
+
+    update.finish(&expected, signature.as_deref())
+}
@@ -0,0 +1,183 @@
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use esp_idf_svc::{
+    hal::gpio::{AnyInputPin, Input, PinDriver, Pull},
+    sys,
+};
+use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
+
+use crate::util::{
+    result::{bail, Result},
+    sync::{arc_sync_mutex, ArcSyncMutex, IntoSendSync},
+};
+
+use super::device::Device;
+
+/// How long a requested reset waits for its confirmation
+pub const CONFIRM_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long the BOOT button has to be held down to reset everything
+pub const BUTTON_HOLD_DURATION: Duration = Duration::from_secs(10);
+
+const TOKEN_LEN: usize = 16;
+const BUTTON_TASK_STACK_SIZE: usize = 4 * 1024;
+const BUTTON_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ResetScope {
+    /// Saved networks, the setup AP keeps its credential so it can still be joined
+    Wifi,
+    /// SMS recipient, message and Twilio account, and the MQTT broker
+    Notifications,
+    /// Formats the whole filesystem
+    All,
+}
+
+impl ResetScope {
+    /// What the reset clears, shown before it's confirmed
+    pub fn clears(self) -> &'static str {
+        match self {
+            ResetScope::Wifi => "Saved Wi-Fi networks, the setup AP keeps its password",
+            ResetScope::Notifications => {
+                "SMS recipient, message and Twilio account, and the MQTT broker settings"
+            }
+            ResetScope::All => "Everything on the device, it restarts as if new",
+        }
+    }
+
+    /// Whether the device has to restart for the reset to take effect
+    pub fn needs_restart(self) -> bool {
+        matches!(self, ResetScope::Wifi | ResetScope::All)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ResetRequest {
+    pub scope: ResetScope,
+    pub clears: &'static str,
+    pub token: String,
+    pub expires_in_secs: u64,
+}
+
+struct Pending {
+    scope: ResetScope,
+    token: String,
+    expires_at: Instant,
+}
+
+/// Two-step confirmation for resets, a reset only happens when the token handed out by
+/// [`ResetConfirmation::request`] comes back within [`CONFIRM_TIMEOUT`]
+#[derive(Default)]
+pub struct ResetConfirmation {
+    pending: Option<Pending>,
+}
+
+pub type SendSyncResetConfirmation = ArcSyncMutex<ResetConfirmation>;
+
+impl IntoSendSync for ResetConfirmation {
+    type SendSync = SendSyncResetConfirmation;
+
+    fn into_send_sync(self) -> Self::SendSync {
+        arc_sync_mutex(self)
+    }
+}
+
+impl ResetConfirmation {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts a reset, replacing any reset still waiting for confirmation
+    pub fn request(&mut self, scope: ResetScope) -> ResetRequest {
+        let token = random_token();
+
+        self.pending = Some(Pending {
+            scope,
+            token: token.clone(),
+            expires_at: Instant::now() + CONFIRM_TIMEOUT,
+        });
+
+        ResetRequest {
+            scope,
+            clears: scope.clears(),
+            token,
+            expires_in_secs: CONFIRM_TIMEOUT.as_secs(),
+        }
+    }
+
+    /// Returns the scope to reset. The pending reset is used up either way,
+    /// so a wrong token means starting over.
+    pub fn confirm(&mut self, token: &str) -> Result<ResetScope> {
+        let Some(pending) = self.pending.take() else {
+            bail!("No reset is waiting for confirmation");
+        };
+
+        if Instant::now() > pending.expires_at {
+            bail!("The reset request expired, request it again");
+        }
+
+        // Compared in constant time so the token can't be guessed a character at a time
+        if !bool::from(pending.token.as_bytes().ct_eq(token.as_bytes())) {
+            bail!("Invalid reset token");
+        }
+
+        Ok(pending.scope)
+    }
+}
+
+fn random_token() -> String {
+    const ALPHABET: &[u8] = b"0123456789abcdef";
+
+    (0..TOKEN_LEN)
+        .map(|_| {
+            let n = unsafe { sys::esp_random() } as usize;
+            ALPHABET[n % ALPHABET.len()] as char
+        })
+        .collect()
+}
+
+/// Offline factory reset, holding the button down for [`BUTTON_HOLD_DURATION`] formats the
+/// filesystem and restarts. The BOOT button is active low.
+pub fn watch_reset_button(mut button: PinDriver<'static, AnyInputPin, Input>) -> Result<()> {
+    button.set_pull(Pull::Up)?;
+
+    thread::Builder::new()
+        .name("reset-button".into())
+        .stack_size(BUTTON_TASK_STACK_SIZE)
+        .spawn(move || {
+            let mut pressed_since: Option<Instant> = None;
+
+            loop {
+                thread::sleep(BUTTON_POLL_INTERVAL);
+
+                if button.is_high() {
+                    pressed_since = None;
+                    continue;
+                }
+
+                let since = *pressed_since.get_or_insert_with(|| {
+                    tracing::info!(
+                        "Reset button pressed, hold for {:?} to reset the device",
+                        BUTTON_HOLD_DURATION
+                    );
+                    Instant::now()
+                });
+
+                if since.elapsed() >= BUTTON_HOLD_DURATION {
+                    tracing::warn!("Reset button held, resetting the device");
+
+                    if let Err(e) = Device::reset() {
+                        tracing::error!("Failed to reset the device: {:?}", e);
+                        pressed_since = None;
+                    }
+                }
+            }
+        })?;
+
+    Ok(())
+}
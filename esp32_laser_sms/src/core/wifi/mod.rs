@@ -1,6 +1,12 @@
-use std::{borrow::BorrowMut, future::Future, str::FromStr};
+pub mod supervisor;
 
-use crate::util::{collection::alloc::hash::HashSet, result::error};
+use std::{borrow::BorrowMut, cmp::Reverse, future::Future, str::FromStr};
+
+use crate::util::{
+    collection::alloc::hash::HashSet,
+    ffi::esp::esp_unsafe,
+    result::{bail, error},
+};
 use derivative::Derivative;
 use embedded_svc::{ipv4::IpInfo, wifi::AccessPointInfo};
 use enumset::{enum_set, EnumSet};
@@ -8,27 +14,45 @@ use esp_idf_svc::{
     eventloop::EspSystemEventLoop,
     hal::modem::Modem,
     nvs::EspDefaultNvsPartition,
+    sys,
     timer::EspTaskTimerService,
     wifi::{self, AccessPointConfiguration, AsyncWifi, AuthMethod, ClientConfiguration, EspWifi},
 };
 use heapless::String as HString;
+use hmac::{Hmac, Mac};
 use serde::{Deserialize, Serialize};
+use sha2::Sha256;
 
 use crate::util::{
     delay::non_blocking::delay_ms,
     sync::{arc_sync_mutex, ArcSyncMutex},
 };
 
-use super::persistent_state;
+use super::{captive_portal::CaptivePortal, persistent_state};
 use crate::util::sync::IntoSendSync;
 
-pub const DEFAULT_AP_SSID: &str = "Laser Security";
-pub const DEFAULT_AP_PSK: &str = "";
+/// The default AP SSID is this prefix followed by the last bytes of the AP MAC address
+pub const DEFAULT_AP_SSID_PREFIX: &str = "Laser Security";
+pub const DEFAULT_AP_PSK_LEN: usize = 12;
 pub const DEFAULT_AP_PROTOCOLS: EnumSet<wifi::Protocol> = enum_set!(wifi::Protocol::P802D11BGNLR);
 pub const DEFAULT_AP_CHANNEL: u8 = 6;
 pub const DEFAULT_AP_SECONDARY_CHANNEL: u8 = 11;
 pub const DEFAULT_AP_MAX_CONNECTIONS: u16 = 1;
 
+/// Leaves out characters that are easily misread off a serial log or a label
+const AP_PSK_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyzABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+
+/// The default AP PSK of every device is derived from its MAC address under this secret,
+/// anyone holding it can work out the PSK of a device, e.g. to print it on a label
+const AP_PSK_SECRET: &str = env!(
+    "AP_PSK_SECRET",
+    "Set AP_PSK_SECRET to a random secret of at least 32 characters, see the README"
+);
+const _: () = assert!(
+    AP_PSK_SECRET.len() >= 32,
+    "AP_PSK_SECRET must be at least 32 characters"
+);
+
 pub type WifiDriver<'a> = AsyncWifi<EspWifi<'a>>;
 pub type ThreadSafeWifiDriver = ArcSyncMutex<WifiDriver<'static>>;
 pub type SendSyncWifi<M = WifiStateManager> = ArcSyncMutex<Wifi<M>>;
@@ -40,11 +64,99 @@ pub struct Credential {
     #[derivative(Hash = "ignore", PartialEq = "ignore")]
     psk: String,
     bssid: [u8; 6],
+    /// Credentials with a higher priority are tried first
+    #[serde(default)]
+    #[derivative(Hash = "ignore", PartialEq = "ignore")]
+    priority: u8,
+}
+
+impl Credential {
+    pub fn ssid(&self) -> &str {
+        &self.ssid
+    }
+
+    pub fn bssid(&self) -> [u8; 6] {
+        self.bssid
+    }
+
+    pub fn priority(&self) -> u8 {
+        self.priority
+    }
+}
+
+/// A saved credential matched against an access point from a scan
+#[derive(Debug, Clone)]
+pub struct Candidate {
+    pub credential: Credential,
+    pub bssid: [u8; 6],
+    pub channel: u8,
+    pub signal_strength: i8,
+}
+
+/// Orders the saved credentials worth trying against the scanned access points.
+/// Higher priority goes first, then the network that last connected, then the strongest signal.
+/// Credentials match by SSID alone so a changed BSSID (mesh nodes, roaming, a replaced router)
+/// is still picked up, each SSID is tried once through its strongest access point.
+pub fn select_candidates(
+    credentials: &[Credential],
+    aps: &[AccessPointInfo],
+    last_successful: Option<&str>,
+) -> Vec<Candidate> {
+    let mut candidates: Vec<Candidate> = Vec::new();
+
+    for ap in aps {
+        let Some(credential) = credentials.iter().find(|cred| *cred.ssid == *ap.ssid) else {
+            continue;
+        };
+
+        match candidates
+            .iter_mut()
+            .find(|c| c.credential.ssid == credential.ssid)
+        {
+            Some(existing) if existing.signal_strength >= ap.signal_strength => {}
+            Some(existing) => {
+                existing.bssid = ap.bssid;
+                existing.channel = ap.channel;
+                existing.signal_strength = ap.signal_strength;
+            }
+            None => candidates.push(Candidate {
+                credential: credential.clone(),
+                bssid: ap.bssid,
+                channel: ap.channel,
+                signal_strength: ap.signal_strength,
+            }),
+        }
+    }
+
+    candidates.sort_by_key(|c| {
+        let is_last_successful = last_successful == Some(c.credential.ssid.as_str());
+
+        (
+            Reverse(c.credential.priority),
+            Reverse(is_last_successful),
+            Reverse(c.signal_strength),
+        )
+    });
+
+    candidates
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct AccessPointState {
+    /// Overrides the SSID derived from the MAC address
+    pub ssid: Option<String>,
+    /// Overrides the PSK derived from the MAC address
+    pub psk: Option<String>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone, Default)]
 pub struct WifiState {
     pub credentials: HashSet<Credential>,
+    #[serde(default)]
+    pub ap: AccessPointState,
+    /// SSID of the network the device last connected to
+    #[serde(default)]
+    pub last_successful: Option<String>,
 }
 
 pub type WifiStateStorage = persistent_state::BinaryFileStorage<WifiState>;
@@ -60,6 +172,7 @@ pub type SendSyncWifiStateManager<S = WifiStateStorage> =
 pub struct Wifi<M: BorrowMut<WifiStateManager> = WifiStateManager> {
     wifi: WifiDriver<'static>,
     config_manager: M,
+    captive_portal: Option<CaptivePortal>,
 }
 
 pub type DefaultWifi = Wifi<DefaultWifiStateManager>;
@@ -85,6 +198,117 @@ fn auth_method(psk: &str) -> AuthMethod {
     }
 }
 
+fn ap_mac() -> Result<[u8; 6]> {
+    let mut mac = [0u8; 6];
+    esp_unsafe!(sys::esp_read_mac(
+        mac.as_mut_ptr(),
+        sys::esp_mac_type_t_ESP_MAC_WIFI_SOFTAP,
+    ))?;
+
+    Ok(mac)
+}
+
+fn default_ap_ssid() -> Result<String> {
+    let mac = ap_mac()?;
+
+    Ok(format!(
+        "{} {:02X}{:02X}{:02X}",
+        DEFAULT_AP_SSID_PREFIX, mac[3], mac[4], mac[5]
+    ))
+}
+
+fn default_ap_psk() -> Result<String> {
+    Ok(derive_ap_psk(AP_PSK_SECRET.as_bytes(), &ap_mac()?))
+}
+
+/// Maps HMAC-SHA256 of the MAC address under `secret` onto [`AP_PSK_ALPHABET`]. Nothing is
+/// stored, the same device always gets the same PSK, resets included.
+///
+/// Bytes at or past the largest multiple of the alphabet length are skipped so every
+/// character is equally likely, the MAC is hashed again with a counter if a block runs out.
+pub fn derive_ap_psk(secret: &[u8], mac: &[u8; 6]) -> String {
+    let limit = 256 - 256 % AP_PSK_ALPHABET.len();
+    let mut psk = String::with_capacity(DEFAULT_AP_PSK_LEN);
+
+    for block in 0..=u8::MAX {
+        let mut hmac =
+            Hmac::<Sha256>::new_from_slice(secret).expect("HMAC takes keys of any length");
+        hmac.update(mac);
+        hmac.update(&[block]);
+
+        for byte in hmac.finalize().into_bytes() {
+            if byte as usize >= limit {
+                continue;
+            }
+
+            psk.push(AP_PSK_ALPHABET[byte as usize % AP_PSK_ALPHABET.len()] as char);
+            if psk.len() == DEFAULT_AP_PSK_LEN {
+                return psk;
+            }
+        }
+    }
+
+    unreachable!("256 blocks always hold enough usable bytes")
+}
+
+/// The payload phones understand when scanning a Wi-Fi QR code
+pub fn wifi_qr_payload(ssid: &str, psk: &str) -> String {
+    fn escape(value: &str) -> String {
+        value
+            .chars()
+            .flat_map(|c| match c {
+                '\\' | ';' | ',' | ':' | '"' => vec!['\\', c],
+                c => vec![c],
+            })
+            .collect()
+    }
+
+    if psk.is_empty() {
+        format!("WIFI:T:nopass;S:{};;", escape(ssid))
+    } else {
+        format!("WIFI:T:WPA;S:{};P:{};;", escape(ssid), escape(psk))
+    }
+}
+
+pub fn validate_ap_credential(ssid: Option<&str>, psk: Option<&str>) -> Result<()> {
+    if let Some(ssid) = ssid {
+        if ssid.is_empty() || ssid.len() > 32 {
+            bail!("SSID must be between 1 and 32 characters");
+        }
+    }
+
+    if let Some(psk) = psk {
+        if psk.len() < 8 || psk.len() > 63 {
+            bail!("PSK must be between 8 and 63 characters");
+        }
+    }
+
+    Ok(())
+}
+
+fn log_ap_credential(ssid: &str, psk: &str) {
+    tracing::info!("Setup AP SSID: {}", ssid);
+    tracing::info!("Setup AP PSK: {}", psk);
+
+    log_ap_qr_code(ssid, psk);
+}
+
+#[cfg(feature = "ap-qr")]
+fn log_ap_qr_code(ssid: &str, psk: &str) {
+    use qrcode::{render::unicode::Dense1x2, QrCode};
+
+    match QrCode::new(wifi_qr_payload(ssid, psk)) {
+        Ok(code) => {
+            let rendered = code.render::<Dense1x2>().quiet_zone(true).build();
+            tracing::info!("Setup AP QR code:\n{}", rendered);
+        }
+        Err(e) => tracing::error!("Failed to render setup AP QR code: {:?}", e),
+    }
+}
+
+#[cfg(not(feature = "ap-qr"))]
+fn log_ap_qr_code(_ssid: &str, _psk: &str) {}
+
 fn send_future<R: Send + 'static>(
     f: impl Future<Output = R> + Send + 'static,
 ) -> impl Future<Output = R> + Send + 'static {
@@ -104,6 +328,7 @@ impl<M: BorrowMut<WifiStateManager>> Wifi<M> {
         Ok(Wifi {
             wifi,
             config_manager,
+            captive_portal: None,
         })
     }
 
@@ -141,6 +366,8 @@ impl<M: BorrowMut<WifiStateManager>> Wifi<M> {
     }
 
     pub async fn stop(&mut self) -> Result<()> {
+        self.stop_captive_portal();
+
         if self.is_started()? {
             self.wifi.stop().await?;
         }
@@ -169,7 +396,11 @@ impl<M: BorrowMut<WifiStateManager>> Wifi<M> {
     }
 
     pub async fn start_ap(&mut self, ssid: &str, psk: &str) -> Result<()> {
-        let auth_method = auth_method(psk);
+        let auth_method = if psk.is_empty() {
+            AuthMethod::None
+        } else {
+            AuthMethod::WPA2Personal
+        };
 
         self.set_ap_configuration(AccessPointConfiguration {
             auth_method,
@@ -183,12 +414,62 @@ impl<M: BorrowMut<WifiStateManager>> Wifi<M> {
         })?;
 
         self.start().await?;
+        self.start_captive_portal()?;
 
         Ok(())
     }
 
     pub async fn start_ap_default(&mut self) -> Result<()> {
-        self.start_ap(DEFAULT_AP_SSID, DEFAULT_AP_PSK).await
+        let (ssid, psk) = self.ap_credential()?;
+        log_ap_credential(&ssid, &psk);
+
+        self.start_ap(&ssid, &psk).await
+    }
+
+    /// The SSID and PSK the setup AP is started with, unless overridden both are derived
+    /// from the AP MAC address
+    pub fn ap_credential(&mut self) -> Result<(String, String)> {
+        let cfg_mng: &mut WifiStateManager = self.config_manager.borrow_mut();
+        let ap = cfg_mng.state().ap.clone();
+
+        let ssid = match ap.ssid {
+            Some(ssid) => ssid,
+            None => default_ap_ssid()?,
+        };
+
+        let psk = match ap.psk {
+            Some(psk) => psk,
+            None => default_ap_psk()?,
+        };
+
+        Ok((ssid, psk))
+    }
+
+    pub fn ap_state(&mut self) -> AccessPointState {
+        let cfg: &mut WifiStateManager = self.config_manager.borrow_mut();
+
+        cfg.state().ap.clone()
+    }
+
+    /// Overrides the setup AP SSID and PSK, `None` reverts to the per device default.
+    /// The AP is restarted with the new credential when it's running.
+    pub async fn set_ap_credential(&mut self, ssid: Option<&str>, psk: Option<&str>) -> Result<()> {
+        validate_ap_credential(ssid, psk)?;
+
+        let cfg_mng: &mut WifiStateManager = self.config_manager.borrow_mut();
+        cfg_mng.update_state(|cfg| {
+            let mut cfg = cfg.clone();
+            cfg.ap.ssid = ssid.map(|s| s.to_string());
+            cfg.ap.psk = psk.map(|s| s.to_string());
+
+            cfg
+        })?;
+
+        if self.is_ap_enabled()? {
+            self.start_ap_default().await?;
+        }
+
+        Ok(())
     }
 
     pub fn has_saved_credentials(&mut self) -> bool {
@@ -207,42 +488,46 @@ impl<M: BorrowMut<WifiStateManager>> Wifi<M> {
         let cfg: &mut WifiStateManager = self.config_manager.borrow_mut();
         let cfg = cfg.state();
 
-        let creds = cfg.credentials.clone();
-
-        let mut aps = self.scan_aps().await?;
-        aps.sort_by(|a, b| a.signal_strength.cmp(&b.signal_strength));
-
-        let filtered_creds = aps
-            .into_iter()
-            .filter_map(|ap| {
-                creds
-                    .iter()
-                    .find(|cred| *cred.ssid == *ap.ssid && cred.bssid == ap.bssid)
-                    .cloned()
-            })
-            .collect::<Vec<_>>();
-
-        if filtered_creds.is_empty() {
-            return Ok(false);
-        }
-
-        Ok({
-            let mut did_connect = false;
-
-            for cred in filtered_creds {
-                let res = self
-                    .connect(&cred.ssid, &cred.psk, None, None, retries)
-                    .await;
-
-                did_connect = res.is_ok();
+        let creds = cfg.credentials.iter().cloned().collect::<Vec<_>>();
+        let last_successful = cfg.last_successful.clone();
+
+        let aps = self.scan_aps().await?;
+        let candidates = select_candidates(&creds, &aps, last_successful.as_deref());
+
+        for candidate in candidates {
+            let Candidate {
+                credential,
+                bssid,
+                channel,
+                ..
+            } = candidate;
+
+            let res = self
+                .connect(
+                    &credential.ssid,
+                    &credential.psk,
+                    Some(bssid),
+                    Some(channel),
+                    retries,
+                )
+                .await;
+
+            if let Err(e) = res {
+                tracing::warn!("Failed to connect to {}: {:?}", credential.ssid, e);
+                continue;
             }
 
-            if did_connect && self.is_ap_enabled()? {
+            // Keep track of the BSSID that worked, it may have changed since it was saved
+            self.save_ap_credential(&credential.ssid, &credential.psk, bssid)?;
+
+            if self.is_ap_enabled()? {
                 self.switch_to_sta_only().await?;
             }
 
-            did_connect
-        })
+            return Ok(true);
+        }
+
+        Ok(false)
     }
 
     pub fn is_ap_enabled(&self) -> Result<bool> {
@@ -281,6 +566,7 @@ impl<M: BorrowMut<WifiStateManager>> Wifi<M> {
 
         self.wifi
             .set_configuration(&wifi::Configuration::Client(client_config))?;
+        self.stop_captive_portal();
 
         self.start().await?;
         self.internal_connect().await?;
@@ -379,16 +665,59 @@ impl<M: BorrowMut<WifiStateManager>> Wifi<M> {
         Ok(())
     }
 
+    /// Saves the credential of the network the device just connected to.
+    /// Any other credential for the same SSID is replaced, keeping its priority.
     pub fn save_ap_credential(&mut self, ssid: &str, psk: &str, bssid: [u8; 6]) -> Result<()> {
         let cfg_mng: &mut WifiStateManager = self.config_manager.borrow_mut();
-        let cred = Credential {
-            ssid: ssid.to_string(),
-            psk: psk.to_string(),
-            bssid,
-        };
         cfg_mng.update_state(move |cfg| {
             let mut cfg = cfg.clone();
-            cfg.credentials.replace(cred);
+            let priority = cfg
+                .credentials
+                .iter()
+                .find(|cred| cred.ssid == ssid)
+                .map(|cred| cred.priority)
+                .unwrap_or_default();
+
+            cfg.credentials.retain(|cred| cred.ssid != ssid);
+            cfg.credentials.insert(Credential {
+                ssid: ssid.to_string(),
+                psk: psk.to_string(),
+                bssid,
+                priority,
+            });
+            cfg.last_successful = Some(ssid.to_string());
+
+            cfg
+        })?;
+
+        Ok(())
+    }
+
+    pub fn set_credential_priority(&mut self, ssid: &str, priority: u8) -> Result<()> {
+        let cfg_mng: &mut WifiStateManager = self.config_manager.borrow_mut();
+
+        if !cfg_mng
+            .state()
+            .credentials
+            .iter()
+            .any(|cred| cred.ssid == ssid)
+        {
+            bail!("No saved credential for {}", ssid);
+        }
+
+        cfg_mng.update_state(move |cfg| {
+            let mut cfg = cfg.clone();
+            cfg.credentials = cfg
+                .credentials
+                .into_iter()
+                .map(|mut cred| {
+                    if cred.ssid == ssid {
+                        cred.priority = priority;
+                    }
+
+                    cred
+                })
+                .collect();
 
             cfg
         })?;
@@ -402,17 +731,49 @@ impl<M: BorrowMut<WifiStateManager>> Wifi<M> {
             let mut cfg = cfg.clone();
             cfg.credentials.retain(|cred| {
                 if let Some(bssid) = bssid {
-                    cred.ssid != ssid && cred.bssid != bssid
+                    !(cred.ssid == ssid && cred.bssid == bssid)
                 } else {
                     cred.ssid != ssid
                 }
             });
+
+            if cfg.last_successful.as_deref() == Some(ssid) {
+                cfg.last_successful = None;
+            }
+
             cfg
         })?;
 
         Ok(())
     }
 
+    /// Forgets every saved network. The setup AP keeps its SSID and PSK, it's the way back in
+    /// once the device has no network to join.
+    pub fn reset_state(&mut self) -> Result<()> {
+        let cfg_mng: &mut WifiStateManager = self.config_manager.borrow_mut();
+        let ap = cfg_mng.state().ap.clone();
+        cfg_mng.set_state(WifiState {
+            ap,
+            ..WifiState::default()
+        })?;
+
+        Ok(())
+    }
+
+    pub async fn disconnect(&mut self) -> Result<()> {
+        if self.is_connected()? {
+            self.wifi.disconnect().await?;
+        }
+
+        Ok(())
+    }
+
+    pub fn last_successful_ssid(&mut self) -> Option<String> {
+        let cfg: &mut WifiStateManager = self.config_manager.borrow_mut();
+
+        cfg.state().last_successful.clone()
+    }
+
     pub fn is_up(&mut self) -> Result<bool> {
         Ok(self.wifi.is_up()?)
     }
@@ -444,6 +805,24 @@ impl<M: BorrowMut<WifiStateManager>> Wifi<M> {
         Ok(creds.into_iter().collect())
     }
 
+    /// Starts answering every DNS query with the AP address, does nothing if it's already running
+    fn start_captive_portal(&mut self) -> Result<()> {
+        if self.captive_portal.is_none() {
+            let ip = self.ap_ip_info()?.ip;
+            self.captive_portal = Some(CaptivePortal::start(ip)?);
+        }
+
+        Ok(())
+    }
+
+    fn stop_captive_portal(&mut self) {
+        self.captive_portal = None;
+    }
+
+    pub fn is_captive_portal_running(&self) -> bool {
+        self.captive_portal.is_some()
+    }
+
     pub async fn wait_netif_up(&mut self) -> Result<()> {
         self.wifi.wait_netif_up().await?;
 
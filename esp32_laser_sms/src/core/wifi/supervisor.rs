@@ -0,0 +1,280 @@
+use std::{
+    sync::mpsc::{self, RecvTimeoutError},
+    thread,
+    time::{Duration, Instant},
+};
+
+use esp_idf_svc::{
+    eventloop::{EspSubscription, EspSystemEventLoop, System},
+    hal::task::block_on,
+    wifi::WifiEvent,
+};
+use serde::Serialize;
+
+use crate::util::{
+    result::Result,
+    sync::{arc_sync_rw_lock, ArcSyncRwLock},
+};
+
+use super::SendSyncWifi;
+
+const SUPERVISOR_TASK_STACK_SIZE: usize = 16 * 1024;
+
+/// How often the link is checked while connected, in case a disconnect event was missed
+const CONNECTED_POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone)]
+pub struct SupervisorConfig {
+    /// Connection retries per saved credential on every reconnect attempt
+    pub retries: u8,
+    pub min_backoff: Duration,
+    pub max_backoff: Duration,
+    /// How long the device may stay offline before the setup AP is brought up next to STA
+    pub ap_fallback_after: Duration,
+}
+
+impl Default for SupervisorConfig {
+    fn default() -> Self {
+        Self {
+            retries: 2,
+            min_backoff: Duration::from_secs(5),
+            max_backoff: Duration::from_secs(5 * 60),
+            ap_fallback_after: Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LinkState {
+    Connected,
+    Disconnected,
+    Reconnecting,
+    /// Still offline, the setup AP is up so the device stays reachable
+    Fallback,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct LinkStatus {
+    pub state: LinkState,
+    pub reconnect_attempts: u32,
+    pub offline_secs: Option<u64>,
+}
+
+#[derive(Debug)]
+struct Link {
+    state: LinkState,
+    reconnect_attempts: u32,
+    offline_since: Option<Instant>,
+}
+
+impl Link {
+    fn set_connected(&mut self) {
+        if self.state != LinkState::Connected {
+            tracing::info!("Wi-Fi link is up");
+        }
+
+        self.state = LinkState::Connected;
+        self.reconnect_attempts = 0;
+        self.offline_since = None;
+    }
+
+    fn set_offline(&mut self, state: LinkState) {
+        if self.state == LinkState::Connected {
+            tracing::warn!("Wi-Fi link is down");
+        }
+
+        self.state = state;
+        self.offline_since.get_or_insert_with(Instant::now);
+    }
+
+    fn status(&self) -> LinkStatus {
+        LinkStatus {
+            state: self.state,
+            reconnect_attempts: self.reconnect_attempts,
+            offline_secs: self.offline_since.map(|since| since.elapsed().as_secs()),
+        }
+    }
+}
+
+enum Signal {
+    Connected,
+    Disconnected,
+}
+
+/// Cheap to clone read-only view of the link state kept by the supervisor
+#[derive(Clone)]
+pub struct LinkMonitor {
+    link: ArcSyncRwLock<Link>,
+}
+
+impl LinkMonitor {
+    pub fn status(&self) -> LinkStatus {
+        self.link.read().status()
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.link.read().state == LinkState::Connected
+    }
+}
+
+/// Keeps the STA link up by reconnecting with backoff across the saved credentials
+/// whenever the station gets disconnected
+pub struct WifiSupervisor {
+    link: ArcSyncRwLock<Link>,
+    _subscription: EspSubscription<'static, System>,
+}
+
+impl WifiSupervisor {
+    pub fn start(
+        wifi: SendSyncWifi,
+        sys_loop: &EspSystemEventLoop,
+        config: SupervisorConfig,
+    ) -> Result<Self> {
+        let connected = wifi.lock().is_connected()?;
+
+        let link = arc_sync_rw_lock(Link {
+            state: LinkState::Disconnected,
+            reconnect_attempts: 0,
+            offline_since: None,
+        });
+
+        if connected {
+            link.write().set_connected();
+        } else {
+            link.write().set_offline(LinkState::Disconnected);
+        }
+
+        let (tx, rx) = mpsc::channel();
+
+        let subscription = sys_loop.subscribe::<WifiEvent, _>(move |event| {
+            let signal = match event {
+                WifiEvent::StaConnected { .. } => Signal::Connected,
+                WifiEvent::StaDisconnected { .. } => Signal::Disconnected,
+                _ => return,
+            };
+
+            _ = tx.send(signal);
+        })?;
+
+        thread::Builder::new()
+            .name("wifi-supervisor".into())
+            .stack_size(SUPERVISOR_TASK_STACK_SIZE)
+            .spawn({
+                let link = link.clone();
+                move || supervise(wifi, link, rx, config)
+            })?;
+
+        Ok(Self {
+            link,
+            _subscription: subscription,
+        })
+    }
+
+    pub fn monitor(&self) -> LinkMonitor {
+        LinkMonitor {
+            link: self.link.clone(),
+        }
+    }
+}
+
+fn offline_state(ap_enabled: bool) -> LinkState {
+    if ap_enabled {
+        LinkState::Fallback
+    } else {
+        LinkState::Disconnected
+    }
+}
+
+fn supervise(
+    wifi: SendSyncWifi,
+    link: ArcSyncRwLock<Link>,
+    signals: mpsc::Receiver<Signal>,
+    config: SupervisorConfig,
+) {
+    let mut backoff = config.min_backoff;
+    let mut next_attempt = Instant::now();
+
+    loop {
+        let is_offline = link.read().state != LinkState::Connected;
+        let timeout = if is_offline {
+            next_attempt.saturating_duration_since(Instant::now())
+        } else {
+            CONNECTED_POLL_INTERVAL
+        };
+
+        match signals.recv_timeout(timeout) {
+            Ok(Signal::Connected) | Ok(Signal::Disconnected) | Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+
+        // The event only says something changed, the driver knows what the link is now.
+        // The driver stays locked through the attempt below, the HTTP handlers only wait for
+        // it so long. The link is never held across a blocking call so its status can be read.
+        let mut wifi_lock = wifi.lock();
+        if wifi_lock.is_connected().unwrap_or(false) {
+            link.write().set_connected();
+            backoff = config.min_backoff;
+            continue;
+        }
+
+        let ap_enabled = wifi_lock.is_ap_enabled().unwrap_or(false);
+        link.write().set_offline(offline_state(ap_enabled));
+
+        // Disconnect events are also raised by our own failed attempts, wait out the backoff
+        if Instant::now() < next_attempt {
+            continue;
+        }
+
+        let offline_for = link
+            .read()
+            .offline_since
+            .map(|since| since.elapsed())
+            .unwrap_or_default();
+
+        if offline_for >= config.ap_fallback_after && !ap_enabled {
+            tracing::warn!("Offline for {:?}, starting the setup AP", offline_for);
+
+            match block_on(wifi_lock.start_ap_default()) {
+                Ok(()) => link.write().state = LinkState::Fallback,
+                Err(e) => tracing::error!("Failed to start the setup AP: {:?}", e),
+            }
+        }
+
+        if !wifi_lock.has_saved_credentials() {
+            next_attempt = Instant::now() + config.max_backoff;
+            continue;
+        }
+
+        {
+            let mut link = link.write();
+            link.reconnect_attempts += 1;
+            if link.state != LinkState::Fallback {
+                link.state = LinkState::Reconnecting;
+            }
+        }
+
+        match block_on(wifi_lock.reconnect(config.retries)) {
+            Ok(true) => {
+                link.write().set_connected();
+                backoff = config.min_backoff;
+                continue;
+            }
+            Ok(false) => {
+                tracing::info!(
+                    "No saved access point could be reached, retrying in {:?}",
+                    backoff
+                );
+            }
+            Err(e) => {
+                tracing::error!("Reconnect failed: {:?}", e);
+            }
+        }
+
+        let ap_enabled = wifi_lock.is_ap_enabled().unwrap_or(false);
+        link.write().set_offline(offline_state(ap_enabled));
+
+        next_attempt = Instant::now() + backoff;
+        backoff = (backoff * 2).min(config.max_backoff);
+    }
+}
@@ -4,21 +4,28 @@
 #![feature(try_blocks)]
 
 use core::{
-    device::Device,
-    wifi::{self, Wifi},
+    captive_portal,
+    counters::{CountersSnapshot, NotificationChannel, COUNTERS},
+    device::{Device, StorageUsage},
+    diagnostics, metrics,
+    wifi::{
+        self,
+        supervisor::{LinkState, LinkStatus, SupervisorConfig, WifiSupervisor},
+        SendSyncWifi, Wifi,
+    },
 };
-use std::rc::Rc;
+use std::{rc::Rc, time::Duration};
 
 pub mod core;
 pub mod service;
 pub mod util;
 
 use base64::Engine;
-use embedded_svc::http::client::Client as HttpClient;
+use embedded_svc::http::{client::Client as HttpClient, server::Request};
 use esp_idf_svc::{
     eventloop::EspSystemEventLoop,
     hal::{
-        gpio::PinDriver,
+        gpio::{InputPin as _, PinDriver},
         ledc::{self, LedcDriver, LedcTimerDriver},
         peripherals::Peripherals,
         task::block_on,
@@ -34,7 +41,9 @@ use esp_idf_svc::{
     sntp::{EspSntp, OperatingMode, SntpConf, SyncMode},
     sys,
     timer::EspTaskTimerService,
+    wifi::AuthMethod,
 };
+use parking_lot::MutexGuard;
 use scopeguard::defer;
 use serde::{Deserialize, Serialize};
 use time::{
@@ -50,10 +59,22 @@ use util::{
 };
 
 use crate::{
-    core::device_state,
+    core::{
+        buzzer::{self, Buzzer, BuzzerSettings, Pattern},
+        device_state,
+        events::{stream::EventStream, SensorEvent, SensorEventBus},
+        mqtt::{Mqtt, MqttSettings, MqttStatus},
+        ota::{
+            self,
+            updater::{Updater, UpdaterStatus},
+            window::MaintenanceWindow,
+            Firmware,
+        },
+        reset::{self, ResetConfirmation, ResetScope},
+    },
     util::{
         delay::blocking::delay_ms,
-        result::{self, bail},
+        result::{self, bail, error},
         sync::IntoSendSync as _,
     },
 };
@@ -70,6 +91,20 @@ const DATE_TIME_FMT: &[FormatItem<'static>] =
 const TIME_FMT: &[FormatItem<'static>] = format_description!("[hour repr:12]:[minute] [period]");
 
 const HTTP_SERVER_STACK_SIZE: usize = 32 * 1024;
+const HTTP_SERVER_MAX_URI_HANDLERS: usize = 64;
+
+const EVENT_STREAM_MAX_FRAME_SIZE: usize = 256;
+
+const WIFI_STATUS_LOCK_TIMEOUT: Duration = Duration::from_millis(500);
+/// How long a request that changes Wi-Fi waits for the driver, the supervisor holds it for as
+/// long as a reconnect takes
+const WIFI_LOCK_TIMEOUT: Duration = Duration::from_secs(2);
+
+const FIRMWARE_CHUNK_SIZE: usize = 4 * 1024;
+/// A new image has this long to prove it's healthy before the bootloader rolls it back
+const FIRMWARE_HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(3 * 60);
+const MIN_HEALTHY_FREE_HEAP: u32 = 32 * 1024;
+const MIN_FIRMWARE_CHECK_INTERVAL_SECS: u64 = 5 * 60;
 
 time::serde::format_description!(time_de, Time, FMT);
 const FMT: &[FormatItem<'_>] = format_description!("[hour repr:24]:[minute][optional [:[second]]]");
@@ -81,6 +116,8 @@ async fn async_main<'a>() -> Result<()> {
 
     let peripherals = Peripherals::take()?;
     let pins = peripherals.pins;
+    reset::watch_reset_button(PinDriver::input(pins.gpio0.downgrade_input())?)?;
+
     let ldr_photoresistor_pin = pins.gpio32;
     let ldr_photoresistor = Rc::new(PinDriver::input(ldr_photoresistor_pin)?);
 
@@ -89,12 +126,12 @@ async fn async_main<'a>() -> Result<()> {
     let timer_driver = LedcTimerDriver::new(
         peripherals.ledc.timer0,
         &ledc::config::TimerConfig {
-            frequency: 2200.Hz(),
+            frequency: buzzer::pattern::BASE_FREQUENCY_HZ.Hz(),
             speed_mode: ledc::SpeedMode::HighSpeed,
             ..Default::default()
         },
     )?;
-    let mut piezo_buzzer = LedcDriver::new(piezo_buzzer_channel, timer_driver, piezo_buzzer_pin)?;
+    let piezo_buzzer = LedcDriver::new(piezo_buzzer_channel, timer_driver, piezo_buzzer_pin)?;
 
     let storage = device_state::DeviceStateStorage::new("/spiflash/conf/device.bin");
     let cfg = device_state::DeviceStateManager::new_loaded_or_default(storage)?;
@@ -109,21 +146,28 @@ async fn async_main<'a>() -> Result<()> {
     let manager = wifi::WifiStateManager::new_loaded_or_default(storage)?;
     let nvs = EspDefaultNvsPartition::take()?;
 
-    let mut wifi = Wifi::new(modem, sys_loop, timer_service, Some(nvs), manager)?;
+    let mut wifi = Wifi::new(modem, sys_loop.clone(), timer_service, Some(nvs), manager)?;
     if !wifi.reconnect(5).await? {
         wifi.start_ap_default().await?;
     }
 
+    // The AP address is fixed, the connectivity checks are answered without locking the driver
+    let ap_ip = wifi.ap_ip_info()?.ip;
     let wifi = wifi.into_send_sync();
 
+    let wifi_supervisor =
+        WifiSupervisor::start(wifi.clone(), &sys_loop, SupervisorConfig::default())?;
+    let wifi_link = wifi_supervisor.monitor();
+
     let _sntp = EspSntp::new_with_callback(
         &SntpConf {
             sync_mode: SyncMode::Immediate,
             operating_mode: OperatingMode::Poll,
             servers: ["121.58.193.100"], // ntp.pagasa.dost.gov.ph
         },
-        |_| unsafe {
-            TIME_SYNCED = true;
+        |_| {
+            unsafe { TIME_SYNCED = true };
+            diagnostics::record_time_sync();
         },
     )?;
 
@@ -132,18 +176,48 @@ async fn async_main<'a>() -> Result<()> {
         get_free_heap_size(),
     );
 
+    let firmware = Firmware::new()?.into_send_sync();
+    let firmware_slot = firmware.lock().running_slot_label()?;
+    let firmware_updates = Updater::start(
+        firmware.clone(),
+        dev_svc.clone(),
+        wifi_link.clone(),
+        synced_time_now,
+    )?;
+    let buzzer = Buzzer::start(
+        piezo_buzzer,
+        sys::ledc_mode_t_LEDC_HIGH_SPEED_MODE,
+        sys::ledc_timer_t_LEDC_TIMER_0,
+        dev_svc.clone(),
+    )?;
+    let sensor_events = SensorEventBus::new();
+    let mqtt = Mqtt::start(dev_svc.clone(), &sensor_events)?;
+    let event_stream = EventStream::start(dev_svc.clone(), &sensor_events, wifi_link.clone())?;
+
     let mut server = EspHttpServer::new(&server::Configuration {
         stack_size: HTTP_SERVER_STACK_SIZE,
+        max_uri_handlers: HTTP_SERVER_MAX_URI_HANDLERS,
         ..Default::default()
     })?;
 
-    server.fn_handler::<result::Error, _>("/", Method::Get, |req| {
+    handle(&mut server, "/", Method::Get, |req| {
         let mut res = req.into_ok_response()?;
         res.write_all(include_bytes!("./html/index.html"))?;
 
         Ok(())
     })?;
 
+    for &path in captive_portal::CONNECTIVITY_CHECK_PATHS {
+        handle_with_status(&mut server, path, Method::Get, 302, move |req| {
+            // Anything other than the expected answer makes the OS open the setup page
+            let location = format!("http://{}/", ap_ip);
+            req.into_response(302, Some("Found"), &[("Location", &location)])?
+                .flush()?;
+
+            Ok(())
+        })?;
+    }
+
     {
         #[derive(Deserialize)]
         struct ConnectRequest {
@@ -151,6 +225,7 @@ async fn async_main<'a>() -> Result<()> {
             bssid: Option<[u8; 6]>,
             psk: String,
             retries: Option<u8>,
+            priority: Option<u8>,
         }
 
         #[derive(Serialize)]
@@ -159,7 +234,8 @@ async fn async_main<'a>() -> Result<()> {
         }
 
         let wifi = wifi.clone();
-        server.fn_handler::<result::Error, _>(
+        handle(
+            &mut server,
             "/wifi-credentials",
             Method::Post,
             move |mut req| {
@@ -169,7 +245,7 @@ async fn async_main<'a>() -> Result<()> {
 
                 let connect_req: ConnectRequest = serde_json::from_slice(&buff[..end])?;
 
-                let mut wifi = wifi.lock();
+                let mut wifi = lock_wifi(&wifi)?;
                 block_on(async {
                     wifi.connect(&connect_req.ssid, &connect_req.psk, None, None, 3)
                         .await
@@ -180,6 +256,10 @@ async fn async_main<'a>() -> Result<()> {
 
                 wifi.save_ap_credential(&connect_req.ssid, &connect_req.psk, bssid)?;
 
+                if let Some(priority) = connect_req.priority {
+                    wifi.set_credential_priority(&connect_req.ssid, priority)?;
+                }
+
                 let resp = ConnectResponse { new_ip };
                 let resp = serde_json::to_string(&resp)?;
                 let mut res =
@@ -199,6 +279,276 @@ async fn async_main<'a>() -> Result<()> {
         )?;
     }
 
+    {
+        #[derive(Serialize)]
+        struct ConnectionInfo {
+            ssid: String,
+            bssid: [u8; 6],
+            rssi: i8,
+            channel: u8,
+            ip: String,
+            gateway: String,
+        }
+
+        #[derive(Serialize)]
+        struct AccessPointStatus {
+            enabled: bool,
+            ip: Option<String>,
+            captive_portal: bool,
+        }
+
+        #[derive(Serialize)]
+        struct GetWifiStatusResponse {
+            link: LinkStatus,
+            connection: Option<ConnectionInfo>,
+            ap: Option<AccessPointStatus>,
+        }
+
+        let wifi = wifi.clone();
+        let wifi_link = wifi_link.clone();
+        handle(&mut server, "/wifi/status", Method::Get, move |req| {
+            let link = wifi_link.status();
+            let is_connected = link.state == LinkState::Connected;
+
+            // The supervisor holds the driver while reconnecting, report the link state alone then
+            let (connection, ap) = match wifi.try_lock_for(WIFI_STATUS_LOCK_TIMEOUT) {
+                Some(mut wifi) => {
+                    let connection = if is_connected {
+                        let info = wifi.sta_ap_info()?;
+                        let ip_info = wifi.sta_ip_info()?;
+
+                        Some(ConnectionInfo {
+                            ssid: info.ssid.to_string(),
+                            bssid: info.bssid,
+                            rssi: info.signal_strength,
+                            channel: info.channel,
+                            ip: ip_info.ip.to_string(),
+                            gateway: ip_info.subnet.gateway.to_string(),
+                        })
+                    } else {
+                        None
+                    };
+
+                    let enabled = wifi.is_ap_enabled()?;
+                    let ap = AccessPointStatus {
+                        enabled,
+                        ip: if enabled {
+                            Some(wifi.ap_ip_info()?.ip.to_string())
+                        } else {
+                            None
+                        },
+                        captive_portal: wifi.is_captive_portal_running(),
+                    };
+
+                    (connection, Some(ap))
+                }
+                None => (None, None),
+            };
+
+            let resp = GetWifiStatusResponse {
+                link,
+                connection,
+                ap,
+            };
+
+            let mut res = req.into_response(200, None, &[("Content-Type", "application/json")])?;
+            res.write_all(serde_json::to_string(&resp)?.as_bytes())?;
+
+            Ok(())
+        })?;
+    }
+
+    {
+        #[derive(Serialize)]
+        struct ScannedAccessPoint {
+            ssid: String,
+            bssid: [u8; 6],
+            rssi: i8,
+            auth: Option<AuthMethod>,
+            channel: u8,
+        }
+
+        let wifi = wifi.clone();
+        handle(&mut server, "/wifi/scan", Method::Get, move |req| {
+            let mut aps = block_on(lock_wifi(&wifi)?.scan_aps())?;
+            aps.sort_by(|a, b| b.signal_strength.cmp(&a.signal_strength));
+
+            let resp = aps
+                .into_iter()
+                .filter(|ap| !ap.ssid.is_empty())
+                .map(|ap| ScannedAccessPoint {
+                    ssid: ap.ssid.to_string(),
+                    bssid: ap.bssid,
+                    rssi: ap.signal_strength,
+                    auth: ap.auth_method,
+                    channel: ap.channel,
+                })
+                .collect::<Vec<_>>();
+
+            let mut res = req.into_response(200, None, &[("Content-Type", "application/json")])?;
+            res.write_all(serde_json::to_string(&resp)?.as_bytes())?;
+
+            Ok(())
+        })?;
+    }
+
+    {
+        #[derive(Serialize)]
+        struct SavedCredential {
+            ssid: String,
+            bssid: [u8; 6],
+            priority: u8,
+            last_successful: bool,
+        }
+
+        let wifi_get = wifi.clone();
+        handle(&mut server, "/wifi/credentials", Method::Get, move |req| {
+            let mut wifi = lock_wifi(&wifi_get)?;
+            let last_successful = wifi.last_successful_ssid();
+
+            // PSKs never leave the device
+            let mut resp = wifi
+                .saved_credentials()?
+                .into_iter()
+                .map(|cred| SavedCredential {
+                    ssid: cred.ssid().to_string(),
+                    bssid: cred.bssid(),
+                    priority: cred.priority(),
+                    last_successful: last_successful.as_deref() == Some(cred.ssid()),
+                })
+                .collect::<Vec<_>>();
+            resp.sort_by(|a, b| b.priority.cmp(&a.priority).then(a.ssid.cmp(&b.ssid)));
+
+            let mut res = req.into_response(200, None, &[("Content-Type", "application/json")])?;
+            res.write_all(serde_json::to_string(&resp)?.as_bytes())?;
+
+            Ok(())
+        })?;
+
+        #[derive(Deserialize)]
+        struct ForgetCredentialRequest {
+            ssid: String,
+            bssid: Option<[u8; 6]>,
+        }
+
+        let wifi_forget = wifi.clone();
+        handle(
+            &mut server,
+            "/wifi/credentials/forget",
+            Method::Post,
+            move |mut req| {
+                let mut buff = [0u8; 2 * 1024];
+
+                let end = req.read(&mut buff)?;
+
+                let forget_req: ForgetCredentialRequest = serde_json::from_slice(&buff[..end])
+                    .inspect_err(|e| {
+                        tracing::error!("Error: {:?}", e);
+                    })?;
+
+                let mut wifi = lock_wifi(&wifi_forget)?;
+                wifi.forget_ap(&forget_req.ssid, forget_req.bssid)?;
+
+                req.into_ok_response()?.flush()?;
+
+                // Drop the link to the forgotten network, the supervisor moves on to the next one
+                let is_current =
+                    wifi.is_connected()? && wifi.sta_ap_info()?.ssid.as_str() == forget_req.ssid;
+                if is_current {
+                    block_on(wifi.disconnect())?;
+                }
+
+                Ok(())
+            },
+        )?;
+
+        #[derive(Deserialize)]
+        struct SetPriorityRequest {
+            ssid: String,
+            priority: u8,
+        }
+
+        let wifi = wifi.clone();
+        handle(
+            &mut server,
+            "/wifi/credentials/priority",
+            Method::Post,
+            move |mut req| {
+                let mut buff = [0u8; 2 * 1024];
+
+                let end = req.read(&mut buff)?;
+
+                let set_req: SetPriorityRequest = serde_json::from_slice(&buff[..end])
+                    .inspect_err(|e| {
+                        tracing::error!("Error: {:?}", e);
+                    })?;
+
+                lock_wifi(&wifi)?.set_credential_priority(&set_req.ssid, set_req.priority)?;
+
+                req.into_ok_response()?.flush()?;
+                Ok(())
+            },
+        )?;
+    }
+
+    {
+        #[derive(Serialize)]
+        struct GetAccessPointResponse {
+            ssid: String,
+            ssid_overridden: bool,
+            psk_overridden: bool,
+        }
+
+        let wifi_get = wifi.clone();
+        handle(&mut server, "/wifi/ap", Method::Get, move |req| {
+            let mut wifi = lock_wifi(&wifi_get)?;
+            let state = wifi.ap_state();
+            let (ssid, _) = wifi.ap_credential()?;
+
+            let resp = GetAccessPointResponse {
+                ssid,
+                ssid_overridden: state.ssid.is_some(),
+                psk_overridden: state.psk.is_some(),
+            };
+
+            let mut res = req.into_response(200, None, &[("Content-Type", "application/json")])?;
+            res.write_all(serde_json::to_string(&resp)?.as_bytes())?;
+
+            Ok(())
+        })?;
+
+        #[derive(Deserialize)]
+        struct SetAccessPointRequest {
+            ssid: Option<String>,
+            psk: Option<String>,
+        }
+
+        let wifi = wifi.clone();
+        handle(&mut server, "/wifi/ap", Method::Post, move |mut req| {
+            let mut buff = [0u8; 2 * 1024];
+
+            let end = req.read(&mut buff)?;
+
+            let set_req: SetAccessPointRequest =
+                serde_json::from_slice(&buff[..end]).inspect_err(|e| {
+                    tracing::error!("Error: {:?}", e);
+                })?;
+
+            wifi::validate_ap_credential(set_req.ssid.as_deref(), set_req.psk.as_deref())?;
+
+            req.into_ok_response()?.flush()?;
+
+            // Respond first, the client may be connected through the AP that is being changed
+            let mut wifi = lock_wifi(&wifi)?;
+            block_on(wifi.set_ap_credential(set_req.ssid.as_deref(), set_req.psk.as_deref()))
+                .inspect_err(|e| {
+                    tracing::error!("Error: {:?}", e);
+                })?;
+
+            Ok(())
+        })?;
+    }
+
     {
         #[derive(Deserialize)]
         struct SetSmsSendRequest {
@@ -211,7 +561,7 @@ async fn async_main<'a>() -> Result<()> {
         }
 
         let dvc = dev_svc.clone();
-        server.fn_handler::<result::Error, _>("/sms-send", Method::Post, move |mut req| {
+        handle(&mut server, "/sms-send", Method::Post, move |mut req| {
             let mut buff = [0u8; 2 * 1024];
 
             let end = req.read(&mut buff)?;
@@ -249,7 +599,7 @@ async fn async_main<'a>() -> Result<()> {
         }
 
         let dvc = dev_svc.clone();
-        server.fn_handler::<result::Error, _>("/activation", Method::Post, move |mut req| {
+        handle(&mut server, "/activation", Method::Post, move |mut req| {
             let mut buff = [0u8; 2 * 1024];
 
             let end = req.read(&mut buff).inspect_err(|e| {
@@ -278,10 +628,14 @@ async fn async_main<'a>() -> Result<()> {
         #[derive(Deserialize)]
         struct SetBuzzerRequest {
             enabled: bool,
+            pattern: Option<Pattern>,
+            volume: Option<u8>,
+            latch_secs: Option<u32>,
+            max_duration_secs: Option<u32>,
         }
 
         let dvc = dev_svc.clone();
-        server.fn_handler::<result::Error, _>("/buzzer", Method::Post, move |mut req| {
+        handle(&mut server, "/buzzer", Method::Post, move |mut req| {
             let mut buff = [0u8; 2 * 1024];
 
             let end = req.read(&mut buff).inspect_err(|e| {
@@ -294,6 +648,21 @@ async fn async_main<'a>() -> Result<()> {
                 })?;
 
             let mut dvc = dvc.lock();
+
+            // Settings left out of the request keep their current value
+            let current = *dvc.buzzer();
+            let settings = BuzzerSettings {
+                pattern: set_req.pattern.unwrap_or(current.pattern),
+                volume: set_req.volume.unwrap_or(current.volume),
+                latch_secs: set_req.latch_secs.unwrap_or(current.latch_secs),
+                max_duration_secs: set_req
+                    .max_duration_secs
+                    .unwrap_or(current.max_duration_secs),
+            };
+            dvc.set_buzzer_settings(settings).inspect_err(|e| {
+                tracing::error!("Error: {:?}", e);
+            })?;
+
             dvc.set_buzzer(set_req.enabled).inspect_err(|e| {
                 tracing::error!("Error: {:?}", e);
             })?;
@@ -303,6 +672,100 @@ async fn async_main<'a>() -> Result<()> {
         })?;
     }
 
+    {
+        #[derive(Deserialize)]
+        struct SetArmedRequest {
+            armed: bool,
+        }
+
+        let dvc = dev_svc.clone();
+        handle(&mut server, "/armed", Method::Post, move |mut req| {
+            let mut buff = [0u8; 2 * 1024];
+
+            let end = req.read(&mut buff).inspect_err(|e| {
+                tracing::error!("Error: {:?}", e);
+            })?;
+
+            let set_req: SetArmedRequest =
+                serde_json::from_slice(&buff[..end]).inspect_err(|e| {
+                    tracing::error!("Error: {:?}", e);
+                })?;
+
+            let mut dvc = dvc.lock();
+            dvc.set_armed(set_req.armed).inspect_err(|e| {
+                tracing::error!("Error: {:?}", e);
+            })?;
+
+            req.into_ok_response()?.flush()?;
+            Ok(())
+        })?;
+    }
+
+    {
+        #[derive(Serialize)]
+        struct GetMqttResponse {
+            settings: Option<MqttSettings>,
+            status: MqttStatus,
+        }
+
+        let dvc = dev_svc.clone();
+        let mqtt = mqtt.clone();
+        handle(&mut server, "/mqtt", Method::Get, move |req| {
+            let resp = GetMqttResponse {
+                settings: dvc.lock().mqtt().map(MqttSettings::masked),
+                status: mqtt.status(),
+            };
+
+            let mut res = req.into_response(200, None, &[("Content-Type", "application/json")])?;
+            res.write_all(serde_json::to_string(&resp)?.as_bytes())?;
+
+            Ok(())
+        })?;
+
+        #[derive(Deserialize)]
+        struct SetMqttRequest {
+            broker_url: Option<String>,
+            username: Option<String>,
+            password: Option<String>,
+            topic_prefix: Option<String>,
+            #[serde(default)]
+            tls: bool,
+        }
+
+        let dvc = dev_svc.clone();
+        handle(&mut server, "/mqtt", Method::Post, move |mut req| {
+            let mut buff = [0u8; 2 * 1024];
+
+            let end = req.read(&mut buff).inspect_err(|e| {
+                tracing::error!("Error: {:?}", e);
+            })?;
+
+            let set_req: SetMqttRequest =
+                serde_json::from_slice(&buff[..end]).inspect_err(|e| {
+                    tracing::error!("Error: {:?}", e);
+                })?;
+
+            let non_empty = |s: Option<String>| s.filter(|s| !s.is_empty());
+
+            let mut dvc = dvc.lock();
+            // An empty broker URL turns MQTT off
+            let settings = non_empty(set_req.broker_url).map(|broker_url| MqttSettings {
+                broker_url,
+                username: non_empty(set_req.username),
+                password: MqttSettings::unmask_password(non_empty(set_req.password), dvc.mqtt()),
+                topic_prefix: non_empty(set_req.topic_prefix),
+                tls: set_req.tls,
+            });
+
+            dvc.set_mqtt(settings).inspect_err(|e| {
+                tracing::error!("Error: {:?}", e);
+            })?;
+
+            req.into_ok_response()?.flush()?;
+            Ok(())
+        })?;
+    }
+
     {
         #[derive(Serialize)]
         struct GetDeviceInfoResponse<'a> {
@@ -315,10 +778,12 @@ async fn async_main<'a>() -> Result<()> {
             activation_time_start: Time,
             activation_time_end: Option<Time>,
             buzzer_enabled: bool,
+            buzzer: BuzzerSettings,
+            armed: bool,
         }
 
         let dvc = dev_svc.clone();
-        server.fn_handler::<result::Error, _>("/device-info", Method::Get, move |req| {
+        handle(&mut server, "/device-info", Method::Get, move |req| {
             let dvc = dvc.lock();
             let resp = GetDeviceInfoResponse {
                 sms_send_phone_number: dvc.sms_send_phone_number(),
@@ -330,6 +795,8 @@ async fn async_main<'a>() -> Result<()> {
                 activation_time_start: *dvc.activation_time_start(),
                 activation_time_end: dvc.activation_time_end().copied(),
                 buzzer_enabled: dvc.buzzer_enabled(),
+                buzzer: *dvc.buzzer(),
+                armed: dvc.armed(),
             };
 
             let mut res = req.into_response(200, None, &[("Content-Type", "application/json")])?;
@@ -340,22 +807,393 @@ async fn async_main<'a>() -> Result<()> {
     }
 
     {
-        server.fn_handler::<result::Error, _>("/reset-device", Method::Post, move |req| {
-            Device::reset()?;
+        #[derive(Deserialize)]
+        struct ResetDeviceRequest {
+            scope: ResetScope,
+        }
+
+        let reset_confirmation = ResetConfirmation::new().into_send_sync();
+
+        let confirmation = reset_confirmation.clone();
+        handle(
+            &mut server,
+            "/reset-device",
+            Method::Post,
+            move |mut req| {
+                let mut buff = [0u8; 2 * 1024];
+
+                let end = req.read(&mut buff).inspect_err(|e| {
+                    tracing::error!("Error: {:?}", e);
+                })?;
+
+                let reset_req: ResetDeviceRequest = serde_json::from_slice(&buff[..end])
+                    .inspect_err(|e| {
+                        tracing::error!("Error: {:?}", e);
+                    })?;
+
+                let resp = confirmation.lock().request(reset_req.scope);
+                tracing::warn!(
+                    "Reset of {:?} requested, waiting for confirmation",
+                    resp.scope
+                );
+
+                let mut res =
+                    req.into_response(200, None, &[("Content-Type", "application/json")])?;
+                res.write_all(serde_json::to_string(&resp)?.as_bytes())?;
+
+                Ok(())
+            },
+        )?;
+
+        #[derive(Deserialize)]
+        struct ConfirmResetDeviceRequest {
+            token: String,
+        }
+
+        let confirmation = reset_confirmation.clone();
+        let wifi = wifi.clone();
+        let dvc = dev_svc.clone();
+        handle(
+            &mut server,
+            "/reset-device/confirm",
+            Method::Post,
+            move |mut req| {
+                let mut buff = [0u8; 2 * 1024];
+
+                let end = req.read(&mut buff).inspect_err(|e| {
+                    tracing::error!("Error: {:?}", e);
+                })?;
+
+                let confirm_req: ConfirmResetDeviceRequest = serde_json::from_slice(&buff[..end])
+                    .inspect_err(|e| {
+                    tracing::error!("Error: {:?}", e);
+                })?;
+
+                let scope = confirmation
+                    .lock()
+                    .confirm(&confirm_req.token)
+                    .inspect_err(|e| {
+                        tracing::error!("Error: {:?}", e);
+                    })?;
+
+                tracing::warn!("Resetting {:?}", scope);
+
+                match scope {
+                    ResetScope::Wifi => lock_wifi(&wifi)?.reset_state()?,
+                    ResetScope::Notifications => dvc.lock().reset_notifications()?,
+                    // Formatting restarts the device, it happens after the response is sent
+                    ResetScope::All => {}
+                }
+
+                req.into_ok_response()?.flush()?;
+
+                if scope.needs_restart() {
+                    defer! {
+                        delay_ms(1000);
+
+                        if scope == ResetScope::All {
+                            if let Err(e) = Device::reset() {
+                                tracing::error!("Error: {:?}", e);
+                            }
+                        }
+
+                        Device::restart();
+                    };
+                }
+
+                Ok(())
+            },
+        )?;
+
+        handle(&mut server, "/restart-device", Method::Post, move |req| {
+            Device::restart();
 
             req.into_ok_response()?.flush()?;
             Ok(())
         })?;
+    }
 
-        server.fn_handler::<result::Error, _>("/restart-device", Method::Post, move |req| {
-            Device::restart();
+    {
+        #[derive(Serialize)]
+        struct WifiDiagnostics {
+            link: LinkState,
+            rssi: Option<i8>,
+            channel: Option<u8>,
+            ip: Option<String>,
+        }
+
+        #[derive(Serialize)]
+        struct GetDiagnosticsResponse {
+            uptime_secs: u64,
+            heap: diagnostics::HeapInfo,
+            reset_reason: &'static str,
+            wifi: WifiDiagnostics,
+            time_synced: bool,
+            storage: Option<StorageUsage>,
+            firmware: diagnostics::FirmwareInfo,
+            counters: CountersSnapshot,
+        }
+
+        let wifi = wifi.clone();
+        let wifi_link = wifi_link.clone();
+        handle(&mut server, "/diagnostics", Method::Get, move |req| {
+            let link = wifi_link.status().state;
+
+            let mut wifi_diagnostics = WifiDiagnostics {
+                link,
+                rssi: None,
+                channel: None,
+                ip: None,
+            };
+
+            if link == LinkState::Connected {
+                if let Some(mut wifi) = wifi.try_lock_for(WIFI_STATUS_LOCK_TIMEOUT) {
+                    let info = wifi.sta_ap_info()?;
+                    wifi_diagnostics.rssi = Some(info.signal_strength);
+                    wifi_diagnostics.channel = Some(info.channel);
+                    wifi_diagnostics.ip = Some(wifi.sta_ip_info()?.ip.to_string());
+                }
+            }
+
+            let resp = GetDiagnosticsResponse {
+                uptime_secs: diagnostics::uptime_secs(),
+                heap: diagnostics::heap(),
+                reset_reason: diagnostics::reset_reason(),
+                wifi: wifi_diagnostics,
+                time_synced: unsafe { TIME_SYNCED },
+                storage: Device::storage_usage()
+                    .inspect_err(|e| tracing::error!("Error: {:?}", e))
+                    .ok(),
+                firmware: diagnostics::firmware(),
+                counters: COUNTERS.snapshot(),
+            };
+
+            let mut res = req.into_response(200, None, &[("Content-Type", "application/json")])?;
+            res.write_all(serde_json::to_string(&resp)?.as_bytes())?;
+
+            Ok(())
+        })?;
+    }
+
+    {
+        let wifi = wifi.clone();
+        let wifi_link = wifi_link.clone();
+        handle(&mut server, "/metrics", Method::Get, move |req| {
+            let mut wifi_rssi = None;
+
+            if wifi_link.status().state == LinkState::Connected {
+                if let Some(wifi) = wifi.try_lock_for(WIFI_STATUS_LOCK_TIMEOUT) {
+                    wifi_rssi = Some(wifi.sta_ap_info()?.signal_strength);
+                }
+            }
+
+            let body = metrics::render(metrics::Sample { wifi_rssi });
+
+            let mut res =
+                req.into_response(200, None, &[("Content-Type", metrics::CONTENT_TYPE)])?;
+            res.write_all(body.as_bytes())?;
+
+            Ok(())
+        })?;
+    }
+
+    {
+        let event_stream = event_stream.clone();
+        server.ws_handler("/events/stream", move |ws| {
+            if ws.is_new() {
+                event_stream.add_client(ws.create_detached_sender()?);
+                return Ok(());
+            }
+
+            if ws.is_closed() {
+                return Ok(());
+            }
+
+            // The stream only goes one way, anything the client sends is read and dropped.
+            // A frame too big for the buffer fails the read and closes the connection.
+            let mut buff = [0u8; EVENT_STREAM_MAX_FRAME_SIZE];
+            ws.recv(&mut buff)?;
+
+            Ok(())
+        })?;
+    }
+
+    {
+        #[derive(Serialize)]
+        struct GetFirmwareResponse {
+            version: &'static str,
+            slot: String,
+            updates: UpdaterStatus,
+        }
+
+        let firmware_updates = firmware_updates.clone();
+        handle(&mut server, "/firmware", Method::Get, move |req| {
+            let resp = GetFirmwareResponse {
+                version: ota::FIRMWARE_VERSION,
+                slot: firmware_slot.clone(),
+                updates: firmware_updates.status(),
+            };
+
+            let mut res = req.into_response(200, None, &[("Content-Type", "application/json")])?;
+            res.write_all(serde_json::to_string(&resp)?.as_bytes())?;
+
+            Ok(())
+        })?;
+
+        #[derive(Serialize)]
+        struct GetFirmwareUpdateServerResponse {
+            manifest_url: Option<String>,
+            check_interval_secs: u64,
+            window: MaintenanceWindow,
+        }
+
+        let dvc = dev_svc.clone();
+        handle(
+            &mut server,
+            "/firmware/update-server",
+            Method::Get,
+            move |req| {
+                let dvc = dvc.lock();
+                let resp = GetFirmwareUpdateServerResponse {
+                    manifest_url: dvc.ota_manifest_url().map(|s| s.to_string()),
+                    check_interval_secs: dvc.ota_check_interval_secs(),
+                    window: *dvc.ota_window(),
+                };
+
+                let mut res =
+                    req.into_response(200, None, &[("Content-Type", "application/json")])?;
+                res.write_all(serde_json::to_string(&resp)?.as_bytes())?;
+
+                Ok(())
+            },
+        )?;
+
+        #[derive(Deserialize)]
+        struct SetFirmwareUpdateServerRequest {
+            manifest_url: Option<String>,
+            check_interval_secs: u64,
+            #[serde(with = "time_de")]
+            window_start: Time,
+            #[serde(with = "time_de")]
+            window_end: Time,
+        }
+
+        let dvc = dev_svc.clone();
+        handle(
+            &mut server,
+            "/firmware/update-server",
+            Method::Post,
+            move |mut req| {
+                let mut buff = [0u8; 2 * 1024];
+
+                let end = req.read(&mut buff).inspect_err(|e| {
+                    tracing::error!("Error: {:?}", e);
+                })?;
+
+                let set_req: SetFirmwareUpdateServerRequest = serde_json::from_slice(&buff[..end])
+                    .inspect_err(|e| {
+                        tracing::error!("Error: {:?}", e);
+                    })?;
+
+                if set_req.check_interval_secs < MIN_FIRMWARE_CHECK_INTERVAL_SECS {
+                    bail!(
+                        "Check interval must be at least {} seconds",
+                        MIN_FIRMWARE_CHECK_INTERVAL_SECS
+                    );
+                }
+
+                let manifest_url = set_req
+                    .manifest_url
+                    .as_deref()
+                    .filter(|url| !url.is_empty());
+                let window = MaintenanceWindow {
+                    start: set_req.window_start,
+                    end: set_req.window_end,
+                };
+
+                dvc.lock()
+                    .set_ota_update(manifest_url, set_req.check_interval_secs, window)
+                    .inspect_err(|e| {
+                        tracing::error!("Error: {:?}", e);
+                    })?;
+
+                req.into_ok_response()?.flush()?;
+                Ok(())
+            },
+        )?;
+
+        let firmware = firmware.clone();
+        handle(&mut server, "/firmware", Method::Post, move |mut req| {
+            let expected = req
+                .header("X-Firmware-Sha256")
+                .ok_or_else(|| error!("Missing X-Firmware-Sha256 header"))?;
+            let expected = ota::parse_sha256(expected)?;
+
+            let signature = req
+                .header("X-Firmware-Signature")
+                .map(|signature| base64::engine::general_purpose::STANDARD.decode(signature))
+                .transpose()?;
+
+            let mut firmware = firmware
+                .try_lock()
+                .ok_or_else(|| error!("A firmware update is already in progress"))?;
+            let mut update = firmware.begin_update()?;
+
+            // Stream the body straight into flash, the image doesn't fit in RAM
+            let mut buff = [0u8; FIRMWARE_CHUNK_SIZE];
+            loop {
+                let read = match req.read(&mut buff) {
+                    Ok(0) => break,
+                    Ok(read) => read,
+                    Err(e) => {
+                        update.abort()?;
+                        return Err(e.into());
+                    }
+                };
+
+                if let Err(e) = update.write(&buff[..read]) {
+                    update.abort()?;
+                    return Err(e);
+                }
+            }
+
+            update
+                .finish(&expected, signature.as_deref())
+                .inspect_err(|e| {
+                    tracing::error!("Error: {:?}", e);
+                })?;
+            // An upload is applied right away, only downloaded updates wait for the window
+            firmware.activate_staged()?;
 
             req.into_ok_response()?.flush()?;
+
+            defer! {
+                delay_ms(1000);
+                Device::restart();
+            };
+
             Ok(())
         })?;
     }
 
+    ota::confirm_when_healthy(
+        firmware,
+        {
+            let wifi_link = wifi_link.clone();
+            move || {
+                let reachable = matches!(
+                    wifi_link.status().state,
+                    LinkState::Connected | LinkState::Fallback
+                );
+
+                reachable && get_free_heap_size() >= MIN_HEALTHY_FREE_HEAP
+            }
+        },
+        FIRMWARE_HEALTH_CHECK_TIMEOUT,
+    )?;
+
     let mut is_prev_high = false;
+    let mut beam_broken = false;
     let mut time_sms_last_sent =
         system_time_now() - dev_svc.lock().sms_send_throttle().std_milliseconds();
     let mut did_trigger_sync_code = false;
@@ -366,30 +1204,36 @@ async fn async_main<'a>() -> Result<()> {
             did_trigger_sync_code = true;
         }
         let photoresistor_is_high = ldr_photoresistor.is_high();
+        metrics::set_beam_broken(photoresistor_is_high);
+
+        if photoresistor_is_high != beam_broken {
+            beam_broken = photoresistor_is_high;
+            sensor_events.publish(SensorEvent::Beam {
+                broken: beam_broken,
+            });
+        }
 
         let dvc = dev_svc.lock();
         let is_active = is_active(dvc.activation_time_start(), dvc.activation_time_end());
 
-        if !is_active {
-            piezo_buzzer.set_duty(0)?;
-            continue;
-        }
+        let is_armed = is_active && dvc.armed();
+        buzzer.set_triggered(is_armed && photoresistor_is_high);
 
-        if dvc.buzzer_enabled() && photoresistor_is_high {
-            piezo_buzzer.set_duty(100)?;
-        } else {
-            piezo_buzzer.set_duty(0)?;
+        if !is_armed {
+            continue;
         }
 
         if photoresistor_is_high && !is_prev_high {
             tracing::info!("Laser is cut");
             is_prev_high = true;
+            COUNTERS.record_sensor_trip();
+            sensor_events.publish(SensorEvent::Tripped);
 
             let throttle = dvc.sms_send_throttle();
             let now = system_time_now();
             let next_send = time_sms_last_sent + throttle.std_milliseconds();
             let passed_throttle = next_send <= now;
-            let connected_to_wifi = wifi.lock().is_sta_enabled()?;
+            let connected_to_wifi = wifi_link.is_connected();
             if passed_throttle && connected_to_wifi {
                 time_sms_last_sent = now;
                 let twilio = (
@@ -414,6 +1258,11 @@ async fn async_main<'a>() -> Result<()> {
                     tracing::info!("Sending SMS to {}", to);
 
                     let result = send_twilio_sms(to, body, from, sid, auth_token);
+                    COUNTERS.record_notification(NotificationChannel::Sms, result.is_ok());
+                    sensor_events.publish(SensorEvent::Notification {
+                        channel: NotificationChannel::Sms,
+                        sent: result.is_ok(),
+                    });
 
                     if let Err(e) = result {
                         tracing::error!("Error: {:?}", e);
@@ -444,6 +1293,51 @@ fn system_time_now() -> Time {
     OffsetDateTime::now_utc().to_offset(OFFSET).time()
 }
 
+fn synced_time_now() -> Option<Time> {
+    unsafe { TIME_SYNCED }.then(system_time_now)
+}
+
+/// Fails the request rather than tying up the HTTP server while the supervisor reconnects
+fn lock_wifi(wifi: &SendSyncWifi) -> Result<MutexGuard<'_, Wifi>> {
+    wifi.try_lock_for(WIFI_LOCK_TIMEOUT)
+        .ok_or_else(|| error!("Wi-Fi is busy reconnecting, try again later"))
+}
+
+/// Registers a handler whose requests are counted by route and status for `/metrics`
+fn handle<'a, F>(
+    server: &mut EspHttpServer<'a>,
+    route: &'static str,
+    method: Method,
+    handler: F,
+) -> Result<()>
+where
+    F: for<'r> Fn(Request<&mut server::EspHttpConnection<'r>>) -> Result<()> + Send + 'a,
+{
+    handle_with_status(server, route, method, 200, handler)
+}
+
+/// Like [`handle`] for handlers that answer with something other than 200 when they succeed,
+/// failed requests are always answered with 500 by the server
+fn handle_with_status<'a, F>(
+    server: &mut EspHttpServer<'a>,
+    route: &'static str,
+    method: Method,
+    ok_status: u16,
+    handler: F,
+) -> Result<()>
+where
+    F: for<'r> Fn(Request<&mut server::EspHttpConnection<'r>>) -> Result<()> + Send + 'a,
+{
+    server.fn_handler::<result::Error, _>(route, method, move |req| {
+        let res = handler(req);
+        COUNTERS.record_http_request(route, if res.is_ok() { ok_status } else { 500 });
+
+        res
+    })?;
+
+    Ok(())
+}
+
 fn send_twilio_sms(
     to_phone_number: &str,
     body: &str,
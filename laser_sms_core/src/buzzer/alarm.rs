@@ -0,0 +1,161 @@
+use std::time::Duration;
+
+/// Decides when the buzzer sounds. Times are monotonic offsets, e.g. since boot, so the
+/// caller owns the clock.
+#[derive(Debug, Clone, Default)]
+pub struct AlarmTimer {
+    /// Keep sounding this long after the trigger clears
+    latch: Duration,
+    /// Silence after sounding this long, `None` never silences
+    max_duration: Option<Duration>,
+    started: Option<Duration>,
+    last_triggered: Option<Duration>,
+    /// Hit the max duration while still triggered, stays quiet until the trigger clears
+    silenced: bool,
+}
+
+impl AlarmTimer {
+    pub fn new(latch: Duration, max_duration: Option<Duration>) -> Self {
+        Self {
+            latch,
+            max_duration,
+            ..Default::default()
+        }
+    }
+
+    pub fn set_limits(&mut self, latch: Duration, max_duration: Option<Duration>) {
+        self.latch = latch;
+        self.max_duration = max_duration;
+    }
+
+    /// Stops the current alarm, a new one starts on the next trigger
+    pub fn reset(&mut self) {
+        self.started = None;
+        self.last_triggered = None;
+        self.silenced = false;
+    }
+
+    /// How long the alarm has been sounding at `now`, `None` while it's quiet
+    pub fn update(&mut self, now: Duration, triggered: bool) -> Option<Duration> {
+        if triggered {
+            self.last_triggered = Some(now);
+
+            if self.started.is_none() && !self.silenced {
+                self.started = Some(now);
+            }
+        } else {
+            self.silenced = false;
+        }
+
+        let started = self.started?;
+
+        let latched = self
+            .last_triggered
+            .is_some_and(|at| now.saturating_sub(at) < self.latch);
+        if !triggered && !latched {
+            self.started = None;
+            return None;
+        }
+
+        let elapsed = now.saturating_sub(started);
+        if self.max_duration.is_some_and(|max| elapsed >= max) {
+            tracing::info!("Alarm silenced after {:?}", elapsed);
+            self.started = None;
+            self.silenced = triggered;
+            return None;
+        }
+
+        Some(elapsed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn sounds_while_triggered() {
+        let mut alarm = AlarmTimer::new(Duration::ZERO, None);
+
+        assert_eq!(alarm.update(ms(0), false), None);
+        assert_eq!(alarm.update(ms(100), true), Some(ms(0)));
+        assert_eq!(alarm.update(ms(120), true), Some(ms(20)));
+        assert_eq!(alarm.update(ms(140), false), None);
+        // A new trigger is a new alarm
+        assert_eq!(alarm.update(ms(200), true), Some(ms(0)));
+    }
+
+    #[test]
+    fn latches_after_the_trigger_clears() {
+        let mut alarm = AlarmTimer::new(ms(1000), None);
+
+        assert_eq!(alarm.update(ms(100), true), Some(ms(0)));
+        assert_eq!(alarm.update(ms(600), false), Some(ms(500)));
+        assert_eq!(alarm.update(ms(1099), false), Some(ms(999)));
+        assert_eq!(alarm.update(ms(1100), false), None);
+        assert_eq!(alarm.update(ms(1500), false), None);
+    }
+
+    #[test]
+    fn retriggering_extends_the_latch_without_restarting() {
+        let mut alarm = AlarmTimer::new(ms(1000), None);
+
+        assert_eq!(alarm.update(ms(0), true), Some(ms(0)));
+        assert_eq!(alarm.update(ms(900), true), Some(ms(900)));
+        assert_eq!(alarm.update(ms(1800), false), Some(ms(1800)));
+        assert_eq!(alarm.update(ms(1900), false), None);
+    }
+
+    #[test]
+    fn silences_after_the_max_duration_until_the_trigger_clears() {
+        let mut alarm = AlarmTimer::new(ms(1000), Some(ms(5000)));
+
+        assert_eq!(alarm.update(ms(0), true), Some(ms(0)));
+        assert_eq!(alarm.update(ms(4999), true), Some(ms(4999)));
+        assert_eq!(alarm.update(ms(5000), true), None);
+        // Still triggered, stays quiet
+        assert_eq!(alarm.update(ms(9000), true), None);
+        // Clearing while latched doesn't start it again
+        assert_eq!(alarm.update(ms(9100), false), None);
+        assert_eq!(alarm.update(ms(9200), true), Some(ms(0)));
+    }
+
+    #[test]
+    fn max_duration_counts_the_latch() {
+        let mut alarm = AlarmTimer::new(ms(10_000), Some(ms(3000)));
+
+        assert_eq!(alarm.update(ms(0), true), Some(ms(0)));
+        assert_eq!(alarm.update(ms(100), false), Some(ms(100)));
+        assert_eq!(alarm.update(ms(3000), false), None);
+        // The trigger had cleared, the next one starts over
+        assert_eq!(alarm.update(ms(3100), true), Some(ms(0)));
+    }
+
+    #[test]
+    fn reset_stops_the_alarm() {
+        let mut alarm = AlarmTimer::new(ms(1000), Some(ms(5000)));
+
+        alarm.update(ms(0), true);
+        alarm.reset();
+        assert_eq!(alarm.update(ms(100), false), None);
+
+        alarm.update(ms(200), true);
+        alarm.update(ms(5200), true);
+        alarm.reset();
+        // No longer silenced
+        assert_eq!(alarm.update(ms(5300), true), Some(ms(0)));
+    }
+
+    #[test]
+    fn new_limits_apply_to_the_running_alarm() {
+        let mut alarm = AlarmTimer::new(Duration::ZERO, None);
+
+        assert_eq!(alarm.update(ms(0), true), Some(ms(0)));
+        alarm.set_limits(Duration::ZERO, Some(ms(1000)));
+        assert_eq!(alarm.update(ms(1000), true), None);
+    }
+}
@@ -0,0 +1,5 @@
+pub mod alarm;
+pub mod pattern;
+
+pub use alarm::AlarmTimer;
+pub use pattern::Pattern;
@@ -0,0 +1,109 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// Tone of the continuous and beep patterns, what the buzzer always played before patterns
+pub const BASE_FREQUENCY_HZ: u32 = 2200;
+
+const BEEP_ON: Duration = Duration::from_millis(250);
+const BEEP_PERIOD: Duration = Duration::from_millis(500);
+
+const SIREN_LOW_HZ: u32 = 1200;
+const SIREN_HIGH_HZ: u32 = 3000;
+/// One sweep up and back down
+const SIREN_PERIOD: Duration = Duration::from_millis(1600);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Pattern {
+    #[default]
+    Continuous,
+    /// Short beeps with pauses in between
+    Beep,
+    /// Sweeps up and down between two tones
+    Siren,
+}
+
+impl Pattern {
+    /// The tone `elapsed` into the alarm, `None` while it should be silent
+    pub fn frequency_at(self, elapsed: Duration) -> Option<u32> {
+        match self {
+            Pattern::Continuous => Some(BASE_FREQUENCY_HZ),
+            Pattern::Beep => (phase(elapsed, BEEP_PERIOD) < BEEP_ON).then_some(BASE_FREQUENCY_HZ),
+            Pattern::Siren => {
+                let half = SIREN_PERIOD / 2;
+                let phase = phase(elapsed, SIREN_PERIOD);
+                // Triangle wave, rising for the first half of the period and falling after
+                let position = if phase < half {
+                    phase
+                } else {
+                    SIREN_PERIOD - phase
+                };
+
+                let span = (SIREN_HIGH_HZ - SIREN_LOW_HZ) as u128;
+                let offset = span * position.as_millis() / half.as_millis();
+
+                Some(SIREN_LOW_HZ + offset as u32)
+            }
+        }
+    }
+}
+
+fn phase(elapsed: Duration, period: Duration) -> Duration {
+    let millis = elapsed.as_millis() % period.as_millis();
+
+    Duration::from_millis(millis as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ms(millis: u64) -> Duration {
+        Duration::from_millis(millis)
+    }
+
+    #[test]
+    fn continuous_never_stops() {
+        for millis in [0, 1, 250, 499, 60_000] {
+            assert_eq!(
+                Pattern::Continuous.frequency_at(ms(millis)),
+                Some(BASE_FREQUENCY_HZ)
+            );
+        }
+    }
+
+    #[test]
+    fn beeps_for_the_first_half_of_every_period() {
+        let beep = |millis| Pattern::Beep.frequency_at(ms(millis));
+
+        assert_eq!(beep(0), Some(BASE_FREQUENCY_HZ));
+        assert_eq!(beep(249), Some(BASE_FREQUENCY_HZ));
+        assert_eq!(beep(250), None);
+        assert_eq!(beep(499), None);
+        assert_eq!(beep(500), Some(BASE_FREQUENCY_HZ));
+        assert_eq!(beep(10_300), None);
+    }
+
+    #[test]
+    fn siren_sweeps_up_and_back_down() {
+        let siren = |millis| Pattern::Siren.frequency_at(ms(millis));
+
+        assert_eq!(siren(0), Some(SIREN_LOW_HZ));
+        assert_eq!(siren(400), Some(2100));
+        assert_eq!(siren(800), Some(SIREN_HIGH_HZ));
+        assert_eq!(siren(1200), Some(2100));
+        assert_eq!(siren(1600), Some(SIREN_LOW_HZ));
+
+        let sweep: Vec<_> = (0..=800).step_by(20).map(|m| siren(m).unwrap()).collect();
+        assert!(sweep.windows(2).all(|pair| pair[0] <= pair[1]));
+    }
+
+    #[test]
+    fn deserializes_from_snake_case() {
+        let pattern: Pattern = serde_json::from_str(r#""siren""#).unwrap();
+
+        assert_eq!(pattern, Pattern::Siren);
+        assert_eq!(Pattern::default(), Pattern::Continuous);
+    }
+}
@@ -0,0 +1,6 @@
+//! The parts of the esp32_laser_sms firmware that don't touch ESP-IDF, kept apart so they
+//! build and are tested on the host.
+
+pub mod buzzer;
+pub mod mqtt;
+pub mod ota;
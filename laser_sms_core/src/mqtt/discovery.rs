@@ -0,0 +1,145 @@
+use serde::Serialize;
+
+use super::topics::{self, Topics};
+
+const DISCOVERY_PREFIX: &str = "homeassistant";
+const MANUFACTURER: &str = "mcu-mini-programs";
+const MODEL: &str = "ESP32 Laser Tripwire";
+
+/// Event types published on the event topic
+pub const EVENT_TYPES: [&str; 3] = ["tripped", "sms_sent", "sms_failed"];
+
+#[derive(Debug, Serialize)]
+struct Device<'a> {
+    identifiers: [&'a str; 1],
+    name: &'a str,
+    manufacturer: &'static str,
+    model: &'static str,
+    sw_version: &'a str,
+}
+
+#[derive(Debug, Serialize)]
+struct Config<'a> {
+    name: &'static str,
+    unique_id: String,
+    object_id: String,
+    availability_topic: &'a str,
+    payload_available: &'static str,
+    payload_not_available: &'static str,
+    device: &'a Device<'a>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    state_topic: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    command_topic: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    payload_on: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    payload_off: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    device_class: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    icon: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    event_types: Option<&'static [&'static str]>,
+}
+
+/// A retained discovery message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiscoveryMessage {
+    pub topic: String,
+    pub payload: String,
+}
+
+/// Discovery messages for the beam sensor, the armed and buzzer switches and the event entity.
+/// `node_id` has to be unique per device and only contain `[a-zA-Z0-9_-]`.
+pub fn messages(
+    topics: &Topics,
+    node_id: &str,
+    name: &str,
+    sw_version: &str,
+) -> serde_json::Result<Vec<DiscoveryMessage>> {
+    let device = Device {
+        identifiers: [node_id],
+        name,
+        manufacturer: MANUFACTURER,
+        model: MODEL,
+        sw_version,
+    };
+
+    let config = |object: &str, name| Config {
+        name,
+        unique_id: format!("{}_{}", node_id, object),
+        object_id: format!("{}_{}", node_id, object),
+        availability_topic: &topics.availability,
+        payload_available: topics::ONLINE,
+        payload_not_available: topics::OFFLINE,
+        device: &device,
+        state_topic: None,
+        command_topic: None,
+        payload_on: None,
+        payload_off: None,
+        device_class: None,
+        icon: None,
+        event_types: None,
+    };
+
+    let entities = [
+        (
+            "binary_sensor",
+            "beam",
+            Config {
+                state_topic: Some(&topics.beam),
+                payload_on: Some(topics::ON),
+                payload_off: Some(topics::OFF),
+                device_class: Some("safety"),
+                ..config("beam", "Beam cut")
+            },
+        ),
+        (
+            "switch",
+            "armed",
+            Config {
+                state_topic: Some(&topics.armed),
+                command_topic: Some(&topics.armed_set),
+                payload_on: Some(topics::ON),
+                payload_off: Some(topics::OFF),
+                icon: Some("mdi:shield-lock"),
+                ..config("armed", "Armed")
+            },
+        ),
+        (
+            "switch",
+            "buzzer",
+            Config {
+                state_topic: Some(&topics.buzzer),
+                command_topic: Some(&topics.buzzer_set),
+                payload_on: Some(topics::ON),
+                payload_off: Some(topics::OFF),
+                icon: Some("mdi:bullhorn"),
+                ..config("buzzer", "Buzzer")
+            },
+        ),
+        (
+            "event",
+            "event",
+            Config {
+                state_topic: Some(&topics.event),
+                event_types: Some(&EVENT_TYPES),
+                ..config("event", "Alarm")
+            },
+        ),
+    ];
+
+    entities
+        .into_iter()
+        .map(|(component, object, config)| {
+            Ok(DiscoveryMessage {
+                topic: format!(
+                    "{}/{}/{}/{}/config",
+                    DISCOVERY_PREFIX, component, node_id, object
+                ),
+                payload: serde_json::to_string(&config)?,
+            })
+        })
+        .collect()
+}
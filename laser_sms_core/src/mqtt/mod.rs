@@ -0,0 +1,293 @@
+use eyre::{bail, Result};
+use serde::{Deserialize, Serialize};
+
+pub mod discovery;
+pub mod topics;
+
+use topics::{on_off, Topics};
+
+/// Shown instead of the saved password, posting it back keeps that password
+pub const PASSWORD_MASK: &str = "********";
+
+/// Broker connection, MQTT is off while these aren't set
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct MqttSettings {
+    /// `mqtt://host:1883` or `mqtts://host:8883`
+    pub broker_url: String,
+    #[serde(default)]
+    pub username: Option<String>,
+    #[serde(default)]
+    pub password: Option<String>,
+    /// The device's node ID when not set, so devices sharing a broker don't share topics
+    #[serde(default)]
+    pub topic_prefix: Option<String>,
+    /// Verify the broker against the bundled CA certificates, needs an `mqtts://` URL
+    #[serde(default)]
+    pub tls: bool,
+}
+
+impl MqttSettings {
+    pub fn validate(&self) -> Result<()> {
+        let Some((scheme, rest)) = self.broker_url.split_once("://") else {
+            bail!("Broker URL must start with mqtt:// or mqtts://");
+        };
+
+        match (scheme, self.tls) {
+            ("mqtt", false) | ("mqtts", true) => {}
+            ("mqtt", true) => bail!("TLS needs an mqtts:// broker URL"),
+            ("mqtts", false) => bail!("An mqtts:// broker URL needs TLS enabled"),
+            _ => bail!("Unsupported broker URL scheme {:?}", scheme),
+        }
+
+        if rest.is_empty() {
+            bail!("Broker URL has no host");
+        }
+
+        if let Some(prefix) = &self.topic_prefix {
+            let prefix = prefix.trim_matches('/');
+            if prefix.is_empty() || prefix.contains(['+', '#']) {
+                bail!("Topic prefix must be non-empty and can't contain wildcards");
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn topic_prefix<'a>(&'a self, node_id: &'a str) -> &'a str {
+        self.topic_prefix.as_deref().unwrap_or(node_id)
+    }
+
+    /// A copy that's safe to show, the password is replaced by [`PASSWORD_MASK`]
+    pub fn masked(&self) -> Self {
+        Self {
+            password: self.password.as_ref().map(|_| PASSWORD_MASK.to_string()),
+            ..self.clone()
+        }
+    }
+
+    /// The password to save for one that was posted, [`PASSWORD_MASK`] keeps the one in
+    /// `current`
+    pub fn unmask_password(password: Option<String>, current: Option<&Self>) -> Option<String> {
+        match password {
+            Some(password) if password == PASSWORD_MASK => {
+                current.and_then(|current| current.password.clone())
+            }
+            password => password,
+        }
+    }
+}
+
+/// Unique per device, derived from the station MAC address
+pub fn node_id(mac: &[u8; 6]) -> String {
+    format!(
+        "laser_{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
+        mac[0], mac[1], mac[2], mac[3], mac[4], mac[5]
+    )
+}
+
+/// State that's published as it changes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Update {
+    Beam(bool),
+    Armed(bool),
+    Buzzer(bool),
+    /// One of [`discovery::EVENT_TYPES`]
+    Event(&'static str),
+}
+
+impl Update {
+    /// The topic, whether it's retained, and the payload
+    pub fn message<'a>(&self, topics: &'a Topics) -> (&'a str, bool, String) {
+        match *self {
+            Update::Beam(broken) => (&topics.beam, true, on_off(broken).to_string()),
+            Update::Armed(armed) => (&topics.armed, true, on_off(armed).to_string()),
+            Update::Buzzer(enabled) => (&topics.buzzer, true, on_off(enabled).to_string()),
+            Update::Event(event_type) => (
+                &topics.event,
+                false,
+                format!(r#"{{"event_type":"{}"}}"#, event_type),
+            ),
+        }
+    }
+}
+
+/// The broker connection a session talks through, messages are sent with QoS 1
+pub trait Client {
+    fn subscribe(&mut self, topic: &str) -> Result<()>;
+    fn publish(&mut self, topic: &str, retain: bool, payload: &str) -> Result<()>;
+}
+
+/// Sends the retained discovery messages
+pub fn publish_discovery(
+    client: &mut impl Client,
+    topics: &Topics,
+    node_id: &str,
+    sw_version: &str,
+) {
+    let name = format!(
+        "Laser Tripwire {}",
+        &node_id[node_id.len().saturating_sub(6)..]
+    );
+
+    match discovery::messages(topics, node_id, &name, sw_version) {
+        Ok(messages) => {
+            for message in messages {
+                if let Err(e) = client.publish(&message.topic, true, &message.payload) {
+                    tracing::error!("Failed to publish to {}: {:?}", message.topic, e);
+                }
+            }
+        }
+        Err(e) => tracing::error!("Failed to build discovery payloads: {:?}", e),
+    }
+}
+
+pub fn publish_update(client: &mut impl Client, topics: &Topics, update: Update) {
+    let (topic, retain, payload) = update.message(topics);
+
+    if let Err(e) = client.publish(topic, retain, &payload) {
+        tracing::error!("Failed to publish to {}: {:?}", topic, e);
+    }
+}
+
+/// Subscribes to the command topics and sends availability, discovery and `state`. The
+/// session is clean, so this is done on every connect.
+pub fn on_connected(
+    client: &mut impl Client,
+    topics: &Topics,
+    node_id: &str,
+    sw_version: &str,
+    state: &[Update],
+) {
+    for topic in topics.subscriptions() {
+        if let Err(e) = client.subscribe(topic) {
+            tracing::error!("Failed to subscribe to {}: {:?}", topic, e);
+        }
+    }
+
+    if let Err(e) = client.publish(&topics.availability, true, topics::ONLINE) {
+        tracing::error!("Failed to publish availability: {:?}", e);
+    }
+    publish_discovery(client, topics, node_id, sw_version);
+
+    for update in state {
+        publish_update(client, topics, *update);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn settings() -> MqttSettings {
+        MqttSettings {
+            broker_url: "mqtt://192.168.1.10:1883".to_string(),
+            username: Some("laser".to_string()),
+            password: Some("hunter2".to_string()),
+            topic_prefix: None,
+            tls: false,
+        }
+    }
+
+    #[test]
+    fn topics_default_to_the_node_id() {
+        let mut settings = settings();
+        assert_eq!(
+            settings.topic_prefix("laser_a1b2c3d4e5f6"),
+            "laser_a1b2c3d4e5f6"
+        );
+
+        settings.topic_prefix = Some("garage/laser".to_string());
+        assert_eq!(settings.topic_prefix("laser_a1b2c3d4e5f6"), "garage/laser");
+    }
+
+    #[test]
+    fn node_id_is_the_mac_in_hex() {
+        assert_eq!(
+            node_id(&[0xa1, 0xb2, 0xc3, 0xd4, 0xe5, 0xf6]),
+            "laser_a1b2c3d4e5f6"
+        );
+    }
+
+    #[test]
+    fn masks_the_password() {
+        let masked = settings().masked();
+
+        assert_eq!(masked.password.as_deref(), Some(PASSWORD_MASK));
+        assert_eq!(masked.username.as_deref(), Some("laser"));
+        assert!(!serde_json::to_string(&masked).unwrap().contains("hunter2"));
+
+        let without = MqttSettings {
+            password: None,
+            ..settings()
+        };
+        assert_eq!(without.masked().password, None);
+    }
+
+    #[test]
+    fn posting_the_mask_keeps_the_password() {
+        let current = settings();
+
+        assert_eq!(
+            MqttSettings::unmask_password(Some(PASSWORD_MASK.to_string()), Some(&current)),
+            Some("hunter2".to_string())
+        );
+        assert_eq!(
+            MqttSettings::unmask_password(Some("new".to_string()), Some(&current)),
+            Some("new".to_string())
+        );
+        assert_eq!(MqttSettings::unmask_password(None, Some(&current)), None);
+        assert_eq!(
+            MqttSettings::unmask_password(Some(PASSWORD_MASK.to_string()), None),
+            None
+        );
+    }
+
+    #[test]
+    fn validates_the_url_and_prefix() {
+        assert!(settings().validate().is_ok());
+
+        let invalid = [
+            MqttSettings {
+                broker_url: "192.168.1.10".to_string(),
+                ..settings()
+            },
+            MqttSettings {
+                broker_url: "mqtt://".to_string(),
+                ..settings()
+            },
+            MqttSettings {
+                broker_url: "http://192.168.1.10".to_string(),
+                ..settings()
+            },
+            MqttSettings {
+                tls: true,
+                ..settings()
+            },
+            MqttSettings {
+                broker_url: "mqtts://broker".to_string(),
+                ..settings()
+            },
+            MqttSettings {
+                topic_prefix: Some("/".to_string()),
+                ..settings()
+            },
+            MqttSettings {
+                topic_prefix: Some("laser/#".to_string()),
+                ..settings()
+            },
+        ];
+
+        for settings in invalid {
+            assert!(settings.validate().is_err(), "{:?}", settings);
+        }
+    }
+
+    #[test]
+    fn settings_saved_without_a_prefix_load() {
+        let settings: MqttSettings =
+            serde_json::from_str(r#"{"broker_url":"mqtt://broker"}"#).unwrap();
+
+        assert_eq!(settings.topic_prefix, None);
+        assert!(!settings.tls);
+    }
+}
@@ -0,0 +1,140 @@
+/// Payloads of the availability topic, also used as the Last Will
+pub const ONLINE: &str = "online";
+pub const OFFLINE: &str = "offline";
+
+/// Switch and binary sensor payloads, Home Assistant's defaults
+pub const ON: &str = "ON";
+pub const OFF: &str = "OFF";
+
+/// Home Assistant publishes `online` here when it starts, discovery is sent again when it does
+pub const HOME_ASSISTANT_STATUS: &str = "homeassistant/status";
+
+pub fn on_off(value: bool) -> &'static str {
+    if value {
+        ON
+    } else {
+        OFF
+    }
+}
+
+/// Every topic lives under the prefix, e.g. `laser_a1b2c3d4e5f6/beam`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Topics {
+    pub availability: String,
+    pub beam: String,
+    pub armed: String,
+    pub armed_set: String,
+    pub buzzer: String,
+    pub buzzer_set: String,
+    pub event: String,
+}
+
+impl Topics {
+    pub fn new(prefix: &str) -> Self {
+        let prefix = prefix.trim_end_matches('/');
+        let topic = |name: &str| format!("{}/{}", prefix, name);
+
+        Self {
+            availability: topic("availability"),
+            beam: topic("beam"),
+            armed: topic("armed"),
+            armed_set: topic("armed/set"),
+            buzzer: topic("buzzer"),
+            buzzer_set: topic("buzzer/set"),
+            event: topic("event"),
+        }
+    }
+
+    /// Topics the client subscribes to once connected
+    pub fn subscriptions(&self) -> [&str; 3] {
+        [&self.armed_set, &self.buzzer_set, HOME_ASSISTANT_STATUS]
+    }
+
+    /// `None` for topics that aren't commands and for payloads that aren't understood
+    pub fn parse_command(&self, topic: &str, payload: &[u8]) -> Option<Command> {
+        let payload = std::str::from_utf8(payload).ok()?.trim();
+
+        if topic == self.armed_set {
+            match_ignore_case(payload, &[ON, "ARM"], &[OFF, "DISARM"]).map(Command::SetArmed)
+        } else if topic == self.buzzer_set {
+            match_ignore_case(payload, &[ON], &[OFF]).map(Command::SetBuzzer)
+        } else if topic == HOME_ASSISTANT_STATUS && payload == ONLINE {
+            Some(Command::RepublishDiscovery)
+        } else {
+            None
+        }
+    }
+}
+
+fn match_ignore_case(payload: &str, on: &[&str], off: &[&str]) -> Option<bool> {
+    if on.iter().any(|s| payload.eq_ignore_ascii_case(s)) {
+        Some(true)
+    } else if off.iter().any(|s| payload.eq_ignore_ascii_case(s)) {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    SetArmed(bool),
+    SetBuzzer(bool),
+    RepublishDiscovery,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn topics_go_under_the_prefix() {
+        let topics = Topics::new("garage/laser/");
+
+        assert_eq!(topics.beam, "garage/laser/beam");
+        assert_eq!(topics.armed_set, "garage/laser/armed/set");
+        assert_eq!(
+            topics.subscriptions(),
+            [
+                "garage/laser/armed/set",
+                "garage/laser/buzzer/set",
+                HOME_ASSISTANT_STATUS
+            ]
+        );
+    }
+
+    #[test]
+    fn parses_commands() {
+        let topics = Topics::new("laser");
+        let parse = |topic: &str, payload: &str| topics.parse_command(topic, payload.as_bytes());
+
+        assert_eq!(
+            parse("laser/armed/set", "ON"),
+            Some(Command::SetArmed(true))
+        );
+        assert_eq!(
+            parse("laser/armed/set", " disarm "),
+            Some(Command::SetArmed(false))
+        );
+        assert_eq!(
+            parse("laser/buzzer/set", "off"),
+            Some(Command::SetBuzzer(false))
+        );
+        assert_eq!(
+            parse(HOME_ASSISTANT_STATUS, "online"),
+            Some(Command::RepublishDiscovery)
+        );
+    }
+
+    #[test]
+    fn ignores_anything_else() {
+        let topics = Topics::new("laser");
+        let parse = |topic: &str, payload: &[u8]| topics.parse_command(topic, payload);
+
+        assert_eq!(parse("laser/armed/set", b"maybe"), None);
+        assert_eq!(parse("laser/buzzer/set", b"ARM"), None);
+        assert_eq!(parse("laser/armed/set", &[0xff]), None);
+        assert_eq!(parse("laser/beam", b"ON"), None);
+        assert_eq!(parse(HOME_ASSISTANT_STATUS, b"offline"), None);
+    }
+}
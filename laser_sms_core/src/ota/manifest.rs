@@ -0,0 +1,340 @@
+//! Update server manifest
+
+use std::{cmp::Ordering, fmt, str::FromStr};
+
+use eyre::{bail, eyre, Result};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Manifests are a few hundred bytes, anything bigger isn't a manifest
+pub const MAX_MANIFEST_SIZE: usize = 2 * 1024;
+
+pub const SHA256_LEN: usize = 32;
+
+pub type Sha256Digest = [u8; SHA256_LEN];
+
+/// Parses a hex encoded SHA-256 digest, as sent in the `X-Firmware-Sha256` header
+pub fn parse_sha256(hex: &str) -> Result<Sha256Digest> {
+    let hex = hex.trim();
+    if hex.len() != SHA256_LEN * 2 {
+        bail!("SHA-256 digest must be {} hex characters", SHA256_LEN * 2);
+    }
+
+    let mut digest = [0u8; SHA256_LEN];
+    for (i, byte) in digest.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16)
+            .map_err(|_| eyre!("Invalid SHA-256 digest"))?;
+    }
+
+    Ok(digest)
+}
+
+/// A `major.minor.patch` firmware version, a leading `v` and `+build` metadata are ignored
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Version {
+    pub major: u32,
+    pub minor: u32,
+    pub patch: u32,
+}
+
+impl Version {
+    pub const fn new(major: u32, minor: u32, patch: u32) -> Self {
+        Self {
+            major,
+            minor,
+            patch,
+        }
+    }
+}
+
+impl Ord for Version {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.major, self.minor, self.patch).cmp(&(other.major, other.minor, other.patch))
+    }
+}
+
+impl PartialOrd for Version {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl FromStr for Version {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s.trim();
+        let s = s.strip_prefix('v').unwrap_or(s);
+        let s = s.split_once('+').map_or(s, |(version, _build)| version);
+
+        if s.contains('-') {
+            bail!("Pre-release versions are not supported: {}", s);
+        }
+
+        let mut parts = s.split('.');
+        let mut next = |name: &str| -> Result<u32> {
+            let part = parts
+                .next()
+                .ok_or_else(|| eyre!("Version {} is missing the {} number", s, name))?;
+
+            part.parse()
+                .map_err(|_| eyre!("Invalid {} number in version {}", name, s))
+        };
+
+        let version = Self::new(next("major")?, next("minor")?, next("patch")?);
+
+        if parts.next().is_some() {
+            bail!("Version {} has too many parts", s);
+        }
+
+        Ok(version)
+    }
+}
+
+impl fmt::Display for Version {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.patch)
+    }
+}
+
+impl Serialize for Version {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Version {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+/// What the update server publishes at the manifest URL
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Manifest {
+    pub version: Version,
+    /// Where the image is downloaded from
+    pub url: String,
+    /// Hex encoded SHA-256 of the image
+    pub sha256: String,
+    /// Devices older than this can't update straight to `version` and have to go through
+    /// an intermediate release first
+    #[serde(default)]
+    pub min_version: Option<Version>,
+    /// Base64 Ed25519 signature of the image digest, required with the `signed-firmware` feature
+    #[serde(default)]
+    pub signature: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UpdateCheck {
+    UpToDate,
+    Available(Version),
+    /// A newer image exists but the running one is below its `min_version`
+    Unsupported {
+        min_version: Version,
+    },
+}
+
+impl Manifest {
+    pub fn parse(bytes: &[u8]) -> Result<Self> {
+        let manifest: Self = serde_json::from_slice(bytes)?;
+
+        if manifest.url.is_empty() {
+            bail!("Manifest has an empty image URL");
+        }
+
+        parse_sha256(&manifest.sha256)?;
+
+        Ok(manifest)
+    }
+
+    pub fn digest(&self) -> Result<Sha256Digest> {
+        parse_sha256(&self.sha256)
+    }
+
+    /// Compares the published image against the running version
+    pub fn check(&self, running: &Version) -> UpdateCheck {
+        if self.version <= *running {
+            return UpdateCheck::UpToDate;
+        }
+
+        match self.min_version {
+            Some(min_version) if *running < min_version => UpdateCheck::Unsupported { min_version },
+            _ => UpdateCheck::Available(self.version),
+        }
+    }
+}
+
+/// Parses the response to a manifest request. `read` reads the body like `Read::read`,
+/// returning 0 at its end, so any HTTP client (or a local stand-in) can serve it.
+pub fn read_manifest(
+    status: u16,
+    mut read: impl FnMut(&mut [u8]) -> Result<usize>,
+) -> Result<Manifest> {
+    if !(200..300).contains(&status) {
+        bail!("Manifest request failed with status {}", status);
+    }
+
+    let mut buff = [0u8; MAX_MANIFEST_SIZE];
+    let mut len = 0;
+    loop {
+        let read = read(&mut buff[len..])?;
+        if read == 0 {
+            break;
+        }
+
+        len += read;
+        if len == buff.len() {
+            bail!("Manifest is larger than {} bytes", MAX_MANIFEST_SIZE);
+        }
+    }
+
+    Manifest::parse(&buff[..len])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIGEST: &str = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+
+    fn manifest(version: &str, min_version: Option<&str>) -> Manifest {
+        Manifest {
+            version: version.parse().unwrap(),
+            url: "http://updates.local/firmware.bin".to_string(),
+            sha256: DIGEST.to_string(),
+            min_version: min_version.map(|v| v.parse().unwrap()),
+            signature: None,
+        }
+    }
+
+    #[test]
+    fn parses_versions() {
+        assert_eq!("1.2.3".parse::<Version>().unwrap(), Version::new(1, 2, 3));
+        assert_eq!("v1.2.3".parse::<Version>().unwrap(), Version::new(1, 2, 3));
+        assert_eq!(
+            " 1.2.3+build.7 ".parse::<Version>().unwrap(),
+            Version::new(1, 2, 3)
+        );
+        assert_eq!(Version::new(10, 0, 1).to_string(), "10.0.1");
+    }
+
+    #[test]
+    fn rejects_invalid_versions() {
+        for invalid in ["", "1", "1.2", "1.2.3.4", "1.x.3", "1.2.3-rc.1", "-1.2.3"] {
+            assert!(invalid.parse::<Version>().is_err(), "{:?}", invalid);
+        }
+    }
+
+    #[test]
+    fn orders_versions_numerically() {
+        assert!(Version::new(1, 10, 0) > Version::new(1, 9, 99));
+        assert!(Version::new(2, 0, 0) > Version::new(1, 99, 99));
+        assert!(Version::new(1, 0, 1) > Version::new(1, 0, 0));
+    }
+
+    #[test]
+    fn parses_sha256() {
+        let digest = parse_sha256(DIGEST).unwrap();
+        assert_eq!(digest[0], 0x9f);
+        assert_eq!(digest[SHA256_LEN - 1], 0x08);
+
+        assert!(parse_sha256(&DIGEST[2..]).is_err());
+        assert!(parse_sha256(&DIGEST.replace('9', "g")).is_err());
+    }
+
+    #[test]
+    fn checks_against_the_running_version() {
+        let running = Version::new(1, 2, 0);
+
+        assert_eq!(
+            manifest("1.2.0", None).check(&running),
+            UpdateCheck::UpToDate
+        );
+        assert_eq!(
+            manifest("1.1.9", None).check(&running),
+            UpdateCheck::UpToDate
+        );
+        assert_eq!(
+            manifest("1.3.0", None).check(&running),
+            UpdateCheck::Available(Version::new(1, 3, 0))
+        );
+        assert_eq!(
+            manifest("1.3.0", Some("1.2.0")).check(&running),
+            UpdateCheck::Available(Version::new(1, 3, 0))
+        );
+        assert_eq!(
+            manifest("2.0.0", Some("1.5.0")).check(&running),
+            UpdateCheck::Unsupported {
+                min_version: Version::new(1, 5, 0)
+            }
+        );
+    }
+
+    #[test]
+    fn parses_a_manifest() {
+        let json = format!(
+            r#"{{"version":"1.3.0","url":"http://updates.local/1.3.0.bin","sha256":"{}","min_version":"v1.0.0"}}"#,
+            DIGEST
+        );
+
+        let parsed = Manifest::parse(json.as_bytes()).unwrap();
+        assert_eq!(parsed.version, Version::new(1, 3, 0));
+        assert_eq!(parsed.min_version, Some(Version::new(1, 0, 0)));
+        assert_eq!(parsed.signature, None);
+        assert_eq!(parsed.digest().unwrap(), parse_sha256(DIGEST).unwrap());
+    }
+
+    #[test]
+    fn rejects_invalid_manifests() {
+        let invalid = [
+            r#"{"version":"1.3.0","url":"","sha256":"DIGEST"}"#,
+            r#"{"version":"1.3.0","url":"http://u/f.bin","sha256":"abc"}"#,
+            r#"{"version":"1.3","url":"http://u/f.bin","sha256":"DIGEST"}"#,
+            r#"{"url":"http://u/f.bin","sha256":"DIGEST"}"#,
+            "not json",
+        ];
+
+        for json in invalid {
+            let json = json.replace("DIGEST", DIGEST);
+            assert!(Manifest::parse(json.as_bytes()).is_err(), "{}", json);
+        }
+    }
+
+    #[test]
+    fn reads_the_body_in_chunks() {
+        let json = serde_json::to_vec(&manifest("1.3.0", None)).unwrap();
+        let mut chunks = json.chunks(7);
+
+        let read = read_manifest(200, |buf| {
+            let Some(chunk) = chunks.next() else {
+                return Ok(0);
+            };
+            buf[..chunk.len()].copy_from_slice(chunk);
+
+            Ok(chunk.len())
+        })
+        .unwrap();
+
+        assert_eq!(read, manifest("1.3.0", None));
+    }
+
+    #[test]
+    fn rejects_failed_requests() {
+        let res = read_manifest(404, |_| panic!("The body of a failed request isn't read"));
+
+        assert!(res.is_err());
+    }
+
+    #[test]
+    fn rejects_oversized_bodies() {
+        let res = read_manifest(200, |buf| {
+            buf.fill(b' ');
+            Ok(buf.len())
+        });
+
+        assert!(res.is_err());
+    }
+}
@@ -0,0 +1,125 @@
+use serde::{Deserialize, Serialize};
+use time::{macros::time, Time};
+
+const MIDNIGHT: Time = time!(00:00:00);
+
+/// True when `now` is between `start` and `end` (inclusive), ranges that end before they
+/// start wrap past midnight and a range that starts where it ends covers the whole day
+pub fn time_in_range(now: Time, start: Time, end: Time) -> bool {
+    if start < end {
+        now >= start && now <= end
+    } else {
+        now >= start || now <= end
+    }
+}
+
+/// Daily window in which a downloaded image may be applied (restarting the device)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub struct MaintenanceWindow {
+    pub start: Time,
+    pub end: Time,
+}
+
+impl Default for MaintenanceWindow {
+    fn default() -> Self {
+        Self {
+            start: time!(03:00:00),
+            end: time!(04:00:00),
+        }
+    }
+}
+
+impl MaintenanceWindow {
+    pub fn contains(&self, now: Time) -> bool {
+        time_in_range(now, self.start, self.end)
+    }
+
+    /// Whether a staged image may be applied at `now`, which is `None` while the clock isn't
+    /// synced. Restarting is kept out of the activation schedule so it never blinds the sensor.
+    pub fn allows_restart(
+        &self,
+        now: Option<Time>,
+        activation_start: Time,
+        activation_end: Option<Time>,
+    ) -> bool {
+        let Some(now) = now else {
+            return false;
+        };
+
+        self.contains(now)
+            && !time_in_range(now, activation_start, activation_end.unwrap_or(MIDNIGHT))
+    }
+
+    /// True when the window shares any time with the activation schedule,
+    /// a missing activation end means midnight like the sensor loop does
+    pub fn overlaps_activation(&self, start: Time, end: Option<Time>) -> bool {
+        let end = end.unwrap_or(MIDNIGHT);
+
+        // Two ranges on a clock overlap exactly when one of them contains the other's start
+        self.contains(start) || time_in_range(self.start, start, end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use time::macros::time;
+
+    use super::*;
+
+    #[test]
+    fn ranges_are_inclusive() {
+        assert!(time_in_range(time!(03:00), time!(03:00), time!(04:00)));
+        assert!(time_in_range(time!(04:00), time!(03:00), time!(04:00)));
+        assert!(!time_in_range(time!(04:00:01), time!(03:00), time!(04:00)));
+        assert!(!time_in_range(time!(02:59:59), time!(03:00), time!(04:00)));
+    }
+
+    #[test]
+    fn ranges_wrap_past_midnight() {
+        assert!(time_in_range(time!(23:30), time!(23:00), time!(01:00)));
+        assert!(time_in_range(time!(00:30), time!(23:00), time!(01:00)));
+        assert!(!time_in_range(time!(12:00), time!(23:00), time!(01:00)));
+    }
+
+    #[test]
+    fn range_starting_where_it_ends_is_the_whole_day() {
+        assert!(time_in_range(time!(12:00), time!(03:00), time!(03:00)));
+        assert!(time_in_range(time!(02:00), time!(03:00), time!(03:00)));
+    }
+
+    #[test]
+    fn restart_waits_for_the_clock() {
+        let window = MaintenanceWindow::default();
+
+        assert!(!window.allows_restart(None, time!(18:00), Some(time!(06:00))));
+        assert!(window.allows_restart(Some(time!(03:30)), time!(18:00), Some(time!(02:00))));
+    }
+
+    #[test]
+    fn restart_stays_inside_the_window() {
+        let window = MaintenanceWindow::default();
+
+        assert!(!window.allows_restart(Some(time!(02:59)), time!(18:00), Some(time!(02:00))));
+        assert!(!window.allows_restart(Some(time!(04:01)), time!(18:00), Some(time!(02:00))));
+    }
+
+    #[test]
+    fn restart_never_blinds_an_active_sensor() {
+        let window = MaintenanceWindow::default();
+
+        // Active overnight until 06:00, the whole window is inside it
+        assert!(!window.allows_restart(Some(time!(03:30)), time!(18:00), Some(time!(06:00))));
+        // No end means active until midnight, the window is after it
+        assert!(window.allows_restart(Some(time!(03:30)), time!(18:00), None));
+    }
+
+    #[test]
+    fn overlap_with_activation() {
+        let window = MaintenanceWindow::default();
+
+        assert!(window.overlaps_activation(time!(18:00), Some(time!(06:00))));
+        assert!(window.overlaps_activation(time!(03:30), Some(time!(05:00))));
+        assert!(!window.overlaps_activation(time!(18:00), Some(time!(02:00))));
+        assert!(!window.overlaps_activation(time!(18:00), None));
+    }
+}
@@ -0,0 +1,455 @@
+//! Runs a session against a local stand-in for the broker, speaking just enough MQTT 3.1.1
+//! to record what the client sends
+
+use std::{
+    io::{self, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
+
+use laser_sms_core::mqtt::{
+    self,
+    topics::{self, Command, Topics},
+    Update,
+};
+use rumqttc::{Event, LastWill, MqttOptions, Packet, QoS};
+use serde_json::Value;
+
+const NODE_ID: &str = "laser_a1b2c3d4e5f6";
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Received {
+    Connect {
+        client_id: String,
+        clean_session: bool,
+        will: Option<(String, Vec<u8>, bool)>,
+        username: Option<String>,
+        password: Option<String>,
+    },
+    Subscribe(Vec<(String, u8)>),
+    Publish {
+        topic: String,
+        payload: Vec<u8>,
+        qos: u8,
+        retain: bool,
+    },
+}
+
+impl Received {
+    fn publish(&self) -> (&str, &[u8], bool) {
+        match self {
+            Received::Publish {
+                topic,
+                payload,
+                qos: 1,
+                retain,
+            } => (topic, payload, *retain),
+            other => panic!("Expected a QoS 1 publish, got {:?}", other),
+        }
+    }
+}
+
+fn read_packet(stream: &mut TcpStream) -> io::Result<(u8, Vec<u8>)> {
+    let mut header = [0u8; 1];
+    stream.read_exact(&mut header)?;
+
+    let mut len = 0usize;
+    for shift in (0..28).step_by(7) {
+        let mut byte = [0u8; 1];
+        stream.read_exact(&mut byte)?;
+        len |= ((byte[0] & 0x7f) as usize) << shift;
+        if byte[0] & 0x80 == 0 {
+            break;
+        }
+    }
+
+    let mut body = vec![0u8; len];
+    stream.read_exact(&mut body)?;
+
+    Ok((header[0], body))
+}
+
+fn write_packet(stream: &mut TcpStream, header: u8, body: &[u8]) -> io::Result<()> {
+    let mut packet = vec![header];
+    let mut len = body.len();
+    loop {
+        let byte = (len % 128) as u8;
+        len /= 128;
+        packet.push(if len > 0 { byte | 0x80 } else { byte });
+        if len == 0 {
+            break;
+        }
+    }
+    packet.extend_from_slice(body);
+
+    stream.write_all(&packet)
+}
+
+fn take<'a>(body: &mut &'a [u8], len: usize) -> &'a [u8] {
+    let (taken, rest) = body.split_at(len);
+    *body = rest;
+
+    taken
+}
+
+fn take_u16(body: &mut &[u8]) -> u16 {
+    let bytes = take(body, 2);
+
+    u16::from_be_bytes([bytes[0], bytes[1]])
+}
+
+fn take_bytes(body: &mut &[u8]) -> Vec<u8> {
+    let len = take_u16(body) as usize;
+
+    take(body, len).to_vec()
+}
+
+fn take_string(body: &mut &[u8]) -> String {
+    String::from_utf8(take_bytes(body)).unwrap()
+}
+
+fn parse_connect(mut body: &[u8]) -> Received {
+    assert_eq!(take_string(&mut body), "MQTT");
+    assert_eq!(take(&mut body, 1), [4], "protocol level 3.1.1");
+    let flags = take(&mut body, 1)[0];
+    take_u16(&mut body);
+
+    let client_id = take_string(&mut body);
+    let will = (flags & 0x04 != 0).then(|| {
+        let topic = take_string(&mut body);
+        let message = take_bytes(&mut body);
+        (topic, message, flags & 0x20 != 0)
+    });
+    let username = (flags & 0x80 != 0).then(|| take_string(&mut body));
+    let password = (flags & 0x40 != 0).then(|| take_string(&mut body));
+
+    Received::Connect {
+        client_id,
+        clean_session: flags & 0x02 != 0,
+        will,
+        username,
+        password,
+    }
+}
+
+/// Accepts one client, acknowledges everything it sends and reports it on `received`.
+/// Anything on `commands` is published to the client.
+fn stand_in_broker(
+    received: mpsc::Sender<Received>,
+    commands: mpsc::Receiver<(String, String)>,
+) -> u16 {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let port = listener.local_addr().unwrap().port();
+
+    thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        stream
+            .set_read_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+
+        loop {
+            for (topic, payload) in commands.try_iter() {
+                let mut body = (topic.len() as u16).to_be_bytes().to_vec();
+                body.extend_from_slice(topic.as_bytes());
+                body.extend_from_slice(payload.as_bytes());
+                write_packet(&mut stream, 0x30, &body).unwrap();
+            }
+
+            let (header, body) = match read_packet(&mut stream) {
+                Ok(packet) => packet,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+                // The client went away
+                Err(_) => return,
+            };
+
+            let packet = match header >> 4 {
+                1 => {
+                    write_packet(&mut stream, 0x20, &[0, 0]).unwrap();
+                    parse_connect(&body)
+                }
+                3 => {
+                    let qos = (header >> 1) & 0x03;
+                    let mut body = &body[..];
+                    let topic = take_string(&mut body);
+                    if qos > 0 {
+                        let id = take(&mut body, 2);
+                        write_packet(&mut stream, 0x40, id).unwrap();
+                    }
+
+                    Received::Publish {
+                        topic,
+                        payload: body.to_vec(),
+                        qos,
+                        retain: header & 0x01 != 0,
+                    }
+                }
+                8 => {
+                    let mut body = &body[..];
+                    let id = take(&mut body, 2).to_vec();
+                    let mut filters = Vec::new();
+                    while !body.is_empty() {
+                        let filter = take_string(&mut body);
+                        filters.push((filter, take(&mut body, 1)[0]));
+                    }
+
+                    let mut ack = id;
+                    ack.extend(filters.iter().map(|(_, qos)| *qos));
+                    write_packet(&mut stream, 0x90, &ack).unwrap();
+
+                    Received::Subscribe(filters)
+                }
+                // PINGREQ
+                12 => {
+                    write_packet(&mut stream, 0xd0, &[]).unwrap();
+                    continue;
+                }
+                // DISCONNECT
+                14 => return,
+                _ => continue,
+            };
+
+            if received.send(packet).is_err() {
+                return;
+            }
+        }
+    });
+
+    port
+}
+
+/// Publishes through rumqttc the way the firmware publishes through ESP-IDF's client
+struct TestClient(rumqttc::Client);
+
+impl mqtt::Client for TestClient {
+    fn subscribe(&mut self, topic: &str) -> eyre::Result<()> {
+        self.0.subscribe(topic, QoS::AtLeastOnce)?;
+
+        Ok(())
+    }
+
+    fn publish(&mut self, topic: &str, retain: bool, payload: &str) -> eyre::Result<()> {
+        self.0
+            .publish(topic, QoS::AtLeastOnce, retain, payload.as_bytes())?;
+
+        Ok(())
+    }
+}
+
+struct Session {
+    client: TestClient,
+    topics: Topics,
+    /// What the stand-in received
+    broker: mpsc::Receiver<Received>,
+    /// Sends commands through the stand-in
+    commands: mpsc::Sender<(String, String)>,
+    /// Publishes the client received
+    incoming: mpsc::Receiver<(String, Vec<u8>)>,
+}
+
+impl Session {
+    fn connect() -> Self {
+        let (received_tx, broker) = mpsc::channel();
+        let (commands, commands_rx) = mpsc::channel();
+        let port = stand_in_broker(received_tx, commands_rx);
+
+        let topics = Topics::new(NODE_ID);
+        let mut options = MqttOptions::new(NODE_ID, "127.0.0.1", port);
+        options
+            .set_clean_session(true)
+            .set_credentials("laser", "hunter2")
+            .set_last_will(LastWill::new(
+                &topics.availability,
+                topics::OFFLINE,
+                QoS::AtLeastOnce,
+                true,
+            ));
+
+        let (client, mut connection) = rumqttc::Client::new(options, 64);
+        let (connected_tx, connected) = mpsc::channel();
+        let (incoming_tx, incoming) = mpsc::channel();
+        thread::spawn(move || {
+            for event in connection.iter() {
+                match event {
+                    Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                        let _ = connected_tx.send(());
+                    }
+                    Ok(Event::Incoming(Packet::Publish(publish))) => {
+                        let _ = incoming_tx.send((publish.topic, publish.payload.to_vec()));
+                    }
+                    Ok(_) => {}
+                    Err(_) => return,
+                }
+            }
+        });
+        connected.recv_timeout(TIMEOUT).unwrap();
+
+        Self {
+            client: TestClient(client),
+            topics,
+            broker,
+            commands,
+            incoming,
+        }
+    }
+
+    fn next(&self) -> Received {
+        self.broker.recv_timeout(TIMEOUT).unwrap()
+    }
+}
+
+#[test]
+fn connects_with_the_node_id_and_last_will() {
+    let session = Session::connect();
+
+    assert_eq!(
+        session.next(),
+        Received::Connect {
+            client_id: NODE_ID.to_string(),
+            clean_session: true,
+            will: Some((
+                format!("{}/availability", NODE_ID),
+                b"offline".to_vec(),
+                true
+            )),
+            username: Some("laser".to_string()),
+            password: Some("hunter2".to_string()),
+        }
+    );
+}
+
+#[test]
+fn announces_the_device_on_connect() {
+    let mut session = Session::connect();
+    session.next();
+
+    mqtt::on_connected(
+        &mut session.client,
+        &session.topics,
+        NODE_ID,
+        "1.2.3",
+        &[
+            Update::Beam(false),
+            Update::Armed(true),
+            Update::Buzzer(false),
+        ],
+    );
+
+    for topic in ["armed/set", "buzzer/set"] {
+        assert_eq!(
+            session.next(),
+            Received::Subscribe(vec![(format!("{}/{}", NODE_ID, topic), 1)])
+        );
+    }
+    assert_eq!(
+        session.next(),
+        Received::Subscribe(vec![(topics::HOME_ASSISTANT_STATUS.to_string(), 1)])
+    );
+
+    let availability = session.next();
+    assert_eq!(
+        availability.publish(),
+        (
+            format!("{}/availability", NODE_ID).as_str(),
+            &b"online"[..],
+            true
+        )
+    );
+
+    for (component, object, state_topic) in [
+        ("binary_sensor", "beam", "beam"),
+        ("switch", "armed", "armed"),
+        ("switch", "buzzer", "buzzer"),
+        ("event", "event", "event"),
+    ] {
+        let discovery = session.next();
+        let (topic, payload, retain) = discovery.publish();
+        let config: Value = serde_json::from_slice(payload).unwrap();
+
+        assert_eq!(
+            topic,
+            format!("homeassistant/{}/{}/{}/config", component, NODE_ID, object)
+        );
+        assert!(retain);
+        assert_eq!(
+            config["state_topic"],
+            format!("{}/{}", NODE_ID, state_topic)
+        );
+        assert_eq!(config["unique_id"], format!("{}_{}", NODE_ID, object));
+        assert_eq!(config["device"]["name"], "Laser Tripwire d4e5f6");
+        assert_eq!(config["device"]["sw_version"], "1.2.3");
+    }
+
+    for (topic, payload) in [("beam", "OFF"), ("armed", "ON"), ("buzzer", "OFF")] {
+        let state = session.next();
+        assert_eq!(
+            state.publish(),
+            (
+                format!("{}/{}", NODE_ID, topic).as_str(),
+                payload.as_bytes(),
+                true
+            )
+        );
+    }
+}
+
+#[test]
+fn events_are_not_retained() {
+    let mut session = Session::connect();
+    session.next();
+
+    mqtt::publish_update(
+        &mut session.client,
+        &session.topics,
+        Update::Event("tripped"),
+    );
+
+    let event = session.next();
+    assert_eq!(
+        event.publish(),
+        (
+            format!("{}/event", NODE_ID).as_str(),
+            &br#"{"event_type":"tripped"}"#[..],
+            false
+        )
+    );
+}
+
+#[test]
+fn commands_from_the_broker_are_understood() {
+    let mut session = Session::connect();
+    session.next();
+
+    mqtt::on_connected(&mut session.client, &session.topics, NODE_ID, "1.2.3", &[]);
+    // Three subscriptions, availability and four discovery messages
+    for _ in 0..8 {
+        session.next();
+    }
+
+    for (topic, payload) in [
+        (session.topics.armed_set.clone(), "DISARM"),
+        (session.topics.buzzer_set.clone(), "ON"),
+        (topics::HOME_ASSISTANT_STATUS.to_string(), "online"),
+    ] {
+        session.commands.send((topic, payload.to_string())).unwrap();
+    }
+
+    let commands: Vec<_> = (0..3)
+        .map(|_| {
+            let (topic, payload) = session.incoming.recv_timeout(TIMEOUT).unwrap();
+            session.topics.parse_command(&topic, &payload)
+        })
+        .collect();
+
+    assert_eq!(
+        commands,
+        [
+            Some(Command::SetArmed(false)),
+            Some(Command::SetBuzzer(true)),
+            Some(Command::RepublishDiscovery)
+        ]
+    );
+}
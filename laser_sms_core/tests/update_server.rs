@@ -0,0 +1,127 @@
+//! Fetches manifests from a local stand-in for the update server
+
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    thread,
+};
+
+use laser_sms_core::ota::manifest::{read_manifest, Manifest, UpdateCheck, Version};
+
+const DIGEST: &str = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+
+/// Answers one request with `status` and `body`, sent a few bytes at a time like a slow link.
+/// Returns the base URL and the path that was requested.
+fn serve_once(status: &'static str, body: Vec<u8>) -> (String, thread::JoinHandle<String>) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+
+    let server = thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = BufReader::new(stream);
+
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).unwrap();
+        loop {
+            let mut header = String::new();
+            reader.read_line(&mut header).unwrap();
+            if header.trim().is_empty() {
+                break;
+            }
+        }
+
+        let mut stream = reader.into_inner();
+        write!(
+            stream,
+            "HTTP/1.0 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n",
+            status,
+            body.len()
+        )
+        .unwrap();
+        for chunk in body.chunks(16) {
+            // The client may stop reading early
+            if stream
+                .write_all(chunk)
+                .and_then(|()| stream.flush())
+                .is_err()
+            {
+                break;
+            }
+        }
+
+        request_line.split(' ').nth(1).unwrap().to_string()
+    });
+
+    (url, server)
+}
+
+/// Just enough of an HTTP client to make a GET and hand the body to [`read_manifest`]
+fn fetch(url: &str, path: &str) -> eyre::Result<Manifest> {
+    let host = url.trim_start_matches("http://");
+    let stream = TcpStream::connect(host)?;
+    write!(&stream, "GET {} HTTP/1.0\r\nHost: {}\r\n\r\n", path, host)?;
+
+    let mut reader = BufReader::new(stream);
+    let mut status_line = String::new();
+    reader.read_line(&mut status_line)?;
+    let status = status_line.split(' ').nth(1).unwrap_or_default().parse()?;
+    loop {
+        let mut header = String::new();
+        reader.read_line(&mut header)?;
+        if header.trim().is_empty() {
+            break;
+        }
+    }
+
+    read_manifest(status, |buf| Ok(reader.read(buf)?))
+}
+
+#[test]
+fn fetches_a_newer_release() {
+    let body = format!(
+        r#"{{"version":"1.4.0","url":"http://updates.local/1.4.0.bin","sha256":"{}","min_version":"1.2.0"}}"#,
+        DIGEST
+    );
+    let (url, server) = serve_once("200 OK", body.into_bytes());
+
+    let manifest = fetch(&url, "/laser/manifest.json").unwrap();
+
+    assert_eq!(server.join().unwrap(), "/laser/manifest.json");
+    assert_eq!(manifest.url, "http://updates.local/1.4.0.bin");
+    assert_eq!(
+        manifest.check(&Version::new(1, 3, 2)),
+        UpdateCheck::Available(Version::new(1, 4, 0))
+    );
+    assert_eq!(
+        manifest.check(&Version::new(1, 1, 0)),
+        UpdateCheck::Unsupported {
+            min_version: Version::new(1, 2, 0)
+        }
+    );
+}
+
+#[test]
+fn fails_on_a_missing_manifest() {
+    let (url, server) = serve_once("404 Not Found", b"not found".to_vec());
+
+    let err = fetch(&url, "/laser/manifest.json").unwrap_err();
+
+    server.join().unwrap();
+    assert!(err.to_string().contains("404"), "{}", err);
+}
+
+#[test]
+fn fails_on_a_truncated_manifest() {
+    let (url, server) = serve_once("200 OK", br#"{"version":"1.4.0","url":"#.to_vec());
+
+    assert!(fetch(&url, "/laser/manifest.json").is_err());
+    server.join().unwrap();
+}
+
+#[test]
+fn fails_on_something_that_isnt_a_manifest() {
+    let (url, server) = serve_once("200 OK", vec![b'x'; 64 * 1024]);
+
+    assert!(fetch(&url, "/laser/manifest.json").is_err());
+    server.join().unwrap();
+}
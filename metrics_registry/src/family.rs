@@ -0,0 +1,193 @@
+use core::{
+    fmt::{self, Write},
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use crate::{slot::Slot, write_sample, Metric, MetricKind, Value};
+
+/// One series of a family, the labels are written once and the value is updated in place
+struct Series<const L: usize> {
+    labels: Slot<[&'static str; L]>,
+    value: AtomicU32,
+}
+
+impl<const L: usize> Series<L> {
+    // Only used to repeat into the series array, never shared
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY: Self = Self {
+        labels: Slot::new(),
+        value: AtomicU32::new(0),
+    };
+}
+
+/// Series keyed by label values, with room for `N` distinct label sets.
+/// Updates to label sets past the capacity are counted in `dropped` instead.
+struct Family<const L: usize, const N: usize> {
+    name: &'static str,
+    help: &'static str,
+    label_names: [&'static str; L],
+    series: [Series<L>; N],
+    dropped: AtomicU32,
+}
+
+impl<const L: usize, const N: usize> Family<L, N> {
+    const fn new(name: &'static str, help: &'static str, label_names: [&'static str; L]) -> Self {
+        Self {
+            name,
+            help,
+            label_names,
+            series: [Series::EMPTY; N],
+            dropped: AtomicU32::new(0),
+        }
+    }
+
+    /// Finds the series for `labels`, claiming an empty one if it doesn't exist yet
+    fn series(&self, labels: [&'static str; L]) -> Option<&AtomicU32> {
+        for series in &self.series {
+            match series.labels.try_set(labels) {
+                Ok(()) => return Some(&series.value),
+                Err(Some(existing)) if existing == labels => return Some(&series.value),
+                Err(Some(_)) => continue,
+                // Claimed by a task that was preempted mid-write, it may be claiming these
+                // same labels so the update is dropped rather than put in a later series
+                Err(None) => break,
+            }
+        }
+
+        self.dropped.fetch_add(1, Ordering::Relaxed);
+
+        None
+    }
+
+    fn get(&self, labels: [&'static str; L]) -> u32 {
+        self.series
+            .iter()
+            .find(|series| series.labels.get() == Some(labels))
+            .map(|series| series.value.load(Ordering::Relaxed))
+            .unwrap_or(0)
+    }
+
+    fn write_samples(&self, out: &mut dyn Write, value: impl Fn(u32) -> Value) -> fmt::Result {
+        for series in &self.series {
+            let Some(labels) = series.labels.get() else {
+                // Series are claimed in order, nothing follows an empty one
+                break;
+            };
+
+            write_sample(
+                out,
+                self.name,
+                &self.label_names,
+                &labels,
+                value(series.value.load(Ordering::Relaxed)),
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Counters with `L` labels and room for `N` label sets
+pub struct CounterVec<const L: usize, const N: usize> {
+    family: Family<L, N>,
+}
+
+impl<const L: usize, const N: usize> CounterVec<L, N> {
+    pub const fn new(
+        name: &'static str,
+        help: &'static str,
+        label_names: [&'static str; L],
+    ) -> Self {
+        Self {
+            family: Family::new(name, help, label_names),
+        }
+    }
+
+    pub fn inc(&self, labels: [&'static str; L]) {
+        self.inc_by(labels, 1);
+    }
+
+    pub fn inc_by(&self, labels: [&'static str; L], n: u32) {
+        if let Some(value) = self.family.series(labels) {
+            value.fetch_add(n, Ordering::Relaxed);
+        }
+    }
+
+    pub fn get(&self, labels: [&'static str; L]) -> u32 {
+        self.family.get(labels)
+    }
+
+    /// Updates that were lost because every series was taken
+    pub fn dropped(&self) -> u32 {
+        self.family.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl<const L: usize, const N: usize> Metric for CounterVec<L, N> {
+    fn name(&self) -> &'static str {
+        self.family.name
+    }
+
+    fn help(&self) -> &'static str {
+        self.family.help
+    }
+
+    fn kind(&self) -> MetricKind {
+        MetricKind::Counter
+    }
+
+    fn write_samples(&self, out: &mut dyn Write) -> fmt::Result {
+        self.family.write_samples(out, Value::Int)
+    }
+}
+
+/// Gauges with `L` labels and room for `N` label sets
+pub struct GaugeVec<const L: usize, const N: usize> {
+    family: Family<L, N>,
+}
+
+impl<const L: usize, const N: usize> GaugeVec<L, N> {
+    pub const fn new(
+        name: &'static str,
+        help: &'static str,
+        label_names: [&'static str; L],
+    ) -> Self {
+        Self {
+            family: Family::new(name, help, label_names),
+        }
+    }
+
+    pub fn set(&self, labels: [&'static str; L], value: f32) {
+        if let Some(bits) = self.family.series(labels) {
+            bits.store(value.to_bits(), Ordering::Relaxed);
+        }
+    }
+
+    pub fn get(&self, labels: [&'static str; L]) -> f32 {
+        f32::from_bits(self.family.get(labels))
+    }
+
+    /// Updates that were lost because every series was taken
+    pub fn dropped(&self) -> u32 {
+        self.family.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl<const L: usize, const N: usize> Metric for GaugeVec<L, N> {
+    fn name(&self) -> &'static str {
+        self.family.name
+    }
+
+    fn help(&self) -> &'static str {
+        self.family.help
+    }
+
+    fn kind(&self) -> MetricKind {
+        MetricKind::Gauge
+    }
+
+    fn write_samples(&self, out: &mut dyn Write) -> fmt::Result {
+        self.family
+            .write_samples(out, |bits| Value::Float(f32::from_bits(bits)))
+    }
+}
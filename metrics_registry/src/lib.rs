@@ -0,0 +1,208 @@
+//! Lock-free Prometheus metrics for the firmwares.
+//!
+//! Metrics are meant to live in statics and only need 32-bit atomics, so they can be
+//! updated from any task or callback. Labelled families and the registry have a fixed
+//! capacity decided at compile time, nothing is allocated.
+
+#![no_std]
+
+use core::fmt::{self, Write};
+
+mod family;
+mod registry;
+mod slot;
+
+pub use family::{CounterVec, GaugeVec};
+pub use registry::Registry;
+
+use core::sync::atomic::{AtomicU32, Ordering};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricKind {
+    Counter,
+    Gauge,
+}
+
+impl MetricKind {
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            MetricKind::Counter => "counter",
+            MetricKind::Gauge => "gauge",
+        }
+    }
+}
+
+/// Anything that can be rendered in the text exposition format
+pub trait Metric: Sync {
+    fn name(&self) -> &'static str;
+    fn help(&self) -> &'static str;
+    fn kind(&self) -> MetricKind;
+
+    /// Writes the sample lines, without the `# HELP` and `# TYPE` header
+    fn write_samples(&self, out: &mut dyn Write) -> fmt::Result;
+}
+
+/// Writes a metric with its `# HELP` and `# TYPE` header
+pub fn write_metric(metric: &dyn Metric, out: &mut dyn Write) -> fmt::Result {
+    out.write_str("# HELP ")?;
+    out.write_str(metric.name())?;
+    out.write_char(' ')?;
+    write_escaped(out, metric.help(), false)?;
+    out.write_char('\n')?;
+
+    writeln!(out, "# TYPE {} {}", metric.name(), metric.kind().as_str())?;
+
+    metric.write_samples(out)
+}
+
+/// Backslashes and newlines are escaped in help text, label values also escape double quotes
+fn write_escaped(out: &mut dyn Write, s: &str, quotes: bool) -> fmt::Result {
+    for c in s.chars() {
+        match c {
+            '\\' => out.write_str("\\\\")?,
+            '\n' => out.write_str("\\n")?,
+            '"' if quotes => out.write_str("\\\"")?,
+            c => out.write_char(c)?,
+        }
+    }
+
+    Ok(())
+}
+
+fn write_sample(
+    out: &mut dyn Write,
+    name: &str,
+    label_names: &[&str],
+    label_values: &[&str],
+    value: Value,
+) -> fmt::Result {
+    out.write_str(name)?;
+
+    if !label_names.is_empty() {
+        out.write_char('{')?;
+
+        for (i, (label, value)) in label_names.iter().zip(label_values).enumerate() {
+            if i > 0 {
+                out.write_char(',')?;
+            }
+
+            out.write_str(label)?;
+            out.write_str("=\"")?;
+            write_escaped(out, value, true)?;
+            out.write_char('"')?;
+        }
+
+        out.write_char('}')?;
+    }
+
+    writeln!(out, " {}", value)
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Value {
+    Int(u32),
+    Float(f32),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Value::Int(value) => write!(f, "{}", value),
+            Value::Float(value) if value.is_nan() => f.write_str("NaN"),
+            Value::Float(value) if value == f32::INFINITY => f.write_str("+Inf"),
+            Value::Float(value) if value == f32::NEG_INFINITY => f.write_str("-Inf"),
+            Value::Float(value) => write!(f, "{}", value),
+        }
+    }
+}
+
+/// A monotonically increasing count, wraps around at `u32::MAX`
+pub struct Counter {
+    name: &'static str,
+    help: &'static str,
+    value: AtomicU32,
+}
+
+impl Counter {
+    pub const fn new(name: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            value: AtomicU32::new(0),
+        }
+    }
+
+    pub fn inc(&self) {
+        self.inc_by(1);
+    }
+
+    pub fn inc_by(&self, n: u32) {
+        self.value.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u32 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+impl Metric for Counter {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn help(&self) -> &'static str {
+        self.help
+    }
+
+    fn kind(&self) -> MetricKind {
+        MetricKind::Counter
+    }
+
+    fn write_samples(&self, out: &mut dyn Write) -> fmt::Result {
+        write_sample(out, self.name, &[], &[], Value::Int(self.get()))
+    }
+}
+
+/// A value that can go up and down, stored as the bits of an `f32`
+pub struct Gauge {
+    name: &'static str,
+    help: &'static str,
+    bits: AtomicU32,
+}
+
+impl Gauge {
+    pub const fn new(name: &'static str, help: &'static str) -> Self {
+        Self {
+            name,
+            help,
+            // 0.0f32 is all zero bits
+            bits: AtomicU32::new(0),
+        }
+    }
+
+    pub fn set(&self, value: f32) {
+        self.bits.store(value.to_bits(), Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> f32 {
+        f32::from_bits(self.bits.load(Ordering::Relaxed))
+    }
+}
+
+impl Metric for Gauge {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn help(&self) -> &'static str {
+        self.help
+    }
+
+    fn kind(&self) -> MetricKind {
+        MetricKind::Gauge
+    }
+
+    fn write_samples(&self, out: &mut dyn Write) -> fmt::Result {
+        write_sample(out, self.name, &[], &[], Value::Float(self.get()))
+    }
+}
@@ -0,0 +1,60 @@
+use core::fmt::{self, Write};
+
+use crate::{slot::Slot, write_metric, Metric};
+
+/// The metrics rendered by a scrape, with room for `N` of them
+pub struct Registry<const N: usize> {
+    metrics: [Slot<&'static dyn Metric>; N],
+}
+
+impl<const N: usize> Registry<N> {
+    // Only used to repeat into the slot array, never shared
+    #[allow(clippy::declare_interior_mutable_const)]
+    const EMPTY: Slot<&'static dyn Metric> = Slot::new();
+
+    pub const fn new() -> Self {
+        Self {
+            metrics: [Self::EMPTY; N],
+        }
+    }
+
+    /// Adds a metric, returns `false` if it was already registered or the registry is full.
+    /// Also `false` if another task is stuck registering into the same slot.
+    pub fn register(&self, metric: &'static dyn Metric) -> bool {
+        for slot in &self.metrics {
+            match slot.try_set(metric) {
+                Ok(()) => return true,
+                Err(Some(existing)) if existing.name() == metric.name() => return false,
+                Err(Some(_)) => continue,
+                Err(None) => return false,
+            }
+        }
+
+        false
+    }
+
+    pub fn register_all(&self, metrics: &[&'static dyn Metric]) {
+        for metric in metrics {
+            self.register(*metric);
+        }
+    }
+
+    /// Renders every registered metric in the Prometheus text exposition format
+    pub fn render(&self, out: &mut dyn Write) -> fmt::Result {
+        for slot in &self.metrics {
+            let Some(metric) = slot.get() else {
+                break;
+            };
+
+            write_metric(metric, out)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl<const N: usize> Default for Registry<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,89 @@
+use core::{
+    cell::UnsafeCell,
+    hint,
+    sync::atomic::{AtomicU8, Ordering},
+};
+
+const EMPTY: u8 = 0;
+const WRITING: u8 = 1;
+const READY: u8 = 2;
+
+/// How long a slot that's being written is waited on. On a single core a writer that was
+/// preempted can't finish while the task that preempted it spins, so the wait is bounded.
+const MAX_SPINS: u32 = 1024;
+
+/// A write-once cell, claimed with a compare-and-swap so no lock is needed
+pub(crate) struct Slot<T: Copy> {
+    state: AtomicU8,
+    value: UnsafeCell<Option<T>>,
+}
+
+// The value is only written by the thread that claimed the slot, and only read once
+// the slot is published as ready
+unsafe impl<T: Copy + Send + Sync> Sync for Slot<T> {}
+
+impl<T: Copy> Slot<T> {
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(EMPTY),
+            value: UnsafeCell::new(None),
+        }
+    }
+
+    /// Stores `value` if the slot is still empty. Otherwise returns what it holds, or `None`
+    /// if another writer claimed it and didn't finish within [`MAX_SPINS`].
+    pub fn try_set(&self, value: T) -> Result<(), Option<T>> {
+        if self
+            .state
+            .compare_exchange(EMPTY, WRITING, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            return Err(self.get());
+        }
+
+        unsafe { *self.value.get() = Some(value) };
+        self.state.store(READY, Ordering::Release);
+
+        Ok(())
+    }
+
+    /// `None` while the slot is empty. A slot that's being written is waited on for at
+    /// most [`MAX_SPINS`], the writer only has to copy the value in, and is taken for
+    /// still empty if that runs out.
+    pub fn get(&self) -> Option<T> {
+        for _ in 0..MAX_SPINS {
+            match self.state.load(Ordering::Acquire) {
+                EMPTY => return None,
+                READY => return unsafe { *self.value.get() },
+                _ => hint::spin_loop(),
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn is_written_once() {
+        let slot = Slot::new();
+
+        assert_eq!(slot.get(), None);
+        assert_eq!(slot.try_set(1), Ok(()));
+        assert_eq!(slot.try_set(2), Err(Some(1)));
+        assert_eq!(slot.get(), Some(1));
+    }
+
+    #[test]
+    fn gives_up_on_a_stalled_writer() {
+        let slot = Slot::<u32>::new();
+        // A writer that claimed the slot and was never scheduled again
+        slot.state.store(WRITING, Ordering::Relaxed);
+
+        assert_eq!(slot.get(), None);
+        assert_eq!(slot.try_set(1), Err(None));
+    }
+}
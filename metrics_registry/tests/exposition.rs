@@ -0,0 +1,160 @@
+use metrics_registry::{Counter, CounterVec, Gauge, GaugeVec, Metric, Registry};
+
+fn render(metric: &dyn Metric) -> String {
+    let mut out = String::new();
+    metrics_registry::write_metric(metric, &mut out).unwrap();
+
+    out
+}
+
+#[test]
+fn counter_has_a_header_and_one_sample() {
+    let counter = Counter::new("sms_sent_total", "SMS messages sent");
+    counter.inc();
+    counter.inc_by(2);
+
+    assert_eq!(
+        render(&counter),
+        "# HELP sms_sent_total SMS messages sent\n\
+         # TYPE sms_sent_total counter\n\
+         sms_sent_total 3\n"
+    );
+}
+
+#[test]
+fn counter_wraps_around() {
+    let counter = Counter::new("wraps_total", "Wraps");
+    counter.inc_by(u32::MAX);
+    counter.inc_by(2);
+
+    assert_eq!(counter.get(), 1);
+}
+
+#[test]
+fn gauge_renders_special_floats() {
+    let gauge = Gauge::new("temperature", "Temperature");
+
+    gauge.set(21.5);
+    assert!(render(&gauge).ends_with("\ntemperature 21.5\n"));
+
+    gauge.set(f32::NAN);
+    assert!(render(&gauge).ends_with("\ntemperature NaN\n"));
+
+    gauge.set(f32::INFINITY);
+    assert!(render(&gauge).ends_with("\ntemperature +Inf\n"));
+
+    gauge.set(f32::NEG_INFINITY);
+    assert!(render(&gauge).ends_with("\ntemperature -Inf\n"));
+}
+
+#[test]
+fn gauge_starts_at_zero() {
+    let gauge = Gauge::new("level", "Level");
+
+    assert_eq!(gauge.get(), 0.0);
+    assert!(render(&gauge).ends_with("\nlevel 0\n"));
+}
+
+#[test]
+fn help_escapes_backslashes_and_newlines() {
+    let counter = Counter::new("escaped_total", "A \\ in\ntwo lines, \"quoted\"");
+
+    assert!(
+        render(&counter).starts_with("# HELP escaped_total A \\\\ in\\ntwo lines, \"quoted\"\n")
+    );
+}
+
+#[test]
+fn label_values_escape_quotes() {
+    let counter = CounterVec::<2, 4>::new("requests_total", "Requests", ["path", "status"]);
+    counter.inc(["/a\"b\\c\nd", "200"]);
+
+    assert!(render(&counter)
+        .ends_with("\nrequests_total{path=\"/a\\\"b\\\\c\\nd\",status=\"200\"} 1\n"));
+}
+
+#[test]
+fn families_render_series_in_the_order_they_appeared() {
+    let counter = CounterVec::<1, 4>::new("events_total", "Events", ["kind"]);
+    counter.inc(["b"]);
+    counter.inc(["a"]);
+    counter.inc_by(["b"], 4);
+
+    assert_eq!(counter.get(["b"]), 5);
+    assert_eq!(counter.get(["missing"]), 0);
+    assert_eq!(
+        render(&counter),
+        "# HELP events_total Events\n\
+         # TYPE events_total counter\n\
+         events_total{kind=\"b\"} 5\n\
+         events_total{kind=\"a\"} 1\n"
+    );
+}
+
+#[test]
+fn families_count_updates_past_their_capacity() {
+    let gauge = GaugeVec::<1, 2>::new("signal", "Signal", ["ssid"]);
+    gauge.set(["a"], -40.0);
+    gauge.set(["b"], -60.5);
+    gauge.set(["c"], -70.0);
+    gauge.set(["c"], -71.0);
+
+    assert_eq!(gauge.dropped(), 2);
+    assert_eq!(gauge.get(["c"]), 0.0);
+    assert_eq!(
+        render(&gauge),
+        "# HELP signal Signal\n\
+         # TYPE signal gauge\n\
+         signal{ssid=\"a\"} -40\n\
+         signal{ssid=\"b\"} -60.5\n"
+    );
+}
+
+#[test]
+fn empty_family_renders_only_the_header() {
+    let counter = CounterVec::<1, 2>::new("unused_total", "Unused", ["kind"]);
+
+    assert_eq!(
+        render(&counter),
+        "# HELP unused_total Unused\n# TYPE unused_total counter\n"
+    );
+}
+
+static FIRST: Counter = Counter::new("first_total", "First");
+static SECOND: Gauge = Gauge::new("second", "Second");
+static THIRD: Counter = Counter::new("third_total", "Third");
+static FIRST_AGAIN: Counter = Counter::new("first_total", "First, again");
+
+#[test]
+fn registry_renders_metrics_in_registration_order() {
+    let registry = Registry::<2>::new();
+
+    assert!(registry.register(&SECOND));
+    assert!(registry.register(&FIRST));
+    // Same name, already registered
+    assert!(!registry.register(&FIRST_AGAIN));
+    // Full
+    assert!(!registry.register(&THIRD));
+
+    let mut out = String::new();
+    registry.render(&mut out).unwrap();
+
+    assert_eq!(
+        out,
+        "# HELP second Second\n\
+         # TYPE second gauge\n\
+         second 0\n\
+         # HELP first_total First\n\
+         # TYPE first_total counter\n\
+         first_total 0\n"
+    );
+}
+
+#[test]
+fn empty_registry_renders_nothing() {
+    let registry = Registry::<4>::default();
+    let mut out = String::new();
+    registry.render(&mut out).unwrap();
+
+    assert!(out.is_empty());
+}
@@ -0,0 +1,515 @@
+//! Partition tables for the ESP32 firmwares, shared by their `build.rs`
+
+use std::{env, fs, path::Path};
+
+use esp_idf_part::{AppType, DataType, Partition, PartitionTable, SubType, Type};
+use eyre::bail;
+
+pub mod spec;
+
+pub use spec::{Filesystem, FlashSize, Kind, PartitionSpec, Size, TableSpec};
+
+/// Path of a TOML [`TableSpec`] to use instead of the preset
+pub const SPEC_ENV: &str = "PARTITION_TABLE";
+/// Overrides the flash size of the preset or the TOML spec, e.g. `8MB`
+pub const FLASH_SIZE_ENV: &str = "PARTITION_FLASH_SIZE";
+/// Picked up from the package root when [`SPEC_ENV`] isn't set
+pub const SPEC_FILE: &str = "partitions.toml";
+
+/// The first 0x8000 bytes (32KB) are reserved for the bootloader,
+/// then another 0x1000 bytes (4KB) for the partition table
+pub const FIRST_PARTITION_OFFSET: u32 = 0x9000;
+/// App offsets must be multiples of 0x10000 (64KB)
+pub const APP_ALIGNMENT: u32 = 0x1_0000;
+/// Data partitions must be multiples of 0x1000 (4KB)
+pub const DATA_ALIGNMENT: u32 = 0x1000;
+/// otadata must be exactly 8KB, it holds the two OTA select entries
+pub const OTADATA_SIZE: u32 = 0x2000;
+pub const MAX_OTA_SLOTS: usize = 16;
+pub const MAX_NAME_LEN: usize = 16;
+
+/// ESP-IDF has no LittleFS subtype of its own, this is the one the esp_littlefs component looks for
+pub const LITTLEFS_SUBTYPE: u8 = 0x83;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlacedPartition {
+    pub name: String,
+    pub kind: Kind,
+    pub offset: u32,
+    pub size: u32,
+    /// Slot number of OTA app partitions
+    pub ota_slot: Option<u8>,
+}
+
+impl PlacedPartition {
+    pub fn end(&self) -> u32 {
+        self.offset + self.size
+    }
+
+    fn to_partition(&self) -> Partition {
+        let (ty, subtype) = match self.kind {
+            Kind::Nvs => (Type::Data, SubType::Data(DataType::Nvs)),
+            Kind::Otadata => (Type::Data, SubType::Data(DataType::Ota)),
+            Kind::Phy => (Type::Data, SubType::Data(DataType::Phy)),
+            Kind::Coredump => (Type::Data, SubType::Data(DataType::Coredump)),
+            Kind::Fat => (Type::Data, SubType::Data(DataType::Fat)),
+            Kind::Spiffs => (Type::Data, SubType::Data(DataType::Spiffs)),
+            Kind::Littlefs => (Type::Data, SubType::Custom(LITTLEFS_SUBTYPE)),
+            Kind::Factory => (Type::App, SubType::App(AppType::Factory)),
+            Kind::Ota => (
+                Type::App,
+                SubType::App(ota_app_type(self.ota_slot.unwrap_or(0))),
+            ),
+        };
+
+        Partition::new(&self.name, ty, subtype, self.offset, self.size, false)
+    }
+}
+
+fn ota_app_type(slot: u8) -> AppType {
+    match slot {
+        0 => AppType::Ota_0,
+        1 => AppType::Ota_1,
+        2 => AppType::Ota_2,
+        3 => AppType::Ota_3,
+        4 => AppType::Ota_4,
+        5 => AppType::Ota_5,
+        6 => AppType::Ota_6,
+        7 => AppType::Ota_7,
+        8 => AppType::Ota_8,
+        9 => AppType::Ota_9,
+        10 => AppType::Ota_10,
+        11 => AppType::Ota_11,
+        12 => AppType::Ota_12,
+        13 => AppType::Ota_13,
+        14 => AppType::Ota_14,
+        _ => AppType::Ota_15,
+    }
+}
+
+/// A validated table with every partition at its final offset
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Layout {
+    pub flash_size: FlashSize,
+    pub partitions: Vec<PlacedPartition>,
+}
+
+impl TableSpec {
+    /// Places the partitions in order and checks the result is a table the bootloader accepts
+    pub fn layout(&self) -> eyre::Result<Layout> {
+        let flash_end = self.flash_size.bytes();
+        let mut cursor = FIRST_PARTITION_OFFSET;
+        let mut ota_slots = 0usize;
+        let mut partitions = Vec::with_capacity(self.partitions.len());
+
+        if self.partitions.is_empty() {
+            bail!("Partition table is empty");
+        }
+
+        for (i, spec) in self.partitions.iter().enumerate() {
+            if spec.name.is_empty() || spec.name.len() > MAX_NAME_LEN {
+                bail!(
+                    "Partition name '{}' must be 1 to {} characters",
+                    spec.name,
+                    MAX_NAME_LEN
+                );
+            }
+
+            if partitions
+                .iter()
+                .any(|p: &PlacedPartition| p.name == spec.name)
+            {
+                bail!("Partition '{}' is defined more than once", spec.name);
+            }
+
+            let alignment = if spec.kind.is_app() {
+                APP_ALIGNMENT
+            } else {
+                DATA_ALIGNMENT
+            };
+
+            let offset = match spec.offset {
+                Some(offset) => {
+                    if offset % alignment != 0 {
+                        bail!(
+                            "Partition '{}' offset {:#x} is not aligned to {:#x}",
+                            spec.name,
+                            offset,
+                            alignment
+                        );
+                    }
+
+                    if offset < cursor {
+                        bail!(
+                            "Partition '{}' at {:#x} overlaps the partition before it, which ends at {:#x}",
+                            spec.name,
+                            offset,
+                            cursor
+                        );
+                    }
+
+                    offset
+                }
+                None => cursor.next_multiple_of(alignment),
+            };
+
+            let size = match spec.size {
+                Size::Bytes(size) => size,
+                Size::Rest if i == self.partitions.len() - 1 => flash_end.saturating_sub(offset),
+                Size::Rest => bail!(
+                    "Partition '{}' can't take the rest of the flash, it isn't the last one",
+                    spec.name
+                ),
+            };
+
+            if size == 0 || size % DATA_ALIGNMENT != 0 {
+                bail!(
+                    "Partition '{}' size {:#x} must be a non-zero multiple of {:#x}",
+                    spec.name,
+                    size,
+                    DATA_ALIGNMENT
+                );
+            }
+
+            if spec.kind == Kind::Otadata && size != OTADATA_SIZE {
+                bail!(
+                    "otadata partition '{}' must be exactly {:#x} bytes",
+                    spec.name,
+                    OTADATA_SIZE
+                );
+            }
+
+            let end = offset as u64 + size as u64;
+            if end > flash_end as u64 {
+                bail!(
+                    "Partition '{}' ends at {:#x}, past the end of the {} flash",
+                    spec.name,
+                    end,
+                    self.flash_size
+                );
+            }
+
+            let ota_slot = if spec.kind == Kind::Ota {
+                if ota_slots == MAX_OTA_SLOTS {
+                    bail!("Partition table has more than {} OTA slots", MAX_OTA_SLOTS);
+                }
+
+                ota_slots += 1;
+                Some(ota_slots as u8 - 1)
+            } else {
+                None
+            };
+
+            partitions.push(PlacedPartition {
+                name: spec.name.clone(),
+                kind: spec.kind,
+                offset,
+                size,
+                ota_slot,
+            });
+
+            cursor = end as u32;
+        }
+
+        let count = |kind| partitions.iter().filter(|p| p.kind == kind).count();
+
+        if count(Kind::Factory) + count(Kind::Ota) == 0 {
+            bail!("Partition table has no app partition");
+        }
+
+        if count(Kind::Factory) > 1 {
+            bail!("Partition table can only have one factory app partition");
+        }
+
+        match (ota_slots, count(Kind::Otadata)) {
+            (0, 0) => {}
+            (0, _) => bail!("otadata partition has no OTA slots to select from"),
+            (1, _) => bail!("OTA needs at least two app slots"),
+            (_, 1) => {}
+            (_, 0) => bail!("OTA app slots need an otadata partition"),
+            (_, _) => bail!("Partition table can only have one otadata partition"),
+        }
+
+        Ok(Layout {
+            flash_size: self.flash_size,
+            partitions,
+        })
+    }
+}
+
+impl Layout {
+    pub fn to_table(&self) -> PartitionTable {
+        PartitionTable::new(
+            self.partitions
+                .iter()
+                .map(PlacedPartition::to_partition)
+                .collect(),
+        )
+    }
+
+    /// Writes `<flash size>.csv` and `<flash size>.bin` into `dir`
+    pub fn write(&self, dir: impl AsRef<Path>) -> eyre::Result<()> {
+        let dir = dir.as_ref();
+        let table = self.to_table();
+
+        let csv = table.to_csv()?;
+        let bin = table.to_bin()?;
+
+        fs::create_dir_all(dir)?;
+        fs::write(dir.join(format!("{}.csv", self.flash_size)), csv)?;
+        fs::write(dir.join(format!("{}.bin", self.flash_size)), bin)?;
+
+        Ok(())
+    }
+}
+
+/// Resolves the spec for this build, in order of precedence: the TOML file in [`SPEC_ENV`],
+/// a [`SPEC_FILE`] in the package root, then `preset`. [`FLASH_SIZE_ENV`] overrides the flash size.
+pub fn spec_from_env(preset: impl FnOnce(FlashSize) -> TableSpec) -> eyre::Result<TableSpec> {
+    println!("cargo:rerun-if-env-changed={}", SPEC_ENV);
+    println!("cargo:rerun-if-env-changed={}", FLASH_SIZE_ENV);
+
+    let flash_size = env::var(FLASH_SIZE_ENV)
+        .ok()
+        .map(|size| size.parse::<FlashSize>())
+        .transpose()?;
+
+    let path = env::var(SPEC_ENV)
+        .ok()
+        .or_else(|| Path::new(SPEC_FILE).exists().then(|| SPEC_FILE.to_string()));
+
+    let spec = match path {
+        Some(path) => {
+            println!("cargo:rerun-if-changed={}", path);
+
+            let mut spec = TableSpec::from_toml_file(&path)?;
+            if let Some(flash_size) = flash_size {
+                spec.flash_size = flash_size;
+            }
+
+            spec
+        }
+        None => preset(flash_size.unwrap_or_default()),
+    };
+
+    Ok(spec)
+}
+
+/// Generates `./partition/<flash size>.csv` and `.bin` for the resolved spec
+pub fn generate(preset: impl FnOnce(FlashSize) -> TableSpec) -> eyre::Result<Layout> {
+    let layout = spec_from_env(preset)?.layout()?;
+    layout.write("./partition")?;
+
+    Ok(layout)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn csv(spec: TableSpec) -> String {
+        spec.layout().unwrap().to_table().to_csv().unwrap()
+    }
+
+    fn layout_err(spec: TableSpec) -> String {
+        spec.layout().unwrap_err().to_string()
+    }
+
+    #[test]
+    fn factory_preset_matches_the_original_table() {
+        assert_eq!(
+            csv(TableSpec::factory(FlashSize::Mb4, Filesystem::Fat)),
+            "# ESP-IDF Partition Table\n\
+             # Name,Type,SubType,Offset,Size,Flags\n\
+             nvs,data,nvs,0x9000,0x6000,\n\
+             phy_init,data,phy,0xf000,0x1000,\n\
+             factory,app,factory,0x10000,0x200000,\n\
+             fs,data,fat,0x210000,0x100000,\n"
+        );
+    }
+
+    #[test]
+    fn ota_preset_keeps_nvs_and_the_fs_size() {
+        assert_eq!(
+            csv(TableSpec::ota(FlashSize::Mb4, Filesystem::Fat)),
+            "# ESP-IDF Partition Table\n\
+             # Name,Type,SubType,Offset,Size,Flags\n\
+             nvs,data,nvs,0x9000,0x6000,\n\
+             phy_init,data,phy,0xf000,0x1000,\n\
+             otadata,data,ota,0x10000,0x2000,\n\
+             ota_0,app,ota_0,0x20000,0x170000,\n\
+             ota_1,app,ota_1,0x190000,0x170000,\n\
+             fs,data,fat,0x300000,0x100000,\n"
+        );
+    }
+
+    #[test]
+    fn presets_fit_every_flash_size() {
+        for flash_size in [FlashSize::Mb4, FlashSize::Mb8, FlashSize::Mb16] {
+            for fs in [Filesystem::Fat, Filesystem::Spiffs, Filesystem::LittleFs] {
+                for spec in [
+                    TableSpec::factory(flash_size, fs),
+                    TableSpec::ota(flash_size, fs),
+                    TableSpec::ota(flash_size, fs).with_coredump(),
+                ] {
+                    let layout = spec.layout().unwrap();
+                    let last = layout.partitions.last().unwrap();
+
+                    assert_eq!(last.name, "fs");
+                    assert_eq!(last.kind, Kind::from(fs));
+                    assert!(last.end() <= flash_size.bytes());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn littlefs_uses_its_custom_subtype() {
+        let table = TableSpec::ota(FlashSize::Mb4, Filesystem::LittleFs)
+            .layout()
+            .unwrap()
+            .to_table();
+        let fs = table.find("fs").unwrap();
+
+        assert_eq!(fs.subtype(), SubType::Custom(LITTLEFS_SUBTYPE));
+    }
+
+    #[test]
+    fn places_partitions_after_each_other_with_alignment() {
+        let layout = TableSpec::new(FlashSize::Mb4)
+            .partition("nvs", Kind::Nvs, Size::Bytes(0x5000))
+            .partition("factory", Kind::Factory, Size::Bytes(0x10_0000))
+            .partition("fs", Kind::Fat, Size::Rest)
+            .layout()
+            .unwrap();
+        let placed: Vec<_> = layout
+            .partitions
+            .iter()
+            .map(|p| (p.offset, p.size))
+            .collect();
+
+        assert_eq!(
+            placed,
+            [
+                (0x9000, 0x5000),
+                (0x1_0000, 0x10_0000),
+                (0x11_0000, 0x2f_0000)
+            ]
+        );
+    }
+
+    #[test]
+    fn numbers_ota_slots_in_order() {
+        let layout = TableSpec::ota(FlashSize::Mb4, Filesystem::Fat)
+            .layout()
+            .unwrap();
+        let slots: Vec<_> = layout
+            .partitions
+            .iter()
+            .filter_map(|p| p.ota_slot)
+            .collect();
+
+        assert_eq!(slots, [0, 1]);
+    }
+
+    #[test]
+    fn rejects_misaligned_offsets() {
+        let mut spec = TableSpec::new(FlashSize::Mb4)
+            .partition("nvs", Kind::Nvs, Size::Bytes(0x6000))
+            .partition("factory", Kind::Factory, Size::Bytes(0x10_0000));
+        spec.partitions[1].offset = Some(0x1_8000);
+
+        assert!(layout_err(spec).contains("not aligned"));
+    }
+
+    #[test]
+    fn rejects_overlapping_offsets() {
+        let mut spec = TableSpec::new(FlashSize::Mb4)
+            .partition("nvs", Kind::Nvs, Size::Bytes(0x6000))
+            .partition("phy_init", Kind::Phy, Size::Bytes(0x1000))
+            .partition("factory", Kind::Factory, Size::Bytes(0x10_0000));
+        spec.partitions[1].offset = Some(0xa000);
+
+        assert!(layout_err(spec).contains("overlaps"));
+    }
+
+    #[test]
+    fn rejects_rest_before_the_last_partition() {
+        let spec = TableSpec::new(FlashSize::Mb4)
+            .partition("fs", Kind::Fat, Size::Rest)
+            .partition("factory", Kind::Factory, Size::Bytes(0x10_0000));
+
+        assert!(layout_err(spec).contains("isn't the last one"));
+    }
+
+    #[test]
+    fn rejects_tables_past_the_end_of_the_flash() {
+        let spec = TableSpec::new(FlashSize::Mb4).partition(
+            "factory",
+            Kind::Factory,
+            Size::Bytes(0x40_0000),
+        );
+
+        assert!(layout_err(spec).contains("past the end"));
+    }
+
+    #[test]
+    fn rejects_sizes_that_arent_sector_multiples() {
+        let spec = TableSpec::new(FlashSize::Mb4)
+            .partition("nvs", Kind::Nvs, Size::Bytes(0x6001))
+            .partition("factory", Kind::Factory, Size::Bytes(0x10_0000));
+
+        assert!(layout_err(spec).contains("non-zero multiple"));
+    }
+
+    #[test]
+    fn rejects_bad_names() {
+        let duplicate = TableSpec::new(FlashSize::Mb4)
+            .partition("app", Kind::Factory, Size::Bytes(0x10_0000))
+            .partition("app", Kind::Fat, Size::Rest);
+        let too_long = TableSpec::new(FlashSize::Mb4).partition(
+            "a_very_long_partition_name",
+            Kind::Factory,
+            Size::Rest,
+        );
+
+        assert!(layout_err(duplicate).contains("more than once"));
+        assert!(layout_err(too_long).contains("1 to 16 characters"));
+    }
+
+    #[test]
+    fn rejects_otadata_of_the_wrong_size() {
+        let spec = TableSpec::new(FlashSize::Mb4)
+            .partition("otadata", Kind::Otadata, Size::Bytes(0x1000))
+            .partition("ota_0", Kind::Ota, Size::Bytes(0x10_0000))
+            .partition("ota_1", Kind::Ota, Size::Bytes(0x10_0000));
+
+        assert!(layout_err(spec).contains("exactly 0x2000"));
+    }
+
+    #[test]
+    fn rejects_incomplete_ota_setups() {
+        let single_slot = TableSpec::new(FlashSize::Mb4)
+            .partition("otadata", Kind::Otadata, Size::Bytes(0x2000))
+            .partition("ota_0", Kind::Ota, Size::Bytes(0x10_0000));
+        let no_otadata = TableSpec::new(FlashSize::Mb4)
+            .partition("ota_0", Kind::Ota, Size::Bytes(0x10_0000))
+            .partition("ota_1", Kind::Ota, Size::Bytes(0x10_0000));
+        let no_slots = TableSpec::new(FlashSize::Mb4)
+            .partition("otadata", Kind::Otadata, Size::Bytes(0x2000))
+            .partition("factory", Kind::Factory, Size::Bytes(0x10_0000));
+
+        assert!(layout_err(single_slot).contains("at least two"));
+        assert!(layout_err(no_otadata).contains("need an otadata"));
+        assert!(layout_err(no_slots).contains("no OTA slots"));
+    }
+
+    #[test]
+    fn rejects_tables_without_an_app() {
+        let empty = TableSpec::new(FlashSize::Mb4);
+        let no_app = TableSpec::new(FlashSize::Mb4).partition("fs", Kind::Fat, Size::Rest);
+
+        assert!(layout_err(empty).contains("empty"));
+        assert!(layout_err(no_app).contains("no app partition"));
+    }
+}
@@ -0,0 +1,389 @@
+use std::{fmt, fs, path::Path, str::FromStr};
+
+use eyre::{bail, eyre};
+use serde::{Deserialize, Deserializer};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FlashSize {
+    #[default]
+    Mb4,
+    Mb8,
+    Mb16,
+}
+
+impl FlashSize {
+    pub const fn bytes(self) -> u32 {
+        match self {
+            FlashSize::Mb4 => 0x40_0000,
+            FlashSize::Mb8 => 0x80_0000,
+            FlashSize::Mb16 => 0x100_0000,
+        }
+    }
+
+    /// Used as the file name of the generated table, e.g. `4mb.csv`
+    pub const fn name(self) -> &'static str {
+        match self {
+            FlashSize::Mb4 => "4mb",
+            FlashSize::Mb8 => "8mb",
+            FlashSize::Mb16 => "16mb",
+        }
+    }
+}
+
+impl FromStr for FlashSize {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> eyre::Result<Self> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "4" | "4m" | "4mb" => Ok(FlashSize::Mb4),
+            "8" | "8m" | "8mb" => Ok(FlashSize::Mb8),
+            "16" | "16m" | "16mb" => Ok(FlashSize::Mb16),
+            _ => bail!("Unsupported flash size '{}', expected 4MB, 8MB or 16MB", s),
+        }
+    }
+}
+
+impl fmt::Display for FlashSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl<'de> Deserialize<'de> for FlashSize {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Kind {
+    Nvs,
+    Otadata,
+    Phy,
+    Factory,
+    /// An OTA app slot, slots are numbered `ota_0`, `ota_1`, ... in the order they're listed
+    Ota,
+    Fat,
+    Spiffs,
+    Littlefs,
+    Coredump,
+}
+
+impl Kind {
+    pub const fn is_app(self) -> bool {
+        matches!(self, Kind::Factory | Kind::Ota)
+    }
+}
+
+/// The filesystem used for the data partition of the presets
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filesystem {
+    Fat,
+    Spiffs,
+    LittleFs,
+}
+
+impl From<Filesystem> for Kind {
+    fn from(fs: Filesystem) -> Self {
+        match fs {
+            Filesystem::Fat => Kind::Fat,
+            Filesystem::Spiffs => Kind::Spiffs,
+            Filesystem::LittleFs => Kind::Littlefs,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Size {
+    Bytes(u32),
+    /// Everything up to the end of the flash, only valid for the last partition
+    Rest,
+}
+
+/// Parses `0x4000`, `16384`, `16K` or `1M`
+pub fn parse_bytes(s: &str) -> eyre::Result<u32> {
+    let s = s.trim();
+    let invalid = || eyre!("Invalid size or offset '{}'", s);
+
+    if let Some(hex) = s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        return u32::from_str_radix(&hex.replace('_', ""), 16).map_err(|_| invalid());
+    }
+
+    let (number, multiplier) = match s.char_indices().last() {
+        Some((i, 'k' | 'K')) => (&s[..i], 1024),
+        Some((i, 'm' | 'M')) => (&s[..i], 1024 * 1024),
+        _ => (s, 1),
+    };
+
+    number
+        .trim()
+        .replace('_', "")
+        .parse::<u32>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+        .ok_or_else(invalid)
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawBytes {
+    Number(u32),
+    Text(String),
+}
+
+impl RawBytes {
+    fn into_bytes(self) -> eyre::Result<u32> {
+        match self {
+            RawBytes::Number(n) => Ok(n),
+            RawBytes::Text(s) => parse_bytes(&s),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Size {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match RawBytes::deserialize(deserializer)? {
+            RawBytes::Text(s) if s.trim().eq_ignore_ascii_case("rest") => Ok(Size::Rest),
+            raw => raw
+                .into_bytes()
+                .map(Size::Bytes)
+                .map_err(serde::de::Error::custom),
+        }
+    }
+}
+
+fn deserialize_offset<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u32>, D::Error> {
+    Option::<RawBytes>::deserialize(deserializer)?
+        .map(RawBytes::into_bytes)
+        .transpose()
+        .map_err(serde::de::Error::custom)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct PartitionSpec {
+    pub name: String,
+    pub kind: Kind,
+    /// Placed right after the previous partition (respecting alignment) when not given
+    #[serde(default, deserialize_with = "deserialize_offset")]
+    pub offset: Option<u32>,
+    pub size: Size,
+}
+
+/// A partition table described by kind and size, offsets are worked out by [`TableSpec::layout`]
+///
+/// ```toml
+/// flash_size = "4MB"
+///
+/// [[partitions]]
+/// name = "nvs"
+/// kind = "nvs"
+/// size = "16K"
+///
+/// [[partitions]]
+/// name = "fs"
+/// kind = "fat"
+/// size = "rest"
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct TableSpec {
+    #[serde(default)]
+    pub flash_size: FlashSize,
+    pub partitions: Vec<PartitionSpec>,
+}
+
+impl TableSpec {
+    pub fn new(flash_size: FlashSize) -> Self {
+        Self {
+            flash_size,
+            partitions: Vec::new(),
+        }
+    }
+
+    pub fn from_toml(toml: &str) -> eyre::Result<Self> {
+        Ok(toml::from_str(toml)?)
+    }
+
+    pub fn from_toml_file(path: impl AsRef<Path>) -> eyre::Result<Self> {
+        let path = path.as_ref();
+        let toml = fs::read_to_string(path)
+            .map_err(|e| eyre!("Failed to read {}: {}", path.display(), e))?;
+
+        Self::from_toml(&toml)
+    }
+
+    pub fn partition(mut self, name: &str, kind: Kind, size: Size) -> Self {
+        self.partitions.push(PartitionSpec {
+            name: name.to_string(),
+            kind,
+            offset: None,
+            size,
+        });
+
+        self
+    }
+
+    /// Two OTA app slots with rollback support and a filesystem on the rest of the flash.
+    ///
+    /// `nvs` and `phy_init` are where [`TableSpec::factory`] has them, so NVS survives moving
+    /// a device over. On 4MB flash `fs` keeps the 1MB of the factory table, only its offset
+    /// changes: the old image can be read out before flashing and written back after, e.g.
+    /// `espflash read-flash 0x210000 0x100000 fs.bin` then `espflash write-bin 0x300000 fs.bin`.
+    pub fn ota(flash_size: FlashSize, fs: Filesystem) -> Self {
+        let app_size = match flash_size {
+            FlashSize::Mb4 => 0x17_0000,
+            FlashSize::Mb8 => 0x30_0000,
+            FlashSize::Mb16 => 0x60_0000,
+        };
+
+        Self::new(flash_size)
+            .partition("nvs", Kind::Nvs, Size::Bytes(0x6000))
+            .partition("phy_init", Kind::Phy, Size::Bytes(0x1000))
+            .partition("otadata", Kind::Otadata, Size::Bytes(0x2000))
+            .partition("ota_0", Kind::Ota, Size::Bytes(app_size))
+            .partition("ota_1", Kind::Ota, Size::Bytes(app_size))
+            .partition("fs", fs.into(), Size::Rest)
+    }
+
+    /// A single factory app and a filesystem after it, updates need a cable.
+    ///
+    /// On 4MB flash this is the table the firmwares shipped with before it was generated,
+    /// `fs` stays 1MB with the last 0xF0000 bytes unused so devices flashed with it keep
+    /// their files.
+    pub fn factory(flash_size: FlashSize, fs: Filesystem) -> Self {
+        let (app_size, fs_size) = match flash_size {
+            FlashSize::Mb4 => (0x20_0000, 0x10_0000),
+            FlashSize::Mb8 => (0x40_0000, 0x20_0000),
+            FlashSize::Mb16 => (0x80_0000, 0x40_0000),
+        };
+
+        Self::new(flash_size)
+            .partition("nvs", Kind::Nvs, Size::Bytes(0x6000))
+            .partition("phy_init", Kind::Phy, Size::Bytes(0x1000))
+            .partition("factory", Kind::Factory, Size::Bytes(app_size))
+            .partition("fs", fs.into(), Size::Bytes(fs_size))
+    }
+
+    /// Adds a 64KB `coredump` partition in front of the last partition
+    pub fn with_coredump(mut self) -> Self {
+        let coredump = PartitionSpec {
+            name: "coredump".to_string(),
+            kind: Kind::Coredump,
+            offset: None,
+            size: Size::Bytes(0x1_0000),
+        };
+
+        let at = self.partitions.len().saturating_sub(1);
+        self.partitions.insert(at, coredump);
+
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_bytes_in_every_notation() {
+        assert_eq!(parse_bytes("0x4000").unwrap(), 0x4000);
+        assert_eq!(parse_bytes("0X10_0000").unwrap(), 0x10_0000);
+        assert_eq!(parse_bytes("16384").unwrap(), 16384);
+        assert_eq!(parse_bytes("16K").unwrap(), 16 * 1024);
+        assert_eq!(parse_bytes(" 1m ").unwrap(), 1024 * 1024);
+        assert_eq!(parse_bytes("1_024k").unwrap(), 1024 * 1024);
+    }
+
+    #[test]
+    fn rejects_invalid_bytes() {
+        for s in ["", "0x", "0xzz", "12Q", "K", "-1", "4096M"] {
+            assert!(parse_bytes(s).is_err(), "{s}");
+        }
+    }
+
+    #[test]
+    fn parses_flash_sizes() {
+        assert_eq!("4MB".parse::<FlashSize>().unwrap(), FlashSize::Mb4);
+        assert_eq!("8m".parse::<FlashSize>().unwrap(), FlashSize::Mb8);
+        assert_eq!(" 16 ".parse::<FlashSize>().unwrap(), FlashSize::Mb16);
+        assert!("2MB".parse::<FlashSize>().is_err());
+    }
+
+    #[test]
+    fn parses_toml_specs() {
+        let spec = TableSpec::from_toml(
+            r#"
+            flash_size = "8MB"
+
+            [[partitions]]
+            name = "nvs"
+            kind = "nvs"
+            size = "16K"
+
+            [[partitions]]
+            name = "factory"
+            kind = "factory"
+            offset = "0x10000"
+            size = 0x100000
+
+            [[partitions]]
+            name = "fs"
+            kind = "littlefs"
+            size = "rest"
+            "#,
+        )
+        .unwrap();
+
+        let mut expected = TableSpec::new(FlashSize::Mb8)
+            .partition("nvs", Kind::Nvs, Size::Bytes(0x4000))
+            .partition("factory", Kind::Factory, Size::Bytes(0x10_0000))
+            .partition("fs", Kind::Littlefs, Size::Rest);
+        expected.partitions[1].offset = Some(0x1_0000);
+
+        assert_eq!(spec, expected);
+    }
+
+    #[test]
+    fn toml_flash_size_defaults_to_4mb() {
+        let spec = TableSpec::from_toml(
+            r#"
+            [[partitions]]
+            name = "factory"
+            kind = "factory"
+            size = "1M"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(spec.flash_size, FlashSize::Mb4);
+    }
+
+    #[test]
+    fn rejects_invalid_toml_specs() {
+        let unknown_kind = r#"
+            [[partitions]]
+            name = "app"
+            kind = "bootloader"
+            size = "1M"
+        "#;
+        let bad_size = r#"
+            [[partitions]]
+            name = "app"
+            kind = "factory"
+            size = "lots"
+        "#;
+
+        assert!(TableSpec::from_toml(unknown_kind).is_err());
+        assert!(TableSpec::from_toml(bad_size).is_err());
+    }
+
+    #[test]
+    fn coredump_goes_in_front_of_the_last_partition() {
+        let spec = TableSpec::factory(FlashSize::Mb4, Filesystem::Fat).with_coredump();
+        let names: Vec<_> = spec.partitions.iter().map(|p| p.name.as_str()).collect();
+
+        assert_eq!(names, ["nvs", "phy_init", "factory", "coredump", "fs"]);
+    }
+}
@@ -0,0 +1,61 @@
+use std::time::{Duration, Instant};
+
+use rfid_lock_mfrc522::Uid;
+
+/// Cards denied this many times within [`WINDOW`] raise an alert
+pub const THRESHOLD: u32 = 3;
+pub const WINDOW: Duration = Duration::from_secs(60);
+/// Cards followed at once, the one denied longest ago makes room for a new one
+const TRACKED_CARDS: usize = 16;
+
+struct Tracked {
+    uid: Uid,
+    first: Instant,
+    last: Instant,
+    count: u32,
+}
+
+/// Counts the denials of each card within a window
+#[derive(Default)]
+pub struct RepeatedDenials {
+    cards: Vec<Tracked>,
+}
+
+impl RepeatedDenials {
+    /// The card's denials within the window if that reaches the threshold, at the threshold
+    /// and every denial after it
+    pub fn record(&mut self, uid: &Uid, now: Instant) -> Option<u32> {
+        let index = match self.cards.iter().position(|card| card.uid == *uid) {
+            Some(index) => index,
+            None => {
+                if self.cards.len() == TRACKED_CARDS {
+                    let oldest = (0..self.cards.len()).min_by_key(|&i| self.cards[i].last)?;
+                    self.cards.swap_remove(oldest);
+                }
+
+                self.cards.push(Tracked {
+                    uid: *uid,
+                    first: now,
+                    last: now,
+                    count: 0,
+                });
+                self.cards.len() - 1
+            }
+        };
+
+        let card = &mut self.cards[index];
+        if now.duration_since(card.first) > WINDOW {
+            card.first = now;
+            card.count = 0;
+        }
+        card.last = now;
+        card.count += 1;
+
+        (card.count >= THRESHOLD).then_some(card.count)
+    }
+
+    /// A granted card starts over
+    pub fn clear(&mut self, uid: &Uid) {
+        self.cards.retain(|card| card.uid != *uid);
+    }
+}
@@ -0,0 +1,352 @@
+use std::{borrow::Cow, io::Write, path::PathBuf, time::Instant};
+
+use eyre::{eyre, Result};
+use rfid_lock_mfrc522::Uid;
+use serde::{Deserialize, Serialize};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use crate::cards::{Decision, DenyReason};
+
+pub mod denials;
+pub mod ring;
+
+pub use denials::RepeatedDenials;
+pub use ring::RingFile;
+
+/// Events kept before the oldest are overwritten
+const CAPACITY: u32 = 1000;
+/// Fits an event with the longest UID, holder and door name
+const SLOT_LEN: usize = 256;
+
+const CSV_HEADER: &str = "timestamp,uptime_secs,uid,holder,door,decision,reason\n";
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct AccessEvent {
+    /// `None` if the clock wasn't synced yet, the master's clock starts at 1970 on every boot
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    pub timestamp: Option<OffsetDateTime>,
+    /// Seconds since the master booted, places events from before the clock was synced
+    #[serde(default)]
+    pub uptime_secs: u64,
+    pub uid: Uid,
+    /// `None` if the card isn't registered
+    pub holder: Option<String>,
+    /// `None` if no doors were registered, the card is still logged and counted
+    pub door: Option<String>,
+    #[serde(flatten)]
+    pub decision: Decision,
+}
+
+/// Raised when a card is denied at every door [`denials::THRESHOLD`] times within
+/// [`denials::WINDOW`], and on every denial after that within the window
+#[derive(Debug, Clone)]
+pub struct RepeatedDenial {
+    pub uid: Uid,
+    pub holder: Option<String>,
+    pub count: u32,
+}
+
+/// Denials since boot by reason, one for every door a card is denied at
+#[derive(Debug, Clone, Default)]
+pub struct DeniedCounts {
+    counts: [u32; DenyReason::ALL.len()],
+}
+
+impl DeniedCounts {
+    pub fn get(&self, reason: DenyReason) -> u32 {
+        self.counts[reason as usize]
+    }
+
+    pub fn total(&self) -> u32 {
+        self.counts.iter().sum()
+    }
+
+    fn record(&mut self, reason: DenyReason) {
+        self.counts[reason as usize] += 1;
+    }
+}
+
+type AlertHook = Box<dyn FnMut(&RepeatedDenial) + Send + 'static>;
+
+/// Who was let through which door, kept on flash until [`CAPACITY`] newer events replace it
+pub struct AccessLog {
+    ring: RingFile,
+    denied: DeniedCounts,
+    repeated: RepeatedDenials,
+    hooks: Vec<AlertHook>,
+}
+
+impl AccessLog {
+    pub fn open(path: impl Into<PathBuf>) -> Result<Self> {
+        let ring = RingFile::open(path, CAPACITY, SLOT_LEN)
+            .map_err(|e| eyre!("Failed to open the access log: {:?}", e))?;
+
+        Ok(Self {
+            ring,
+            denied: DeniedCounts::default(),
+            repeated: RepeatedDenials::default(),
+            hooks: Vec::new(),
+        })
+    }
+
+    /// The hooks run on the task recording the event, they should only hand the alert off
+    pub fn on_repeated_denial<F: FnMut(&RepeatedDenial) + Send + 'static>(&mut self, f: F) {
+        self.hooks.push(Box::new(f));
+    }
+
+    pub fn denied(&self) -> &DeniedCounts {
+        &self.denied
+    }
+
+    /// Logs the decisions made for one presented card, one event per door or a single one
+    /// without a door if none are registered. A card denied at every door counts towards its
+    /// repeated denials, one let through anywhere starts over.
+    pub fn record(&mut self, events: &[AccessEvent], now: Instant) -> Result<()> {
+        // Counted and alerted on before it's written, so a failing flash doesn't hide it
+        for event in events {
+            if let Decision::Denied(reason) = event.decision {
+                self.denied.record(reason);
+            }
+        }
+
+        let Some(first) = events.first() else {
+            return Ok(());
+        };
+
+        if events.iter().any(|event| event.decision.is_granted()) {
+            self.repeated.clear(&first.uid);
+        } else if let Some(count) = self.repeated.record(&first.uid, now) {
+            let alert = RepeatedDenial {
+                uid: first.uid,
+                holder: first.holder.clone(),
+                count,
+            };
+            for hook in &mut self.hooks {
+                hook(&alert);
+            }
+        }
+
+        let mut buf = Vec::with_capacity(SLOT_LEN);
+        for event in events {
+            buf.clear();
+            ciborium::into_writer(event, &mut buf)
+                .map_err(|e| eyre!("Failed to encode access event: {:?}", e))?;
+            self.ring
+                .append(&buf)
+                .map_err(|e| eyre!("Failed to write access event: {:?}", e))?;
+        }
+
+        Ok(())
+    }
+
+    /// Calls `f` with every event, oldest first. Events that fail to decode are skipped.
+    pub fn for_each(&self, mut f: impl FnMut(AccessEvent) -> Result<()>) -> Result<()> {
+        self.ring.for_each(|record| {
+            let Ok(event) = ciborium::from_reader::<AccessEvent, _>(record) else {
+                return Ok(());
+            };
+
+            f(event)
+        })
+    }
+
+    pub fn write_csv(&self, mut out: impl Write) -> Result<()> {
+        out.write_all(CSV_HEADER.as_bytes())?;
+
+        self.for_each(|event| {
+            let (decision, reason) = match event.decision {
+                Decision::Granted => ("granted", ""),
+                Decision::Denied(reason) => ("denied", reason.as_str()),
+            };
+
+            let timestamp = match event.timestamp {
+                Some(timestamp) => timestamp.format(&Rfc3339)?,
+                None => String::new(),
+            };
+
+            writeln!(
+                out,
+                "{},{},{},{},{},{},{}",
+                timestamp,
+                event.uptime_secs,
+                event.uid,
+                csv_field(event.holder.as_deref().unwrap_or("")),
+                csv_field(event.door.as_deref().unwrap_or("")),
+                decision,
+                reason
+            )?;
+
+            Ok(())
+        })
+    }
+
+    /// A JSON array of the events, written as they're read so the log is never all in memory
+    pub fn write_json(&self, mut out: impl Write) -> Result<()> {
+        out.write_all(b"[")?;
+
+        let mut first = true;
+        self.for_each(|event| {
+            if !first {
+                out.write_all(b",")?;
+            }
+            first = false;
+
+            serde_json::to_writer(&mut out, &event)?;
+
+            Ok(())
+        })?;
+
+        out.write_all(b"]")?;
+
+        Ok(())
+    }
+
+    pub fn clear(&mut self) -> Result<()> {
+        self.ring
+            .clear()
+            .map_err(|e| eyre!("Failed to clear the access log: {:?}", e))
+    }
+}
+
+/// Quotes the field if it holds a separator, quote or line break
+fn csv_field(field: &str) -> Cow<'_, str> {
+    if field.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(field)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tempfile::TempDir;
+    use time::macros::datetime;
+
+    use super::*;
+
+    fn event(door: &str, decision: Decision) -> AccessEvent {
+        AccessEvent {
+            timestamp: Some(datetime!(2024-03-01 08:30 +8)),
+            uptime_secs: 120,
+            uid: "04:A2:3B:1C".parse().unwrap(),
+            holder: Some("Doe, Jane".into()),
+            door: Some(door.into()),
+            decision,
+        }
+    }
+
+    fn events(log: &AccessLog) -> Vec<AccessEvent> {
+        let mut events = Vec::new();
+        log.for_each(|event| {
+            events.push(event);
+            Ok(())
+        })
+        .unwrap();
+
+        events
+    }
+
+    #[test]
+    fn keeps_events_across_opens() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("access.log");
+        let mut log = AccessLog::open(&path).unwrap();
+        let recorded = [
+            event("Front", Decision::Granted),
+            event("Back", Decision::Denied(DenyReason::NoAccess)),
+        ];
+        log.record(&recorded, Instant::now()).unwrap();
+
+        let unsynced = AccessEvent {
+            timestamp: None,
+            ..event("Front", Decision::Denied(DenyReason::TimeNotSynced))
+        };
+        log.record(std::slice::from_ref(&unsynced), Instant::now())
+            .unwrap();
+        drop(log);
+
+        let log = AccessLog::open(&path).unwrap();
+        assert_eq!(
+            events(&log),
+            [recorded[0].clone(), recorded[1].clone(), unsynced]
+        );
+    }
+
+    #[test]
+    fn writes_csv_with_quoted_fields() {
+        let dir = TempDir::new().unwrap();
+        let mut log = AccessLog::open(dir.path().join("access.log")).unwrap();
+        log.record(&[event("Front", Decision::Granted)], Instant::now())
+            .unwrap();
+
+        let mut csv = Vec::new();
+        log.write_csv(&mut csv).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "timestamp,uptime_secs,uid,holder,door,decision,reason\n\
+             2024-03-01T08:30:00+08:00,120,04:A2:3B:1C,\"Doe, Jane\",Front,granted,\n"
+        );
+    }
+
+    #[test]
+    fn counts_denials_and_alerts_on_repeats() {
+        let dir = TempDir::new().unwrap();
+        let mut log = AccessLog::open(dir.path().join("access.log")).unwrap();
+        let alerts = Arc::new(Mutex::new(Vec::new()));
+        let sink = alerts.clone();
+        log.on_repeated_denial(move |alert| sink.lock().unwrap().push(alert.count));
+
+        let now = Instant::now();
+        let denied = [
+            event("Front", Decision::Denied(DenyReason::Expired)),
+            event("Back", Decision::Denied(DenyReason::NoAccess)),
+        ];
+        for _ in 0..4 {
+            log.record(&denied, now).unwrap();
+        }
+
+        assert_eq!(*alerts.lock().unwrap(), [3, 4]);
+        assert_eq!(log.denied().get(DenyReason::Expired), 4);
+        assert_eq!(log.denied().total(), 8);
+
+        // Let through at one door, the count starts over
+        let mixed = [
+            event("Front", Decision::Granted),
+            event("Back", Decision::Denied(DenyReason::NoAccess)),
+        ];
+        log.record(&mixed, now).unwrap();
+        log.record(&denied, now).unwrap();
+        assert_eq!(alerts.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn logs_cards_presented_without_doors() {
+        let dir = TempDir::new().unwrap();
+        let mut log = AccessLog::open(dir.path().join("access.log")).unwrap();
+        let alerts = Arc::new(Mutex::new(0));
+        let sink = alerts.clone();
+        log.on_repeated_denial(move |_| *sink.lock().unwrap() += 1);
+
+        let unknown = AccessEvent {
+            holder: None,
+            door: None,
+            ..event("", Decision::Denied(DenyReason::UnknownCard))
+        };
+        for _ in 0..3 {
+            log.record(std::slice::from_ref(&unknown), Instant::now())
+                .unwrap();
+        }
+
+        assert_eq!(*alerts.lock().unwrap(), 1);
+        assert_eq!(log.denied().get(DenyReason::UnknownCard), 3);
+        assert_eq!(events(&log), [unknown.clone(), unknown.clone(), unknown]);
+
+        let mut csv = Vec::new();
+        log.write_csv(&mut csv).unwrap();
+        assert!(String::from_utf8(csv)
+            .unwrap()
+            .ends_with("04:A2:3B:1C,,,denied,unknown_card\n"));
+    }
+}
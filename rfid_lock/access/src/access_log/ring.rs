@@ -0,0 +1,284 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, ErrorKind, Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+};
+
+use rfid_lock_protocol::crc8;
+
+/// Sequence number, record length and CRC
+pub const SLOT_OVERHEAD: usize = 4 + 2 + 1;
+
+/// Records in fixed size slots of one file, once it's full the oldest is overwritten.
+///
+/// A slot starts with the record's sequence number, the newest record is found by reading
+/// them all when the file is opened. Every slot carries a CRC, a write cut short by losing
+/// power spoils that slot and no other.
+pub struct RingFile {
+    path: PathBuf,
+    capacity: u32,
+    slot_len: usize,
+    next_slot: u32,
+    next_seq: u32,
+}
+
+impl RingFile {
+    pub fn open(path: impl Into<PathBuf>, capacity: u32, slot_len: usize) -> io::Result<Self> {
+        let mut ring = Self {
+            path: path.into(),
+            capacity,
+            slot_len,
+            next_slot: 0,
+            // 0 marks a slot that was never written
+            next_seq: 1,
+        };
+
+        let mut newest = None;
+        ring.scan::<io::Error>(0, |slot, seq, _| {
+            if !newest.is_some_and(|(_, newest_seq)| newest_seq > seq) {
+                newest = Some((slot, seq));
+            }
+            Ok(())
+        })?;
+        if let Some((slot, seq)) = newest {
+            ring.next_slot = (slot + 1) % capacity;
+            ring.next_seq = seq + 1;
+        }
+
+        Ok(ring)
+    }
+
+    pub fn max_record_len(&self) -> usize {
+        self.slot_len - SLOT_OVERHEAD
+    }
+
+    pub fn append(&mut self, record: &[u8]) -> io::Result<()> {
+        if record.len() > self.max_record_len() {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                format!("{} byte record doesn't fit a slot", record.len()),
+            ));
+        }
+
+        let mut slot = vec![0u8; self.slot_len];
+        slot[..4].copy_from_slice(&self.next_seq.to_le_bytes());
+        slot[4..6].copy_from_slice(&(record.len() as u16).to_le_bytes());
+        slot[6..6 + record.len()].copy_from_slice(record);
+        slot[self.slot_len - 1] = crc8(&slot[..self.slot_len - 1]);
+
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&self.path)?;
+        file.seek(SeekFrom::Start(self.offset(self.next_slot)))?;
+        file.write_all(&slot)?;
+        file.sync_all()?;
+
+        self.next_slot = (self.next_slot + 1) % self.capacity;
+        self.next_seq += 1;
+
+        Ok(())
+    }
+
+    /// Calls `f` with every record, oldest first
+    pub fn for_each<E: From<io::Error>>(
+        &self,
+        mut f: impl FnMut(&[u8]) -> Result<(), E>,
+    ) -> Result<(), E> {
+        // Slots are written in turn, the one written next holds the oldest record
+        self.scan(self.next_slot, |_, _, record| f(record))
+    }
+
+    pub fn clear(&mut self) -> io::Result<()> {
+        match fs::remove_file(&self.path) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+
+        self.next_slot = 0;
+        self.next_seq = 1;
+
+        Ok(())
+    }
+
+    fn offset(&self, slot: u32) -> u64 {
+        slot as u64 * self.slot_len as u64
+    }
+
+    /// Calls `f` with the index, sequence number and record of every intact slot, in slot
+    /// order from `first`
+    fn scan<E: From<io::Error>>(
+        &self,
+        first: u32,
+        mut f: impl FnMut(u32, u32, &[u8]) -> Result<(), E>,
+    ) -> Result<(), E> {
+        let mut file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut buf = vec![0u8; self.slot_len];
+        for i in 0..self.capacity {
+            let index = (first + i) % self.capacity;
+
+            file.seek(SeekFrom::Start(self.offset(index)))?;
+            match file.read_exact(&mut buf) {
+                Ok(()) => {}
+                // Slots past the end of the file were never written
+                Err(e) if e.kind() == ErrorKind::UnexpectedEof => continue,
+                Err(e) => return Err(e.into()),
+            }
+
+            if crc8(&buf[..self.slot_len - 1]) != buf[self.slot_len - 1] {
+                continue;
+            }
+
+            let seq = u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]);
+            let len = u16::from_le_bytes([buf[4], buf[5]]) as usize;
+            if seq == 0 || len > self.max_record_len() {
+                continue;
+            }
+
+            f(index, seq, &buf[6..6 + len])?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    const SLOT_LEN: usize = 16;
+
+    fn records(ring: &RingFile) -> Vec<Vec<u8>> {
+        let mut records = Vec::new();
+        ring.for_each::<io::Error>(|record| {
+            records.push(record.to_vec());
+            Ok(())
+        })
+        .unwrap();
+
+        records
+    }
+
+    fn path(dir: &TempDir) -> PathBuf {
+        dir.path().join("ring.log")
+    }
+
+    #[test]
+    fn is_empty_until_written() {
+        let dir = TempDir::new().unwrap();
+        let ring = RingFile::open(path(&dir), 4, SLOT_LEN).unwrap();
+
+        assert!(records(&ring).is_empty());
+        assert_eq!(ring.max_record_len(), SLOT_LEN - SLOT_OVERHEAD);
+    }
+
+    #[test]
+    fn keeps_records_in_order() {
+        let dir = TempDir::new().unwrap();
+        let mut ring = RingFile::open(path(&dir), 4, SLOT_LEN).unwrap();
+        ring.append(b"one").unwrap();
+        ring.append(b"").unwrap();
+        ring.append(b"three").unwrap();
+
+        assert_eq!(records(&ring), [&b"one"[..], b"", b"three"]);
+    }
+
+    #[test]
+    fn overwrites_the_oldest_once_full() {
+        let dir = TempDir::new().unwrap();
+        let mut ring = RingFile::open(path(&dir), 3, SLOT_LEN).unwrap();
+        for record in [b"a", b"b", b"c", b"d", b"e"] {
+            ring.append(record).unwrap();
+        }
+
+        assert_eq!(records(&ring), [b"c", b"d", b"e"]);
+        assert_eq!(fs::metadata(path(&dir)).unwrap().len(), 3 * SLOT_LEN as u64);
+    }
+
+    #[test]
+    fn carries_on_after_the_newest_when_reopened() {
+        let dir = TempDir::new().unwrap();
+        let mut ring = RingFile::open(path(&dir), 3, SLOT_LEN).unwrap();
+        for record in [b"a", b"b", b"c", b"d"] {
+            ring.append(record).unwrap();
+        }
+        drop(ring);
+
+        let mut ring = RingFile::open(path(&dir), 3, SLOT_LEN).unwrap();
+        assert_eq!(records(&ring), [b"b", b"c", b"d"]);
+        ring.append(b"e").unwrap();
+        assert_eq!(records(&ring), [b"c", b"d", b"e"]);
+    }
+
+    #[test]
+    fn skips_a_spoiled_slot() {
+        let dir = TempDir::new().unwrap();
+        let mut ring = RingFile::open(path(&dir), 4, SLOT_LEN).unwrap();
+        for record in [b"a", b"b", b"c"] {
+            ring.append(record).unwrap();
+        }
+
+        // A write cut short in the middle slot
+        let mut file = OpenOptions::new().write(true).open(path(&dir)).unwrap();
+        file.seek(SeekFrom::Start(SLOT_LEN as u64 + 6)).unwrap();
+        file.write_all(b"x").unwrap();
+        drop(file);
+
+        let ring = RingFile::open(path(&dir), 4, SLOT_LEN).unwrap();
+        assert_eq!(records(&ring), [b"a", b"c"]);
+    }
+
+    #[test]
+    fn ignores_a_partly_written_last_slot() {
+        let dir = TempDir::new().unwrap();
+        let mut ring = RingFile::open(path(&dir), 4, SLOT_LEN).unwrap();
+        ring.append(b"a").unwrap();
+        ring.append(b"b").unwrap();
+
+        let len = fs::metadata(path(&dir)).unwrap().len();
+        let file = OpenOptions::new().write(true).open(path(&dir)).unwrap();
+        file.set_len(len - 3).unwrap();
+        drop(file);
+
+        let mut ring = RingFile::open(path(&dir), 4, SLOT_LEN).unwrap();
+        assert_eq!(records(&ring), [b"a"]);
+        ring.append(b"c").unwrap();
+        assert_eq!(records(&ring), [b"a", b"c"]);
+    }
+
+    #[test]
+    fn refuses_records_longer_than_a_slot() {
+        let dir = TempDir::new().unwrap();
+        let mut ring = RingFile::open(path(&dir), 4, SLOT_LEN).unwrap();
+
+        let too_long = vec![0u8; SLOT_LEN - SLOT_OVERHEAD + 1];
+        assert_eq!(
+            ring.append(&too_long).unwrap_err().kind(),
+            ErrorKind::InvalidInput
+        );
+        ring.append(&too_long[1..]).unwrap();
+    }
+
+    #[test]
+    fn starts_over_when_cleared() {
+        let dir = TempDir::new().unwrap();
+        let mut ring = RingFile::open(path(&dir), 4, SLOT_LEN).unwrap();
+        ring.append(b"a").unwrap();
+        ring.clear().unwrap();
+        ring.clear().unwrap();
+
+        assert!(records(&ring).is_empty());
+        ring.append(b"b").unwrap();
+        let ring = RingFile::open(path(&dir), 4, SLOT_LEN).unwrap();
+        assert_eq!(records(&ring), [b"b"]);
+    }
+}
@@ -0,0 +1,704 @@
+use std::{fmt::Debug, io, path::PathBuf};
+
+use eyre::{bail, eyre, Result};
+use rfid_lock_mfrc522::{Uid, MAX_UID_LEN};
+use serde::{Deserialize, Serialize};
+use time::{Date, OffsetDateTime};
+
+use crate::{
+    access_log::{ring, RingFile},
+    persistent_state::Storage,
+};
+
+pub mod rules;
+
+pub use rules::{AccessRule, Group, Schedule};
+
+const MAX_HOLDER_LEN: usize = 32;
+const MAX_GROUP_NAME_LEN: usize = 32;
+/// Uses kept in the journal before they're saved with the cards
+const USE_JOURNAL_LEN: u32 = 64;
+const USE_SLOT_LEN: usize = MAX_UID_LEN + ring::SLOT_OVERHEAD;
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct CardRecord {
+    pub uid: Uid,
+    pub holder: String,
+    pub enabled: bool,
+    /// First day the card opens the door, `None` if it always has
+    #[serde(default)]
+    pub valid_from: Option<Date>,
+    /// Last day the card opens the door, `None` if it never expires
+    #[serde(default)]
+    pub valid_until: Option<Date>,
+    /// Cards saved before doors were registered open all of them
+    #[serde(default)]
+    pub doors: DoorAccess,
+    /// Switches enrollment on and off instead of opening doors
+    #[serde(default)]
+    pub admin: bool,
+    /// The card has to pass its group's rule as well as its own
+    #[serde(default)]
+    pub group: Option<String>,
+    #[serde(default)]
+    pub rule: AccessRule,
+    /// Times the card was let through, only counted while a rule limits it
+    #[serde(default)]
+    pub uses: u32,
+}
+
+/// The doors a card opens
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DoorAccess {
+    #[default]
+    All,
+    /// Door names
+    Only(Vec<String>),
+}
+
+impl DoorAccess {
+    pub fn allows(&self, door: &str) -> bool {
+        match self {
+            DoorAccess::All => true,
+            DoorAccess::Only(doors) => doors.iter().any(|name| name == door),
+        }
+    }
+}
+
+impl CardRecord {
+    pub fn new(uid: Uid, holder: impl Into<String>) -> Self {
+        Self {
+            uid,
+            holder: holder.into(),
+            enabled: true,
+            valid_from: None,
+            valid_until: None,
+            doors: DoorAccess::All,
+            admin: false,
+            group: None,
+            rule: AccessRule::default(),
+            uses: 0,
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.holder.trim().is_empty() {
+            bail!("Holder name is required");
+        }
+
+        if self.holder.len() > MAX_HOLDER_LEN {
+            bail!("Holder name must be at most {} bytes", MAX_HOLDER_LEN);
+        }
+
+        if let (Some(from), Some(until)) = (self.valid_from, self.valid_until) {
+            if from > until {
+                bail!("Card validity must start before it ends");
+            }
+        }
+
+        self.rule.validate()
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct CardDatabase {
+    #[serde(default)]
+    pub cards: Vec<CardRecord>,
+    #[serde(default)]
+    pub groups: Vec<Group>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DenyReason {
+    UnknownCard,
+    Disabled,
+    /// The card doesn't open this door
+    NoAccess,
+    NotYetValid,
+    Expired,
+    /// Outside every schedule of the card's rules
+    OutsideSchedule,
+    /// The card was let through as many times as its rules allow
+    UsesExhausted,
+    /// The card's rules need the time and the clock isn't synced yet
+    TimeNotSynced,
+}
+
+impl DenyReason {
+    pub const ALL: [DenyReason; 8] = [
+        DenyReason::UnknownCard,
+        DenyReason::Disabled,
+        DenyReason::NoAccess,
+        DenyReason::NotYetValid,
+        DenyReason::Expired,
+        DenyReason::OutsideSchedule,
+        DenyReason::UsesExhausted,
+        DenyReason::TimeNotSynced,
+    ];
+
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            DenyReason::UnknownCard => "unknown_card",
+            DenyReason::Disabled => "disabled",
+            DenyReason::NoAccess => "no_access",
+            DenyReason::NotYetValid => "not_yet_valid",
+            DenyReason::Expired => "expired",
+            DenyReason::OutsideSchedule => "outside_schedule",
+            DenyReason::UsesExhausted => "uses_exhausted",
+            DenyReason::TimeNotSynced => "time_not_synced",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(tag = "decision", content = "reason", rename_all = "snake_case")]
+pub enum Decision {
+    Granted,
+    Denied(DenyReason),
+}
+
+impl Decision {
+    pub fn is_granted(&self) -> bool {
+        matches!(self, Decision::Granted)
+    }
+}
+
+/// The cards allowed through the doors. Every change is saved before it's applied, a change
+/// that fails to save leaves the store as it was.
+pub struct CardStore<S: Storage<CardDatabase>> {
+    db: CardDatabase,
+    storage: S,
+    uses: Option<UseJournal>,
+}
+
+/// Uses appended to a ring file one slot at a time, rather than saving every card on each
+struct UseJournal {
+    ring: RingFile,
+    /// Uses in the journal that aren't saved with the cards yet
+    pending: u32,
+}
+
+impl<S: Storage<CardDatabase>> CardStore<S> {
+    /// Starts empty if nothing was saved yet. Fails if the cards were saved and can't be read,
+    /// rather than starting empty and replacing them with the next change.
+    pub fn load(storage: S) -> Result<Self> {
+        let db = storage
+            .load()
+            .map_err(|e| eyre!("Failed to load cards: {:?}", e))?
+            .unwrap_or_default();
+
+        Ok(Self {
+            db,
+            storage,
+            uses: None,
+        })
+    }
+
+    /// Counts uses in a journal at `path` instead of saving all the cards for each one. The
+    /// journal is emptied whenever the cards are saved, if power is lost in between its uses
+    /// are counted twice, never lost.
+    pub fn with_use_journal(mut self, path: impl Into<PathBuf>) -> Result<Self> {
+        let ring = RingFile::open(path, USE_JOURNAL_LEN, USE_SLOT_LEN)
+            .map_err(|e| eyre!("Failed to open the use journal: {:?}", e))?;
+
+        let mut pending = 0;
+        ring.for_each::<io::Error>(|record| {
+            pending += 1;
+            let uid = Uid::new(record);
+            if let Some(card) = self.db.cards.iter_mut().find(|card| Some(card.uid) == uid) {
+                card.uses += 1;
+            }
+            Ok(())
+        })
+        .map_err(|e| eyre!("Failed to read the use journal: {:?}", e))?;
+
+        self.uses = Some(UseJournal { ring, pending });
+
+        Ok(self)
+    }
+
+    pub fn cards(&self) -> &[CardRecord] {
+        &self.db.cards
+    }
+
+    pub fn get(&self, uid: &Uid) -> Option<&CardRecord> {
+        self.db.cards.iter().find(|card| card.uid == *uid)
+    }
+
+    pub fn add(&mut self, card: CardRecord) -> Result<()> {
+        self.validate(&card)?;
+        if self.get(&card.uid).is_some() {
+            bail!("Card {} is already registered", card.uid);
+        }
+
+        self.modify(|db| db.cards.push(card))
+    }
+
+    /// Replaces the record with the same UID
+    pub fn update(&mut self, card: CardRecord) -> Result<()> {
+        self.validate(&card)?;
+        let Some(index) = self.position(&card.uid) else {
+            bail!("Card {} is not registered", card.uid);
+        };
+
+        self.modify(|db| db.cards[index] = card)
+    }
+
+    /// Registers the card as an admin card, or makes the registered one an admin. Nothing is
+    /// saved if it already is one.
+    pub fn ensure_admin(&mut self, uid: Uid) -> Result<()> {
+        let mut card = match self.get(&uid) {
+            Some(card) if card.admin => return Ok(()),
+            Some(card) => card.clone(),
+            None => CardRecord::new(uid, "Admin"),
+        };
+        card.admin = true;
+
+        match self.position(&uid) {
+            Some(index) => self.modify(|db| db.cards[index] = card),
+            None => self.modify(|db| db.cards.push(card)),
+        }
+    }
+
+    pub fn remove(&mut self, uid: &Uid) -> Result<CardRecord> {
+        let Some(index) = self.position(uid) else {
+            bail!("Card {} is not registered", uid);
+        };
+
+        let card = self.db.cards[index].clone();
+        self.modify(|db| {
+            db.cards.remove(index);
+        })?;
+
+        Ok(card)
+    }
+
+    pub fn groups(&self) -> &[Group] {
+        &self.db.groups
+    }
+
+    pub fn group(&self, name: &str) -> Option<&Group> {
+        self.db.groups.iter().find(|group| group.name == name)
+    }
+
+    /// Adds the group, or replaces the one with the same name
+    pub fn put_group(&mut self, group: Group) -> Result<()> {
+        if group.name.trim().is_empty() {
+            bail!("Group name is required");
+        }
+        if group.name.len() > MAX_GROUP_NAME_LEN {
+            bail!("Group name must be at most {} bytes", MAX_GROUP_NAME_LEN);
+        }
+        group.rule.validate()?;
+
+        let index = self.db.groups.iter().position(|g| g.name == group.name);
+        self.modify(|db| match index {
+            Some(index) => db.groups[index] = group,
+            None => db.groups.push(group),
+        })
+    }
+
+    pub fn remove_group(&mut self, name: &str) -> Result<Group> {
+        let Some(index) = self.db.groups.iter().position(|group| group.name == name) else {
+            bail!("Group {} doesn't exist", name);
+        };
+        let member = self
+            .db
+            .cards
+            .iter()
+            .find(|card| card.group.as_deref() == Some(name));
+        if let Some(card) = member {
+            bail!("Group {} still has card {}", name, card.uid);
+        }
+
+        let group = self.db.groups[index].clone();
+        self.modify(|db| {
+            db.groups.remove(index);
+        })?;
+
+        Ok(group)
+    }
+
+    /// Whether the card opens `door` at `now`, local time or `None` if the clock isn't synced.
+    /// A card that has any dates or times to check is denied until the clock is synced.
+    pub fn decide(&self, uid: &Uid, door: &str, now: Option<OffsetDateTime>) -> Decision {
+        let Some(card) = self.get(uid) else {
+            return Decision::Denied(DenyReason::UnknownCard);
+        };
+
+        if !card.enabled {
+            return Decision::Denied(DenyReason::Disabled);
+        }
+
+        if !card.doors.allows(door) {
+            return Decision::Denied(DenyReason::NoAccess);
+        }
+
+        if card.valid_from.is_some() || card.valid_until.is_some() {
+            let Some(today) = now.map(|now| now.date()) else {
+                return Decision::Denied(DenyReason::TimeNotSynced);
+            };
+
+            if card.valid_from.is_some_and(|from| today < from) {
+                return Decision::Denied(DenyReason::NotYetValid);
+            }
+
+            if card.valid_until.is_some_and(|until| today > until) {
+                return Decision::Denied(DenyReason::Expired);
+            }
+        }
+
+        for rule in self.rules(card) {
+            if let Err(reason) = rule.check(now, card.uses) {
+                return Decision::Denied(reason);
+            }
+        }
+
+        Decision::Granted
+    }
+
+    /// The decision logged for a card presented while no doors are registered, it can't be
+    /// let through anywhere
+    pub fn decide_without_doors(&self, uid: &Uid) -> Decision {
+        match self.get(uid) {
+            None => Decision::Denied(DenyReason::UnknownCard),
+            Some(card) if !card.enabled => Decision::Denied(DenyReason::Disabled),
+            Some(_) => Decision::Denied(DenyReason::NoAccess),
+        }
+    }
+
+    /// Counts a presentation that let the card through, if its rules limit the uses
+    pub fn record_use(&mut self, uid: &Uid) -> Result<()> {
+        let Some(index) = self.position(uid) else {
+            bail!("Card {} is not registered", uid);
+        };
+        let card = &self.db.cards[index];
+        if self.rules(card).all(|rule| rule.max_uses.is_none()) {
+            return Ok(());
+        }
+
+        let Some(pending) = self.uses.as_ref().map(|journal| journal.pending) else {
+            return self.modify(|db| db.cards[index].uses += 1);
+        };
+        // Saved with the cards once full, before the next use overwrites the oldest
+        if pending >= USE_JOURNAL_LEN {
+            self.modify(|_| {})?;
+        }
+
+        if let Some(journal) = &mut self.uses {
+            journal
+                .ring
+                .append(uid.as_bytes())
+                .map_err(|e| eyre!("Failed to record the use: {:?}", e))?;
+            journal.pending += 1;
+        }
+        self.db.cards[index].uses += 1;
+
+        Ok(())
+    }
+
+    /// The card's group's rule and its own
+    fn rules<'a>(&'a self, card: &'a CardRecord) -> impl Iterator<Item = &'a AccessRule> {
+        let group = card.group.as_deref().and_then(|name| self.group(name));
+
+        group
+            .map(|group| &group.rule)
+            .into_iter()
+            .chain([&card.rule])
+    }
+
+    fn validate(&self, card: &CardRecord) -> Result<()> {
+        card.validate()?;
+        if let Some(group) = &card.group {
+            if self.group(group).is_none() {
+                bail!("Group {} doesn't exist", group);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn position(&self, uid: &Uid) -> Option<usize> {
+        self.db.cards.iter().position(|card| card.uid == *uid)
+    }
+
+    fn modify(&mut self, f: impl FnOnce(&mut CardDatabase)) -> Result<()> {
+        let mut db = self.db.clone();
+        f(&mut db);
+
+        self.storage
+            .save(&db)
+            .map_err(|e| eyre!("Failed to save cards: {:?}", e))?;
+        self.db = db;
+
+        // The saved cards hold the journal's uses now. A journal that fails to clear only
+        // counts its uses twice.
+        if let Some(journal) = &mut self.uses {
+            if journal.pending > 0 {
+                match journal.ring.clear() {
+                    Ok(()) => journal.pending = 0,
+                    Err(e) => tracing::warn!("Failed to clear the use journal: {:?}", e),
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use tempfile::TempDir;
+    use time::macros::{date, datetime};
+
+    use super::*;
+    use crate::persistent_state::BinaryFileStorage;
+
+    fn uid(s: &str) -> Uid {
+        s.parse().unwrap()
+    }
+
+    fn storage(dir: &TempDir) -> BinaryFileStorage<CardDatabase> {
+        BinaryFileStorage::new(dir.path().join("cards.bin"))
+    }
+
+    #[test]
+    fn starts_empty_when_nothing_was_saved() {
+        let dir = TempDir::new().unwrap();
+
+        assert!(CardStore::load(storage(&dir)).unwrap().cards().is_empty());
+    }
+
+    #[test]
+    fn keeps_the_cards_across_loads() {
+        let dir = TempDir::new().unwrap();
+        let mut store = CardStore::load(storage(&dir)).unwrap();
+        store
+            .add(CardRecord::new(uid("04:A2:3B:1C"), "Alice"))
+            .unwrap();
+        store
+            .add(CardRecord::new(uid("04:A2:3B:1C:5E:80:01"), "Bob"))
+            .unwrap();
+        store.remove(&uid("04:A2:3B:1C")).unwrap();
+
+        let store = CardStore::load(storage(&dir)).unwrap();
+        assert_eq!(store.cards().len(), 1);
+        assert_eq!(store.cards()[0].holder, "Bob");
+    }
+
+    #[test]
+    fn refuses_to_start_empty_over_a_corrupt_file() {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("cards.bin"), [0xA1, 0x00]).unwrap();
+
+        assert!(CardStore::load(storage(&dir)).is_err());
+        // Still there for someone to recover
+        assert_eq!(
+            fs::read(dir.path().join("cards.bin")).unwrap(),
+            [0xA1, 0x00]
+        );
+    }
+
+    #[test]
+    fn leaves_the_store_as_it_was_when_a_save_fails() {
+        let dir = TempDir::new().unwrap();
+        let mut store = CardStore::load(BinaryFileStorage::new(
+            dir.path().join("missing").join("cards.bin"),
+        ))
+        .unwrap();
+
+        assert!(store
+            .add(CardRecord::new(uid("04:A2:3B:1C"), "Alice"))
+            .is_err());
+        assert!(store.cards().is_empty());
+    }
+
+    #[test]
+    fn makes_a_card_admin_once() {
+        let dir = TempDir::new().unwrap();
+        let mut store = CardStore::load(storage(&dir)).unwrap();
+        store
+            .add(CardRecord::new(uid("04:A2:3B:1C"), "Alice"))
+            .unwrap();
+
+        store.ensure_admin(uid("04:A2:3B:1C")).unwrap();
+        store.ensure_admin(uid("01:02:03:04")).unwrap();
+        assert!(store.cards().iter().all(|card| card.admin));
+        assert_eq!(store.get(&uid("04:A2:3B:1C")).unwrap().holder, "Alice");
+
+        // Already an admin, not saved again
+        fs::remove_file(dir.path().join("cards.bin")).unwrap();
+        store.ensure_admin(uid("01:02:03:04")).unwrap();
+        assert!(!dir.path().join("cards.bin").exists());
+    }
+
+    #[test]
+    fn rejects_duplicates_and_invalid_records() {
+        let dir = TempDir::new().unwrap();
+        let mut store = CardStore::load(storage(&dir)).unwrap();
+        store
+            .add(CardRecord::new(uid("04:A2:3B:1C"), "Alice"))
+            .unwrap();
+
+        assert!(store
+            .add(CardRecord::new(uid("04:A2:3B:1C"), "Again"))
+            .is_err());
+        assert!(store.add(CardRecord::new(uid("01:02:03:04"), " ")).is_err());
+        let mut grouped = CardRecord::new(uid("01:02:03:04"), "Carol");
+        grouped.group = Some("night".into());
+        assert!(store.add(grouped).is_err());
+    }
+
+    fn limited(uid: Uid, max_uses: u32) -> CardRecord {
+        let mut card = CardRecord::new(uid, "Visitor");
+        card.rule.max_uses = Some(max_uses);
+        card
+    }
+
+    #[test]
+    fn checks_the_group_rule_and_the_card_rule() {
+        let dir = TempDir::new().unwrap();
+        let mut store = CardStore::load(storage(&dir)).unwrap();
+        store
+            .put_group(Group {
+                name: "contractors".into(),
+                rule: AccessRule {
+                    expires: Some(date!(2024 - 06 - 30)),
+                    ..AccessRule::default()
+                },
+            })
+            .unwrap();
+        let mut card = limited(uid("04:A2:3B:1C"), 1);
+        card.group = Some("contractors".into());
+        store.add(card).unwrap();
+
+        let june = Some(datetime!(2024-06-01 09:00 +8));
+        let july = Some(datetime!(2024-07-01 09:00 +8));
+        let card = uid("04:A2:3B:1C");
+        assert_eq!(store.decide(&card, "Door", june), Decision::Granted);
+        assert_eq!(
+            store.decide(&card, "Door", july),
+            Decision::Denied(DenyReason::Expired)
+        );
+        assert_eq!(
+            store.decide(&card, "Door", None),
+            Decision::Denied(DenyReason::TimeNotSynced)
+        );
+
+        store.record_use(&card).unwrap();
+        assert_eq!(
+            store.decide(&card, "Door", june),
+            Decision::Denied(DenyReason::UsesExhausted)
+        );
+        assert!(store.remove_group("contractors").is_err());
+    }
+
+    #[test]
+    fn denies_every_card_without_doors() {
+        let dir = TempDir::new().unwrap();
+        let mut store = CardStore::load(storage(&dir)).unwrap();
+        let mut disabled = CardRecord::new(uid("01:02:03:04"), "Bob");
+        disabled.enabled = false;
+        store.add(disabled).unwrap();
+        store
+            .add(CardRecord::new(uid("04:A2:3B:1C"), "Alice"))
+            .unwrap();
+
+        assert_eq!(
+            store.decide_without_doors(&uid("04:A2:3B:1C")),
+            Decision::Denied(DenyReason::NoAccess)
+        );
+        assert_eq!(
+            store.decide_without_doors(&uid("01:02:03:04")),
+            Decision::Denied(DenyReason::Disabled)
+        );
+        assert_eq!(
+            store.decide_without_doors(&uid("0A:0B:0C:0D")),
+            Decision::Denied(DenyReason::UnknownCard)
+        );
+    }
+
+    #[test]
+    fn only_counts_uses_a_rule_limits() {
+        let dir = TempDir::new().unwrap();
+        let mut store = CardStore::load(storage(&dir)).unwrap();
+        store
+            .add(CardRecord::new(uid("04:A2:3B:1C"), "Alice"))
+            .unwrap();
+        let saved = fs::read(dir.path().join("cards.bin")).unwrap();
+
+        store.record_use(&uid("04:A2:3B:1C")).unwrap();
+        assert_eq!(store.get(&uid("04:A2:3B:1C")).unwrap().uses, 0);
+        assert_eq!(fs::read(dir.path().join("cards.bin")).unwrap(), saved);
+    }
+
+    #[test]
+    fn journals_uses_without_saving_the_cards() {
+        let dir = TempDir::new().unwrap();
+        let journal = dir.path().join("uses.log");
+        let mut store = CardStore::load(storage(&dir))
+            .unwrap()
+            .with_use_journal(&journal)
+            .unwrap();
+        store.add(limited(uid("04:A2:3B:1C"), 100)).unwrap();
+        let saved = fs::read(dir.path().join("cards.bin")).unwrap();
+
+        for _ in 0..3 {
+            store.record_use(&uid("04:A2:3B:1C")).unwrap();
+        }
+        assert_eq!(store.get(&uid("04:A2:3B:1C")).unwrap().uses, 3);
+        assert_eq!(fs::read(dir.path().join("cards.bin")).unwrap(), saved);
+
+        // The uses come back from the journal
+        drop(store);
+        let mut store = CardStore::load(storage(&dir))
+            .unwrap()
+            .with_use_journal(&journal)
+            .unwrap();
+        assert_eq!(store.get(&uid("04:A2:3B:1C")).unwrap().uses, 3);
+
+        // Saving the cards for any other change empties the journal
+        store
+            .add(CardRecord::new(uid("01:02:03:04"), "Bob"))
+            .unwrap();
+        assert!(!journal.exists());
+        let store = CardStore::load(storage(&dir))
+            .unwrap()
+            .with_use_journal(&journal)
+            .unwrap();
+        assert_eq!(store.get(&uid("04:A2:3B:1C")).unwrap().uses, 3);
+    }
+
+    #[test]
+    fn saves_the_uses_once_the_journal_is_full() {
+        let dir = TempDir::new().unwrap();
+        let journal = dir.path().join("uses.log");
+        let mut store = CardStore::load(storage(&dir))
+            .unwrap()
+            .with_use_journal(&journal)
+            .unwrap();
+        store.add(limited(uid("04:A2:3B:1C"), 1000)).unwrap();
+
+        for _ in 0..USE_JOURNAL_LEN + 1 {
+            store.record_use(&uid("04:A2:3B:1C")).unwrap();
+        }
+
+        let saved = CardStore::load(storage(&dir)).unwrap();
+        assert_eq!(
+            saved.get(&uid("04:A2:3B:1C")).unwrap().uses,
+            USE_JOURNAL_LEN
+        );
+        let journaled = CardStore::load(storage(&dir))
+            .unwrap()
+            .with_use_journal(&journal)
+            .unwrap();
+        assert_eq!(
+            journaled.get(&uid("04:A2:3B:1C")).unwrap().uses,
+            USE_JOURNAL_LEN + 1
+        );
+    }
+}
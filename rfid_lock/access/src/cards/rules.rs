@@ -0,0 +1,227 @@
+use eyre::{bail, Result};
+use serde::{Deserialize, Serialize};
+use time::{Date, OffsetDateTime, Time, Weekday};
+
+use super::DenyReason;
+
+/// A time of day cards are let through. `end` is exclusive, a schedule that ends before it
+/// starts runs past midnight and belongs to the day it starts on.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Schedule {
+    /// Every day if empty
+    #[serde(default)]
+    pub weekdays: Vec<Weekday>,
+    pub start: Time,
+    pub end: Time,
+}
+
+impl Schedule {
+    /// `now` is local time
+    pub fn contains(&self, now: OffsetDateTime) -> bool {
+        let on = |date: Date| self.weekdays.is_empty() || self.weekdays.contains(&date.weekday());
+        let (date, time) = (now.date(), now.time());
+
+        if self.start < self.end {
+            on(date) && time >= self.start && time < self.end
+        } else {
+            (time >= self.start && on(date))
+                || (time < self.end && date.previous_day().is_some_and(on))
+        }
+    }
+}
+
+/// Limits on when and how often a card opens doors, set on the card or on its group
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct AccessRule {
+    /// Any time if empty, otherwise within one of them
+    #[serde(default)]
+    pub schedules: Vec<Schedule>,
+    /// Last day cards are let through
+    #[serde(default)]
+    pub expires: Option<Date>,
+    /// Times a card is let through, a card opening several doors at once uses it once
+    #[serde(default)]
+    pub max_uses: Option<u32>,
+}
+
+impl AccessRule {
+    pub fn validate(&self) -> Result<()> {
+        if self.schedules.iter().any(|s| s.start == s.end) {
+            bail!("A schedule must end at a different time than it starts");
+        }
+
+        Ok(())
+    }
+
+    /// Whether checking the rule needs the time
+    pub fn is_timed(&self) -> bool {
+        !self.schedules.is_empty() || self.expires.is_some()
+    }
+
+    /// `now` is local time, `None` until the clock is synced. A rule that needs the time
+    /// denies every card until then, one that doesn't is checked as usual.
+    pub fn check(
+        &self,
+        now: Option<OffsetDateTime>,
+        uses: u32,
+    ) -> std::result::Result<(), DenyReason> {
+        if self.max_uses.is_some_and(|max| uses >= max) {
+            return Err(DenyReason::UsesExhausted);
+        }
+
+        if !self.is_timed() {
+            return Ok(());
+        }
+        let Some(now) = now else {
+            return Err(DenyReason::TimeNotSynced);
+        };
+
+        if self.expires.is_some_and(|expires| now.date() > expires) {
+            return Err(DenyReason::Expired);
+        }
+
+        if !self.schedules.is_empty() && !self.schedules.iter().any(|s| s.contains(now)) {
+            return Err(DenyReason::OutsideSchedule);
+        }
+
+        Ok(())
+    }
+}
+
+/// A rule shared by the cards in the group
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Group {
+    pub name: String,
+    pub rule: AccessRule,
+}
+
+#[cfg(test)]
+mod tests {
+    use time::macros::{date, datetime, time};
+
+    use super::*;
+
+    fn night_shift() -> Schedule {
+        Schedule {
+            weekdays: vec![Weekday::Friday],
+            start: time!(22:00),
+            end: time!(06:00),
+        }
+    }
+
+    #[test]
+    fn a_day_schedule_covers_its_hours() {
+        let office = Schedule {
+            weekdays: vec![Weekday::Monday, Weekday::Tuesday],
+            start: time!(08:00),
+            end: time!(17:00),
+        };
+
+        // 2024-03-04 is a Monday
+        assert!(office.contains(datetime!(2024-03-04 08:00 +8)));
+        assert!(office.contains(datetime!(2024-03-05 16:59:59 +8)));
+        assert!(!office.contains(datetime!(2024-03-04 17:00 +8)));
+        assert!(!office.contains(datetime!(2024-03-04 07:59 +8)));
+        assert!(!office.contains(datetime!(2024-03-06 12:00 +8)));
+    }
+
+    #[test]
+    fn a_night_schedule_belongs_to_the_day_it_starts() {
+        let night = night_shift();
+
+        // Friday night and the early hours of Saturday
+        assert!(night.contains(datetime!(2024-03-08 22:00 +8)));
+        assert!(night.contains(datetime!(2024-03-08 23:59 +8)));
+        assert!(night.contains(datetime!(2024-03-09 00:00 +8)));
+        assert!(night.contains(datetime!(2024-03-09 05:59 +8)));
+        assert!(!night.contains(datetime!(2024-03-09 06:00 +8)));
+
+        // Not the early hours of Friday, nor Saturday night
+        assert!(!night.contains(datetime!(2024-03-08 03:00 +8)));
+        assert!(!night.contains(datetime!(2024-03-09 22:30 +8)));
+        assert!(!night.contains(datetime!(2024-03-08 12:00 +8)));
+    }
+
+    #[test]
+    fn a_schedule_without_weekdays_runs_daily() {
+        let nightly = Schedule {
+            weekdays: Vec::new(),
+            ..night_shift()
+        };
+
+        assert!(nightly.contains(datetime!(2024-03-05 23:00 +8)));
+        assert!(nightly.contains(datetime!(2024-03-06 01:00 +8)));
+        assert!(!nightly.contains(datetime!(2024-03-06 12:00 +8)));
+    }
+
+    #[test]
+    fn an_empty_rule_lets_cards_through_unsynced() {
+        let rule = AccessRule::default();
+
+        assert!(!rule.is_timed());
+        assert_eq!(rule.check(None, 1000), Ok(()));
+    }
+
+    #[test]
+    fn a_timed_rule_denies_until_the_clock_is_synced() {
+        let rule = AccessRule {
+            expires: Some(date!(2024 - 12 - 31)),
+            ..AccessRule::default()
+        };
+
+        assert_eq!(rule.check(None, 0), Err(DenyReason::TimeNotSynced));
+        assert_eq!(rule.check(Some(datetime!(2024-12-31 23:59 +8)), 0), Ok(()));
+        assert_eq!(
+            rule.check(Some(datetime!(2025-01-01 00:00 +8)), 0),
+            Err(DenyReason::Expired)
+        );
+    }
+
+    #[test]
+    fn a_use_limit_is_checked_without_the_time() {
+        let rule = AccessRule {
+            max_uses: Some(2),
+            ..AccessRule::default()
+        };
+
+        assert_eq!(rule.check(None, 1), Ok(()));
+        assert_eq!(rule.check(None, 2), Err(DenyReason::UsesExhausted));
+    }
+
+    #[test]
+    fn a_scheduled_rule_denies_outside_every_schedule() {
+        let rule = AccessRule {
+            schedules: vec![
+                night_shift(),
+                Schedule {
+                    weekdays: Vec::new(),
+                    start: time!(12:00),
+                    end: time!(13:00),
+                },
+            ],
+            ..AccessRule::default()
+        };
+
+        assert_eq!(rule.check(Some(datetime!(2024-03-09 02:00 +8)), 0), Ok(()));
+        assert_eq!(rule.check(Some(datetime!(2024-03-06 12:30 +8)), 0), Ok(()));
+        assert_eq!(
+            rule.check(Some(datetime!(2024-03-06 14:00 +8)), 0),
+            Err(DenyReason::OutsideSchedule)
+        );
+    }
+
+    #[test]
+    fn a_schedule_has_to_end_when_it_doesnt_start() {
+        let rule = AccessRule {
+            schedules: vec![Schedule {
+                weekdays: Vec::new(),
+                start: time!(08:00),
+                end: time!(08:00),
+            }],
+            ..AccessRule::default()
+        };
+
+        assert!(rule.validate().is_err());
+        assert!(AccessRule::default().validate().is_ok());
+    }
+}
@@ -0,0 +1,685 @@
+use std::io::Write;
+
+use eyre::{bail, eyre, Result};
+use rfid_lock_mfrc522::Uid;
+use time::{macros::format_description, Date, Time, Weekday};
+
+use crate::{
+    access_log::AccessLog,
+    cards::{
+        AccessRule, CardDatabase, CardRecord, CardStore, DenyReason, DoorAccess, Group, Schedule,
+    },
+    doors::{Door, DoorList, DoorRegistry},
+    persistent_state::Storage,
+};
+
+pub const HELP: &str = "\
+door list
+door add <name> <address>       address of the slave, e.g. 0x05
+door remove <name>
+card list
+card add <uid> <holder>
+card remove <uid>
+card admin <uid> on|off         admin cards switch enrollment instead of opening doors
+card doors <uid> all|<door>[,<door>...]
+card group <uid> <group>|none
+card rule <uid> [<rule>...]     replaces the card's rule and resets its uses
+group list
+group put <name> [<rule>...]    adds the group or replaces its rule
+group remove <name>
+log csv|json                    the access log, oldest first
+log denied                      denials since boot by reason
+log clear
+help
+
+A rule is any of, no rule lets the card through any time:
+  <days>@HH:MM-HH:MM            days are daily or e.g. mon,tue, repeat for more schedules
+  expires=YYYY-MM-DD            last day the card is let through
+  uses=<n>                      times the card is let through";
+
+/// A line typed on the master's serial console. Names are single words, the line is split on
+/// whitespace.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Help,
+    Doors,
+    AddDoor { name: String, address: u8 },
+    RemoveDoor { name: String },
+    Cards,
+    AddCard { uid: Uid, holder: String },
+    RemoveCard { uid: Uid },
+    CardAdmin { uid: Uid, admin: bool },
+    CardDoors { uid: Uid, doors: DoorAccess },
+    CardGroup { uid: Uid, group: Option<String> },
+    CardRule { uid: Uid, rule: AccessRule },
+    Groups,
+    PutGroup(Group),
+    RemoveGroup { name: String },
+    LogCsv,
+    LogJson,
+    Denied,
+    ClearLog,
+}
+
+impl Command {
+    /// `None` for a blank line
+    pub fn parse(line: &str) -> Result<Option<Self>> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+
+        let command = match words[..] {
+            [] => return Ok(None),
+            ["help"] => Command::Help,
+            ["door", "list"] => Command::Doors,
+            ["door", "add", name, address] => Command::AddDoor {
+                name: name.into(),
+                address: parse_address(address)?,
+            },
+            ["door", "remove", name] => Command::RemoveDoor { name: name.into() },
+            ["card", "list"] => Command::Cards,
+            ["card", "add", uid, ref holder @ ..] if !holder.is_empty() => Command::AddCard {
+                uid: parse_uid(uid)?,
+                holder: holder.join(" "),
+            },
+            ["card", "remove", uid] => Command::RemoveCard {
+                uid: parse_uid(uid)?,
+            },
+            ["card", "admin", uid, admin @ ("on" | "off")] => Command::CardAdmin {
+                uid: parse_uid(uid)?,
+                admin: admin == "on",
+            },
+            ["card", "doors", uid, doors] => Command::CardDoors {
+                uid: parse_uid(uid)?,
+                doors: match doors {
+                    "all" => DoorAccess::All,
+                    doors => DoorAccess::Only(doors.split(',').map(Into::into).collect()),
+                },
+            },
+            ["card", "group", uid, group] => Command::CardGroup {
+                uid: parse_uid(uid)?,
+                group: (group != "none").then(|| group.into()),
+            },
+            ["card", "rule", uid, ref rule @ ..] => Command::CardRule {
+                uid: parse_uid(uid)?,
+                rule: parse_rule(rule)?,
+            },
+            ["group", "list"] => Command::Groups,
+            ["group", "put", name, ref rule @ ..] => Command::PutGroup(Group {
+                name: name.into(),
+                rule: parse_rule(rule)?,
+            }),
+            ["group", "remove", name] => Command::RemoveGroup { name: name.into() },
+            ["log", "csv"] => Command::LogCsv,
+            ["log", "json"] => Command::LogJson,
+            ["log", "denied"] => Command::Denied,
+            ["log", "clear"] => Command::ClearLog,
+            _ => bail!("Unknown command, try help"),
+        };
+
+        Ok(Some(command))
+    }
+
+    /// Whether running the command can change the registered doors
+    pub fn changes_doors(&self) -> bool {
+        matches!(self, Command::AddDoor { .. } | Command::RemoveDoor { .. })
+    }
+
+    /// Applies the command, what it prints goes to `out`
+    pub fn run<C, D>(self, ctx: Context<'_, C, D>, out: &mut impl Write) -> Result<()>
+    where
+        C: Storage<CardDatabase>,
+        D: Storage<DoorList>,
+    {
+        match self {
+            Command::Help => writeln!(out, "{}", HELP)?,
+            Command::Doors => {
+                for door in ctx.doors.doors() {
+                    writeln!(out, "{} {:#04x}", door.name, door.address)?;
+                }
+            }
+            Command::AddDoor { name, address } => {
+                ctx.doors.add(Door::new(name, address))?;
+            }
+            Command::RemoveDoor { name } => {
+                if let Some(card) = ctx.cards.cards().iter().find(|card| match &card.doors {
+                    DoorAccess::All => false,
+                    DoorAccess::Only(doors) => doors.contains(&name),
+                }) {
+                    bail!("Card {} still opens {}", card.uid, name);
+                }
+
+                ctx.doors.remove(&name)?;
+            }
+            Command::Cards => {
+                for card in ctx.cards.cards() {
+                    let doors = match &card.doors {
+                        DoorAccess::All => "all".to_string(),
+                        DoorAccess::Only(doors) => doors.join(","),
+                    };
+                    write!(out, "{} {} doors: {}", card.uid, card.holder, doors)?;
+                    if let Some(group) = &card.group {
+                        write!(out, " group: {}", group)?;
+                    }
+                    if card.rule != AccessRule::default() {
+                        write!(
+                            out,
+                            " rule: {} used: {}",
+                            format_rule(&card.rule),
+                            card.uses
+                        )?;
+                    }
+                    if card.admin {
+                        write!(out, " admin")?;
+                    }
+                    writeln!(out)?;
+                }
+            }
+            Command::AddCard { uid, holder } => ctx.cards.add(CardRecord::new(uid, holder))?,
+            Command::RemoveCard { uid } => {
+                ctx.cards.remove(&uid)?;
+            }
+            Command::CardAdmin { uid, admin } => {
+                let mut card = ctx
+                    .cards
+                    .get(&uid)
+                    .ok_or_else(|| eyre!("Card {} is not registered", uid))?
+                    .clone();
+                card.admin = admin;
+                ctx.cards.update(card)?;
+            }
+            Command::CardDoors { uid, doors } => {
+                if let DoorAccess::Only(names) = &doors {
+                    if let Some(name) = names.iter().find(|name| ctx.doors.get(name).is_none()) {
+                        bail!("Door {} is not registered", name);
+                    }
+                }
+
+                let mut card = ctx
+                    .cards
+                    .get(&uid)
+                    .ok_or_else(|| eyre!("Card {} is not registered", uid))?
+                    .clone();
+                card.doors = doors;
+                ctx.cards.update(card)?;
+            }
+            Command::CardGroup { uid, group } => {
+                let mut card = ctx
+                    .cards
+                    .get(&uid)
+                    .ok_or_else(|| eyre!("Card {} is not registered", uid))?
+                    .clone();
+                card.group = group;
+                ctx.cards.update(card)?;
+            }
+            Command::CardRule { uid, rule } => {
+                let mut card = ctx
+                    .cards
+                    .get(&uid)
+                    .ok_or_else(|| eyre!("Card {} is not registered", uid))?
+                    .clone();
+                card.rule = rule;
+                card.uses = 0;
+                ctx.cards.update(card)?;
+            }
+            Command::Groups => {
+                for group in ctx.cards.groups() {
+                    writeln!(out, "{} {}", group.name, format_rule(&group.rule))?;
+                }
+            }
+            Command::PutGroup(group) => ctx.cards.put_group(group)?,
+            Command::RemoveGroup { name } => {
+                ctx.cards.remove_group(&name)?;
+            }
+            Command::LogCsv => ctx.log.write_csv(&mut *out)?,
+            Command::LogJson => {
+                ctx.log.write_json(&mut *out)?;
+                writeln!(out)?;
+            }
+            Command::Denied => {
+                let denied = ctx.log.denied();
+                for reason in DenyReason::ALL {
+                    writeln!(out, "{} {}", reason.as_str(), denied.get(reason))?;
+                }
+                writeln!(out, "total {}", denied.total())?;
+            }
+            Command::ClearLog => ctx.log.clear()?,
+        }
+
+        Ok(())
+    }
+}
+
+/// What console commands act on
+pub struct Context<'a, C: Storage<CardDatabase>, D: Storage<DoorList>> {
+    pub cards: &'a mut CardStore<C>,
+    pub doors: &'a mut DoorRegistry<D>,
+    pub log: &'a mut AccessLog,
+}
+
+fn parse_uid(s: &str) -> Result<Uid> {
+    s.parse().map_err(|e| eyre!("{}: {}", s, e))
+}
+
+/// Hex with a `0x` prefix or decimal
+fn parse_address(s: &str) -> Result<u8> {
+    let address = match s.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16),
+        None => s.parse(),
+    };
+
+    address.map_err(|_| eyre!("{} is not an address", s))
+}
+
+/// The words of a rule, see [`HELP`]
+fn parse_rule(words: &[&str]) -> Result<AccessRule> {
+    let mut rule = AccessRule::default();
+
+    for &word in words {
+        if let Some(date) = word.strip_prefix("expires=") {
+            rule.expires = Some(
+                Date::parse(date, format_description!("[year]-[month]-[day]"))
+                    .map_err(|_| eyre!("{} is not a date", date))?,
+            );
+        } else if let Some(uses) = word.strip_prefix("uses=") {
+            rule.max_uses = Some(uses.parse().map_err(|_| eyre!("{} is not a count", uses))?);
+        } else if let Some((days, times)) = word.split_once('@') {
+            let (start, end) = times
+                .split_once('-')
+                .ok_or_else(|| eyre!("{} is not a time range", times))?;
+            let weekdays = match days {
+                "daily" => Vec::new(),
+                days => days.split(',').map(parse_weekday).collect::<Result<_>>()?,
+            };
+
+            rule.schedules.push(Schedule {
+                weekdays,
+                start: parse_time(start)?,
+                end: parse_time(end)?,
+            });
+        } else {
+            bail!("Unknown rule {}, try help", word);
+        }
+    }
+
+    rule.validate()?;
+    Ok(rule)
+}
+
+/// The rule in the words [`parse_rule`] takes
+fn format_rule(rule: &AccessRule) -> String {
+    let mut words = Vec::new();
+
+    for schedule in &rule.schedules {
+        let days = if schedule.weekdays.is_empty() {
+            "daily".to_string()
+        } else {
+            let days: Vec<_> = schedule
+                .weekdays
+                .iter()
+                .map(|&day| weekday_name(day))
+                .collect();
+            days.join(",")
+        };
+        words.push(format!(
+            "{}@{:02}:{:02}-{:02}:{:02}",
+            days,
+            schedule.start.hour(),
+            schedule.start.minute(),
+            schedule.end.hour(),
+            schedule.end.minute()
+        ));
+    }
+    if let Some(expires) = rule.expires {
+        words.push(format!("expires={}", expires));
+    }
+    if let Some(max_uses) = rule.max_uses {
+        words.push(format!("uses={}", max_uses));
+    }
+
+    if words.is_empty() {
+        "any time".to_string()
+    } else {
+        words.join(" ")
+    }
+}
+
+const WEEKDAYS: [(Weekday, &str); 7] = [
+    (Weekday::Monday, "mon"),
+    (Weekday::Tuesday, "tue"),
+    (Weekday::Wednesday, "wed"),
+    (Weekday::Thursday, "thu"),
+    (Weekday::Friday, "fri"),
+    (Weekday::Saturday, "sat"),
+    (Weekday::Sunday, "sun"),
+];
+
+fn parse_weekday(s: &str) -> Result<Weekday> {
+    WEEKDAYS
+        .iter()
+        .find(|(_, name)| *name == s)
+        .map(|&(day, _)| day)
+        .ok_or_else(|| eyre!("{} is not a day, e.g. mon", s))
+}
+
+fn weekday_name(day: Weekday) -> &'static str {
+    WEEKDAYS.iter().find(|(d, _)| *d == day).unwrap().1
+}
+
+/// `HH:MM`, 24 hour
+fn parse_time(s: &str) -> Result<Time> {
+    let time = s
+        .split_once(':')
+        .and_then(|(hour, minute)| Some((hour.parse().ok()?, minute.parse().ok()?)))
+        .and_then(|(hour, minute)| Time::from_hms(hour, minute, 0).ok());
+
+    time.ok_or_else(|| eyre!("{} is not a time, e.g. 08:30", s))
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+    use time::macros::{date, datetime, time};
+
+    use std::time::Instant;
+
+    use super::*;
+    use crate::{
+        access_log::AccessEvent,
+        cards::{CardRecord, Decision},
+        persistent_state::BinaryFileStorage,
+    };
+
+    struct Master {
+        _dir: TempDir,
+        cards: CardStore<BinaryFileStorage<CardDatabase>>,
+        doors: DoorRegistry<BinaryFileStorage<DoorList>>,
+        log: AccessLog,
+    }
+
+    impl Master {
+        fn new() -> Self {
+            let dir = TempDir::new().unwrap();
+            let cards = CardStore::load(BinaryFileStorage::new(dir.path().join("cards.bin")));
+            let doors = DoorRegistry::load(BinaryFileStorage::new(dir.path().join("doors.bin")));
+            let log = AccessLog::open(dir.path().join("access.log"));
+
+            Self {
+                cards: cards.unwrap(),
+                doors: doors.unwrap(),
+                log: log.unwrap(),
+                _dir: dir,
+            }
+        }
+
+        fn run(&mut self, line: &str) -> Result<String> {
+            let command = Command::parse(line)?.unwrap();
+            let mut out = Vec::new();
+            command.run(
+                Context {
+                    cards: &mut self.cards,
+                    doors: &mut self.doors,
+                    log: &mut self.log,
+                },
+                &mut out,
+            )?;
+
+            Ok(String::from_utf8(out).unwrap())
+        }
+    }
+
+    fn uid(s: &str) -> Uid {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_commands() {
+        assert_eq!(Command::parse("  ").unwrap(), None);
+        assert_eq!(
+            Command::parse("door add Back 0x05").unwrap(),
+            Some(Command::AddDoor {
+                name: "Back".into(),
+                address: 0x05
+            })
+        );
+        assert_eq!(
+            Command::parse("door  add Side 6").unwrap(),
+            Some(Command::AddDoor {
+                name: "Side".into(),
+                address: 6
+            })
+        );
+        assert_eq!(
+            Command::parse("card doors 04a23b1c Front,Back").unwrap(),
+            Some(Command::CardDoors {
+                uid: uid("04:A2:3B:1C"),
+                doors: DoorAccess::Only(vec!["Front".into(), "Back".into()]),
+            })
+        );
+        assert_eq!(
+            Command::parse("card doors 04:A2:3B:1C all").unwrap(),
+            Some(Command::CardDoors {
+                uid: uid("04:A2:3B:1C"),
+                doors: DoorAccess::All,
+            })
+        );
+
+        assert!(Command::parse("door add Back").is_err());
+        assert!(Command::parse("door add Back 0x1FF").is_err());
+        assert!(Command::parse("card doors 04:A2 all").is_err());
+        assert!(Command::parse("unlock everything").is_err());
+    }
+
+    #[test]
+    fn adds_and_removes_doors() {
+        let mut master = Master::new();
+
+        master.run("door add Back 0x05").unwrap();
+        assert_eq!(master.run("door list").unwrap(), "Door 0x04\nBack 0x05\n");
+        assert!(master.run("door add Side 0x05").is_err());
+        assert!(master.run("door add Side 0x0C").is_err());
+
+        master.run("door remove Door").unwrap();
+        assert_eq!(master.run("door list").unwrap(), "Back 0x05\n");
+        assert!(master.run("door remove Door").is_err());
+    }
+
+    #[test]
+    fn registers_cards_and_admins() {
+        let mut master = Master::new();
+
+        master.run("card add 04:A2:3B:1C Jane  Doe").unwrap();
+        master.run("card add 01:02:03:04 Bob").unwrap();
+        master.run("card admin 01:02:03:04 on").unwrap();
+        assert_eq!(
+            master.run("card list").unwrap(),
+            "04:A2:3B:1C Jane Doe doors: all\n01:02:03:04 Bob doors: all admin\n"
+        );
+
+        master.run("card admin 01:02:03:04 off").unwrap();
+        master.run("card remove 04:A2:3B:1C").unwrap();
+        assert_eq!(
+            master.run("card list").unwrap(),
+            "01:02:03:04 Bob doors: all\n"
+        );
+
+        assert!(Command::parse("card add 04:A2:3B:1C").is_err());
+        assert!(Command::parse("card admin 04:A2:3B:1C yes").is_err());
+        assert!(master.run("card admin 04:A2:3B:1C on").is_err());
+        assert!(master.run("card add 01:02:03:04 Again").is_err());
+    }
+
+    #[test]
+    fn limits_a_card_to_registered_doors() {
+        let mut master = Master::new();
+        master.run("door add Back 0x05").unwrap();
+        master
+            .cards
+            .add(CardRecord::new(uid("04:A2:3B:1C"), "Alice"))
+            .unwrap();
+
+        master.run("card doors 04:A2:3B:1C Back").unwrap();
+        assert!(master
+            .cards
+            .decide(&uid("04:A2:3B:1C"), "Back", None)
+            .is_granted());
+        assert!(!master
+            .cards
+            .decide(&uid("04:A2:3B:1C"), "Door", None)
+            .is_granted());
+        assert_eq!(
+            master.run("card list").unwrap(),
+            "04:A2:3B:1C Alice doors: Back\n"
+        );
+
+        assert!(master.run("card doors 04:A2:3B:1C Back,Attic").is_err());
+        assert!(master.run("card doors 01:02:03:04 all").is_err());
+    }
+
+    #[test]
+    fn keeps_doors_a_card_still_opens() {
+        let mut master = Master::new();
+        master.run("door add Back 0x05").unwrap();
+        master
+            .cards
+            .add(CardRecord::new(uid("04:A2:3B:1C"), "Alice"))
+            .unwrap();
+        master.run("card doors 04:A2:3B:1C Back").unwrap();
+
+        assert!(master.run("door remove Back").is_err());
+        master.run("card doors 04:A2:3B:1C all").unwrap();
+        master.run("door remove Back").unwrap();
+    }
+
+    #[test]
+    fn exports_the_access_log() {
+        let mut master = Master::new();
+        let event = AccessEvent {
+            timestamp: None,
+            uptime_secs: 42,
+            uid: uid("04:A2:3B:1C"),
+            holder: None,
+            door: Some("Door".into()),
+            decision: Decision::Denied(DenyReason::UnknownCard),
+        };
+        master.log.record(&[event], Instant::now()).unwrap();
+
+        assert_eq!(
+            master.run("log csv").unwrap(),
+            "timestamp,uptime_secs,uid,holder,door,decision,reason\n\
+             ,42,04:A2:3B:1C,,Door,denied,unknown_card\n"
+        );
+        assert_eq!(
+            master.run("log json").unwrap(),
+            "[{\"timestamp\":null,\"uptime_secs\":42,\"uid\":\"04:A2:3B:1C\",\"holder\":null,\
+             \"door\":\"Door\",\"decision\":\"denied\",\"reason\":\"unknown_card\"}]\n"
+        );
+        let denied = master.run("log denied").unwrap();
+        assert!(denied.starts_with("unknown_card 1\n"));
+        assert!(denied.ends_with("total 1\n"));
+
+        master.run("log clear").unwrap();
+        assert_eq!(master.run("log json").unwrap(), "[]\n");
+    }
+
+    #[test]
+    fn parses_rules() {
+        assert_eq!(
+            Command::parse("group put Staff mon,fri@08:00-18:30 sat@22:00-02:00").unwrap(),
+            Some(Command::PutGroup(Group {
+                name: "Staff".into(),
+                rule: AccessRule {
+                    schedules: vec![
+                        Schedule {
+                            weekdays: vec![Weekday::Monday, Weekday::Friday],
+                            start: time!(08:00),
+                            end: time!(18:30),
+                        },
+                        Schedule {
+                            weekdays: vec![Weekday::Saturday],
+                            start: time!(22:00),
+                            end: time!(02:00),
+                        },
+                    ],
+                    ..AccessRule::default()
+                },
+            }))
+        );
+        assert_eq!(
+            Command::parse("card rule 04:A2:3B:1C expires=2024-06-30 uses=3").unwrap(),
+            Some(Command::CardRule {
+                uid: uid("04:A2:3B:1C"),
+                rule: AccessRule {
+                    expires: Some(date!(2024 - 06 - 30)),
+                    max_uses: Some(3),
+                    ..AccessRule::default()
+                },
+            })
+        );
+        assert_eq!(
+            Command::parse("card rule 04:A2:3B:1C").unwrap(),
+            Some(Command::CardRule {
+                uid: uid("04:A2:3B:1C"),
+                rule: AccessRule::default(),
+            })
+        );
+
+        assert!(Command::parse("group put Staff daily@08:00-08:00").is_err());
+        assert!(Command::parse("group put Staff daily@8-17").is_err());
+        assert!(Command::parse("group put Staff daily@08:00-24:00").is_err());
+        assert!(Command::parse("group put Staff weekdays@08:00-17:00").is_err());
+        assert!(Command::parse("group put Staff expires=30/06/2024").is_err());
+        assert!(Command::parse("group put Staff uses=-1").is_err());
+        assert!(Command::parse("group put Staff always").is_err());
+    }
+
+    #[test]
+    fn provisions_groups_and_rules() {
+        let mut master = Master::new();
+        master.run("card add 04:A2:3B:1C Alice").unwrap();
+
+        master
+            .run("group put Staff daily@08:00-18:00 expires=2024-12-31")
+            .unwrap();
+        assert!(master.run("card group 04:A2:3B:1C Contractors").is_err());
+        master.run("card group 04:A2:3B:1C Staff").unwrap();
+        master.run("card rule 04:A2:3B:1C uses=2").unwrap();
+        assert_eq!(
+            master.run("group list").unwrap(),
+            "Staff daily@08:00-18:00 expires=2024-12-31
+"
+        );
+        assert_eq!(
+            master.run("card list").unwrap(),
+            "04:A2:3B:1C Alice doors: all group: Staff rule: uses=2 used: 0
+"
+        );
+
+        let card = uid("04:A2:3B:1C");
+        let morning = Some(datetime!(2024-06-03 09:00 +8));
+        let night = Some(datetime!(2024-06-03 21:00 +8));
+        assert!(master.cards.decide(&card, "Door", morning).is_granted());
+        assert_eq!(
+            master.cards.decide(&card, "Door", night),
+            Decision::Denied(DenyReason::OutsideSchedule)
+        );
+
+        // A new rule starts counting again
+        master.cards.record_use(&card).unwrap();
+        master.cards.record_use(&card).unwrap();
+        assert_eq!(
+            master.cards.decide(&card, "Door", morning),
+            Decision::Denied(DenyReason::UsesExhausted)
+        );
+        master.run("card rule 04:A2:3B:1C uses=5").unwrap();
+        assert!(master.cards.decide(&card, "Door", morning).is_granted());
+
+        // Replacing the group's rule applies to its cards
+        master.run("group put Staff").unwrap();
+        assert!(master.cards.decide(&card, "Door", night).is_granted());
+
+        assert!(master.run("group remove Staff").is_err());
+        master.run("card group 04:A2:3B:1C none").unwrap();
+        master.run("group remove Staff").unwrap();
+        assert_eq!(master.run("group list").unwrap(), "");
+        assert!(master.run("group remove Staff").is_err());
+    }
+}
@@ -0,0 +1,117 @@
+use eyre::{bail, eyre, Result};
+use rfid_lock_protocol::{slave_address, DEFAULT_ADDRESS, MAX_SLAVES};
+use serde::{Deserialize, Serialize};
+
+use crate::persistent_state::Storage;
+
+const MAX_NAME_LEN: usize = 32;
+
+/// A door and the slave locking it. Cards are given access to a door by its name.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Door {
+    pub name: String,
+    pub address: u8,
+}
+
+impl Door {
+    pub fn new(name: impl Into<String>, address: u8) -> Self {
+        Self {
+            name: name.into(),
+            address,
+        }
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.name.trim().is_empty() {
+            bail!("Door name is required");
+        }
+
+        if self.name.len() > MAX_NAME_LEN {
+            bail!("Door name must be at most {} bytes", MAX_NAME_LEN);
+        }
+
+        if !(0..MAX_SLAVES).any(|index| slave_address(index) == Some(self.address)) {
+            bail!("No slave can be strapped to {:#04x}", self.address);
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct DoorList {
+    #[serde(default)]
+    pub doors: Vec<Door>,
+}
+
+/// A single door on the default address, what the master drove before doors were registered
+impl Default for DoorList {
+    fn default() -> Self {
+        Self {
+            doors: vec![Door::new("Door", DEFAULT_ADDRESS)],
+        }
+    }
+}
+
+/// The doors the master locks, a name and an address each. Changes are saved before they're
+/// applied, like the cards.
+pub struct DoorRegistry<S: Storage<DoorList>> {
+    list: DoorList,
+    storage: S,
+}
+
+impl<S: Storage<DoorList>> DoorRegistry<S> {
+    pub fn load(storage: S) -> Result<Self> {
+        let list = storage
+            .load()
+            .map_err(|e| eyre!("Failed to load doors: {:?}", e))?
+            .unwrap_or_default();
+
+        Ok(Self { list, storage })
+    }
+
+    pub fn doors(&self) -> &[Door] {
+        &self.list.doors
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Door> {
+        self.list.doors.iter().find(|door| door.name == name)
+    }
+
+    pub fn add(&mut self, door: Door) -> Result<()> {
+        door.validate()?;
+        if self.get(&door.name).is_some() {
+            bail!("Door {} is already registered", door.name);
+        }
+        if let Some(other) = self.list.doors.iter().find(|d| d.address == door.address) {
+            bail!("{:#04x} is already used by {}", door.address, other.name);
+        }
+
+        self.modify(|list| list.doors.push(door))
+    }
+
+    pub fn remove(&mut self, name: &str) -> Result<Door> {
+        let Some(index) = self.list.doors.iter().position(|door| door.name == name) else {
+            bail!("Door {} is not registered", name);
+        };
+
+        let door = self.list.doors[index].clone();
+        self.modify(|list| {
+            list.doors.remove(index);
+        })?;
+
+        Ok(door)
+    }
+
+    fn modify(&mut self, f: impl FnOnce(&mut DoorList)) -> Result<()> {
+        let mut list = self.list.clone();
+        f(&mut list);
+
+        self.storage
+            .save(&list)
+            .map_err(|e| eyre!("Failed to save doors: {:?}", e))?;
+        self.list = list;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,146 @@
+use std::time::{Duration, Instant};
+
+use rfid_lock_mfrc522::Uid;
+
+/// Enrollment ends when no card is presented for this long
+pub const INACTIVITY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// What the presented card is to the card store
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CardKind {
+    Admin,
+    Known,
+    Unknown,
+}
+
+/// What to do with the presented card
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    /// Not enrolling, the card opens the doors it has access to
+    Decide,
+    Add(Uid),
+    Remove(Uid),
+    /// The admin card switched enrollment on or off
+    Switched,
+}
+
+/// Whether presented cards open doors or are added and removed. The admin card switches
+/// enrollment on and off, while it's on an unknown card is added and a known one removed.
+///
+/// Only states and actions, the caller looks the card up, keeps the time and carries out the
+/// action.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Enrollment {
+    Off,
+    On { last_activity: Instant },
+}
+
+impl Enrollment {
+    pub fn is_on(&self) -> bool {
+        matches!(self, Enrollment::On { .. })
+    }
+
+    pub fn present(self, uid: Uid, kind: CardKind, now: Instant) -> (Self, Action) {
+        let on = Enrollment::On { last_activity: now };
+
+        match (self.tick(now), kind) {
+            (Enrollment::Off, CardKind::Admin) => (on, Action::Switched),
+            (Enrollment::Off, CardKind::Known | CardKind::Unknown) => {
+                (Enrollment::Off, Action::Decide)
+            }
+            (Enrollment::On { .. }, CardKind::Admin) => (Enrollment::Off, Action::Switched),
+            (Enrollment::On { .. }, CardKind::Known) => (on, Action::Remove(uid)),
+            (Enrollment::On { .. }, CardKind::Unknown) => (on, Action::Add(uid)),
+        }
+    }
+
+    /// Switches enrollment off once no card was presented for [`INACTIVITY_TIMEOUT`]
+    pub fn tick(self, now: Instant) -> Self {
+        match self {
+            Enrollment::On { last_activity }
+                if now.duration_since(last_activity) >= INACTIVITY_TIMEOUT =>
+            {
+                Enrollment::Off
+            }
+            state => state,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn uid() -> Uid {
+        "04:A2:3B:1C".parse().unwrap()
+    }
+
+    #[test]
+    fn cards_open_doors_while_off() {
+        let now = Instant::now();
+
+        for kind in [CardKind::Known, CardKind::Unknown] {
+            assert_eq!(
+                Enrollment::Off.present(uid(), kind, now),
+                (Enrollment::Off, Action::Decide)
+            );
+        }
+    }
+
+    #[test]
+    fn the_admin_card_switches_enrollment() {
+        let now = Instant::now();
+        let on = Enrollment::On { last_activity: now };
+
+        assert_eq!(
+            Enrollment::Off.present(uid(), CardKind::Admin, now),
+            (on, Action::Switched)
+        );
+        assert_eq!(
+            on.present(uid(), CardKind::Admin, now),
+            (Enrollment::Off, Action::Switched)
+        );
+    }
+
+    #[test]
+    fn adds_unknown_and_removes_known_cards_while_on() {
+        let start = Instant::now();
+        let later = start + Duration::from_secs(5);
+        let on = Enrollment::On {
+            last_activity: start,
+        };
+        let extended = Enrollment::On {
+            last_activity: later,
+        };
+
+        assert_eq!(
+            on.present(uid(), CardKind::Unknown, later),
+            (extended, Action::Add(uid()))
+        );
+        assert_eq!(
+            on.present(uid(), CardKind::Known, later),
+            (extended, Action::Remove(uid()))
+        );
+    }
+
+    #[test]
+    fn ends_after_inactivity() {
+        let start = Instant::now();
+        let on = Enrollment::On {
+            last_activity: start,
+        };
+
+        assert!(on.tick(start + INACTIVITY_TIMEOUT / 2).is_on());
+        assert_eq!(on.tick(start + INACTIVITY_TIMEOUT), Enrollment::Off);
+        // A card presented after the timeout is decided as usual
+        assert_eq!(
+            on.present(uid(), CardKind::Unknown, start + INACTIVITY_TIMEOUT),
+            (Enrollment::Off, Action::Decide)
+        );
+        // The admin card presented after the timeout starts enrollment again
+        assert!(on
+            .present(uid(), CardKind::Admin, start + INACTIVITY_TIMEOUT)
+            .0
+            .is_on());
+    }
+}
@@ -0,0 +1,9 @@
+//! The parts of the rfid_lock master that don't touch ESP-IDF, kept apart so they build and
+//! are tested on the host.
+
+pub mod access_log;
+pub mod cards;
+pub mod console;
+pub mod doors;
+pub mod enrollment;
+pub mod persistent_state;
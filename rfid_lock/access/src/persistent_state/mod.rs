@@ -0,0 +1,188 @@
+use std::{
+    fmt::Debug,
+    fs::{self, File},
+    io::{ErrorKind, Write},
+    path::{Path, PathBuf},
+};
+
+use eyre::{eyre, Report};
+use serde::{Deserialize, Serialize};
+
+pub trait Storage<T: Default> {
+    type Error: Debug;
+
+    fn save(&self, item: &T) -> Result<(), Self::Error>;
+    /// `None` if nothing was saved yet, an error if something was and it can't be read
+    fn load(&self) -> Result<Option<T>, Self::Error>;
+}
+
+/// Stores the state as CBOR in a file.
+///
+/// A save is written to a temporary file that then replaces the old one, so losing power part
+/// way through leaves either the old or the new state on flash. FAT won't rename over an
+/// existing file, the temporary file is read instead if power is lost between removing the old
+/// file and renaming, or if the file can't be read.
+pub struct BinaryFileStorage<State: Serialize + for<'a> Deserialize<'a>> {
+    path: PathBuf,
+    _phantom: std::marker::PhantomData<State>,
+}
+
+impl<Config: Serialize + for<'a> Deserialize<'a>> BinaryFileStorage<Config> {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            _phantom: std::marker::PhantomData,
+        }
+    }
+
+    fn temp_path(&self) -> PathBuf {
+        self.path.with_extension("tmp")
+    }
+
+    fn read(path: &Path) -> Result<Option<Config>, Report> {
+        let value = match fs::read(path) {
+            Ok(value) => value,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(eyre!("Failed to read {}: {}", path.display(), e)),
+        };
+
+        ciborium::from_reader(&value[..])
+            .map(Some)
+            .map_err(|e| eyre!("{} is corrupt: {}", path.display(), e))
+    }
+}
+
+impl<Config: Serialize + for<'a> Deserialize<'a> + Default> Storage<Config>
+    for BinaryFileStorage<Config>
+{
+    type Error = Report;
+
+    fn save(&self, item: &Config) -> Result<(), Self::Error> {
+        let temp_path = self.temp_path();
+
+        let mut buf = Vec::new();
+        ciborium::into_writer(item, &mut buf)?;
+
+        let mut f = File::options()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .open(&temp_path)?;
+        f.write_all(&buf)?;
+        f.sync_all()?;
+        drop(f);
+
+        if self.path.exists() {
+            fs::remove_file(&self.path)?;
+        }
+        fs::rename(&temp_path, &self.path)?;
+
+        Ok(())
+    }
+
+    fn load(&self) -> Result<Option<Config>, Self::Error> {
+        let e = match Self::read(&self.path) {
+            Ok(Some(state)) => return Ok(Some(state)),
+            Ok(None) => return Self::read(&self.temp_path()),
+            Err(e) => e,
+        };
+
+        match Self::read(&self.temp_path()) {
+            Ok(Some(state)) => {
+                tracing::warn!("{:?}, loaded {} instead", e, self.temp_path().display());
+                Ok(Some(state))
+            }
+            _ => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[derive(Debug, Default, PartialEq, Deserialize, Serialize)]
+    struct State {
+        count: u32,
+        name: String,
+    }
+
+    fn state(count: u32) -> State {
+        State {
+            count,
+            name: "front".into(),
+        }
+    }
+
+    fn storage(dir: &TempDir) -> BinaryFileStorage<State> {
+        BinaryFileStorage::new(dir.path().join("state.bin"))
+    }
+
+    #[test]
+    fn loads_none_when_nothing_was_saved() {
+        let dir = TempDir::new().unwrap();
+
+        assert_eq!(storage(&dir).load().unwrap(), None);
+    }
+
+    #[test]
+    fn loads_what_was_saved() {
+        let dir = TempDir::new().unwrap();
+        let storage = storage(&dir);
+
+        storage.save(&state(1)).unwrap();
+        storage.save(&state(2)).unwrap();
+
+        assert_eq!(storage.load().unwrap(), Some(state(2)));
+        assert!(!dir.path().join("state.tmp").exists());
+    }
+
+    #[test]
+    fn loads_the_temporary_file_when_the_save_stopped_before_renaming() {
+        let dir = TempDir::new().unwrap();
+        let storage = storage(&dir);
+
+        storage.save(&state(3)).unwrap();
+        fs::rename(dir.path().join("state.bin"), dir.path().join("state.tmp")).unwrap();
+
+        assert_eq!(storage.load().unwrap(), Some(state(3)));
+    }
+
+    #[test]
+    fn fails_on_a_corrupt_file() {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("state.bin"), [0xFF, 0x00, 0x13]).unwrap();
+
+        assert!(storage(&dir).load().is_err());
+    }
+
+    #[test]
+    fn fails_on_a_corrupt_temporary_file_alone() {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("state.tmp"), b"not cbor").unwrap();
+
+        assert!(storage(&dir).load().is_err());
+    }
+
+    #[test]
+    fn falls_back_to_the_temporary_file_when_the_file_is_corrupt() {
+        let dir = TempDir::new().unwrap();
+        let storage = storage(&dir);
+
+        storage.save(&state(4)).unwrap();
+        fs::copy(dir.path().join("state.bin"), dir.path().join("state.tmp")).unwrap();
+        fs::write(dir.path().join("state.bin"), [0xFF]).unwrap();
+
+        assert_eq!(storage.load().unwrap(), Some(state(4)));
+    }
+
+    #[test]
+    fn fails_when_the_file_is_not_readable() {
+        let dir = TempDir::new().unwrap();
+        fs::create_dir(dir.path().join("state.bin")).unwrap();
+
+        assert!(storage(&dir).load().is_err());
+    }
+}
@@ -0,0 +1,54 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use esp_idf_svc::{
+    sntp::{EspSntp, OperatingMode, SntpConf, SyncMode},
+    sys,
+};
+use time::{OffsetDateTime, UtcOffset};
+
+use crate::util::result::Result;
+
+/// Uptime in seconds at the last SNTP sync plus one, zero until the first sync.
+/// The ESP32 has no 64-bit atomics, 32 bits of seconds is plenty.
+static LAST_TIME_SYNC: AtomicU32 = AtomicU32::new(0);
+
+/// Keeps the clock synced for as long as it's held. Until the network is up it just retries.
+pub fn start_sntp() -> Result<EspSntp<'static>> {
+    let sntp = EspSntp::new_with_callback(
+        &SntpConf {
+            sync_mode: SyncMode::Immediate,
+            operating_mode: OperatingMode::Poll,
+            servers: ["121.58.193.100"], // ntp.pagasa.dost.gov.ph
+        },
+        |_| {
+            if secs_since_time_sync().is_none() {
+                tracing::info!("Time synced");
+            }
+            record_time_sync();
+        },
+    )?;
+
+    Ok(sntp)
+}
+
+pub fn uptime_secs() -> u64 {
+    (unsafe { sys::esp_timer_get_time() } / 1_000_000) as u64
+}
+
+/// Called from the SNTP callback on every sync
+pub fn record_time_sync() {
+    LAST_TIME_SYNC.store(uptime_secs() as u32 + 1, Ordering::Relaxed);
+}
+
+pub fn secs_since_time_sync() -> Option<u64> {
+    match LAST_TIME_SYNC.load(Ordering::Relaxed) {
+        0 => None,
+        synced_at => Some(uptime_secs() + 1 - synced_at as u64),
+    }
+}
+
+/// The time at `offset`, `None` until the clock was synced once. The clock starts at 1970 on
+/// every boot, it can't be trusted before that.
+pub fn synced_now(offset: UtcOffset) -> Option<OffsetDateTime> {
+    secs_since_time_sync().map(|_| OffsetDateTime::now_utc().to_offset(offset))
+}
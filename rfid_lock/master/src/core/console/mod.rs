@@ -0,0 +1,53 @@
+use std::{
+    io::{self, BufRead},
+    ptr,
+    sync::mpsc,
+    thread,
+};
+
+use esp_idf_svc::sys;
+
+use crate::util::{ffi::esp::esp_unsafe, result::Result};
+
+pub use rfid_lock_access::console::{Command, Context, HELP};
+
+const CONSOLE_TASK_STACK_SIZE: usize = 4 * 1024;
+const RX_BUF_LEN: i32 = 256;
+
+/// Reads lines typed on the serial console on a task of its own. Doors and card access are
+/// provisioned over it, from the machine the master is plugged into.
+pub fn start() -> Result<mpsc::Receiver<String>> {
+    let uart = sys::CONFIG_ESP_CONSOLE_UART_NUM as i32;
+    // Without the driver reads from stdin don't block, they fail when nothing was typed yet
+    esp_unsafe!(sys::uart_driver_install(
+        uart,
+        RX_BUF_LEN,
+        0,
+        0,
+        ptr::null_mut(),
+        0
+    ))?;
+    unsafe { sys::esp_vfs_dev_uart_use_driver(uart) };
+
+    let (tx, rx) = mpsc::channel();
+    thread::Builder::new()
+        .name("console".into())
+        .stack_size(CONSOLE_TASK_STACK_SIZE)
+        .spawn(move || {
+            for line in io::stdin().lock().lines() {
+                let line = match line {
+                    Ok(line) => line,
+                    Err(e) => {
+                        tracing::warn!("Console read failed: {}", e);
+                        continue;
+                    }
+                };
+
+                if tx.send(line).is_err() {
+                    return;
+                }
+            }
+        })?;
+
+    Ok(rx)
+}
@@ -0,0 +1,42 @@
+/// Failed exchanges in a row before the slave counts as gone
+const MAX_FAILURES: u32 = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HealthChange {
+    /// The slave stopped answering
+    Unresponsive,
+    /// It answers again after being unresponsive
+    Recovered,
+}
+
+/// Tells from the outcome of each exchange when a slave stops or starts answering
+#[derive(Debug, Clone, Default)]
+pub struct SlaveHealth {
+    failures: u32,
+    unresponsive: bool,
+}
+
+impl SlaveHealth {
+    pub fn is_unresponsive(&self) -> bool {
+        self.unresponsive
+    }
+
+    pub fn record(&mut self, answered: bool) -> Option<HealthChange> {
+        if answered {
+            self.failures = 0;
+
+            return self.unresponsive.then(|| {
+                self.unresponsive = false;
+                HealthChange::Recovered
+            });
+        }
+
+        self.failures = self.failures.saturating_add(1);
+        if self.failures >= MAX_FAILURES && !self.unresponsive {
+            self.unresponsive = true;
+            return Some(HealthChange::Unresponsive);
+        }
+
+        None
+    }
+}
@@ -0,0 +1,172 @@
+use std::{thread, time::Duration};
+
+use embedded_hal::i2c::I2c;
+use rfid_lock_protocol::{
+    slave_address, Command, ErrorFlags, Key, Message, Request, Status, MAX_FRAME_LEN, MAX_SLAVES,
+};
+
+use crate::util::result::{bail, error, Result};
+
+pub mod health;
+
+pub use health::{HealthChange, SlaveHealth};
+
+/// Gives the slave time to execute the command and queue its status
+const STATUS_READ_DELAY: Duration = Duration::from_millis(20);
+const STATUS_READ_ATTEMPTS: u32 = 3;
+/// A status reporting the command as older than this is left over from an earlier one
+const CONFIRM_WINDOW_MS: u32 = 1000;
+
+/// A lock slave on the I2C bus. The bus is passed in, so the slaves can share it.
+pub struct LockSlave {
+    address: u8,
+    key: Key,
+    health: SlaveHealth,
+}
+
+impl LockSlave {
+    pub fn new(address: u8, key: Key) -> Self {
+        Self {
+            address,
+            key,
+            health: SlaveHealth::default(),
+        }
+    }
+
+    pub fn address(&self) -> u8 {
+        self.address
+    }
+
+    pub fn health(&self) -> &SlaveHealth {
+        &self.health
+    }
+
+    /// Sends the command and reads back the slave's status to confirm it was carried out.
+    /// The change in health is returned along with the outcome, so it can be alarmed on.
+    pub fn execute<I2C: I2c>(
+        &mut self,
+        i2c: &mut I2C,
+        command: Command,
+    ) -> (Result<Status>, Option<HealthChange>) {
+        let res = self.exchange(i2c, command);
+        let change = self.health.record(res.is_ok());
+
+        (res, change)
+    }
+
+    fn exchange<I2C: I2c>(&mut self, i2c: &mut I2C, command: Command) -> Result<Status> {
+        // Signed for a nonce fresh from the slave, one from an earlier status could have been
+        // replaced by a restart
+        let nonce = if command.requires_auth() {
+            self.transfer(i2c, Command::GetStatus, 0)?.nonce
+        } else {
+            0
+        };
+
+        let status = self.transfer(i2c, command, nonce)?;
+
+        let expected_locked = match command {
+            Command::Unlock { .. } => Some(false),
+            Command::Lock => Some(true),
+            Command::GetStatus
+            | Command::Ping
+            | Command::Configure { .. }
+            | Command::Indicate { .. } => None,
+        };
+        if expected_locked.is_some_and(|locked| locked != status.locked) {
+            bail!(
+                "Slave {:#04x} is {} after {:?}",
+                self.address,
+                if status.locked { "locked" } else { "unlocked" },
+                command
+            );
+        }
+
+        Ok(status)
+    }
+
+    /// Sends the command and reads back the status confirming it
+    fn transfer<I2C: I2c>(
+        &mut self,
+        i2c: &mut I2C,
+        command: Command,
+        nonce: u32,
+    ) -> Result<Status> {
+        self.send(i2c, Request::new(command, &self.key, nonce))?;
+        let status = self.read_status(i2c)?;
+
+        if status.errors.contains(ErrorFlags::AUTH_FAILED) {
+            tracing::error!(
+                "ALARM: slave {:#04x} rejected an unauthenticated command, {} frames rejected since boot",
+                self.address,
+                status.rejected_frames
+            );
+        } else if !status.errors.is_empty() {
+            tracing::warn!(
+                "Slave {:#04x} reports errors {:#04x}",
+                self.address,
+                status.errors.bits()
+            );
+        }
+
+        let confirmed = status
+            .last_command
+            .is_some_and(|last| last.id == command.id() && last.elapsed_ms < CONFIRM_WINDOW_MS);
+        if !confirmed {
+            bail!("Slave {:#04x} did not confirm {:?}", self.address, command);
+        }
+
+        Ok(status)
+    }
+
+    fn send<I2C: I2c>(&mut self, i2c: &mut I2C, request: Request) -> Result<()> {
+        let mut buf = [0u8; MAX_FRAME_LEN];
+        let len = request
+            .encode(&mut buf)
+            .map_err(|e| error!("Failed to encode {:?}: {}", request.command(), e))?;
+
+        i2c.write(self.address, &buf[..len]).map_err(|e| {
+            error!(
+                "Failed to send {:?} to {:#04x}: {:?}",
+                request.command(),
+                self.address,
+                e
+            )
+        })
+    }
+
+    fn read_status<I2C: I2c>(&mut self, i2c: &mut I2C) -> Result<Status> {
+        let mut buf = [0u8; Status::FRAME_LEN];
+        let mut last_error = error!("No status read");
+
+        for _ in 0..STATUS_READ_ATTEMPTS {
+            thread::sleep(STATUS_READ_DELAY);
+
+            let res = i2c
+                .read(self.address, &mut buf)
+                .map_err(|e| error!("Failed to read status from {:#04x}: {:?}", self.address, e))
+                .and_then(|_| {
+                    Status::decode(&buf)
+                        .map_err(|e| error!("Bad status from {:#04x}: {}", self.address, e))
+                });
+
+            match res {
+                Ok(status) => return Ok(status),
+                Err(e) => last_error = e,
+            }
+        }
+
+        Err(last_error)
+    }
+}
+
+/// The addresses a slave can be strapped to that one answers a ping on
+pub fn scan<I2C: I2c>(i2c: &mut I2C, key: &Key) -> Vec<u8> {
+    (0..MAX_SLAVES)
+        .filter_map(slave_address)
+        .filter(|&address| {
+            let (res, _) = LockSlave::new(address, key.clone()).execute(i2c, Command::Ping);
+            res.is_ok()
+        })
+        .collect()
+}
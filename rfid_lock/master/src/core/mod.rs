@@ -0,0 +1,8 @@
+pub use rfid_lock_access::{access_log, cards, doors, enrollment, persistent_state};
+
+pub mod clock;
+pub mod console;
+pub mod device;
+pub mod lock;
+pub mod rfid;
+pub mod wifi;
@@ -0,0 +1,82 @@
+use std::{
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use embedded_hal::spi::SpiDevice;
+
+use crate::util::result::Result;
+
+pub use rfid_lock_mfrc522 as mfrc522;
+
+pub use mfrc522::{Card, Mfrc522, ParseUidError, Uid};
+
+const READER_TASK_STACK_SIZE: usize = 4 * 1024;
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// A card at the edge of the field can drop out and come back, it's only reported again after
+/// this long
+const REPRESENT_HOLDOFF: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CardEvent {
+    Presented(Card),
+}
+
+/// Polls the reader for cards on a task of its own.
+///
+/// A card is halted after it's read, so it's reported once per time it's brought to the reader
+/// rather than for as long as it's held there.
+pub struct CardReader<SPI> {
+    driver: Mfrc522<SPI>,
+    last: Option<(Uid, Instant)>,
+}
+
+impl<SPI: SpiDevice + Send + 'static> CardReader<SPI> {
+    /// `driver` should already be initialized
+    pub fn start(driver: Mfrc522<SPI>) -> Result<mpsc::Receiver<CardEvent>> {
+        let (tx, rx) = mpsc::channel();
+        let mut reader = Self { driver, last: None };
+
+        thread::Builder::new()
+            .name("rfid".into())
+            .stack_size(READER_TASK_STACK_SIZE)
+            .spawn(move || loop {
+                thread::sleep(POLL_INTERVAL);
+
+                let card = match reader.poll() {
+                    Ok(Some(card)) => card,
+                    Ok(None) => continue,
+                    Err(e) => {
+                        tracing::warn!("Card read failed: {}", e);
+                        continue;
+                    }
+                };
+
+                if tx.send(CardEvent::Presented(card)).is_err() {
+                    return;
+                }
+            })?;
+
+        Ok(rx)
+    }
+
+    fn poll(&mut self) -> std::result::Result<Option<Card>, mfrc522::Error<SPI::Error>> {
+        if self.driver.request_a()?.is_none() {
+            return Ok(None);
+        }
+
+        let card = self.driver.select()?;
+        if let Err(e) = self.driver.halt_a() {
+            tracing::warn!("Failed to halt card {}: {}", card.uid, e);
+        }
+
+        let now = Instant::now();
+        let repeated = self
+            .last
+            .is_some_and(|(uid, at)| uid == card.uid && now - at < REPRESENT_HOLDOFF);
+        self.last = Some((card.uid, now));
+
+        Ok((!repeated).then_some(card))
+    }
+}
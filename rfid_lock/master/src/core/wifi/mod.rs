@@ -0,0 +1,58 @@
+use std::{thread, time::Duration};
+
+use esp_idf_svc::{
+    eventloop::EspSystemEventLoop,
+    hal::modem::Modem,
+    nvs::EspDefaultNvsPartition,
+    wifi::{AuthMethod, BlockingWifi, ClientConfiguration, Configuration, EspWifi},
+};
+
+use crate::util::result::{error, Result};
+
+const WIFI_TASK_STACK_SIZE: usize = 8 * 1024;
+/// How often the connection is checked, and retried if it dropped
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Joins the network and keeps rejoining it on a task of its own. It's only needed for the
+/// time, the doors work without it.
+pub fn start(
+    modem: Modem,
+    sys_loop: EspSystemEventLoop,
+    nvs: EspDefaultNvsPartition,
+    ssid: &'static str,
+    psk: &str,
+) -> Result<()> {
+    let mut wifi = BlockingWifi::wrap(EspWifi::new(modem, sys_loop.clone(), Some(nvs))?, sys_loop)?;
+
+    wifi.set_configuration(&Configuration::Client(ClientConfiguration {
+        ssid: ssid
+            .try_into()
+            .map_err(|_| error!("SSID {} is too long", ssid))?,
+        password: psk
+            .try_into()
+            .map_err(|_| error!("Wi-Fi password is too long"))?,
+        auth_method: if psk.is_empty() {
+            AuthMethod::None
+        } else {
+            AuthMethod::WPA2Personal
+        },
+        ..Default::default()
+    }))?;
+    wifi.start()?;
+
+    thread::Builder::new()
+        .name("wifi".into())
+        .stack_size(WIFI_TASK_STACK_SIZE)
+        .spawn(move || loop {
+            if !wifi.is_connected().unwrap_or(false) {
+                match wifi.connect().and_then(|()| wifi.wait_netif_up()) {
+                    Ok(()) => tracing::info!("Connected to {}", ssid),
+                    Err(e) => tracing::warn!("Failed to join Wi-Fi: {:?}", e),
+                }
+            }
+
+            thread::sleep(RECONNECT_INTERVAL);
+        })?;
+
+    Ok(())
+}
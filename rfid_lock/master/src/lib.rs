@@ -1,12 +1,375 @@
 #![feature(decl_macro)]
-use util::{result, tracing};
+use core::{
+    access_log::{AccessEvent, AccessLog},
+    cards::{CardDatabase, CardRecord, CardStore},
+    clock, console,
+    device::Device,
+    doors::{Door, DoorList, DoorRegistry},
+    enrollment::{Action, CardKind, Enrollment},
+    lock::{self, HealthChange, LockSlave},
+    persistent_state::{BinaryFileStorage, Storage},
+    rfid::{CardEvent, CardReader, Mfrc522, Uid},
+    wifi,
+};
 
+use std::{
+    io::{self, Write as _},
+    mem,
+    sync::mpsc::RecvTimeoutError,
+    time::{Duration, Instant},
+};
+
+use esp_idf_svc::{
+    eventloop::EspSystemEventLoop,
+    hal::{
+        delay::FreeRtos,
+        i2c::{I2cConfig, I2cDriver},
+        modem::Modem,
+        peripherals::Peripherals,
+        spi::{self, SpiDeviceDriver, SpiDriverConfig},
+        units::FromValueType as _,
+    },
+    nvs::EspDefaultNvsPartition,
+    sntp::EspSntp,
+};
+use rfid_lock_protocol::{Command, Indicator, Key};
+use time::{macros::offset, UtcOffset};
+use util::{
+    result::{self, error},
+    tracing,
+};
+
+pub mod core;
 pub mod util;
 
+const UNLOCK_DURATION_MS: u32 = 5000;
+/// The slaves are pinged this often while no cards are presented, to notice one going away
+const SLAVE_PING_INTERVAL: Duration = Duration::from_secs(5);
+/// How long a card is waited for before checking the console for commands
+const CONSOLE_POLL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Provisioned at build time, the slaves have to be built with the same key
+const KEY: Key = match Key::from_hex(env!(
+    "RFID_LOCK_KEY",
+    "Set RFID_LOCK_KEY to the key shared with the slaves, 64 hex digits"
+)) {
+    Some(key) => key,
+    None => panic!("RFID_LOCK_KEY must be 64 hex digits"),
+};
+
+/// Made an admin card on every boot, so a new master can enroll cards. More admin cards are
+/// added over the console.
+const ADMIN_UID: Option<&str> = option_env!("RFID_LOCK_ADMIN_UID");
+
+/// The network the time is synced over, a master built without one never has the time
+const WIFI_SSID: Option<&str> = option_env!("RFID_LOCK_WIFI_SSID");
+const WIFI_PSK: &str = match option_env!("RFID_LOCK_WIFI_PSK") {
+    Some(psk) => psk,
+    None => "",
+};
+
+/// Card dates and schedules are in local time
+const OFFSET: UtcOffset = offset!(+8);
+
 fn run() -> result::Result<()> {
+    Device::init()?;
+
+    // Uses are journaled so a card with a use limit doesn't rewrite every card when it's used
+    let mut card_store = CardStore::load(BinaryFileStorage::new("/spiflash/conf/cards.bin"))?
+        .with_use_journal("/spiflash/data/uses.log")?;
+    tracing::info!("{} cards registered", card_store.cards().len());
+
+    if let Some(uid) = ADMIN_UID {
+        let uid: Uid = uid
+            .parse()
+            .map_err(|e| error!("RFID_LOCK_ADMIN_UID {}: {}", uid, e))?;
+        card_store.ensure_admin(uid)?;
+        tracing::info!("{} is an admin card", uid);
+    }
+
+    let mut door_registry = DoorRegistry::load(BinaryFileStorage::new("/spiflash/conf/doors.bin"))?;
+    tracing::info!("{} doors registered", door_registry.doors().len());
+
+    let mut access_log = AccessLog::open("/spiflash/data/access.log")?;
+    access_log.on_repeated_denial(|alert| {
+        tracing::error!(
+            "ALARM: card {} ({}) denied {} times in a row",
+            alert.uid,
+            alert.holder.as_deref().unwrap_or("unknown"),
+            alert.count
+        );
+    });
+
+    let p = Peripherals::take()?;
+
+    // Cards with dates or schedules are denied until the time is synced
+    let _sntp = match WIFI_SSID {
+        Some(ssid) => start_time_sync(p.modem, ssid)
+            .inspect_err(|e| tracing::error!("Failed to start time sync: {:?}", e))
+            .ok(),
+        None => {
+            tracing::warn!("No Wi-Fi configured, the time won't be synced");
+            None
+        }
+    };
+
+    // MFRC522 on the VSPI pins
+    let spi = SpiDeviceDriver::new_single(
+        p.spi3,
+        p.pins.gpio18,
+        p.pins.gpio23,
+        Some(p.pins.gpio19),
+        Some(p.pins.gpio5),
+        &SpiDriverConfig::new(),
+        &spi::config::Config::new().baudrate(4.MHz().into()),
+    )?;
+    let mut reader = Mfrc522::new(spi);
+    reader.init(&mut FreeRtos)?;
+    tracing::info!("MFRC522 version: {:#04x}", reader.version()?);
+
+    let mut i2c = I2cDriver::new(
+        p.i2c0,
+        p.pins.gpio21,
+        p.pins.gpio22,
+        &I2cConfig::new().baudrate(100.kHz().into()),
+    )?;
+
+    let answering = lock::scan(&mut i2c, &KEY);
+    let mut doors = Vec::new();
+    for door in door_registry.doors() {
+        if answering.contains(&door.address) {
+            tracing::info!("Door {} answering at {:#04x}", door.name, door.address);
+        } else {
+            tracing::error!(
+                "ALARM: no slave answering for door {} at {:#04x}",
+                door.name,
+                door.address
+            );
+        }
+
+        doors.push((door.clone(), LockSlave::new(door.address, KEY)));
+    }
+    for address in answering {
+        if doors.iter().all(|(door, _)| door.address != address) {
+            tracing::warn!(
+                "Slave at {:#04x} isn't registered to a door, add it with: door add <name> {:#04x}",
+                address,
+                address
+            );
+        }
+    }
+
+    let cards = CardReader::start(reader)?;
+    let console = console::start()?;
+
+    let mut enrollment = Enrollment::Off;
+    let mut last_ping = Instant::now();
+
+    loop {
+        let event = cards.recv_timeout(CONSOLE_POLL_INTERVAL);
+        let was_enrolling = enrollment.is_on();
+        enrollment = enrollment.tick(Instant::now());
+
+        match event {
+            Ok(CardEvent::Presented(card)) => {
+                tracing::info!("Card presented: {} (SAK {:#04x})", card.uid, card.sak);
+
+                let kind = match card_store.get(&card.uid) {
+                    Some(record) if record.admin => CardKind::Admin,
+                    Some(_) => CardKind::Known,
+                    None => CardKind::Unknown,
+                };
+                let (next, action) = enrollment.present(card.uid, kind, Instant::now());
+                enrollment = next;
+
+                match action {
+                    // The reader is shared, a card opens every door it has access to
+                    Action::Decide => {
+                        let now = clock::synced_now(OFFSET);
+                        let uptime_secs = clock::uptime_secs();
+                        let holder = card_store
+                            .get(&card.uid)
+                            .map(|record| record.holder.clone());
+                        let mut events = Vec::with_capacity(doors.len());
+                        for (door, slave) in &mut doors {
+                            let decision = card_store.decide(&card.uid, &door.name, now);
+                            tracing::info!("{}: {:?}", door.name, decision);
+                            events.push(AccessEvent {
+                                timestamp: now,
+                                uptime_secs,
+                                uid: card.uid,
+                                holder: holder.clone(),
+                                door: Some(door.name.clone()),
+                                decision,
+                            });
+
+                            if decision.is_granted() {
+                                let command = Command::Unlock {
+                                    duration_ms: UNLOCK_DURATION_MS,
+                                };
+                                execute(&mut i2c, door, slave, command);
+                            }
+                        }
+
+                        if doors.is_empty() {
+                            tracing::warn!("No doors registered, {} opens nothing", card.uid);
+                            events.push(AccessEvent {
+                                timestamp: now,
+                                uptime_secs,
+                                uid: card.uid,
+                                holder: holder.clone(),
+                                door: None,
+                                decision: card_store.decide_without_doors(&card.uid),
+                            });
+                        }
+
+                        if events.iter().any(|event| event.decision.is_granted()) {
+                            if let Err(e) = card_store.record_use(&card.uid) {
+                                tracing::error!("{:?}", e);
+                            }
+                        }
+
+                        if let Err(e) = access_log.record(&events, Instant::now()) {
+                            tracing::error!("{:?}", e);
+                        }
+                    }
+                    Action::Add(uid) => {
+                        // Named after the UID until it's given to someone
+                        let record = CardRecord::new(uid, uid.to_string());
+                        match card_store.add(record) {
+                            Ok(()) => tracing::info!("Enrolled {}", uid),
+                            Err(e) => tracing::error!("Failed to enroll {}: {:?}", uid, e),
+                        }
+                    }
+                    Action::Remove(uid) => match card_store.remove(&uid) {
+                        Ok(record) => tracing::info!("Removed {} ({})", uid, record.holder),
+                        Err(e) => tracing::error!("Failed to remove {}: {:?}", uid, e),
+                    },
+                    Action::Switched => {}
+                }
+            }
+            Err(RecvTimeoutError::Timeout) if last_ping.elapsed() >= SLAVE_PING_INTERVAL => {
+                for (door, slave) in &mut doors {
+                    execute(&mut i2c, door, slave, Command::Ping);
+                }
+                last_ping = Instant::now();
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+
+        while let Ok(line) = console.try_recv() {
+            run_console(
+                &line,
+                &mut card_store,
+                &mut door_registry,
+                &mut access_log,
+                &mut doors,
+            );
+        }
+
+        if enrollment.is_on() != was_enrolling {
+            let indicator = if enrollment.is_on() {
+                tracing::info!("Enrolling cards");
+                Indicator::Enrolling
+            } else {
+                tracing::info!("Enrollment ended");
+                Indicator::Lock
+            };
+
+            for (door, slave) in &mut doors {
+                execute(&mut i2c, door, slave, Command::Indicate { indicator });
+            }
+        }
+    }
+
     Ok(())
 }
 
+fn start_time_sync(modem: Modem, ssid: &'static str) -> result::Result<EspSntp<'static>> {
+    wifi::start(
+        modem,
+        EspSystemEventLoop::take()?,
+        EspDefaultNvsPartition::take()?,
+        ssid,
+        WIFI_PSK,
+    )?;
+
+    clock::start_sntp()
+}
+
+/// Runs a console command and answers on the console
+fn run_console<C: Storage<CardDatabase>, D: Storage<DoorList>>(
+    line: &str,
+    card_store: &mut CardStore<C>,
+    door_registry: &mut DoorRegistry<D>,
+    access_log: &mut AccessLog,
+    doors: &mut Vec<(Door, LockSlave)>,
+) {
+    let mut out = io::stdout().lock();
+    let command = match console::Command::parse(line) {
+        Ok(Some(command)) => command,
+        Ok(None) => return,
+        Err(e) => {
+            let _ = writeln!(out, "error: {}", e);
+            return;
+        }
+    };
+
+    let changes_doors = command.changes_doors();
+    let context = console::Context {
+        cards: card_store,
+        doors: door_registry,
+        log: access_log,
+    };
+    let _ = match command.run(context, &mut out) {
+        Ok(()) => writeln!(out, "ok"),
+        Err(e) => writeln!(out, "error: {}", e),
+    };
+
+    if changes_doors {
+        sync_doors(door_registry.doors(), doors);
+    }
+}
+
+/// Matches the slaves driven to the registered doors, the ones of doors still registered are
+/// kept along with their health
+fn sync_doors(registered: &[Door], doors: &mut Vec<(Door, LockSlave)>) {
+    let mut previous = mem::take(doors);
+    for door in registered {
+        let slave = match previous.iter().position(|(d, _)| d == door) {
+            Some(index) => previous.swap_remove(index).1,
+            None => LockSlave::new(door.address, KEY),
+        };
+        doors.push((door.clone(), slave));
+    }
+}
+
+fn execute(i2c: &mut I2cDriver<'_>, door: &Door, slave: &mut LockSlave, command: Command) {
+    let (res, change) = slave.execute(i2c, command);
+    if let Err(e) = res {
+        tracing::error!("{}: {:?} failed: {:?}", door.name, command, e);
+    }
+
+    match change {
+        Some(HealthChange::Unresponsive) => {
+            tracing::error!(
+                "ALARM: lock slave of {} at {:#04x} stopped responding",
+                door.name,
+                slave.address()
+            );
+        }
+        Some(HealthChange::Recovered) => {
+            tracing::info!(
+                "Lock slave of {} at {:#04x} is responding again",
+                door.name,
+                slave.address()
+            );
+        }
+        None => {}
+    }
+}
+
 pub fn main() -> eyre::Result<()> {
     esp_idf_svc::sys::link_patches();
     tracing::init()?;
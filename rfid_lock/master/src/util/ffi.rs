@@ -0,0 +1,5 @@
+pub mod esp {
+    pub macro esp_unsafe($expr:expr) {
+        esp_idf_svc::sys::esp!(unsafe { $expr })
+    }
+}
@@ -1,2 +1,3 @@
+pub mod ffi;
 pub mod result;
 pub mod tracing;
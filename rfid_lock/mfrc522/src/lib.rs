@@ -0,0 +1,528 @@
+//! Driver for the NXP MFRC522 reader, ISO/IEC 14443 type A cards over an embedded-hal SPI
+//! device. Kept apart from the firmware so it's tested on the host against SPI transcripts.
+
+use std::{fmt, str::FromStr};
+
+use embedded_hal::{
+    delay::DelayNs,
+    spi::{Operation, SpiDevice},
+};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+/// The longest UID a card can have, three cascade levels
+pub const MAX_UID_LEN: usize = 10;
+
+const FIFO_SIZE: usize = 64;
+/// Upper bound on polls of the interrupt register, the chip's own timer should always fire first
+const MAX_IRQ_POLLS: u32 = 2000;
+const MAX_RESET_POLLS: u32 = 10;
+
+mod reg {
+    pub const COMMAND: u8 = 0x01;
+    pub const COM_IRQ: u8 = 0x04;
+    pub const ERROR: u8 = 0x06;
+    pub const FIFO_DATA: u8 = 0x09;
+    pub const FIFO_LEVEL: u8 = 0x0A;
+    pub const CONTROL: u8 = 0x0C;
+    pub const BIT_FRAMING: u8 = 0x0D;
+    pub const COLL: u8 = 0x0E;
+    pub const MODE: u8 = 0x11;
+    pub const TX_CONTROL: u8 = 0x14;
+    pub const TX_ASK: u8 = 0x15;
+    pub const T_MODE: u8 = 0x2A;
+    pub const T_PRESCALER: u8 = 0x2B;
+    pub const T_RELOAD_H: u8 = 0x2C;
+    pub const T_RELOAD_L: u8 = 0x2D;
+    pub const VERSION: u8 = 0x37;
+}
+
+mod command {
+    pub const IDLE: u8 = 0x00;
+    pub const TRANSCEIVE: u8 = 0x0C;
+    pub const SOFT_RESET: u8 = 0x0F;
+}
+
+/// ISO/IEC 14443-3 type A card commands
+mod picc {
+    pub const REQA: u8 = 0x26;
+    pub const WUPA: u8 = 0x52;
+    pub const HLTA: u8 = 0x50;
+    /// Select commands of cascade levels 1 to 3
+    pub const SEL_CL: [u8; 3] = [0x93, 0x95, 0x97];
+    /// NVB of a full select, all 7 bytes known
+    pub const NVB_SELECT: u8 = 0x70;
+    /// Cascade tag, the UID continues on the next level
+    pub const CT: u8 = 0x88;
+    /// SAK bit set while the UID isn't complete
+    pub const SAK_CASCADE: u8 = 0x04;
+}
+
+const COMMAND_POWER_DOWN: u8 = 0x10;
+const IRQ_TIMER: u8 = 0x01;
+const IRQ_IDLE: u8 = 0x10;
+const IRQ_RX: u8 = 0x20;
+const IRQ_ALL: u8 = 0x7F;
+const ERROR_BUFFER_OVERFLOW: u8 = 0x10;
+const ERROR_COLLISION: u8 = 0x08;
+const ERROR_PARITY: u8 = 0x02;
+const ERROR_PROTOCOL: u8 = 0x01;
+const FIFO_FLUSH: u8 = 0x80;
+const BIT_FRAMING_START_SEND: u8 = 0x80;
+const COLL_VALUES_AFTER_COLL: u8 = 0x80;
+const COLL_POS_NOT_VALID: u8 = 0x20;
+const TX_ANTENNA_ON: u8 = 0x03;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error<E> {
+    #[error("SPI transfer failed: {0:?}")]
+    Spi(E),
+    #[error("MFRC522 not found, version register reads {0:#04x}")]
+    NotDetected(u8),
+    #[error("No response from card")]
+    Timeout,
+    #[error("Cards collided and the collision position is unknown")]
+    Collision,
+    #[error("Card response failed the CRC check")]
+    Crc,
+    #[error("Card UID failed the BCC check")]
+    Bcc,
+    #[error("Card response does not follow the protocol")]
+    Protocol,
+    #[error("Card response does not fit the buffer")]
+    BufferOverflow,
+}
+
+/// Answer to a REQA or WUPA
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Atqa(pub [u8; 2]);
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Uid {
+    bytes: [u8; MAX_UID_LEN],
+    len: u8,
+}
+
+impl Uid {
+    /// `None` unless `bytes` is 4, 7 or 10 bytes long
+    pub fn new(bytes: &[u8]) -> Option<Self> {
+        if !matches!(bytes.len(), 4 | 7 | 10) {
+            return None;
+        }
+
+        let mut uid = Self {
+            bytes: [0; MAX_UID_LEN],
+            len: bytes.len() as u8,
+        };
+        uid.bytes[..bytes.len()].copy_from_slice(bytes);
+
+        Some(uid)
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len as usize]
+    }
+}
+
+/// Colon separated hex, e.g. `04:A2:3B:1C`
+impl fmt::Display for Uid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, byte) in self.as_bytes().iter().enumerate() {
+            if i > 0 {
+                f.write_str(":")?;
+            }
+            write!(f, "{:02X}", byte)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Uid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Uid({})", self)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("UID must be 4, 7 or 10 hex bytes")]
+pub struct ParseUidError;
+
+/// Hex with or without colons, e.g. `04:A2:3B:1C` or `04a23b1c`
+impl FromStr for Uid {
+    type Err = ParseUidError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let digits = s.as_bytes().iter().filter(|&&c| c != b':');
+        let mut bytes = [0u8; MAX_UID_LEN];
+        let mut len = 0;
+        let mut high = None;
+
+        for &c in digits {
+            let nibble = (c as char).to_digit(16).ok_or(ParseUidError)? as u8;
+            match high.take() {
+                None => high = Some(nibble),
+                Some(high) => {
+                    *bytes.get_mut(len).ok_or(ParseUidError)? = high << 4 | nibble;
+                    len += 1;
+                }
+            }
+        }
+
+        if high.is_some() {
+            return Err(ParseUidError);
+        }
+
+        Self::new(&bytes[..len]).ok_or(ParseUidError)
+    }
+}
+
+impl Serialize for Uid {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+impl<'de> Deserialize<'de> for Uid {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+/// A selected card
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Card {
+    pub uid: Uid,
+    /// SAK of the last cascade level, tells the card type
+    pub sak: u8,
+}
+
+struct Received {
+    len: usize,
+    /// Valid bits of the last byte, 0 when it's whole
+    last_bits: u8,
+}
+
+/// NXP MFRC522 ISO 14443 type A reader on an SPI bus
+pub struct Mfrc522<SPI> {
+    spi: SPI,
+}
+
+impl<SPI: SpiDevice> Mfrc522<SPI> {
+    pub fn new(spi: SPI) -> Self {
+        Self { spi }
+    }
+
+    pub fn release(self) -> SPI {
+        self.spi
+    }
+
+    /// Resets the chip and turns the antenna on, fails if there's no MFRC522 on the bus
+    pub fn init(&mut self, delay: &mut impl DelayNs) -> Result<(), Error<SPI::Error>> {
+        let version = self.version()?;
+        if version == 0x00 || version == 0xFF {
+            return Err(Error::NotDetected(version));
+        }
+
+        self.write(reg::COMMAND, command::SOFT_RESET)?;
+        let mut polls = 0;
+        loop {
+            // The oscillator takes a while to start after the reset
+            delay.delay_ms(50);
+            if self.read(reg::COMMAND)? & COMMAND_POWER_DOWN == 0 {
+                break;
+            }
+
+            polls += 1;
+            if polls >= MAX_RESET_POLLS {
+                return Err(Error::Timeout);
+            }
+        }
+
+        // Timer starts when a transmission ends, 40 kHz ticks reloaded at 1000 time out after 25 ms
+        self.write(reg::T_MODE, 0x80)?;
+        self.write(reg::T_PRESCALER, 0xA9)?;
+        self.write(reg::T_RELOAD_H, 0x03)?;
+        self.write(reg::T_RELOAD_L, 0xE8)?;
+        // 100% ASK modulation
+        self.write(reg::TX_ASK, 0x40)?;
+        // CRC preset 0x6363, per ISO 14443-3
+        self.write(reg::MODE, 0x3D)?;
+        self.set_bits(reg::TX_CONTROL, TX_ANTENNA_ON)?;
+
+        Ok(())
+    }
+
+    /// 0x91 or 0x92 for genuine chips, 0x88 and 0x12 are common clones
+    pub fn version(&mut self) -> Result<u8, Error<SPI::Error>> {
+        self.read(reg::VERSION)
+    }
+
+    /// Wakes cards in the idle state, `None` if there are none in the field
+    pub fn request_a(&mut self) -> Result<Option<Atqa>, Error<SPI::Error>> {
+        self.request(picc::REQA)
+    }
+
+    /// Like [`Self::request_a`] but also wakes halted cards
+    pub fn wake_up_a(&mut self) -> Result<Option<Atqa>, Error<SPI::Error>> {
+        self.request(picc::WUPA)
+    }
+
+    fn request(&mut self, cmd: u8) -> Result<Option<Atqa>, Error<SPI::Error>> {
+        let mut atqa = [0u8; 2];
+        match self.transceive(&[cmd], 7, &mut atqa, 0) {
+            Ok(Received {
+                len: 2,
+                last_bits: 0,
+            }) => Ok(Some(Atqa(atqa))),
+            Ok(_) => Err(Error::Protocol),
+            Err(Error::Timeout) => Ok(None),
+            // More than one card answered, they are told apart during select
+            Err(Error::Collision) => Ok(Some(Atqa(atqa))),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Runs anticollision and select over all cascade levels. One of the cards that answered
+    /// the last request is selected, the others stay quiet until the next request.
+    pub fn select(&mut self) -> Result<Card, Error<SPI::Error>> {
+        let mut uid = [0u8; MAX_UID_LEN];
+        let mut uid_len = 0;
+
+        for sel in picc::SEL_CL {
+            // SEL, NVB, 4 UID bytes or CT and 3 bytes, BCC and CRC_A
+            let mut frame = [0u8; 9];
+            frame[0] = sel;
+            let mut known_bits = 0usize;
+
+            let sak = loop {
+                if known_bits >= 32 {
+                    frame[1] = picc::NVB_SELECT;
+                    frame[6] = frame[2] ^ frame[3] ^ frame[4] ^ frame[5];
+                    let crc = crc_a(&frame[..7]);
+                    frame[7..9].copy_from_slice(&crc);
+
+                    let mut sak = [0u8; 3];
+                    let received = self.transceive(&frame, 0, &mut sak, 0)?;
+                    if received.len != 3 || received.last_bits != 0 {
+                        return Err(Error::Protocol);
+                    }
+                    if crc_a(&sak[..1]) != sak[1..3] {
+                        return Err(Error::Crc);
+                    }
+
+                    break sak[0];
+                }
+
+                let whole_bytes = known_bits / 8;
+                let tx_last_bits = (known_bits % 8) as u8;
+                let index = 2 + whole_bytes;
+                frame[1] = ((index as u8) << 4) | tx_last_bits;
+                let tx_len = index + usize::from(tx_last_bits > 0);
+
+                // The card answers with the rest of the UID and BCC, the first byte received
+                // completes the partial byte sent
+                let expected = 7 - index;
+                let mut response = [0u8; 5];
+                let collided = match self.transceive(
+                    &frame[..tx_len],
+                    tx_last_bits,
+                    &mut response[..expected],
+                    tx_last_bits,
+                ) {
+                    Ok(Received { len, .. }) if len == expected => false,
+                    Ok(_) => return Err(Error::Protocol),
+                    Err(Error::Collision) => true,
+                    Err(e) => return Err(e),
+                };
+
+                let sent = !(0xFFu8 << tx_last_bits);
+                frame[index] = (frame[index] & sent) | (response[0] & !sent);
+                frame[index + 1..7].copy_from_slice(&response[1..expected]);
+
+                if !collided {
+                    if frame[2] ^ frame[3] ^ frame[4] ^ frame[5] != frame[6] {
+                        return Err(Error::Bcc);
+                    }
+
+                    known_bits = 32;
+                    continue;
+                }
+
+                let coll = self.read(reg::COLL)?;
+                if coll & COLL_POS_NOT_VALID != 0 {
+                    return Err(Error::Collision);
+                }
+
+                // 1 to 32, the bit in the UID part of the frame the cards disagree on
+                let position = match (coll & 0x1F) as usize {
+                    0 => 32,
+                    position => position,
+                };
+                if position <= known_bits {
+                    return Err(Error::Protocol);
+                }
+
+                // Picks the cards with a 1 at the collision, resent up to and including that bit
+                known_bits = position;
+                let bit = (position - 1) % 8;
+                let byte = 2 + (position - 1) / 8;
+                frame[byte] &= 0xFFu8 >> (7 - bit);
+                frame[byte] |= 1 << bit;
+            };
+
+            if frame[2] == picc::CT {
+                uid[uid_len..uid_len + 3].copy_from_slice(&frame[3..6]);
+                uid_len += 3;
+            } else {
+                uid[uid_len..uid_len + 4].copy_from_slice(&frame[2..6]);
+                uid_len += 4;
+            }
+
+            if sak & picc::SAK_CASCADE == 0 {
+                let uid = Uid::new(&uid[..uid_len]).ok_or(Error::Protocol)?;
+                return Ok(Card { uid, sak });
+            }
+        }
+
+        // Still cascading after the third level
+        Err(Error::Protocol)
+    }
+
+    /// Puts the selected card to sleep, it ignores requests until it leaves the field or is
+    /// woken with [`Self::wake_up_a`]
+    pub fn halt_a(&mut self) -> Result<(), Error<SPI::Error>> {
+        let mut frame = [picc::HLTA, 0x00, 0, 0];
+        let crc = crc_a(&frame[..2]);
+        frame[2..4].copy_from_slice(&crc);
+
+        // A halted card doesn't answer, an answer means the halt was rejected
+        match self.transceive(&frame, 0, &mut [], 0) {
+            Err(Error::Timeout) => Ok(()),
+            Ok(_) => Err(Error::Protocol),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Sends `data` and receives the card's answer into `response`. `tx_last_bits` is the
+    /// number of bits sent from the last byte, 0 for all of them. `rx_align` is the bit
+    /// position the first received bit is stored at.
+    fn transceive(
+        &mut self,
+        data: &[u8],
+        tx_last_bits: u8,
+        response: &mut [u8],
+        rx_align: u8,
+    ) -> Result<Received, Error<SPI::Error>> {
+        self.write(reg::COMMAND, command::IDLE)?;
+        self.write(reg::COM_IRQ, IRQ_ALL)?;
+        self.write(reg::FIFO_LEVEL, FIFO_FLUSH)?;
+        self.clear_bits(reg::COLL, COLL_VALUES_AFTER_COLL)?;
+        self.write_fifo(data)?;
+        self.write(reg::BIT_FRAMING, (rx_align << 4) | tx_last_bits)?;
+        self.write(reg::COMMAND, command::TRANSCEIVE)?;
+        self.set_bits(reg::BIT_FRAMING, BIT_FRAMING_START_SEND)?;
+
+        let mut polls = 0;
+        loop {
+            let irq = self.read(reg::COM_IRQ)?;
+            if irq & (IRQ_RX | IRQ_IDLE) != 0 {
+                break;
+            }
+            if irq & IRQ_TIMER != 0 {
+                return Err(Error::Timeout);
+            }
+
+            polls += 1;
+            if polls >= MAX_IRQ_POLLS {
+                return Err(Error::Timeout);
+            }
+        }
+
+        let error = self.read(reg::ERROR)?;
+        if error & (ERROR_BUFFER_OVERFLOW | ERROR_PARITY | ERROR_PROTOCOL) != 0 {
+            return Err(Error::Protocol);
+        }
+
+        let len = self.read(reg::FIFO_LEVEL)? as usize;
+        if len > response.len() {
+            return Err(Error::BufferOverflow);
+        }
+        self.read_fifo(&mut response[..len])?;
+        let last_bits = self.read(reg::CONTROL)? & 0x07;
+
+        if error & ERROR_COLLISION != 0 {
+            return Err(Error::Collision);
+        }
+
+        Ok(Received { len, last_bits })
+    }
+
+    fn read(&mut self, reg: u8) -> Result<u8, Error<SPI::Error>> {
+        let mut buf = [read_address(reg), 0];
+        self.spi.transfer_in_place(&mut buf).map_err(Error::Spi)?;
+
+        Ok(buf[1])
+    }
+
+    fn write(&mut self, reg: u8, value: u8) -> Result<(), Error<SPI::Error>> {
+        self.spi
+            .write(&[write_address(reg), value])
+            .map_err(Error::Spi)
+    }
+
+    fn set_bits(&mut self, reg: u8, mask: u8) -> Result<(), Error<SPI::Error>> {
+        let value = self.read(reg)?;
+        self.write(reg, value | mask)
+    }
+
+    fn clear_bits(&mut self, reg: u8, mask: u8) -> Result<(), Error<SPI::Error>> {
+        let value = self.read(reg)?;
+        self.write(reg, value & !mask)
+    }
+
+    fn write_fifo(&mut self, data: &[u8]) -> Result<(), Error<SPI::Error>> {
+        self.spi
+            .transaction(&mut [
+                Operation::Write(&[write_address(reg::FIFO_DATA)]),
+                Operation::Write(data),
+            ])
+            .map_err(Error::Spi)
+    }
+
+    fn read_fifo(&mut self, buf: &mut [u8]) -> Result<(), Error<SPI::Error>> {
+        if buf.is_empty() {
+            return Ok(());
+        }
+
+        // Each byte clocked in is the address of the next read, the last one ends the burst
+        let mut transfer = [read_address(reg::FIFO_DATA); FIFO_SIZE + 1];
+        let transfer = &mut transfer[..=buf.len()];
+        transfer[buf.len()] = 0;
+        self.spi.transfer_in_place(transfer).map_err(Error::Spi)?;
+        buf.copy_from_slice(&transfer[1..]);
+
+        Ok(())
+    }
+}
+
+fn read_address(reg: u8) -> u8 {
+    0x80 | (reg << 1)
+}
+
+fn write_address(reg: u8) -> u8 {
+    reg << 1
+}
+
+/// CRC_A of ISO/IEC 14443-3, least significant byte first
+pub fn crc_a(data: &[u8]) -> [u8; 2] {
+    let mut crc: u16 = 0x6363;
+    for &byte in data {
+        let mut byte = byte ^ (crc as u8);
+        byte ^= byte << 4;
+        crc = (crc >> 8) ^ ((byte as u16) << 8) ^ ((byte as u16) << 3) ^ ((byte as u16) >> 4);
+    }
+
+    crc.to_le_bytes()
+}
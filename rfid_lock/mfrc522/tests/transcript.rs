@@ -0,0 +1,395 @@
+//! The driver against recorded SPI traffic. Every register access is its own SPI transaction,
+//! the transcripts are built from the same steps the driver takes.
+
+use embedded_hal_mock::eh1::{
+    delay::NoopDelay,
+    spi::{Mock, Transaction},
+};
+use rfid_lock_mfrc522::{crc_a, Atqa, Card, Error, Mfrc522, Uid};
+
+const COMMAND: u8 = 0x01;
+const COM_IRQ: u8 = 0x04;
+const ERROR: u8 = 0x06;
+const FIFO_DATA: u8 = 0x09;
+const FIFO_LEVEL: u8 = 0x0A;
+const CONTROL: u8 = 0x0C;
+const BIT_FRAMING: u8 = 0x0D;
+const COLL: u8 = 0x0E;
+const VERSION: u8 = 0x37;
+
+const IRQ_TIMER: u8 = 0x01;
+const IRQ_RX_IDLE: u8 = 0x30;
+const ERROR_COLLISION: u8 = 0x08;
+
+/// What the chip answers to one transceive
+struct Answer<'a> {
+    irq: u8,
+    error: u8,
+    fifo: &'a [u8],
+    last_bits: u8,
+}
+
+impl<'a> Answer<'a> {
+    fn received(fifo: &'a [u8]) -> Self {
+        Self {
+            irq: IRQ_RX_IDLE,
+            error: 0,
+            fifo,
+            last_bits: 0,
+        }
+    }
+
+    fn collided(fifo: &'a [u8]) -> Self {
+        Self {
+            error: ERROR_COLLISION,
+            ..Self::received(fifo)
+        }
+    }
+
+    fn timeout() -> Self {
+        Self {
+            irq: IRQ_TIMER,
+            ..Self::received(&[])
+        }
+    }
+}
+
+#[derive(Default)]
+struct Transcript(Vec<Transaction<u8>>);
+
+impl Transcript {
+    fn read(&mut self, reg: u8, value: u8) -> &mut Self {
+        self.0.extend([
+            Transaction::transaction_start(),
+            Transaction::transfer_in_place(vec![0x80 | reg << 1, 0], vec![0, value]),
+            Transaction::transaction_end(),
+        ]);
+        self
+    }
+
+    fn write(&mut self, reg: u8, value: u8) -> &mut Self {
+        self.0.extend([
+            Transaction::transaction_start(),
+            Transaction::write_vec(vec![reg << 1, value]),
+            Transaction::transaction_end(),
+        ]);
+        self
+    }
+
+    fn transceive(
+        &mut self,
+        data: &[u8],
+        tx_last_bits: u8,
+        rx_align: u8,
+        answer: Answer,
+    ) -> &mut Self {
+        self.write(COMMAND, 0x00)
+            .write(COM_IRQ, 0x7F)
+            .write(FIFO_LEVEL, 0x80)
+            .read(COLL, 0x80)
+            .write(COLL, 0x00);
+        self.0.extend([
+            Transaction::transaction_start(),
+            Transaction::write_vec(vec![FIFO_DATA << 1]),
+            Transaction::write_vec(data.to_vec()),
+            Transaction::transaction_end(),
+        ]);
+        self.write(BIT_FRAMING, rx_align << 4 | tx_last_bits)
+            .write(COMMAND, 0x0C)
+            .read(BIT_FRAMING, rx_align << 4 | tx_last_bits)
+            .write(BIT_FRAMING, 0x80 | rx_align << 4 | tx_last_bits)
+            .read(COM_IRQ, answer.irq);
+        if answer.irq & IRQ_RX_IDLE == 0 {
+            return self;
+        }
+
+        self.read(ERROR, answer.error)
+            .read(FIFO_LEVEL, answer.fifo.len() as u8);
+        if !answer.fifo.is_empty() {
+            let mut sent = vec![0x80 | FIFO_DATA << 1; answer.fifo.len() + 1];
+            *sent.last_mut().unwrap() = 0;
+            let mut received = vec![0];
+            received.extend_from_slice(answer.fifo);
+
+            self.0.extend([
+                Transaction::transaction_start(),
+                Transaction::transfer_in_place(sent, received),
+                Transaction::transaction_end(),
+            ]);
+        }
+        self.read(CONTROL, answer.last_bits)
+    }
+
+    /// Anticollision and select of one cascade level with a single card in the field
+    fn cascade_level(&mut self, sel: u8, part: [u8; 4], sak: u8) -> &mut Self {
+        let bcc = bcc(&part);
+        let mut answer = part.to_vec();
+        answer.push(bcc);
+        self.transceive(&[sel, 0x20], 0, 0, Answer::received(&answer));
+        self.select(sel, part, sak)
+    }
+
+    fn select(&mut self, sel: u8, part: [u8; 4], sak: u8) -> &mut Self {
+        let bcc = bcc(&part);
+        let mut frame = vec![sel, 0x70];
+        frame.extend_from_slice(&part);
+        frame.push(bcc);
+        frame.extend_from_slice(&crc_a(&frame));
+
+        let mut answer = vec![sak];
+        answer.extend_from_slice(&crc_a(&[sak]));
+        self.transceive(&frame, 0, 0, Answer::received(&answer))
+    }
+
+    fn driver(&self) -> Mfrc522<Mock<u8>> {
+        Mfrc522::new(Mock::new(&self.0))
+    }
+}
+
+fn bcc(part: &[u8]) -> u8 {
+    part.iter().fold(0, |bcc, byte| bcc ^ byte)
+}
+
+fn uid(bytes: &[u8]) -> Uid {
+    Uid::new(bytes).unwrap()
+}
+
+#[test]
+fn crc_a_matches_the_standard() {
+    // ISO/IEC 14443-3 annex B
+    assert_eq!(crc_a(&[0x00, 0x00]), [0xA0, 0x1E]);
+    assert_eq!(crc_a(&[0x12, 0x34]), [0x26, 0xCF]);
+    // HLTA as every card expects it
+    assert_eq!(crc_a(&[0x50, 0x00]), [0x57, 0xCD]);
+    assert_eq!(crc_a(&[]), [0x63, 0x63]);
+}
+
+#[test]
+fn init_resets_and_turns_the_antenna_on() {
+    let mut t = Transcript::default();
+    t.read(VERSION, 0x92)
+        .write(COMMAND, 0x0F)
+        .read(COMMAND, 0x10)
+        .read(COMMAND, 0x00)
+        .write(0x2A, 0x80)
+        .write(0x2B, 0xA9)
+        .write(0x2C, 0x03)
+        .write(0x2D, 0xE8)
+        .write(0x15, 0x40)
+        .write(0x11, 0x3D)
+        .read(0x14, 0x80)
+        .write(0x14, 0x83);
+
+    let mut driver = t.driver();
+    driver.init(&mut NoopDelay::new()).unwrap();
+    driver.release().done();
+}
+
+#[test]
+fn init_fails_without_a_chip() {
+    let mut t = Transcript::default();
+    t.read(VERSION, 0xFF);
+
+    let mut driver = t.driver();
+    assert!(matches!(
+        driver.init(&mut NoopDelay::new()),
+        Err(Error::NotDetected(0xFF))
+    ));
+    driver.release().done();
+}
+
+#[test]
+fn request_reports_an_empty_field_as_none() {
+    let mut t = Transcript::default();
+    t.transceive(&[0x26], 7, 0, Answer::timeout());
+
+    let mut driver = t.driver();
+    assert_eq!(driver.request_a().unwrap(), None);
+    driver.release().done();
+}
+
+#[test]
+fn request_returns_the_atqa() {
+    let mut t = Transcript::default();
+    t.transceive(&[0x52], 7, 0, Answer::received(&[0x44, 0x00]));
+
+    let mut driver = t.driver();
+    assert_eq!(driver.wake_up_a().unwrap(), Some(Atqa([0x44, 0x00])));
+    driver.release().done();
+}
+
+#[test]
+fn selects_a_4_byte_uid() {
+    let mut t = Transcript::default();
+    t.cascade_level(0x93, [0xDE, 0xAD, 0xBE, 0xEF], 0x08);
+
+    let mut driver = t.driver();
+    assert_eq!(
+        driver.select().unwrap(),
+        Card {
+            uid: uid(&[0xDE, 0xAD, 0xBE, 0xEF]),
+            sak: 0x08,
+        }
+    );
+    driver.release().done();
+}
+
+#[test]
+fn selects_a_7_byte_uid_over_two_levels() {
+    let mut t = Transcript::default();
+    t.cascade_level(0x93, [0x88, 0x04, 0xA2, 0x3B], 0x04)
+        .cascade_level(0x95, [0x1C, 0x5E, 0x80, 0x01], 0x00);
+
+    let mut driver = t.driver();
+    assert_eq!(
+        driver.select().unwrap(),
+        Card {
+            uid: uid(&[0x04, 0xA2, 0x3B, 0x1C, 0x5E, 0x80, 0x01]),
+            sak: 0x00,
+        }
+    );
+    driver.release().done();
+}
+
+#[test]
+fn selects_a_10_byte_uid_over_three_levels() {
+    let mut t = Transcript::default();
+    t.cascade_level(0x93, [0x88, 0x01, 0x02, 0x03], 0x04)
+        .cascade_level(0x95, [0x88, 0x04, 0x05, 0x06], 0x04)
+        .cascade_level(0x97, [0x07, 0x08, 0x09, 0x0A], 0x20);
+
+    let mut driver = t.driver();
+    let card = driver.select().unwrap();
+    assert_eq!(card.uid, uid(&[1, 2, 3, 4, 5, 6, 7, 8, 9, 10]));
+    assert_eq!(card.sak, 0x20);
+    driver.release().done();
+}
+
+#[test]
+fn fails_when_the_card_still_cascades_after_three_levels() {
+    let mut t = Transcript::default();
+    t.cascade_level(0x93, [0x88, 0x01, 0x02, 0x03], 0x04)
+        .cascade_level(0x95, [0x88, 0x04, 0x05, 0x06], 0x04)
+        .cascade_level(0x97, [0x88, 0x07, 0x08, 0x09], 0x04);
+
+    let mut driver = t.driver();
+    assert!(matches!(driver.select(), Err(Error::Protocol)));
+    driver.release().done();
+}
+
+#[test]
+fn resolves_a_collision_by_picking_the_card_with_the_bit_set() {
+    // 0x11 and 0x01 differ at the fifth bit of the first byte. The bits before it come through,
+    // the colliding bit and the ones after it read as 0.
+    let mut t = Transcript::default();
+    t.transceive(&[0x93, 0x20], 0, 0, Answer::collided(&[0x01]))
+        .read(COLL, 0x05);
+    // Four bits known plus the chosen one, sent as a partial byte, the card with a 1 there
+    // answers with the rest of the byte and the UID
+    let bcc = bcc(&[0x11, 0x22, 0x33, 0x44]);
+    t.transceive(
+        &[0x93, 0x25, 0x11],
+        5,
+        5,
+        Answer::received(&[0x00, 0x22, 0x33, 0x44, bcc]),
+    )
+    .select(0x93, [0x11, 0x22, 0x33, 0x44], 0x08);
+
+    let mut driver = t.driver();
+    assert_eq!(driver.select().unwrap().uid, uid(&[0x11, 0x22, 0x33, 0x44]));
+    driver.release().done();
+}
+
+#[test]
+fn resolves_a_collision_in_a_later_byte() {
+    // The cards agree on the first two bytes and differ at the first bit of the third
+    let mut t = Transcript::default();
+    t.transceive(&[0x93, 0x20], 0, 0, Answer::collided(&[0xAB, 0xCD, 0x00]))
+        .read(COLL, 17);
+    let bcc = bcc(&[0xAB, 0xCD, 0x01, 0x99]);
+    t.transceive(
+        &[0x93, 0x41, 0xAB, 0xCD, 0x01],
+        1,
+        1,
+        Answer::received(&[0x00, 0x99, bcc]),
+    )
+    .select(0x93, [0xAB, 0xCD, 0x01, 0x99], 0x08);
+
+    let mut driver = t.driver();
+    assert_eq!(driver.select().unwrap().uid, uid(&[0xAB, 0xCD, 0x01, 0x99]));
+    driver.release().done();
+}
+
+#[test]
+fn fails_on_a_collision_at_an_unknown_position() {
+    let mut t = Transcript::default();
+    t.transceive(&[0x93, 0x20], 0, 0, Answer::collided(&[]))
+        .read(COLL, 0x20);
+
+    let mut driver = t.driver();
+    assert!(matches!(driver.select(), Err(Error::Collision)));
+    driver.release().done();
+}
+
+#[test]
+fn rejects_a_uid_with_a_bad_bcc() {
+    let mut t = Transcript::default();
+    t.transceive(
+        &[0x93, 0x20],
+        0,
+        0,
+        Answer::received(&[0x01, 0x02, 0x03, 0x04, 0xFF]),
+    );
+
+    let mut driver = t.driver();
+    assert!(matches!(driver.select(), Err(Error::Bcc)));
+    driver.release().done();
+}
+
+#[test]
+fn rejects_a_sak_with_a_bad_crc() {
+    let bcc = bcc(&[0x01, 0x02, 0x03, 0x04]);
+    let mut frame = vec![0x93, 0x70, 0x01, 0x02, 0x03, 0x04, bcc];
+    frame.extend_from_slice(&crc_a(&frame));
+
+    let mut t = Transcript::default();
+    t.transceive(&[0x93, 0x20], 0, 0, Answer::received(&[1, 2, 3, 4, bcc]))
+        .transceive(&frame, 0, 0, Answer::received(&[0x08, 0x00, 0x00]));
+
+    let mut driver = t.driver();
+    assert!(matches!(driver.select(), Err(Error::Crc)));
+    driver.release().done();
+}
+
+#[test]
+fn halt_succeeds_when_the_card_stays_quiet() {
+    let mut t = Transcript::default();
+    t.transceive(&[0x50, 0x00, 0x57, 0xCD], 0, 0, Answer::timeout());
+
+    let mut driver = t.driver();
+    driver.halt_a().unwrap();
+    driver.release().done();
+}
+
+#[test]
+fn halt_fails_when_the_card_answers() {
+    let mut t = Transcript::default();
+    t.transceive(&[0x50, 0x00, 0x57, 0xCD], 0, 0, Answer::received(&[]));
+
+    let mut driver = t.driver();
+    assert!(matches!(driver.halt_a(), Err(Error::Protocol)));
+    driver.release().done();
+}
+
+#[test]
+fn parses_and_prints_uids() {
+    let uid: Uid = "04:a2:3b:1c".parse().unwrap();
+    assert_eq!(uid.as_bytes(), [0x04, 0xA2, 0x3B, 0x1C]);
+    assert_eq!(uid.to_string(), "04:A2:3B:1C");
+    assert_eq!("04A23B1C5E8001".parse::<Uid>().unwrap().as_bytes().len(), 7);
+
+    assert!("04:A2:3B".parse::<Uid>().is_err());
+    assert!("04:A2:3B:1".parse::<Uid>().is_err());
+    assert!("04:A2:3B:XY".parse::<Uid>().is_err());
+    assert!(Uid::new(&[0; 5]).is_none());
+}
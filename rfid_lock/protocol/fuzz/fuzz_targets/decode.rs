@@ -0,0 +1,48 @@
+//! Bytes off the bus, as a slave or the master would receive them. Decoding must never panic,
+//! and whatever decodes has to encode back to the same message.
+//!
+//! `cargo +nightly fuzz run decode` from the protocol directory.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rfid_lock_protocol::{decode_frame, Message, Receiver, Request, Status, MAX_FRAME_LEN};
+
+fn round_trip<M: Message + PartialEq + core::fmt::Debug>(message: &M) {
+    let mut buf = [0u8; MAX_FRAME_LEN];
+    let len = message
+        .encode(&mut buf)
+        .expect("decoded message must encode");
+
+    assert_eq!(M::decode(&buf[..len]).as_ref(), Ok(message));
+}
+
+fuzz_target!(|data: &[u8]| {
+    let _ = decode_frame(data);
+
+    if let Ok(request) = Request::decode(data) {
+        round_trip(&request);
+    }
+    if let Ok(status) = Status::decode(data) {
+        round_trip(&status);
+    }
+
+    let mut requests = Receiver::<Request>::new();
+    let mut statuses = Receiver::<Status>::new();
+    for &byte in data {
+        requests.push(byte);
+        statuses.push(byte);
+        while let Some(request) = requests.poll() {
+            if let Ok(request) = request {
+                round_trip(&request);
+            }
+        }
+        while let Some(status) = statuses.poll() {
+            if let Ok(status) = status {
+                round_trip(&status);
+            }
+        }
+    }
+    while requests.poll_idle().is_some() {}
+    while statuses.poll_idle().is_some() {}
+});
@@ -0,0 +1,172 @@
+use core::fmt;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::{decode_frame, encode_frame, Command, Error, Message, MAX_PAYLOAD_LEN, VERSION};
+
+pub const KEY_LEN: usize = 32;
+/// Bytes of the HMAC-SHA256 that are sent, guessing 64 bits isn't practical over I2C
+pub const TAG_LEN: usize = 8;
+const NONCE_LEN: usize = 4;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Shared by the master and its slaves, commands that change the lock are signed with it
+#[derive(Clone)]
+pub struct Key([u8; KEY_LEN]);
+
+impl Key {
+    pub const fn new(bytes: [u8; KEY_LEN]) -> Self {
+        Self(bytes)
+    }
+
+    /// `None` unless `hex` is exactly 64 hex digits. Usable in consts, for keys given at build
+    /// time.
+    pub const fn from_hex(hex: &str) -> Option<Self> {
+        let hex = hex.as_bytes();
+        if hex.len() != KEY_LEN * 2 {
+            return None;
+        }
+
+        let mut bytes = [0u8; KEY_LEN];
+        let mut i = 0;
+        while i < KEY_LEN {
+            let (Some(high), Some(low)) = (hex_digit(hex[i * 2]), hex_digit(hex[i * 2 + 1])) else {
+                return None;
+            };
+            bytes[i] = high << 4 | low;
+            i += 1;
+        }
+
+        Some(Self(bytes))
+    }
+
+    fn mac(&self, command: &Command, nonce: u32) -> HmacSha256 {
+        let mut payload = [0u8; MAX_PAYLOAD_LEN];
+        let len = command.encode_payload(&mut payload);
+
+        let Ok(mut mac) = HmacSha256::new_from_slice(&self.0) else {
+            unreachable!("HMAC takes keys of any length");
+        };
+        mac.update(&[VERSION, command.id()]);
+        mac.update(&payload[..len]);
+        mac.update(&nonce.to_le_bytes());
+
+        mac
+    }
+}
+
+/// Doesn't print the key
+impl fmt::Debug for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Key(..)")
+    }
+}
+
+const fn hex_digit(c: u8) -> Option<u8> {
+    match c {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    }
+}
+
+/// A command as sent over the bus.
+///
+/// Commands that require authentication are followed by the slave's current nonce and a tag,
+/// the truncated HMAC of the version, command and nonce. The slave hands out a new nonce in
+/// every status, so a recorded command can't be replayed. Statuses also answer unsigned
+/// commands, a device on the bus can't forge commands but it can make the master's go stale.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Request {
+    command: Command,
+    auth: Option<(u32, [u8; TAG_LEN])>,
+}
+
+impl Request {
+    /// Signs `command` if it requires authentication, `nonce` is the one in the slave's latest
+    /// status and is ignored otherwise
+    pub fn new(command: Command, key: &Key, nonce: u32) -> Self {
+        let auth = command.requires_auth().then(|| {
+            let mut tag = [0u8; TAG_LEN];
+            tag.copy_from_slice(&key.mac(&command, nonce).finalize().into_bytes()[..TAG_LEN]);
+
+            (nonce, tag)
+        });
+
+        Self { command, auth }
+    }
+
+    /// The command, before it's checked with [`Request::verify`]
+    pub fn command(&self) -> Command {
+        self.command
+    }
+
+    /// The command if it was signed with `key` for `nonce`, or doesn't need to be signed
+    pub fn verify(&self, key: &Key, nonce: u32) -> Result<Command, Error> {
+        let Some((sent_nonce, tag)) = self.auth else {
+            if self.command.requires_auth() {
+                return Err(Error::Unauthenticated);
+            }
+
+            return Ok(self.command);
+        };
+
+        key.mac(&self.command, sent_nonce)
+            .verify_truncated_left(&tag)
+            .map_err(|_| Error::BadTag)?;
+
+        if sent_nonce != nonce {
+            return Err(Error::StaleNonce);
+        }
+
+        Ok(self.command)
+    }
+}
+
+impl Message for Request {
+    fn encode(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        let mut payload = [0u8; MAX_PAYLOAD_LEN];
+        let mut len = self.command.encode_payload(&mut payload);
+
+        if let Some((nonce, tag)) = self.auth {
+            payload[len..len + NONCE_LEN].copy_from_slice(&nonce.to_le_bytes());
+            len += NONCE_LEN;
+            payload[len..len + TAG_LEN].copy_from_slice(&tag);
+            len += TAG_LEN;
+        }
+
+        encode_frame(self.command.id(), &payload[..len], buf)
+    }
+
+    fn decode(buf: &[u8]) -> Result<Self, Error> {
+        let frame = decode_frame(buf)?;
+
+        if !Command::id_requires_auth(frame.id) {
+            return Ok(Self {
+                command: Command::decode_payload(frame.id, frame.payload)?,
+                auth: None,
+            });
+        }
+
+        let fields_len = frame
+            .payload
+            .len()
+            .checked_sub(NONCE_LEN + TAG_LEN)
+            .ok_or(Error::Unauthenticated)?;
+        let (fields, auth) = frame.payload.split_at(fields_len);
+        let (nonce, tag) = auth.split_at(NONCE_LEN);
+
+        let mut nonce_bytes = [0u8; NONCE_LEN];
+        nonce_bytes.copy_from_slice(nonce);
+        let mut tag_bytes = [0u8; TAG_LEN];
+        tag_bytes.copy_from_slice(tag);
+
+        Ok(Self {
+            command: Command::decode_payload(frame.id, fields)?,
+            auth: Some((u32::from_le_bytes(nonce_bytes), tag_bytes)),
+        })
+    }
+}
@@ -0,0 +1,138 @@
+use crate::{Error, MAX_PAYLOAD_LEN};
+
+const UNLOCK: u8 = 0x01;
+const LOCK: u8 = 0x02;
+const GET_STATUS: u8 = 0x03;
+const PING: u8 = 0x04;
+const CONFIGURE: u8 = 0x05;
+const INDICATE: u8 = 0x06;
+
+/// What the lock does without power
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LockPolicy {
+    /// Locked without power, the coil is powered to unlock
+    #[default]
+    FailSecure,
+    /// Unlocked without power, the coil is powered to lock
+    FailSafe,
+}
+
+impl LockPolicy {
+    pub const fn coil_powered(self, locked: bool) -> bool {
+        match self {
+            LockPolicy::FailSecure => !locked,
+            LockPolicy::FailSafe => locked,
+        }
+    }
+}
+
+/// What the slave's indicator LED shows
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Indicator {
+    /// Lit while unlocked
+    #[default]
+    Lock,
+    /// Blinks while the master is enrolling cards
+    Enrolling,
+}
+
+/// Sent by the master to a slave
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Command {
+    /// Releases the lock, the slave locks again after `duration_ms` or its maximum unlock
+    /// time, whichever is shorter. 0 unlocks for the maximum.
+    Unlock {
+        duration_ms: u32,
+    },
+    Lock,
+    GetStatus,
+    Ping,
+    /// Replaces the slave's settings until it restarts
+    Configure {
+        max_unlock_ms: u32,
+        policy: LockPolicy,
+    },
+    /// Switches what the indicator LED shows, until the slave restarts
+    Indicate {
+        indicator: Indicator,
+    },
+}
+
+impl Command {
+    pub const fn id(&self) -> u8 {
+        match self {
+            Command::Unlock { .. } => UNLOCK,
+            Command::Lock => LOCK,
+            Command::GetStatus => GET_STATUS,
+            Command::Ping => PING,
+            Command::Configure { .. } => CONFIGURE,
+            Command::Indicate { .. } => INDICATE,
+        }
+    }
+
+    /// Whether the command has to be signed, only the ones that change the lock do
+    pub const fn requires_auth(&self) -> bool {
+        Self::id_requires_auth(self.id())
+    }
+
+    pub(crate) const fn id_requires_auth(id: u8) -> bool {
+        matches!(id, UNLOCK | LOCK | CONFIGURE)
+    }
+
+    /// Writes the command's fields into `payload`, returns their length
+    pub(crate) fn encode_payload(&self, payload: &mut [u8; MAX_PAYLOAD_LEN]) -> usize {
+        match *self {
+            Command::Unlock { duration_ms } => {
+                payload[..4].copy_from_slice(&duration_ms.to_le_bytes());
+                4
+            }
+            Command::Configure {
+                max_unlock_ms,
+                policy,
+            } => {
+                payload[..4].copy_from_slice(&max_unlock_ms.to_le_bytes());
+                payload[4] = match policy {
+                    LockPolicy::FailSecure => 0,
+                    LockPolicy::FailSafe => 1,
+                };
+                5
+            }
+            Command::Indicate { indicator } => {
+                payload[0] = match indicator {
+                    Indicator::Lock => 0,
+                    Indicator::Enrolling => 1,
+                };
+                1
+            }
+            Command::Lock | Command::GetStatus | Command::Ping => 0,
+        }
+    }
+
+    pub(crate) fn decode_payload(id: u8, payload: &[u8]) -> Result<Self, Error> {
+        match (id, payload) {
+            (UNLOCK, &[a, b, c, d]) => Ok(Command::Unlock {
+                duration_ms: u32::from_le_bytes([a, b, c, d]),
+            }),
+            (LOCK, []) => Ok(Command::Lock),
+            (GET_STATUS, []) => Ok(Command::GetStatus),
+            (PING, []) => Ok(Command::Ping),
+            (CONFIGURE, &[a, b, c, d, policy]) => Ok(Command::Configure {
+                max_unlock_ms: u32::from_le_bytes([a, b, c, d]),
+                policy: match policy {
+                    0 => LockPolicy::FailSecure,
+                    1 => LockPolicy::FailSafe,
+                    _ => return Err(Error::BadPayload),
+                },
+            }),
+            (INDICATE, &[indicator]) => Ok(Command::Indicate {
+                indicator: match indicator {
+                    0 => Indicator::Lock,
+                    1 => Indicator::Enrolling,
+                    _ => return Err(Error::BadPayload),
+                },
+            }),
+            (UNLOCK | LOCK | GET_STATUS | PING | CONFIGURE | INDICATE, _) => Err(Error::BadPayload),
+            (id, _) => Err(Error::UnknownMessage(id)),
+        }
+    }
+}
@@ -0,0 +1,179 @@
+//! Frames exchanged between the rfid_lock master and its slaves over I2C.
+//!
+//! A frame is a magic byte, the protocol version, a message id, the payload length, the
+//! payload and a CRC-8 over everything before it. Multi-byte payload fields are little
+//! endian. Commands go from the master to a slave wrapped in a [`Request`], the ones that
+//! change the lock are signed. A slave answers with its status. Nothing is allocated, frames
+//! are written to and read from caller buffers.
+
+#![no_std]
+
+use core::fmt;
+
+mod auth;
+mod command;
+mod receiver;
+mod status;
+
+pub use auth::{Key, Request, KEY_LEN, TAG_LEN};
+pub use command::{Command, Indicator, LockPolicy};
+pub use receiver::Receiver;
+pub use status::{ErrorFlags, FirmwareVersion, LastCommand, Status};
+
+/// I2C address of a slave with its address straps open
+pub const DEFAULT_ADDRESS: u8 = 0x04;
+/// A slave adds the number set on its three address straps to [`DEFAULT_ADDRESS`]
+pub const MAX_SLAVES: u8 = 8;
+
+/// Address of the slave strapped to `index`, `None` past [`MAX_SLAVES`]
+pub const fn slave_address(index: u8) -> Option<u8> {
+    if index < MAX_SLAVES {
+        Some(DEFAULT_ADDRESS + index)
+    } else {
+        None
+    }
+}
+
+pub const MAGIC: u8 = 0xA5;
+pub const VERSION: u8 = 2;
+
+/// Magic, version, id and payload length
+pub const HEADER_LEN: usize = 4;
+pub const MAX_PAYLOAD_LEN: usize = 32;
+pub const MAX_FRAME_LEN: usize = HEADER_LEN + MAX_PAYLOAD_LEN + 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    /// The buffer can't hold the frame
+    BufferTooSmall,
+    /// Fewer bytes than the header says
+    Truncated,
+    BadMagic(u8),
+    UnsupportedVersion(u8),
+    PayloadTooLong(usize),
+    BadCrc {
+        expected: u8,
+        actual: u8,
+    },
+    UnknownMessage(u8),
+    /// The payload doesn't fit the message
+    BadPayload,
+    /// A command that has to be signed wasn't
+    Unauthenticated,
+    /// The command's tag doesn't match, it wasn't signed with the shared key
+    BadTag,
+    /// Signed for an earlier nonce, a replayed command
+    StaleNonce,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::BufferTooSmall => f.write_str("buffer too small for the frame"),
+            Error::Truncated => f.write_str("frame is truncated"),
+            Error::BadMagic(magic) => write!(f, "bad magic byte {:#04x}", magic),
+            Error::UnsupportedVersion(version) => {
+                write!(f, "unsupported protocol version {}", version)
+            }
+            Error::PayloadTooLong(len) => write!(f, "payload of {} bytes is too long", len),
+            Error::BadCrc { expected, actual } => {
+                write!(f, "CRC {:#04x} does not match {:#04x}", actual, expected)
+            }
+            Error::UnknownMessage(id) => write!(f, "unknown message id {:#04x}", id),
+            Error::BadPayload => f.write_str("payload does not match the message"),
+            Error::Unauthenticated => f.write_str("command is not signed"),
+            Error::BadTag => f.write_str("command signature does not match"),
+            Error::StaleNonce => f.write_str("command was signed for an old nonce"),
+        }
+    }
+}
+
+/// A message carried in one frame
+pub trait Message: Sized {
+    /// Writes the message's frame into `buf`, returns its length
+    fn encode(&self, buf: &mut [u8]) -> Result<usize, Error>;
+    /// Reads the frame at the start of `buf`, bytes after it are ignored
+    fn decode(buf: &[u8]) -> Result<Self, Error>;
+}
+
+/// A frame checked for its magic, version, length and CRC
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Frame<'a> {
+    pub id: u8,
+    pub payload: &'a [u8],
+}
+
+/// Writes a frame into `buf`, returns its length
+pub fn encode_frame(id: u8, payload: &[u8], buf: &mut [u8]) -> Result<usize, Error> {
+    if payload.len() > MAX_PAYLOAD_LEN {
+        return Err(Error::PayloadTooLong(payload.len()));
+    }
+
+    let len = HEADER_LEN + payload.len() + 1;
+    let buf = buf.get_mut(..len).ok_or(Error::BufferTooSmall)?;
+
+    buf[0] = MAGIC;
+    buf[1] = VERSION;
+    buf[2] = id;
+    buf[3] = payload.len() as u8;
+    buf[HEADER_LEN..len - 1].copy_from_slice(payload);
+    buf[len - 1] = crc8(&buf[..len - 1]);
+
+    Ok(len)
+}
+
+/// Reads the frame at the start of `buf`, bytes after it are ignored
+pub fn decode_frame(buf: &[u8]) -> Result<Frame<'_>, Error> {
+    let len = frame_len(buf)?.ok_or(Error::Truncated)?;
+    let frame = buf.get(..len).ok_or(Error::Truncated)?;
+
+    let expected = crc8(&frame[..len - 1]);
+    let actual = frame[len - 1];
+    if expected != actual {
+        return Err(Error::BadCrc { expected, actual });
+    }
+
+    Ok(Frame {
+        id: frame[2],
+        payload: &frame[HEADER_LEN..len - 1],
+    })
+}
+
+/// Length of the frame starting `buf` once enough of the header is there to tell
+fn frame_len(buf: &[u8]) -> Result<Option<usize>, Error> {
+    if let Some(&magic) = buf.first() {
+        if magic != MAGIC {
+            return Err(Error::BadMagic(magic));
+        }
+    }
+
+    if let Some(&version) = buf.get(1) {
+        if version != VERSION {
+            return Err(Error::UnsupportedVersion(version));
+        }
+    }
+
+    let Some(&payload_len) = buf.get(3) else {
+        return Ok(None);
+    };
+
+    let payload_len = payload_len as usize;
+    if payload_len > MAX_PAYLOAD_LEN {
+        return Err(Error::PayloadTooLong(payload_len));
+    }
+
+    Ok(Some(HEADER_LEN + payload_len + 1))
+}
+
+/// CRC-8 with polynomial 0x07, as used by SMBus
+pub fn crc8(data: &[u8]) -> u8 {
+    data.iter().fold(0u8, |crc, &byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x07
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
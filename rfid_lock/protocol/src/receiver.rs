@@ -0,0 +1,99 @@
+use core::marker::PhantomData;
+
+use crate::{frame_len, Error, Message, MAGIC, MAX_FRAME_LEN};
+
+/// Splits a byte stream into messages.
+///
+/// Bytes can arrive in any chunks, call [`Receiver::poll`] until it returns `None` after each
+/// [`Receiver::push`]. After a bad frame the bytes following its magic byte are searched for
+/// the next one, so a frame sent after garbage or after a cut off frame is still found.
+pub struct Receiver<M> {
+    buf: [u8; MAX_FRAME_LEN],
+    len: usize,
+    _phantom: PhantomData<M>,
+}
+
+impl<M: Message> Receiver<M> {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0; MAX_FRAME_LEN],
+            len: 0,
+            _phantom: PhantomData,
+        }
+    }
+
+    pub fn push(&mut self, byte: u8) {
+        if self.len == 0 && byte != MAGIC {
+            return;
+        }
+
+        // Only when `poll` wasn't called, a full buffer always holds a frame
+        if self.len == MAX_FRAME_LEN {
+            self.resync();
+        }
+
+        self.buf[self.len] = byte;
+        self.len += 1;
+    }
+
+    /// The next message or bad frame in the bytes pushed so far
+    pub fn poll(&mut self) -> Option<Result<M, Error>> {
+        if self.len == 0 {
+            return None;
+        }
+
+        match frame_len(&self.buf[..self.len]) {
+            Ok(Some(len)) if len <= self.len => {
+                let result = M::decode(&self.buf[..len]);
+                if result.is_ok() {
+                    self.consume(len);
+                } else {
+                    self.resync();
+                }
+
+                Some(result)
+            }
+            Ok(_) => None,
+            Err(e) => {
+                self.resync();
+                Some(Err(e))
+            }
+        }
+    }
+
+    /// Like [`Receiver::poll`], for when no more bytes are coming for now, e.g. the bus went
+    /// quiet. A partly received frame is given up on as truncated, frames starting inside it are
+    /// still found. Call it until it returns `None`.
+    pub fn poll_idle(&mut self) -> Option<Result<M, Error>> {
+        match self.poll() {
+            Some(result) => Some(result),
+            None if self.len > 0 => {
+                self.resync();
+                Some(Err(Error::Truncated))
+            }
+            None => None,
+        }
+    }
+
+    /// Drops the frame at the start, the bytes after it wait for the next call to `poll`
+    fn consume(&mut self, len: usize) {
+        let next = self.buf[len..self.len]
+            .iter()
+            .position(|&byte| byte == MAGIC)
+            .map_or(self.len, |position| len + position);
+
+        self.buf.copy_within(next..self.len, 0);
+        self.len -= next;
+    }
+
+    /// Skips the magic byte of a bad frame, the next one could start inside it
+    fn resync(&mut self) {
+        self.consume(1);
+    }
+}
+
+impl<M: Message> Default for Receiver<M> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
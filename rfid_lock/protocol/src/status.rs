@@ -0,0 +1,132 @@
+use crate::{decode_frame, encode_frame, Error, Message};
+
+const STATUS: u8 = 0x81;
+const PAYLOAD_LEN: usize = 20;
+
+/// Problems the slave ran into since it last reported its status
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ErrorFlags(u8);
+
+impl ErrorFlags {
+    /// A frame failed to decode
+    pub const INVALID_FRAME: Self = Self(0x01);
+    /// Driving the solenoid or indicator pin failed
+    pub const OUTPUT_FAULT: Self = Self(0x02);
+    /// A command failed authentication, someone may be writing to the bus
+    pub const AUTH_FAILED: Self = Self(0x04);
+
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    pub const fn from_bits(bits: u8) -> Self {
+        Self(bits)
+    }
+
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
+
+    pub const fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn insert(&mut self, other: Self) {
+        self.0 |= other.0;
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FirmwareVersion {
+    pub major: u8,
+    pub minor: u8,
+    pub patch: u8,
+}
+
+/// The command the slave executed last
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LastCommand {
+    pub id: u8,
+    /// Time from executing it to taking the status
+    pub elapsed_ms: u32,
+}
+
+/// Sent by a slave after each command it executes, the master reads it back to confirm
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Status {
+    pub locked: bool,
+    /// `None` until the first command after boot
+    pub last_command: Option<LastCommand>,
+    pub uptime_secs: u32,
+    pub errors: ErrorFlags,
+    /// Frames rejected since boot, invalid or unauthenticated. Wraps around.
+    pub rejected_frames: u16,
+    /// The next signed command has to be signed for this
+    pub nonce: u32,
+    pub firmware: FirmwareVersion,
+}
+
+impl Message for Status {
+    fn encode(&self, buf: &mut [u8]) -> Result<usize, Error> {
+        // A command id of 0 stands for no command yet
+        let (id, elapsed_ms) = self
+            .last_command
+            .map_or((0, 0), |last| (last.id, last.elapsed_ms));
+
+        let mut payload = [0u8; PAYLOAD_LEN];
+        payload[0] = self.locked as u8;
+        payload[1] = id;
+        payload[2..6].copy_from_slice(&elapsed_ms.to_le_bytes());
+        payload[6..10].copy_from_slice(&self.uptime_secs.to_le_bytes());
+        payload[10] = self.errors.bits();
+        payload[11] = self.firmware.major;
+        payload[12] = self.firmware.minor;
+        payload[13] = self.firmware.patch;
+        payload[14..16].copy_from_slice(&self.rejected_frames.to_le_bytes());
+        payload[16..20].copy_from_slice(&self.nonce.to_le_bytes());
+
+        encode_frame(STATUS, &payload, buf)
+    }
+
+    fn decode(buf: &[u8]) -> Result<Self, Error> {
+        let frame = decode_frame(buf)?;
+        if frame.id != STATUS {
+            return Err(Error::UnknownMessage(frame.id));
+        }
+
+        let payload: &[u8; PAYLOAD_LEN] =
+            frame.payload.try_into().map_err(|_| Error::BadPayload)?;
+        let locked = match payload[0] {
+            0 => false,
+            1 => true,
+            _ => return Err(Error::BadPayload),
+        };
+        let last_command = (payload[1] != 0).then(|| LastCommand {
+            id: payload[1],
+            elapsed_ms: u32::from_le_bytes([payload[2], payload[3], payload[4], payload[5]]),
+        });
+
+        Ok(Status {
+            locked,
+            last_command,
+            uptime_secs: u32::from_le_bytes([payload[6], payload[7], payload[8], payload[9]]),
+            errors: ErrorFlags::from_bits(payload[10]),
+            rejected_frames: u16::from_le_bytes([payload[14], payload[15]]),
+            nonce: u32::from_le_bytes([payload[16], payload[17], payload[18], payload[19]]),
+            firmware: FirmwareVersion {
+                major: payload[11],
+                minor: payload[12],
+                patch: payload[13],
+            },
+        })
+    }
+}
+
+impl Status {
+    /// Length of the status frame, what the master reads back
+    pub const FRAME_LEN: usize = crate::HEADER_LEN + PAYLOAD_LEN + 1;
+}
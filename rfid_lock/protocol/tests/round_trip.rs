@@ -0,0 +1,285 @@
+use rfid_lock_protocol::{
+    crc8, decode_frame, encode_frame, Command, Error, ErrorFlags, FirmwareVersion, Indicator, Key,
+    LastCommand, LockPolicy, Message, Receiver, Request, Status, MAX_FRAME_LEN, MAX_PAYLOAD_LEN,
+};
+
+const KEY: Key = Key::new([0x42; 32]);
+const NONCE: u32 = 0xDEAD_BEEF;
+
+const COMMANDS: [Command; 8] = [
+    Command::Unlock { duration_ms: 5000 },
+    Command::Unlock { duration_ms: 0 },
+    Command::Lock,
+    Command::GetStatus,
+    Command::Ping,
+    Command::Configure {
+        max_unlock_ms: u32::MAX,
+        policy: LockPolicy::FailSafe,
+    },
+    Command::Indicate {
+        indicator: Indicator::Enrolling,
+    },
+    Command::Indicate {
+        indicator: Indicator::Lock,
+    },
+];
+
+fn status() -> Status {
+    Status {
+        locked: true,
+        last_command: Some(LastCommand {
+            id: Command::Unlock { duration_ms: 0 }.id(),
+            elapsed_ms: 1234,
+        }),
+        uptime_secs: 86_400,
+        errors: ErrorFlags::AUTH_FAILED,
+        rejected_frames: 65_535,
+        nonce: NONCE,
+        firmware: FirmwareVersion {
+            major: 1,
+            minor: 2,
+            patch: 3,
+        },
+    }
+}
+
+fn encode(message: &impl Message) -> Vec<u8> {
+    let mut buf = [0u8; MAX_FRAME_LEN];
+    let len = message.encode(&mut buf).unwrap();
+
+    buf[..len].to_vec()
+}
+
+#[test]
+fn crc8_matches_smbus() {
+    assert_eq!(crc8(b"123456789"), 0xF4);
+    assert_eq!(crc8(&[]), 0x00);
+}
+
+#[test]
+fn frames_round_trip() {
+    for len in [0, 1, MAX_PAYLOAD_LEN] {
+        let payload: Vec<u8> = (0..len as u8).collect();
+        let mut buf = [0u8; MAX_FRAME_LEN + 4];
+        let written = encode_frame(0x42, &payload, &mut buf).unwrap();
+
+        let frame = decode_frame(&buf).unwrap();
+        assert_eq!(written, len + 5);
+        assert_eq!(frame.id, 0x42);
+        assert_eq!(frame.payload, payload);
+    }
+}
+
+#[test]
+fn frames_are_refused_when_they_dont_fit() {
+    let mut buf = [0u8; MAX_FRAME_LEN];
+    assert_eq!(
+        encode_frame(0x01, &[0; MAX_PAYLOAD_LEN + 1], &mut buf),
+        Err(Error::PayloadTooLong(MAX_PAYLOAD_LEN + 1))
+    );
+    assert_eq!(
+        encode_frame(0x01, &[0; 4], &mut buf[..8]),
+        Err(Error::BufferTooSmall)
+    );
+}
+
+#[test]
+fn bad_frames_are_told_apart() {
+    let mut frame = encode(&Request::new(Command::Ping, &KEY, 0));
+
+    assert_eq!(decode_frame(&frame[..3]), Err(Error::Truncated));
+    assert_eq!(decode_frame(&frame[..4]), Err(Error::Truncated));
+
+    frame[1] = 9;
+    assert_eq!(decode_frame(&frame), Err(Error::UnsupportedVersion(9)));
+    frame[0] = 0x5A;
+    assert_eq!(decode_frame(&frame), Err(Error::BadMagic(0x5A)));
+
+    let mut frame = encode(&Request::new(Command::Ping, &KEY, 0));
+    let crc = frame[4];
+    frame[4] ^= 0xFF;
+    assert_eq!(
+        decode_frame(&frame),
+        Err(Error::BadCrc {
+            expected: crc,
+            actual: crc ^ 0xFF
+        })
+    );
+}
+
+#[test]
+fn every_command_round_trips_and_verifies() {
+    for command in COMMANDS {
+        let request = Request::new(command, &KEY, NONCE);
+        let decoded = Request::decode(&encode(&request)).unwrap();
+
+        assert_eq!(decoded, request);
+        assert_eq!(decoded.command(), command);
+        assert_eq!(decoded.verify(&KEY, NONCE), Ok(command));
+    }
+}
+
+#[test]
+fn only_commands_that_change_the_lock_are_signed() {
+    for command in COMMANDS {
+        let unsigned = 5 + match command {
+            Command::Unlock { .. } => 4,
+            Command::Configure { .. } => 5,
+            Command::Indicate { .. } => 1,
+            _ => 0,
+        };
+        let len = encode(&Request::new(command, &KEY, NONCE)).len();
+
+        if command.requires_auth() {
+            assert_eq!(len, unsigned + 4 + 8, "{:?}", command);
+        } else {
+            assert_eq!(len, unsigned, "{:?}", command);
+        }
+    }
+}
+
+#[test]
+fn signed_commands_fail_with_another_key_or_nonce() {
+    let request = Request::new(Command::Lock, &KEY, NONCE);
+
+    assert_eq!(
+        request.verify(&Key::new([0x24; 32]), NONCE),
+        Err(Error::BadTag)
+    );
+    assert_eq!(request.verify(&KEY, NONCE + 1), Err(Error::StaleNonce));
+    // Unsigned commands don't care about the nonce
+    assert_eq!(
+        Request::new(Command::Ping, &KEY, NONCE).verify(&KEY, 0),
+        Ok(Command::Ping)
+    );
+}
+
+#[test]
+fn a_tampered_command_fails_verification() {
+    let mut frame = encode(&Request::new(
+        Command::Unlock { duration_ms: 10 },
+        &KEY,
+        NONCE,
+    ));
+    // A longer unlock, with the CRC fixed up
+    frame[4] = 0xFF;
+    let last = frame.len() - 1;
+    frame[last] = crc8(&frame[..last]);
+
+    let request = Request::decode(&frame).unwrap();
+    assert_eq!(request.verify(&KEY, NONCE), Err(Error::BadTag));
+}
+
+#[test]
+fn unsigned_lock_commands_are_refused() {
+    let mut buf = [0u8; MAX_FRAME_LEN];
+    let len = encode_frame(0x01, &5000u32.to_le_bytes(), &mut buf).unwrap();
+    assert_eq!(Request::decode(&buf[..len]), Err(Error::Unauthenticated));
+
+    let len = encode_frame(0x02, &[], &mut buf).unwrap();
+    assert_eq!(Request::decode(&buf[..len]), Err(Error::Unauthenticated));
+}
+
+#[test]
+fn malformed_payloads_are_refused() {
+    let mut buf = [0u8; MAX_FRAME_LEN];
+
+    let len = encode_frame(0x04, &[0], &mut buf).unwrap();
+    assert_eq!(Request::decode(&buf[..len]), Err(Error::BadPayload));
+
+    let len = encode_frame(0x06, &[2], &mut buf).unwrap();
+    assert_eq!(Request::decode(&buf[..len]), Err(Error::BadPayload));
+
+    let len = encode_frame(0x7F, &[], &mut buf).unwrap();
+    assert_eq!(
+        Request::decode(&buf[..len]),
+        Err(Error::UnknownMessage(0x7F))
+    );
+}
+
+#[test]
+fn status_round_trips() {
+    let status = status();
+    let frame = encode(&status);
+    assert_eq!(frame.len(), Status::FRAME_LEN);
+    assert_eq!(Status::decode(&frame), Ok(status));
+
+    let fresh = Status {
+        locked: false,
+        last_command: None,
+        errors: ErrorFlags::empty(),
+        ..status
+    };
+    assert_eq!(Status::decode(&encode(&fresh)), Ok(fresh));
+}
+
+#[test]
+fn status_is_not_a_request() {
+    let frame = encode(&status());
+
+    assert_eq!(Request::decode(&frame), Err(Error::UnknownMessage(0x81)));
+    assert_eq!(
+        Status::decode(&encode(&Request::new(Command::Ping, &KEY, 0))),
+        Err(Error::UnknownMessage(0x04))
+    );
+}
+
+#[test]
+fn receiver_finds_frames_in_a_noisy_stream() {
+    let mut stream = vec![0x00, 0xA5, 0x13];
+    for command in COMMANDS {
+        stream.extend(encode(&Request::new(command, &KEY, NONCE)));
+    }
+    // A cut off frame, then one more
+    let ping = encode(&Request::new(Command::Ping, &KEY, NONCE));
+    stream.extend(&ping[..3]);
+    stream.extend(&ping);
+
+    let mut receiver = Receiver::<Request>::new();
+    let mut commands = Vec::new();
+    let mut errors = 0;
+    for chunk in stream.chunks(3) {
+        for &byte in chunk {
+            receiver.push(byte);
+        }
+        while let Some(result) = receiver.poll() {
+            match result {
+                Ok(request) => commands.push(request.verify(&KEY, NONCE).unwrap()),
+                Err(_) => errors += 1,
+            }
+        }
+    }
+
+    let mut expected = COMMANDS.to_vec();
+    expected.push(Command::Ping);
+    assert_eq!(commands, expected);
+    assert!(errors > 0);
+    assert!(receiver.poll_idle().is_none());
+}
+
+#[test]
+fn receiver_gives_up_on_a_partial_frame_when_idle() {
+    let frame = encode(&status());
+    let mut receiver = Receiver::<Status>::new();
+    for &byte in &frame[..10] {
+        receiver.push(byte);
+    }
+
+    assert_eq!(receiver.poll(), None);
+    assert_eq!(receiver.poll_idle(), Some(Err(Error::Truncated)));
+    assert_eq!(receiver.poll_idle(), None);
+
+    for &byte in &frame {
+        receiver.push(byte);
+    }
+    assert_eq!(receiver.poll(), Some(Ok(status())));
+}
+
+#[test]
+fn keys_parse_from_hex() {
+    let hex = "42".repeat(32);
+    assert!(Key::from_hex(&hex).is_some());
+    assert!(Key::from_hex(&hex[..62]).is_none());
+    assert!(Key::from_hex(&"4g".repeat(32)).is_none());
+    assert_eq!(format!("{:?}", KEY), "Key(..)");
+}
@@ -1,24 +1,73 @@
 #![feature(decl_macro)]
 
-use esp_idf_svc::hal::{delay::TickType, gpio::PinDriver, i2c, peripherals::Peripherals};
-use util::{result, tracing};
+use std::time::{Duration, Instant};
 
+use esp_idf_svc::{
+    hal::{
+        delay::TickType,
+        gpio::{InputPin, Output, OutputPin, PinDriver, Pull},
+        i2c,
+        peripherals::Peripherals,
+    },
+    sys::{self, esp},
+};
+use lock::Lock;
+use rfid_lock_protocol::{
+    slave_address, Command, ErrorFlags, Indicator, Key, LockPolicy, Message, Receiver, Request,
+    Status, MAX_FRAME_LEN,
+};
+use status::StatusBlock;
+use util::{
+    result::{self, error},
+    tracing,
+};
+
+mod lock;
+mod status;
 mod util;
 
+/// A frame is sent in one go, a pause this long means the rest of a partial frame isn't coming
+const BUS_IDLE_MS: u64 = 50;
+const STATUS_WRITE_TIMEOUT_MS: u64 = 10;
+/// The indicator blinks on and off this long while the master is enrolling cards
+const ENROLLING_BLINK: Duration = Duration::from_millis(250);
+
+#[cfg(not(feature = "fail-safe"))]
+const DEFAULT_POLICY: LockPolicy = LockPolicy::FailSecure;
+#[cfg(feature = "fail-safe")]
+const DEFAULT_POLICY: LockPolicy = LockPolicy::FailSafe;
+/// Keeps the coil from overheating if the master never sends a lock
+const DEFAULT_MAX_UNLOCK: Duration = Duration::from_secs(10);
+
+/// Provisioned at build time, the master has to be built with the same key
+const KEY: Key = match Key::from_hex(env!(
+    "RFID_LOCK_KEY",
+    "Set RFID_LOCK_KEY to the key shared with the master, 64 hex digits"
+)) {
+    Some(key) => key,
+    None => panic!("RFID_LOCK_KEY must be 64 hex digits"),
+};
+
 pub fn main() -> result::Result<()> {
     esp_idf_svc::sys::link_patches();
     tracing::init()?;
 
     let p = Peripherals::take()?;
+
+    let index = read_strap(p.pins.gpio27)? as u8
+        | (read_strap(p.pins.gpio14)? as u8) << 1
+        | (read_strap(p.pins.gpio13)? as u8) << 2;
+    let address = slave_address(index).ok_or_else(|| error!("No address for strap {}", index))?;
+    tracing::info!("Answering at {:#04x}", address);
+
     let sda_pin = p.pins.gpio32;
     let scl_pin = p.pins.gpio33;
-    let slave_addr = 0x04;
     let i2c = p.i2c0;
     let mut i2c_slave = i2c::I2cSlaveDriver::new(
         i2c,
         sda_pin,
         scl_pin,
-        slave_addr,
+        address,
         &i2c::config::SlaveConfig {
             rx_buf_len: 128,
             tx_buf_len: 128,
@@ -32,27 +81,141 @@ pub fn main() -> result::Result<()> {
     let indicator_led_pin = p.pins.gpio26;
     let mut indicator_led = PinDriver::output(indicator_led_pin)?;
 
-    solenoid_lock.set_low()?;
-    indicator_led.set_low()?;
+    let mut lock = Lock::new(DEFAULT_POLICY, DEFAULT_MAX_UNLOCK);
+    drive(&mut solenoid_lock, &lock)?;
+
+    let mut indicator = Indicator::default();
+    let mut indicator_since = Instant::now();
+
+    let mut receiver = Receiver::<Request>::new();
+    let mut status = StatusBlock::new();
 
     loop {
         let mut buff = [0u8; 1];
-        let timeout = TickType(1000);
+        let timeout = TickType::new_millis(BUS_IDLE_MS);
         let res = i2c_slave.read(&mut buff, timeout.into());
 
-        if let Ok(read) = res {
-            if read == 1 {
-                let value = buff[0];
-                tracing::info!("Value: {}", value);
-
-                if value == 1 {
-                    solenoid_lock.set_high()?;
-                    indicator_led.set_high()?;
-                } else {
-                    solenoid_lock.set_low()?;
-                    indicator_led.set_low()?;
+        if lock.expire(Instant::now()) {
+            tracing::info!("Unlock time is up, locking");
+            if let Err(e) = drive(&mut solenoid_lock, &lock) {
+                tracing::error!("Failed to drive the lock: {:?}", e);
+                status.record_error(ErrorFlags::OUTPUT_FAULT);
+            }
+        }
+
+        let idle = !matches!(res, Ok(1));
+        if !idle {
+            receiver.push(buff[0]);
+        }
+
+        while let Some(res) = if idle {
+            receiver.poll_idle()
+        } else {
+            receiver.poll()
+        } {
+            let request = match res {
+                Ok(request) => request,
+                Err(e) => {
+                    tracing::warn!("Invalid frame: {}", e);
+                    status.record_rejected(ErrorFlags::INVALID_FRAME);
+                    continue;
+                }
+            };
+
+            let command = match request.verify(&KEY, status.nonce()) {
+                Ok(command) => command,
+                Err(e) => {
+                    tracing::warn!("Rejected {:?}: {}", request.command(), e);
+                    status.record_rejected(ErrorFlags::AUTH_FAILED);
+                    continue;
+                }
+            };
+
+            tracing::info!("Command: {:?}", command);
+
+            let now = Instant::now();
+            match command {
+                Command::Unlock { duration_ms } => {
+                    let duration = lock.unlock(now, Duration::from_millis(duration_ms.into()));
+                    tracing::info!("Unlocked for {:?}", duration);
+                }
+                Command::Lock => lock.lock(),
+                Command::Configure {
+                    max_unlock_ms,
+                    policy,
+                } => lock.configure(now, policy, Duration::from_millis(max_unlock_ms.into())),
+                Command::Indicate { indicator: next } => {
+                    indicator = next;
+                    indicator_since = now;
                 }
+                Command::GetStatus | Command::Ping => {}
             }
+
+            if let Err(e) = drive(&mut solenoid_lock, &lock) {
+                tracing::error!("Failed to drive the lock: {:?}", e);
+                status.record_error(ErrorFlags::OUTPUT_FAULT);
+            }
+
+            status.record_command(&command);
+            // Read back from the pin, so a lock that failed to drive isn't reported as changed
+            let locked = solenoid_lock.is_set_high() == lock.policy().coil_powered(true);
+            if let Err(e) = publish_status(&mut i2c_slave, &status.take(locked)) {
+                tracing::error!("Failed to publish status: {:?}", e);
+            }
+        }
+
+        // Updated every pass, the read times out often enough to blink it
+        let elapsed = indicator_since.elapsed();
+        if indicate(&mut indicator_led, &lock, indicator, elapsed).is_err() {
+            status.record_error(ErrorFlags::OUTPUT_FAULT);
         }
     }
 }
+
+/// An address strap is set by tying its pin to ground
+fn read_strap(pin: impl InputPin + OutputPin) -> result::Result<bool> {
+    let mut strap = PinDriver::input(pin)?;
+    strap.set_pull(Pull::Up)?;
+
+    Ok(strap.is_low())
+}
+
+fn drive<S: OutputPin>(
+    solenoid_lock: &mut PinDriver<'_, S, Output>,
+    lock: &Lock,
+) -> result::Result<()> {
+    solenoid_lock.set_level(lock.coil_powered().into())?;
+
+    Ok(())
+}
+
+/// `elapsed` is the time since the indicator was switched, a blink starts lit
+fn indicate<L: OutputPin>(
+    indicator_led: &mut PinDriver<'_, L, Output>,
+    lock: &Lock,
+    indicator: Indicator,
+    elapsed: Duration,
+) -> result::Result<()> {
+    let lit = match indicator {
+        Indicator::Lock => !lock.is_locked(),
+        Indicator::Enrolling => (elapsed.as_millis() / ENROLLING_BLINK.as_millis()) % 2 == 0,
+    };
+    indicator_led.set_level(lit.into())?;
+
+    Ok(())
+}
+
+/// Queues the status for the master's next read
+fn publish_status(i2c_slave: &mut i2c::I2cSlaveDriver<'_>, status: &Status) -> result::Result<()> {
+    let mut buf = [0u8; MAX_FRAME_LEN];
+    let len = status.encode(&mut buf).map_err(|e| error!("{}", e))?;
+
+    // A status the master never read would be read instead of this one
+    esp!(unsafe { sys::i2c_reset_tx_fifo(i2c_slave.port()) })?;
+    i2c_slave.write(
+        &buf[..len],
+        TickType::new_millis(STATUS_WRITE_TIMEOUT_MS).into(),
+    )?;
+
+    Ok(())
+}
@@ -0,0 +1,71 @@
+use std::time::{Duration, Instant};
+
+use rfid_lock_protocol::LockPolicy;
+
+/// Longest the coil may be kept unlocked, whatever the master asks for
+pub const MAX_UNLOCK_LIMIT: Duration = Duration::from_secs(60);
+
+/// Whether the door should be locked. The pins are driven from it, the caller owns the clock.
+pub struct Lock {
+    policy: LockPolicy,
+    max_unlock: Duration,
+    /// Set while unlocked
+    relock_at: Option<Instant>,
+}
+
+impl Lock {
+    pub fn new(policy: LockPolicy, max_unlock: Duration) -> Self {
+        Self {
+            policy,
+            max_unlock: max_unlock.min(MAX_UNLOCK_LIMIT),
+            relock_at: None,
+        }
+    }
+
+    pub fn policy(&self) -> LockPolicy {
+        self.policy
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.relock_at.is_none()
+    }
+
+    /// Whether the coil should be powered
+    pub fn coil_powered(&self) -> bool {
+        self.policy.coil_powered(self.is_locked())
+    }
+
+    /// Unlocks for `duration` capped at the maximum, zero unlocks for the maximum. Returns how
+    /// long it stays unlocked.
+    pub fn unlock(&mut self, now: Instant, duration: Duration) -> Duration {
+        let duration = if duration.is_zero() {
+            self.max_unlock
+        } else {
+            duration.min(self.max_unlock)
+        };
+        self.relock_at = Some(now + duration);
+
+        duration
+    }
+
+    pub fn lock(&mut self) {
+        self.relock_at = None;
+    }
+
+    /// A shorter maximum also shortens the current unlock
+    pub fn configure(&mut self, now: Instant, policy: LockPolicy, max_unlock: Duration) {
+        self.policy = policy;
+        self.max_unlock = max_unlock.min(MAX_UNLOCK_LIMIT);
+        self.relock_at = self.relock_at.map(|at| at.min(now + self.max_unlock));
+    }
+
+    /// Relocks once the unlock time is up, returns whether it did
+    pub fn expire(&mut self, now: Instant) -> bool {
+        if self.relock_at.is_some_and(|at| now >= at) {
+            self.relock_at = None;
+            return true;
+        }
+
+        false
+    }
+}
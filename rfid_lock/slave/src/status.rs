@@ -0,0 +1,91 @@
+use std::time::Instant;
+
+use esp_idf_svc::sys;
+use rfid_lock_protocol::{Command, ErrorFlags, FirmwareVersion, LastCommand, Status};
+
+/// What the slave reports to the master after each command
+pub struct StatusBlock {
+    booted: Instant,
+    last_command: Option<(u8, Instant)>,
+    errors: ErrorFlags,
+    rejected_frames: u16,
+    /// Signed commands are only accepted for the nonce in the last status
+    nonce: u32,
+}
+
+impl StatusBlock {
+    pub fn new() -> Self {
+        // Without Wi-Fi or Bluetooth running the hardware RNG only stretches its seed, and the
+        // nonces could repeat from one boot to the next. The ADC noise this samples is real
+        // entropy, it's left on for good since the slave uses neither the ADC nor I2S.
+        unsafe { sys::bootloader_random_enable() };
+
+        Self {
+            booted: Instant::now(),
+            last_command: None,
+            errors: ErrorFlags::empty(),
+            rejected_frames: 0,
+            nonce: random_nonce(),
+        }
+    }
+
+    pub fn nonce(&self) -> u32 {
+        self.nonce
+    }
+
+    pub fn record_command(&mut self, command: &Command) {
+        self.last_command = Some((command.id(), Instant::now()));
+    }
+
+    pub fn record_error(&mut self, error: ErrorFlags) {
+        self.errors.insert(error);
+    }
+
+    pub fn record_rejected(&mut self, error: ErrorFlags) {
+        self.record_error(error);
+        self.rejected_frames = self.rejected_frames.wrapping_add(1);
+    }
+
+    /// The status as of now, errors are only reported once. Hands out a new nonce, a command
+    /// signed for an earlier one is a replay.
+    ///
+    /// Unsigned commands like `GetStatus` rotate the nonce too, so anything on the bus can keep
+    /// the master's signed commands failing as stale by polling the slave. Nothing on the bus
+    /// is trusted to unlock, but it can deny service.
+    pub fn take(&mut self, locked: bool) -> Status {
+        self.nonce = random_nonce();
+
+        let status = Status {
+            locked,
+            last_command: self.last_command.map(|(id, at)| LastCommand {
+                id,
+                elapsed_ms: at.elapsed().as_millis().try_into().unwrap_or(u32::MAX),
+            }),
+            uptime_secs: self
+                .booted
+                .elapsed()
+                .as_secs()
+                .try_into()
+                .unwrap_or(u32::MAX),
+            errors: self.errors,
+            rejected_frames: self.rejected_frames,
+            nonce: self.nonce,
+            firmware: firmware_version(),
+        };
+        self.errors = ErrorFlags::empty();
+
+        status
+    }
+}
+
+fn random_nonce() -> u32 {
+    unsafe { sys::esp_random() }
+}
+
+fn firmware_version() -> FirmwareVersion {
+    FirmwareVersion {
+        major: env!("CARGO_PKG_VERSION_MAJOR").parse().unwrap_or(0),
+        minor: env!("CARGO_PKG_VERSION_MINOR").parse().unwrap_or(0),
+        patch: env!("CARGO_PKG_VERSION_PATCH").parse().unwrap_or(0),
+    }
+}